@@ -1,21 +1,40 @@
-use byteorder::{LittleEndian, ReadBytesExt};
 use clap::Parser;
-use log::info;
-use ndarray::{Array, Array0, Array3};
-use ndarray_npy::{NpzReader, NpzWriter};
+use log::{error, info, warn};
+use memmap2::Mmap;
+use ndarray::{Array, Array0, Array1, Array2, Array3, ArrayView3, Axis, Ix0, Ix1};
+use ndarray_npy::{NpzReader, NpzWriter, ReadableElement, ViewNpyExt, WritableElement};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
 use std::{
     error::Error,
     fs::{File, OpenOptions},
+    io::BufRead,
     io::BufReader,
     io::BufWriter,
-    path::PathBuf,
+    io::Read,
+    path::{Path, PathBuf},
 };
 
 use compressed_dynamic_word_embeddings::{
-    embedding_file::{builder::write_compressed_dwe_file, EmbeddingFile, FileHeader, HEADER_SIZE},
-    tensors::RankThreeTensor,
+    embedding_file::{
+        builder::{
+            append_timesteps, write_compressed_dwe_file_from_float,
+            write_compressed_dwe_file_streaming, write_compressed_dwe_file_with_distortion,
+            write_compressed_dwe_file_with_distortion_and_stream_overhead,
+            write_compressed_dwe_file_with_options, write_compressed_dwe_file_with_stream_overhead,
+            write_compressed_dwe_file_with_target_size, CompressionOptions, SizeTarget,
+        },
+        file_bytes::FileBytes,
+        model_contexts::ModelContexts,
+        model_groups::ModelSharing,
+        predictor::PredictionScheme,
+        quantization::{QuantizationOptions, QuantizationStep},
+        scale_factors::ScaleFactors,
+        symbol::{Symbol, SymbolType},
+        timestep_labels::{TimestepLabel, TimestepLabels},
+        EmbeddingFile, EntropyPrecision, FileHeader, HEADER_SIZE_V2,
+    },
+    tensors::{RankThreeTensor, RankThreeTensorView, RankTwoTensor},
 };
 
 #[derive(Parser, Debug)]
@@ -34,6 +53,10 @@ enum Args {
 
     /// Prints out the file header of a compressed dynamic word embedding file.
     Inspect(InspectArgs),
+
+    /// Appends new time steps to a compressed dynamic embedding file without
+    /// recompressing the existing time steps.
+    Append(AppendArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -45,16 +68,183 @@ struct CreateArgs {
     #[arg(long, short = 'C', default_value = "100")]
     jump_interval: u32,
 
+    /// Precision of the entropy models in bits (12, 16, or 24). Higher precisions
+    /// can improve the compression rate for time steps with a peaked distribution
+    /// or many rare residuals, and they are required if a time step has more than
+    /// 4096 distinct residuals. Precisions other than 12 bits require version 2 of
+    /// the file format.
+    #[arg(long, default_value = "12", value_parser = parse_entropy_precision)]
+    entropy_precision: EntropyPrecision,
+
+    /// Integer type of the quantized values in `uncompressed_quantized` ("i8",
+    /// "i16", or "i32"), which the compressed file stores as well. Types other than
+    /// "i16" require version 2 of the file format. Narrower types decode into
+    /// smaller buffers, and wider types allow for finer quantization.
+    #[arg(
+        long,
+        default_value = "i16",
+        value_parser = parse_symbol_type,
+        conflicts_with_all = ["from_float", "streaming"]
+    )]
+    symbol_type: SymbolType,
+
+    /// Compress lossily, trading off the squared error of each value against the
+    /// number of bits it takes up with the provided factor. Larger factors lead to
+    /// smaller files and larger errors. Errors are measured in the real numbers
+    /// that the quantized values represent.
+    #[arg(long)]
+    rate_distortion_tradeoff: Option<f32>,
+
+    /// Insert a keyframe every this many time steps. Keyframes are compressed
+    /// independently of all other time steps, which bounds the time it takes to
+    /// decode any single time step at the cost of a slightly larger file. By
+    /// default, only the first and the last time step are keyframes.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    keyframe_interval: Option<u32>,
+
+    /// How each time step gets predicted from the two time steps it is bisected
+    /// from: "mean" (their integer mean), "distance" (linear interpolation by
+    /// distance in time), "least-squares" (weights fitted per level of the
+    /// bisection tree), or "previous" (the earlier time step only). Predictors other
+    /// than "mean" store their weights in the file.
+    #[arg(long, default_value = "mean", value_parser = parse_prediction_scheme)]
+    predictor: PredictionScheme,
+
+    /// Split the vocabulary into this many ranges of consecutive word IDs with
+    /// separate entropy models. This can improve the compression rate if frequent
+    /// and rare words (sorted by frequency) have differently distributed residuals,
+    /// but each additional entropy model takes up space in the file.
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    word_ranges: u32,
+
+    /// Split the embedding dimensions into this many groups of consecutive
+    /// dimensions with separate entropy models, similar to --word-ranges.
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    dimension_buckets: u32,
+
+    /// Which time steps share their entropy models: "never" (each time step has its
+    /// own), "tree-levels" (time steps at the same level of the bisection tree share
+    /// them), or "auto" (share by tree level if this makes the file smaller).
+    /// Sharing pays off for small vocabularies or many time steps.
+    #[arg(long, default_value = "never", value_parser = parse_model_sharing)]
+    share_models: ModelSharing,
+
+    /// Store the jump table in a compact form, which makes small values of
+    /// --jump-interval affordable. The resulting file requires a reader that
+    /// supports version 1.3 (or 2.2) of the file format.
+    #[arg(long)]
+    compact_jump_table: bool,
+
+    /// Encode each time step into its own ANS stream so that time steps can be
+    /// compressed independently of each other. The resulting file can be read by
+    /// any reader.
+    #[arg(long)]
+    independent_streams: bool,
+
     /// Path to output file [defaults to input file with extension replaced by
     /// ".dwe"].
     #[arg(long, short)]
     output: Option<PathBuf>,
 
+    /// Path to a text file with one word per line, ordered by word ID. If provided,
+    /// the vocabulary gets stored in the compressed file so that other commands
+    /// (and the web app) can refer to words by their spelling rather than by ID.
+    /// The number of lines must match the vocabulary size.
+    #[arg(long)]
+    vocab: Option<PathBuf>,
+
+    /// Name of a rank-one integer tensor in the input file (e.g., "years") that
+    /// contains one label per time step. If provided, the labels get stored in the
+    /// compressed file so that other tools can refer to time steps by their labels.
+    /// The labels must be distinct.
+    #[arg(long)]
+    timestep_labels: Option<String>,
+
+    /// Name of a rank-two float32 tensor in the input file (e.g., "scale_factors")
+    /// with scale factors that refine `scale_factor` per time step and/or per
+    /// embedding dimension. Its shape must be `(num_timesteps, embedding_dim)`,
+    /// `(num_timesteps, 1)`, or `(1, embedding_dim)`, i.e., it gets broadcast like
+    /// in numpy. A quantized value `u` at time step `t` and dimension `d`
+    /// represents the real number `scale_factor * scale_factors[t, d] * u`.
+    #[arg(long)]
+    scale_factors: Option<String>,
+
+    /// Read real valued embeddings from a rank-three tensor `embeddings` with dtype
+    /// `numpy.float32` (instead of `uncompressed_quantized` and `scale_factor`) and
+    /// quantize them before compression. If --scale-factors is provided, each
+    /// value gets divided by its scale factor before quantization.
+    #[arg(long)]
+    from_float: bool,
+
+    /// Quantization step for --from-float. Defaults to the standard deviation of
+    /// all values times --relative-quantization-step.
+    #[arg(
+        long,
+        requires = "from_float",
+        conflicts_with = "relative_quantization_step"
+    )]
+    quantization_step: Option<f32>,
+
+    /// Quantization step for --from-float relative to the standard deviation of all
+    /// values [default: 0.05]. The step is automatically increased if necessary to
+    /// fit the quantized values into the file format.
+    #[arg(long, requires = "from_float")]
+    relative_quantization_step: Option<f32>,
+
+    /// Seed for dithering with --from-float. If provided, a pseudorandom offset
+    /// gets added to each value before rounding it to the nearest multiple of the
+    /// quantization step.
+    #[arg(long, requires = "from_float")]
+    dither_seed: Option<u64>,
+
+    /// Maximum size of the output file in bytes for --from-float. Chooses the
+    /// smallest quantization step with which the file fits.
+    #[arg(
+        long,
+        requires = "from_float",
+        conflicts_with_all = [
+            "quantization_step",
+            "relative_quantization_step",
+            "target_bits_per_coordinate",
+        ]
+    )]
+    target_size: Option<u64>,
+
+    /// Maximum size of the output file for --from-float in bits per embedding
+    /// vector component. Chooses the smallest quantization step with which the
+    /// file fits.
+    #[arg(
+        long,
+        requires = "from_float",
+        conflicts_with_all = ["quantization_step", "relative_quantization_step"]
+    )]
+    target_bits_per_coordinate: Option<f64>,
+
+    /// Compress without loading the whole tensor into memory. The input file must
+    /// then be an uncompressed `.npy` file with a rank-three tensor of dtype
+    /// `numpy.int16` (create with `np.save('filename.npy', uncompressed_quantized)`),
+    /// which gets memory mapped and read one time step at a time. Intermediate
+    /// results get written to a temporary file next to the output file.
+    #[arg(
+        long,
+        requires = "scale_factor",
+        conflicts_with_all = ["from_float", "timestep_labels", "scale_factors"]
+    )]
+    streaming: bool,
+
+    /// Scale factor for --streaming (which can't read `scale_factor` from an `.npz`
+    /// file).
+    #[arg(long, requires = "streaming")]
+    scale_factor: Option<f32>,
+
     /// Path to a `.npz` file containing a rank-three tensor `uncompressed_quantized`
-    /// with dtype `numpy.int16` and a 32-bit precision float scalar value
+    /// with dtype `numpy.int16` (or the dtype that corresponds to --symbol-type) and
+    /// a 32-bit precision float scalar value
     /// `scale_factor` (which is typically < 1). Create with:
     /// `np.savez_compressed('filename.npz', scale_factor=scale_factor,
-    /// uncompressed_quantized=uncompressed_quantized)`.
+    /// uncompressed_quantized=uncompressed_quantized)`. With --from-float, the
+    /// file has to contain a tensor `embeddings` instead. With --streaming, the path
+    /// to a `.npy` file instead.
     input: PathBuf,
 }
 
@@ -71,16 +261,18 @@ struct DecodeArgs {
 
 #[derive(Parser, Debug)]
 struct PairwiseTrajectoriesArgs {
-    /// Space separated list of zero based word IDs. For each word in the list, the
-    /// program calculates the trajectory of its cosine similarity with the
-    /// corresponding word (at the same index in the list) provided with --words2.
+    /// Space separated list of words. For each word in the list, the program
+    /// calculates the trajectory of its cosine similarity with the corresponding
+    /// word (at the same index in the list) provided with --words2. Words are looked
+    /// up in the vocabulary stored in the file; if the file contains no vocabulary
+    /// then words have to be provided as zero based word IDs.
     #[arg(long)]
-    words1: Vec<u32>,
+    words1: Vec<String>,
 
-    /// Space separated list of zero based word IDs. Must have the same length as
-    /// --words1.
+    /// Space separated list of words or word IDs (see --words1). Must have the same
+    /// length as --words1.
     #[arg(long)]
-    words2: Vec<u32>,
+    words2: Vec<String>,
 
     /// Path to a compressed dynamic word embeddings file. Separate from the word
     /// lists with " -- " or provide this argument first.
@@ -93,21 +285,61 @@ struct InspectArgs {
     input: PathBuf,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+#[derive(Parser, Debug)]
+struct AppendArgs {
+    /// Path to output file [defaults to the existing file with extension replaced
+    /// by ".appended.dwe"].
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// Name of a rank-one integer tensor in the `.npz` file with one label per new
+    /// time step. Required if (and only if) the existing file has time step labels.
+    #[arg(long)]
+    timestep_labels: Option<String>,
+
+    /// Name of a rank-two float32 tensor in the `.npz` file with scale factors for
+    /// the new time steps (see `create --scale-factors`). Required if the existing
+    /// file has scale factors that depend on the time step. Otherwise, the new time
+    /// steps use the existing scale factors by default.
+    #[arg(long)]
+    scale_factors: Option<String>,
+
+    /// Path to an existing `.dwe` file.
+    existing: PathBuf,
+
+    /// Path to a `.npz` file containing a rank-three tensor `uncompressed_quantized`
+    /// that holds the new time steps. Its dtype must match the symbol type of the
+    /// existing file (`numpy.int16` unless it was created with --symbol-type). They must have the
+    /// same vocabulary size and embedding dimension as the existing file and must be
+    /// quantized with the same scale factor. If the `.npz` file contains a
+    /// `scale_factor`, then it is checked against the existing file.
+    input: PathBuf,
+}
+
+fn main() {
     let args = Args::parse();
 
     stderrlog::new()
         .verbosity(2)
         .timestamp(stderrlog::Timestamp::Second)
-        .init()?;
+        .init()
+        .expect("logger is initialized only once");
 
-    match args {
+    let result = match args {
         Args::Create(create_args) => create(create_args),
         Args::Decode(decode_args) => decode(decode_args),
         Args::PairwiseTrajectories(pairwise_trajectories_args) => {
             pairwise_trajectories(pairwise_trajectories_args)
         }
         Args::Inspect(inspect_args) => inspect(inspect_args),
+        Args::Append(append_args) => append(append_args),
+    };
+
+    // Print errors with their `Display` implementation, which (unlike `Debug`)
+    // explains the problem in human readable form.
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(1);
     }
 }
 
@@ -123,6 +355,10 @@ fn create(mut args: CreateArgs) -> Result<(), Box<dyn Error>> {
         .create_new(true)
         .open(&output_path)?;
 
+    if args.streaming {
+        return create_streaming(args, &output_path, output_file);
+    }
+
     info!(
         "Loading uncompressed tensor from file at {} ...",
         args.input.display()
@@ -130,46 +366,286 @@ fn create(mut args: CreateArgs) -> Result<(), Box<dyn Error>> {
 
     let mut npz_reader = NpzReader::new(File::open(&args.input)?)?;
 
-    let uncompressed: Array3<i16> = npz_reader.by_name("uncompressed_quantized.npy")?;
-    if !uncompressed.is_standard_layout() {
-        Err("Tensor `uncompressed_quantized` must be stored in standard layout.")?;
-    }
-    let (num_timesteps, vocab_size, embedding_dim) = uncompressed.dim();
-    info!(
-        "Found `uncompressed_quantized` tensor with {} time steps, \
-            vocabulary size {}, and embedding dimension {}.",
-        num_timesteps, vocab_size, embedding_dim
-    );
-    let (uncompressed, offset) = uncompressed.into_raw_vec_and_offset();
-    assert_eq!(offset, Some(0));
-    let uncompressed =
-        RankThreeTensor::from_flattened(uncompressed, num_timesteps, vocab_size, embedding_dim);
+    let embeddings = if args.from_float {
+        Embeddings::Float(read_rank_three_tensor(&mut npz_reader, "embeddings")?)
+    } else {
+        let name = "uncompressed_quantized";
+        let uncompressed = match args.symbol_type {
+            SymbolType::I8 => Quantized::I8(read_rank_three_tensor(&mut npz_reader, name)?),
+            SymbolType::I16 => Quantized::I16(read_rank_three_tensor(&mut npz_reader, name)?),
+            SymbolType::I32 => Quantized::I32(read_rank_three_tensor(&mut npz_reader, name)?),
+        };
+        let scale_factor: Array0<f32> = npz_reader.by_name("scale_factor.npy")?;
+        let scale_factor = scale_factor.into_scalar();
+        info!("scale_factor = {}", scale_factor);
+        Embeddings::Quantized(uncompressed, scale_factor)
+    };
+    let (num_timesteps, vocab_size, embedding_dim) = match &embeddings {
+        Embeddings::Quantized(uncompressed, _) => uncompressed.shape(),
+        Embeddings::Float(embeddings) => embeddings.as_view().shape(),
+    };
 
-    let scale_factor: Array0<f32> = npz_reader.by_name("scale_factor.npy")?;
-    let scale_factor = scale_factor.into_scalar();
-    info!("scale_factor = {}", scale_factor);
+    let timestep_labels = args
+        .timestep_labels
+        .map(|name| -> Result<_, Box<dyn Error>> {
+            let labels = read_integer_vector(&mut npz_reader, &format!("{}.npy", name))?;
+            if labels.len() != num_timesteps {
+                Err(format!(
+                    "Found {} time step labels but there are {} time steps.",
+                    labels.len(),
+                    num_timesteps
+                ))?;
+            }
+            info!(
+                "Found time step labels from {} to {}.",
+                labels[0],
+                labels[labels.len() - 1]
+            );
+            Ok(TimestepLabels::from_integers(labels)?)
+        })
+        .transpose()?;
+
+    let scale_factors = args
+        .scale_factors
+        .map(|name| -> Result<_, Box<dyn Error>> {
+            let scale_factors: Array2<f32> = npz_reader.by_name(&format!("{}.npy", name))?;
+            let (num_rows, num_columns) = scale_factors.dim();
+            if (num_rows != 1 && num_rows != num_timesteps)
+                || (num_columns != 1 && num_columns != embedding_dim)
+            {
+                Err(format!(
+                    "Scale factors have shape ({}, {}) but there are {} time steps \
+                        and the embedding dimension is {}.",
+                    num_rows, num_columns, num_timesteps, embedding_dim
+                ))?;
+            }
+            info!(
+                "Found scale factors with shape ({}, {}).",
+                num_rows, num_columns
+            );
+            Ok(ScaleFactors::from_flattened(
+                scale_factors.iter().cloned().collect(),
+                num_rows,
+                num_columns,
+            ))
+        })
+        .transpose()?;
 
     std::mem::drop(npz_reader);
 
+    let vocab = args
+        .vocab
+        .map(|path| read_vocab(&path, vocab_size))
+        .transpose()?;
+
     info!(
         "Building compressed representation and saving to {}...",
         output_path.display()
     );
 
     let output_file = BufWriter::new(output_file);
-    write_compressed_dwe_file(
-        uncompressed.as_view(),
-        args.jump_interval,
-        scale_factor,
-        output_file,
-    )
-    .map_err(|()| "Error when compressing file")?;
+    let mut options = CompressionOptions::new(args.jump_interval, 1.0);
+    options.entropy_precision = args.entropy_precision;
+    options.scale_factors = scale_factors;
+    options.rate_distortion_tradeoff = args.rate_distortion_tradeoff;
+    options.keyframe_interval = args.keyframe_interval;
+    options.prediction_scheme = args.predictor;
+    options.model_sharing = args.share_models;
+    options.compact_jump_table = args.compact_jump_table;
+    options.independent_streams = args.independent_streams;
+    options.model_contexts = model_contexts(
+        args.word_ranges,
+        args.dimension_buckets,
+        vocab_size,
+        embedding_dim,
+    )?;
+    match embeddings {
+        Embeddings::Quantized(uncompressed, scale_factor) => {
+            options.scale_factor = scale_factor;
+            let (vocab, timestep_labels) = (vocab.as_deref(), timestep_labels.as_ref());
+            match uncompressed {
+                Quantized::I8(uncompressed) => write_quantized(
+                    uncompressed.as_view(),
+                    vocab,
+                    timestep_labels,
+                    &options,
+                    output_file,
+                ),
+                Quantized::I16(uncompressed) => write_quantized(
+                    uncompressed.as_view(),
+                    vocab,
+                    timestep_labels,
+                    &options,
+                    output_file,
+                ),
+                Quantized::I32(uncompressed) => write_quantized(
+                    uncompressed.as_view(),
+                    vocab,
+                    timestep_labels,
+                    &options,
+                    output_file,
+                ),
+            }?;
+        }
+        Embeddings::Float(embeddings) => {
+            let target = match (args.target_size, args.target_bits_per_coordinate) {
+                (Some(size), _) => Some(SizeTarget::FileSize(size)),
+                (None, Some(bits)) => Some(SizeTarget::BitsPerCoordinate(bits)),
+                (None, None) => None,
+            };
+            if let Some(target) = target {
+                let report = write_compressed_dwe_file_with_target_size(
+                    embeddings.as_view(),
+                    vocab.as_deref(),
+                    timestep_labels.as_ref(),
+                    target,
+                    args.dither_seed,
+                    &options,
+                    output_file,
+                )?;
+                let quantization = &report.quantization;
+                info!(
+                    "Wrote {} bytes ({:.3} bits per coordinate) with quantization step \
+                        (scale_factor) {}: mean squared error {}, signal to noise ratio \
+                        {:.1} dB.",
+                    report.file_size,
+                    report.bits_per_coordinate,
+                    quantization.scale_factor,
+                    quantization.mean_squared_error,
+                    10.0 * (quantization.mean_squared_value / quantization.mean_squared_error)
+                        .log10()
+                );
+                for (t, (rms_error, bits)) in quantization
+                    .rms_errors
+                    .iter()
+                    .zip(&report.timestep_bits)
+                    .enumerate()
+                {
+                    info!(
+                        "Time step {}: RMS error {}, about {} compressed bytes.",
+                        t,
+                        rms_error,
+                        (bits / 8.0).round()
+                    );
+                }
+            } else {
+                let step = match args.quantization_step {
+                    Some(step) => QuantizationStep::Fixed(step),
+                    None => QuantizationStep::RelativeToStdDev(
+                        args.relative_quantization_step.unwrap_or(0.05),
+                    ),
+                };
+                let mut quantization = QuantizationOptions::new(step);
+                quantization.dither_seed = args.dither_seed;
+                let (_, report) = write_compressed_dwe_file_from_float(
+                    embeddings.as_view(),
+                    vocab.as_deref(),
+                    timestep_labels.as_ref(),
+                    &quantization,
+                    &options,
+                    output_file,
+                )?;
+                info!(
+                    "Quantized with step (scale_factor) {}: mean squared error {}, \
+                        max absolute error {}, signal to noise ratio {:.1} dB.",
+                    report.scale_factor,
+                    report.mean_squared_error,
+                    report.max_abs_error,
+                    10.0 * (report.mean_squared_value / report.mean_squared_error).log10()
+                );
+            }
+        }
+    }
 
     info!("Done.");
     Ok(())
 }
 
-fn decode(mut args: DecodeArgs) -> Result<(), Box<dyn Error>> {
+/// Compresses already quantized embeddings (see `Embeddings::Quantized`).
+fn write_quantized<T: Symbol>(
+    uncompressed: RankThreeTensorView<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    output_file: BufWriter<File>,
+) -> Result<(), Box<dyn Error>> {
+    let (distortion, stream_overhead) = match (
+        options.rate_distortion_tradeoff.is_some(),
+        options.independent_streams,
+    ) {
+        (true, true) => {
+            let (_, distortion, stream_overhead) =
+                write_compressed_dwe_file_with_distortion_and_stream_overhead(
+                    uncompressed,
+                    vocab,
+                    timestep_labels,
+                    options,
+                    output_file,
+                )?;
+            (Some(distortion), Some(stream_overhead))
+        }
+        (true, false) => {
+            let (_, distortion) = write_compressed_dwe_file_with_distortion(
+                uncompressed,
+                vocab,
+                timestep_labels,
+                options,
+                output_file,
+            )?;
+            (Some(distortion), None)
+        }
+        (false, true) => {
+            let (_, stream_overhead) = write_compressed_dwe_file_with_stream_overhead(
+                uncompressed,
+                vocab,
+                timestep_labels,
+                options,
+                output_file,
+            )?;
+            (None, Some(stream_overhead))
+        }
+        (false, false) => {
+            write_compressed_dwe_file_with_options(
+                uncompressed,
+                vocab,
+                timestep_labels,
+                options,
+                output_file,
+            )?;
+            (None, None)
+        }
+    };
+
+    if let Some(report) = distortion {
+        info!(
+            "Lossy compression: mean squared error {}, max absolute error {}, \
+                signal to noise ratio {:.1} dB.",
+            report.mean_squared_error,
+            report.max_abs_error,
+            10.0 * (report.mean_squared_value / report.mean_squared_error).log10()
+        );
+    }
+    if let Some(report) = stream_overhead {
+        info!(
+            "Independent streams: {} streams, {} bits of compressed data ({:+} bits \
+                compared to a single continuous stream).",
+            report.num_streams,
+            report.compressed_bits,
+            report.overhead_bits()
+        );
+    }
+    Ok(())
+}
+
+fn decode(args: DecodeArgs) -> Result<(), Box<dyn Error>> {
+    match read_header(&args.input)?.symbol_type {
+        SymbolType::I8 => decode_typed::<i8>(args),
+        SymbolType::I16 => decode_typed::<i16>(args),
+        SymbolType::I32 => decode_typed::<i32>(args),
+    }
+}
+
+fn decode_typed<T: Symbol + WritableElement>(mut args: DecodeArgs) -> Result<(), Box<dyn Error>> {
     // Fail early if we can't open output file (e.g., if it already exists).
     let output_path = args.output.take().unwrap_or_else(|| {
         let mut output_path = args.input.clone();
@@ -186,8 +662,7 @@ fn decode(mut args: DecodeArgs) -> Result<(), Box<dyn Error>> {
         "Opening compressed dynamic embeddings file at {} ...",
         args.input.display()
     );
-    let file = BufReader::new(File::open(args.input)?);
-    let embedding_file = EmbeddingFile::from_reader(file).map_err(|()| "Error loading file.")?;
+    let embedding_file = open_embedding_file::<T>(&args.input)?;
     let header = embedding_file.header();
     println!("{:#?}", header);
 
@@ -195,6 +670,19 @@ fn decode(mut args: DecodeArgs) -> Result<(), Box<dyn Error>> {
     let vocab_size = header.vocab_size;
     let embedding_dim = header.embedding_dim;
     let scale_factor = header.scale_factor;
+    let scale_factors = embedding_file.scale_factors().map(|scale_factors| {
+        Array::from_shape_vec(scale_factors.shape(), scale_factors.as_slice().to_vec())
+            .expect("size and shape match by construction")
+    });
+    let timestep_labels = embedding_file.timestep_labels().map(|labels| {
+        labels
+            .iter()
+            .map(|label| match label {
+                TimestepLabel::Integer(label) => Some(label),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+    });
 
     info!("Decoding .dwe file...");
 
@@ -205,9 +693,9 @@ fn decode(mut args: DecodeArgs) -> Result<(), Box<dyn Error>> {
     let reader = embedding_file.into_random_access_reader();
     let uncompressed = (0..num_timesteps)
         .into_par_iter()
-        .map(|t| reader.get_embeddings_at(t).into_inner())
-        .flatten()
-        .collect::<Vec<_>>();
+        .map(|t| reader.get_embeddings_at(t).map(RankTwoTensor::into_inner))
+        .collect::<Result<Vec<_>, _>>()?
+        .concat();
     let uncompressed = Array::from_shape_vec(
         (
             num_timesteps as usize,
@@ -227,6 +715,18 @@ fn decode(mut args: DecodeArgs) -> Result<(), Box<dyn Error>> {
     let scale_factor =
         Array::from_shape_vec((), vec![scale_factor]).expect("scalars have shape `()`");
     npz_writer.add_array("scale_factor.npy", &scale_factor)?;
+    if let Some(scale_factors) = scale_factors {
+        info!("Writing scale factors to tensor `scale_factors`.");
+        npz_writer.add_array("scale_factors.npy", &scale_factors)?;
+    }
+    match timestep_labels {
+        Some(Some(timestep_labels)) => {
+            info!("Writing time step labels to tensor `timestep_labels`.");
+            npz_writer.add_array("timestep_labels.npy", &Array::from(timestep_labels))?;
+        }
+        Some(None) => warn!("Time step labels are not integers; they won't be exported."),
+        None => {}
+    }
 
     std::mem::drop(npz_writer);
 
@@ -235,18 +735,30 @@ fn decode(mut args: DecodeArgs) -> Result<(), Box<dyn Error>> {
 }
 
 fn pairwise_trajectories(args: PairwiseTrajectoriesArgs) -> Result<(), Box<dyn Error>> {
+    match read_header(&args.input)?.symbol_type {
+        SymbolType::I8 => pairwise_trajectories_typed::<i8>(args),
+        SymbolType::I16 => pairwise_trajectories_typed::<i16>(args),
+        SymbolType::I32 => pairwise_trajectories_typed::<i32>(args),
+    }
+}
+
+fn pairwise_trajectories_typed<T: Symbol>(
+    args: PairwiseTrajectoriesArgs,
+) -> Result<(), Box<dyn Error>> {
     info!(
         "Loading compressed dynamic embeddings from {} ...",
         args.input.display()
     );
-    let file = BufReader::new(File::open(args.input)?);
-    let embedding_file = EmbeddingFile::from_reader(file).map_err(|()| "Error loading file.")?;
+    let embedding_file = open_embedding_file::<T>(&args.input)?;
+
+    let words1 = resolve_words(&embedding_file, &args.words1)?;
+    let words2 = resolve_words(&embedding_file, &args.words2)?;
 
     info!("Calculating trajectories ...");
 
     let trajectories = embedding_file
         .into_random_access_reader()
-        .pairwise_trajectories(args.words1, args.words2);
+        .pairwise_trajectories(words1, words2)?;
 
     println!("[");
     for trajectory in trajectories.as_view().iter_subviews() {
@@ -259,18 +771,407 @@ fn pairwise_trajectories(args: PairwiseTrajectoriesArgs) -> Result<(), Box<dyn E
     Ok(())
 }
 
+/// Memory maps a compressed dynamic word embeddings file with symbol type `T`
+/// instead of reading it into memory, so that loading is fast even for large files.
+fn open_embedding_file<T: Symbol>(
+    path: &Path,
+) -> Result<EmbeddingFile<FileBytes<Mmap>, T>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let mmap = unsafe {
+        // SAFETY: This is only unsafe if some other process modifies the file while
+        // we're reading it, which would be a bug in any case.
+        Mmap::map(&file)?
+    };
+    Ok(EmbeddingFile::from_bytes_typed(mmap)?)
+}
+
+/// Reads only the header of a compressed dynamic word embeddings file, e.g., to
+/// find out its symbol type before opening it.
+fn read_header(path: &Path) -> Result<FileHeader, Box<dyn Error>> {
+    // Read enough for the header of any version of the file format.
+    let mut buf = Vec::new();
+    File::open(path)?
+        .take(4 * HEADER_SIZE_V2 as u64)
+        .read_to_end(&mut buf)?;
+    Ok(FileHeader::from_le_bytes(&buf)?)
+}
+
+/// The input tensor of the `create` subcommand.
+enum Embeddings {
+    /// Already quantized embeddings and their scale factor.
+    Quantized(Quantized, f32),
+
+    /// Real valued embeddings that still need to be quantized.
+    Float(RankThreeTensor<f32>),
+}
+
+/// Quantized embeddings with the integer type that `--symbol-type` selects.
+enum Quantized {
+    I8(RankThreeTensor<i8>),
+    I16(RankThreeTensor<i16>),
+    I32(RankThreeTensor<i32>),
+}
+
+impl Quantized {
+    fn shape(&self) -> (usize, usize, usize) {
+        match self {
+            Quantized::I8(tensor) => tensor.as_view().shape(),
+            Quantized::I16(tensor) => tensor.as_view().shape(),
+            Quantized::I32(tensor) => tensor.as_view().shape(),
+        }
+    }
+}
+
+/// Compresses a memory mapped `.npy` file one time step at a time (see `--streaming`).
+fn create_streaming(
+    args: CreateArgs,
+    output_path: &Path,
+    output_file: File,
+) -> Result<(), Box<dyn Error>> {
+    info!(
+        "Memory mapping uncompressed tensor from file at {} ...",
+        args.input.display()
+    );
+
+    let input_file = File::open(&args.input)?;
+    // SAFETY: We only read from the mapped memory and hope that nobody modifies the
+    // input file concurrently.
+    let mmap = unsafe { Mmap::map(&input_file)? };
+    let uncompressed = ArrayView3::<i16>::view_npy(&mmap)?;
+    let (num_timesteps, vocab_size, embedding_dim) = uncompressed.dim();
+    if num_timesteps == 0 || vocab_size == 0 || embedding_dim == 0 {
+        Err("The input tensor must not be empty.")?;
+    }
+    info!(
+        "Found tensor with {} time steps, vocabulary size {}, and embedding dimension {}.",
+        num_timesteps, vocab_size, embedding_dim
+    );
+
+    let vocab = args
+        .vocab
+        .map(|path| read_vocab(&path, vocab_size))
+        .transpose()?;
+
+    let mut scratch_path = output_path.as_os_str().to_owned();
+    scratch_path.push(".tmp");
+    let scratch_path = PathBuf::from(scratch_path);
+    let scratch = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&scratch_path)?;
+
+    info!(
+        "Building compressed representation and saving to {} (using temporary file {}) ...",
+        output_path.display(),
+        scratch_path.display()
+    );
+
+    let mut options = CompressionOptions::new(
+        args.jump_interval,
+        args.scale_factor.expect("required by clap"),
+    );
+    options.entropy_precision = args.entropy_precision;
+    options.rate_distortion_tradeoff = args.rate_distortion_tradeoff;
+    options.keyframe_interval = args.keyframe_interval;
+    options.prediction_scheme = args.predictor;
+    options.model_sharing = args.share_models;
+    options.compact_jump_table = args.compact_jump_table;
+    options.independent_streams = args.independent_streams;
+    options.model_contexts = model_contexts(
+        args.word_ranges,
+        args.dimension_buckets,
+        vocab_size,
+        embedding_dim,
+    )?;
+    let result = write_compressed_dwe_file_streaming(
+        (num_timesteps, vocab_size, embedding_dim),
+        |t| {
+            let timestep = uncompressed.index_axis(Axis(0), t);
+            Ok(RankTwoTensor::from_flattened(
+                timestep.iter().cloned().collect(),
+                vocab_size,
+                embedding_dim,
+            ))
+        },
+        vocab.as_deref(),
+        None,
+        &options,
+        scratch,
+        BufWriter::new(output_file),
+    );
+    std::fs::remove_file(&scratch_path)?;
+    let file_size = result.inspect_err(|_| {
+        // Don't leave a truncated file behind if compression failed halfway through.
+        let _ = std::fs::remove_file(output_path);
+    })?;
+
+    info!("Done ({} bytes).", file_size);
+    Ok(())
+}
+
+fn append(args: AppendArgs) -> Result<(), Box<dyn Error>> {
+    match read_header(&args.existing)?.symbol_type {
+        SymbolType::I8 => append_typed::<i8>(args),
+        SymbolType::I16 => append_typed::<i16>(args),
+        SymbolType::I32 => append_typed::<i32>(args),
+    }
+}
+
+fn append_typed<T: Symbol + ReadableElement>(mut args: AppendArgs) -> Result<(), Box<dyn Error>> {
+    // Fail early if we can't open output file (e.g., if it already exists).
+    let output_path = args.output.take().unwrap_or_else(|| {
+        let mut output_path = args.existing.clone();
+        output_path.set_extension("appended.dwe");
+        output_path
+    });
+    let output_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&output_path)?;
+
+    info!(
+        "Loading existing compressed file from {} ...",
+        args.existing.display()
+    );
+    let embedding_file = open_embedding_file::<T>(&args.existing)?;
+    let header = embedding_file.header();
+
+    info!(
+        "Loading new time steps from file at {} ...",
+        args.input.display()
+    );
+    let mut npz_reader = NpzReader::new(File::open(&args.input)?)?;
+    let uncompressed = read_rank_three_tensor::<T>(&mut npz_reader, "uncompressed_quantized")?;
+    let num_timesteps = uncompressed.as_view().shape().0;
+    if num_timesteps == 0 {
+        Err(format!(
+            "The file at {} contains no time steps to append.",
+            args.input.display()
+        ))?;
+    }
+    if let Ok(scale_factor) = npz_reader.by_name::<_, Ix0>("scale_factor.npy") {
+        let scale_factor: Array0<f32> = scale_factor;
+        let scale_factor = scale_factor.into_scalar();
+        if scale_factor != header.scale_factor {
+            Err(format!(
+                "The new time steps have scale_factor = {} but the existing file has \
+                    scale_factor = {}.",
+                scale_factor, header.scale_factor
+            ))?;
+        }
+    }
+
+    let timestep_labels = args
+        .timestep_labels
+        .map(|name| -> Result<_, Box<dyn Error>> {
+            let labels = read_integer_vector(&mut npz_reader, &format!("{}.npy", name))?;
+            Ok(TimestepLabels::from_integers(labels)?)
+        })
+        .transpose()?;
+    let scale_factors = args
+        .scale_factors
+        .map(|name| -> Result<_, Box<dyn Error>> {
+            let scale_factors: Array2<f32> = npz_reader.by_name(&format!("{}.npy", name))?;
+            let (num_rows, num_columns) = scale_factors.dim();
+            Ok(ScaleFactors::from_flattened(
+                scale_factors.iter().cloned().collect(),
+                num_rows,
+                num_columns,
+            ))
+        })
+        .transpose()?;
+
+    info!(
+        "Appending {} time steps to the existing {} and saving to {} ...",
+        num_timesteps,
+        header.num_timesteps,
+        output_path.display()
+    );
+    let file_size = append_timesteps(
+        &embedding_file,
+        uncompressed.as_view(),
+        timestep_labels.as_ref(),
+        scale_factors.as_ref(),
+        BufWriter::new(output_file),
+    )?;
+
+    info!("Done ({} bytes).", file_size);
+    Ok(())
+}
+
+fn read_vocab(path: &Path, vocab_size: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    info!("Loading vocabulary from file at {} ...", path.display());
+    let vocab = BufReader::new(File::open(path)?)
+        .lines()
+        .collect::<Result<Vec<_>, _>>()?;
+    if vocab.len() != vocab_size {
+        Err(format!(
+            "The vocabulary file has {} lines but the vocabulary size is {}.",
+            vocab.len(),
+            vocab_size
+        ))?;
+    }
+    Ok(vocab)
+}
+
+/// Reads the nonempty rank-three tensor `name` from the input file.
+fn read_rank_three_tensor<T: ReadableElement + Default>(
+    npz_reader: &mut NpzReader<File>,
+    name: &str,
+) -> Result<RankThreeTensor<T>, Box<dyn Error>> {
+    let tensor: Array3<T> = npz_reader.by_name(&format!("{}.npy", name))?;
+    if !tensor.is_standard_layout() {
+        Err(format!(
+            "Tensor `{}` must be stored in standard layout.",
+            name
+        ))?;
+    }
+    let (num_timesteps, vocab_size, embedding_dim) = tensor.dim();
+    if num_timesteps == 0 || vocab_size == 0 || embedding_dim == 0 {
+        Err(format!("Tensor `{}` must not be empty.", name))?;
+    }
+    info!(
+        "Found `{}` tensor with {} time steps, vocabulary size {}, \
+            and embedding dimension {}.",
+        name, num_timesteps, vocab_size, embedding_dim
+    );
+    let (data, offset) = tensor.into_raw_vec_and_offset();
+    assert_eq!(offset, Some(0));
+    Ok(RankThreeTensor::from_flattened(
+        data,
+        num_timesteps,
+        vocab_size,
+        embedding_dim,
+    ))
+}
+
+/// Reads a rank-one tensor with any signed integer dtype from a `.npz` file.
+fn read_integer_vector(
+    npz_reader: &mut NpzReader<File>,
+    name: &str,
+) -> Result<Vec<i64>, Box<dyn Error>> {
+    if let Ok(vector) = npz_reader.by_name::<_, Ix1>(name) {
+        let vector: Array1<i64> = vector;
+        return Ok(vector.to_vec());
+    }
+    if let Ok(vector) = npz_reader.by_name::<_, Ix1>(name) {
+        let vector: Array1<i32> = vector;
+        return Ok(vector.iter().map(|&x| x as i64).collect());
+    }
+    let vector: Array1<i16> = npz_reader.by_name(name)?;
+    Ok(vector.iter().map(|&x| x as i64).collect())
+}
+
+/// Maps words to word IDs using the file's vocabulary, or parses them as word IDs
+/// if the file doesn't contain a vocabulary.
+fn resolve_words(
+    embedding_file: &EmbeddingFile<impl AsRef<[u32]>, impl Symbol>,
+    words: &[String],
+) -> Result<Vec<u32>, Box<dyn Error>> {
+    let vocab_size = embedding_file.header().vocab_size;
+    words
+        .iter()
+        .map(|word| -> Result<u32, Box<dyn Error>> {
+            let id = match embedding_file.vocabulary() {
+                Some(vocabulary) => vocabulary
+                    .word_to_id(word)
+                    .ok_or_else(|| format!("The word \"{}\" is not in the vocabulary.", word))?,
+                None => word.parse().map_err(|_| {
+                    format!(
+                        "The file contains no vocabulary, so words have to be provided as \
+                        word IDs (found \"{}\").",
+                        word
+                    )
+                })?,
+            };
+            if id >= vocab_size {
+                Err(format!(
+                    "Word ID {} is out of bounds for vocabulary size {}.",
+                    id, vocab_size
+                ))?;
+            }
+            Ok(id)
+        })
+        .collect()
+}
+
+/// Returns the model contexts that `--word-ranges` and `--dimension-buckets`
+/// request, or `None` if they request only a single context.
+fn model_contexts(
+    word_ranges: u32,
+    dimension_buckets: u32,
+    vocab_size: usize,
+    embedding_dim: usize,
+) -> Result<Option<ModelContexts>, Box<dyn Error>> {
+    if word_ranges as usize > vocab_size {
+        Err(format!(
+            "Can't split {} words into {} word ranges.",
+            vocab_size, word_ranges
+        ))?;
+    }
+    if dimension_buckets as usize > embedding_dim {
+        Err(format!(
+            "Can't split {} embedding dimensions into {} dimension buckets.",
+            embedding_dim, dimension_buckets
+        ))?;
+    }
+    if word_ranges == 1 && dimension_buckets == 1 {
+        return Ok(None);
+    }
+    Ok(Some(ModelContexts::uniform(
+        vocab_size as u32,
+        word_ranges,
+        embedding_dim as u32,
+        dimension_buckets,
+    )))
+}
+
+fn parse_entropy_precision(bits: &str) -> Result<EntropyPrecision, String> {
+    bits.parse()
+        .ok()
+        .and_then(EntropyPrecision::from_bits)
+        .ok_or_else(|| String::from("must be 12, 16, or 24"))
+}
+
+fn parse_symbol_type(name: &str) -> Result<SymbolType, String> {
+    match name {
+        "i8" => Ok(SymbolType::I8),
+        "i16" => Ok(SymbolType::I16),
+        "i32" => Ok(SymbolType::I32),
+        _ => Err(String::from("must be \"i8\", \"i16\", or \"i32\"")),
+    }
+}
+
+fn parse_prediction_scheme(name: &str) -> Result<PredictionScheme, String> {
+    match name {
+        "mean" => Ok(PredictionScheme::Mean),
+        "distance" => Ok(PredictionScheme::DistanceWeighted),
+        "least-squares" => Ok(PredictionScheme::LeastSquaresPerLevel),
+        "previous" => Ok(PredictionScheme::PreviousOnly),
+        _ => Err(String::from(
+            "must be \"mean\", \"distance\", \"least-squares\", or \"previous\"",
+        )),
+    }
+}
+
+fn parse_model_sharing(name: &str) -> Result<ModelSharing, String> {
+    match name {
+        "never" => Ok(ModelSharing::PerTimestep),
+        "tree-levels" => Ok(ModelSharing::TreeLevels),
+        "auto" => Ok(ModelSharing::Automatic),
+        _ => Err(String::from(
+            "must be \"never\", \"tree-levels\", or \"auto\"",
+        )),
+    }
+}
+
 fn inspect(args: InspectArgs) -> Result<(), Box<dyn Error>> {
     info!(
         "Peeking into compressed dynamic embeddings at {} ...",
         args.input.display()
     );
-    let mut file = File::open(args.input)?;
-    let mut buf = [0u32; HEADER_SIZE as usize];
-    file.read_u32_into::<LittleEndian>(&mut buf)?;
-    let header = unsafe {
-        // SAFETY: `buf` has correct size.
-        FileHeader::memory_map_unsafe(&buf)
-    };
+    let header = read_header(&args.input)?;
     println!("{:#?}", header);
 
     Ok(())
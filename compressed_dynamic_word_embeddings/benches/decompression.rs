@@ -3,7 +3,6 @@ extern crate criterion;
 
 use std::{fs::File, io::BufReader};
 
-use byteorder::{LittleEndian, ReadBytesExt};
 use constriction::{
     stream::{model::SmallNonContiguousLookupDecoderModel, stack::AnsCoder, Decode},
     Seek,
@@ -12,7 +11,7 @@ use criterion::{black_box, Criterion};
 use rand::prelude::*;
 
 use compressed_dynamic_word_embeddings::{
-    embedding_file::{EmbeddingFile, FileHeader, TimestepReader, HEADER_SIZE},
+    embedding_file::{EmbeddingFile, TimestepReader, HEADER_SIZE},
     u12::unpack_u12s,
 };
 
@@ -26,6 +25,8 @@ fn decompress(c: &mut Criterion) {
     let embedding_file = EmbeddingFile::from_reader(file).unwrap();
 
     let header = embedding_file.header();
+    // The manual decoding below only understands version 1 of the file format.
+    assert_eq!(header.major_version, 1);
     let num_timesteps = header.num_timesteps;
     let vocab_size = header.vocab_size;
     let embedding_dim = header.embedding_dim;
@@ -93,20 +94,9 @@ fn construct_decoder_models(c: &mut Criterion) {
         env!("CARGO_MANIFEST_DIR"),
     );
 
-    let mut file = BufReader::new(File::open(file_name).unwrap());
-    let mut buf = vec![0; HEADER_SIZE as usize];
-
-    let header = unsafe {
-        // SAFETY: We made sure that buf.len() == HEADER_SIZE
-        FileHeader::memory_map_unsafe(&buf)
-    };
-    let file_size = header.file_size;
-
-    buf.reserve_exact((file_size - HEADER_SIZE) as usize);
-    for _ in HEADER_SIZE..file_size {
-        buf.push(file.read_u32::<LittleEndian>().map_err(|_| ()).unwrap());
-    }
-    let mut buf_container = Some(buf.into());
+    let file = BufReader::new(File::open(file_name).unwrap());
+    let buf = EmbeddingFile::from_reader(file).unwrap().into_inner();
+    let mut buf_container = Some(buf);
 
     c.bench_function("construct_decoder_models", |b| {
         b.iter(|| {
@@ -115,6 +105,15 @@ fn construct_decoder_models(c: &mut Criterion) {
             buf_container = Some(embedding_file.into_inner())
         })
     });
+
+    c.bench_function("construct_and_precompute_decoder_models", |b| {
+        b.iter(|| {
+            let buf = buf_container.take().unwrap();
+            let embedding_file = black_box(EmbeddingFile::new(black_box(buf)).unwrap());
+            embedding_file.precompute_entropy_models();
+            buf_container = Some(embedding_file.into_inner())
+        })
+    });
 }
 
 fn decompress_constriction(c: &mut Criterion) {
@@ -127,6 +126,8 @@ fn decompress_constriction(c: &mut Criterion) {
     let embedding_file = EmbeddingFile::from_reader(file).unwrap();
 
     let header = embedding_file.header();
+    // The manual decoding below only understands version 1 of the file format.
+    assert_eq!(header.major_version, 1);
     let num_timesteps = header.num_timesteps;
     let vocab_size = header.vocab_size;
     let embedding_dim = header.embedding_dim;
@@ -1,46 +1,285 @@
-use super::{FileHeader, JumpPointer, HEADER_SIZE};
+use super::{
+    compact_jump_table::{self, COMPACT_JUMP_TABLE_SECTION_TAG},
+    decoding_minor_version,
+    model_contexts::{ModelContexts, MODEL_CONTEXTS_SECTION_TAG},
+    model_groups::{ModelGroups, ModelSharing, MODEL_GROUPS_SECTION_TAG},
+    packed_frequencies_size,
+    predictor::{Prediction, PredictionScheme, Predictor, PREDICTOR_SECTION_TAG},
+    quantization::{
+        analyze, f32_at_least, measure_distortion, quantize, quantize_with_step,
+        QuantizationOptions, QuantizationReport,
+    },
+    scale_factors::{ScaleFactors, SCALE_FACTORS_SECTION_TAG},
+    segments::{Segments, SEGMENTS_SECTION_TAG},
+    split_u64,
+    symbol::{serialize_symbol, Symbol, SymbolType},
+    timestep_labels::{TimestepLabels, TIMESTEP_LABELS_SECTION_TAG},
+    vocabulary::{Vocabulary, VOCABULARY_SECTION_TAG},
+    EntropyPrecision, FileHeader, JumpPointer, HEADER_SIZE, HEADER_SIZE_V2, MAGIC,
+};
 use crate::{
+    error::{Error, Result},
     tensors::{RankThreeTensor, RankThreeTensorView},
     u12::pack_u12s,
 };
 
 use byteorder::{LittleEndian, WriteBytesExt};
-use constriction::{stream::stack::SmallAnsCoder, Pos, UnwrapInfallible};
+use constriction::{
+    stream::{
+        model::{
+            DefaultNonContiguousCategoricalEncoderModel, NonContiguousCategoricalEncoderModel,
+            SmallNonContiguousCategoricalEncoderModel,
+        },
+        stack::{AnsCoder, DefaultAnsCoder, SmallAnsCoder},
+        Encode,
+    },
+    Pos, UnwrapInfallible,
+};
+
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    io::Write,
+};
+
+mod append;
+mod parallel;
+mod prediction;
+mod streaming;
+
+pub use append::append_timesteps;
+use prediction::PredictorFit;
+pub use streaming::{write_compressed_dwe_file_streaming, StreamingBuilder};
+
+type EncoderModel12<T> = SmallNonContiguousCategoricalEncoderModel<T>;
+type EncoderModel16<T> = NonContiguousCategoricalEncoderModel<T, u16, 16>;
+type EncoderModel24<T> = DefaultNonContiguousCategoricalEncoderModel<T>;
+
+/// Maps each symbol that occurs in a time step to the number of its occurrences.
+type SymbolCounts<T> = HashMap<T, u32>;
+
+/// Settings for [`write_compressed_dwe_file_with_options`].
+///
+/// Create with [`new`](#method.new), which sets all fields that aren't arguments
+/// of `new` to their defaults, and then modify individual fields as needed.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CompressionOptions {
+    /// Number of words between two consecutive entries in the jump table.
+    pub jump_interval: u32,
+
+    /// Gets stored in the file header, see
+    /// [`FileHeader::scale_factor`](../struct.FileHeader.html#structfield.scale_factor).
+    pub scale_factor: f32,
+
+    /// Precision of the entropy models (defaults to 12 bits). Precisions other than
+    /// 12 bits require version 2 of the file format.
+    pub entropy_precision: EntropyPrecision,
+
+    /// The lowest major version of the file format that may be used (defaults to
+    /// 1). Files that don't fit into version 1 get written in version 2 anyway.
+    pub min_major_version: u32,
+
+    /// Scale factors per embedding dimension and/or per time step (defaults to
+    /// `None`), which get stored in an optional section and refine `scale_factor`.
+    /// The table must have either one row per time step or a single row, and
+    /// either one column per embedding dimension or a single column.
+    pub scale_factors: Option<ScaleFactors>,
+
+    /// Enables lossy compression of the prediction residuals if set (defaults to
+    /// `None`, i.e., lossless compression). The builder then stores, for each
+    /// component, the residual that minimizes `squared_error + tradeoff * bits`,
+    /// where `squared_error` is measured in the real numbers that the quantized
+    /// values represent (i.e., after multiplying with `scale_factor` and
+    /// `scale_factors`), and `bits` is the information content of the residual
+    /// under the entropy model of its time step. Larger values lead to smaller
+    /// files and larger errors.
+    pub rate_distortion_tradeoff: Option<f32>,
+
+    /// Inserts a keyframe every `keyframe_interval` time steps if set (defaults to
+    /// `None`, i.e., only the first and the last time step are keyframes). Keyframes
+    /// are coded independently of all other time steps, and the time steps between
+    /// two consecutive keyframes are predicted only from each other and from these
+    /// two keyframes (see [`Segments`](../segments/struct.Segments.html)). This
+    /// bounds the number of time steps that readers have to decode for random access
+    /// at the cost of a slightly larger file. Must not be zero.
+    pub keyframe_interval: Option<u32>,
+
+    /// How each time step gets predicted from its two parents in the bisection tree
+    /// (defaults to [`PredictionScheme::Mean`]). Schemes other than `Mean` store
+    /// their weights in an optional section (see
+    /// [`Predictor`](../predictor/struct.Predictor.html)), which costs two `u32`s
+    /// per time step and requires readers that know about predictors.
+    pub prediction_scheme: PredictionScheme,
+
+    /// Splits the residuals of each time step into several contexts with separate
+    /// entropy models if set (defaults to `None`, i.e., a single entropy model per
+    /// time step), see [`ModelContexts`]. The contexts must fit to the vocabulary
+    /// size and the embedding dimension. More than one context requires readers that
+    /// know about model contexts.
+    pub model_contexts: Option<ModelContexts>,
+
+    /// Which time steps share their entropy models (defaults to
+    /// [`ModelSharing::PerTimestep`], i.e., each time step has its own entropy
+    /// models), see [`ModelGroups`]. Sharing makes the file smaller and faster to
+    /// load if the entropy models make up a noticeable part of it, e.g., for small
+    /// vocabularies or many time steps. Files with shared entropy models require
+    /// readers that know about model groups.
+    pub model_sharing: ModelSharing,
+
+    /// Stores the jump table in a compact form (defaults to `false`), which
+    /// delta-codes the offsets and packs all fields into as few bits as possible.
+    /// This typically saves about half of the size of the jump table, which makes
+    /// small values of `jump_interval` affordable. Files with a compact jump table
+    /// follow version 1.3 (or 2.2) of the file format, which older readers can't
+    /// read.
+    pub compact_jump_table: bool,
+
+    /// Terminates the ANS stream at the end of each time step (defaults to `false`,
+    /// i.e., all time steps share one continuous stream). Each time step then gets
+    /// encoded from scratch, so that different time steps can be compressed (and, as
+    /// always, decoded) independently of each other, in parallel if the `rayon`
+    /// feature is enabled. This changes the size of the
+    /// compressed data by at most a few bytes per time step (see
+    /// [`write_compressed_dwe_file_with_stream_overhead`]). Since readers already
+    /// start decoding each time step from its own jump table entry, the file format
+    /// doesn't change.
+    pub independent_streams: bool,
+}
+
+impl CompressionOptions {
+    pub fn new(jump_interval: u32, scale_factor: f32) -> Self {
+        Self {
+            jump_interval,
+            scale_factor,
+            entropy_precision: EntropyPrecision::default(),
+            min_major_version: 1,
+            scale_factors: None,
+            rate_distortion_tradeoff: None,
+            keyframe_interval: None,
+            prediction_scheme: PredictionScheme::default(),
+            model_contexts: None,
+            model_sharing: ModelSharing::default(),
+            compact_jump_table: false,
+            independent_streams: false,
+        }
+    }
+
+    /// Returns the model contexts of a file with shape `shape`, or
+    /// `Error::LengthMismatch` if `model_contexts` doesn't fit to the shape.
+    fn model_contexts(&self, shape: (usize, usize, usize)) -> Result<ModelContexts> {
+        let (_, vocab_size, embedding_dim) = shape;
+        match &self.model_contexts {
+            None => Ok(ModelContexts::single(
+                embedding_dim.try_into().expect("checked by caller"),
+            )),
+            Some(model_contexts) => {
+                model_contexts.check_shape(vocab_size, embedding_dim)?;
+                Ok(model_contexts.clone())
+            }
+        }
+    }
+
+    /// Returns the segments of a file with `num_timesteps` time steps, as implied
+    /// by `keyframe_interval`.
+    fn segments(&self, num_timesteps: usize) -> Segments {
+        let num_timesteps = num_timesteps.try_into().expect("checked by caller");
+        match self.keyframe_interval {
+            None => Segments::single(num_timesteps),
+            Some(keyframe_interval) => {
+                Segments::with_keyframe_interval(num_timesteps, keyframe_interval)
+            }
+        }
+    }
+}
 
-use std::{collections::HashMap, convert::TryInto, io::Write};
+/// The entropy models of all time steps, in the precision that the caller chose.
+enum EncoderModels<T: Symbol> {
+    Bits12(Vec<EncoderModel12<T>>),
+    Bits16(Vec<EncoderModel16<T>>),
+    Bits24(Vec<EncoderModel24<T>>),
+}
 
-type EncoderModel = constriction::stream::model::SmallNonContiguousCategoricalEncoderModel<i16>;
+impl<T: Symbol> EncoderModels<T> {
+    fn new(precision: EntropyPrecision, capacity: usize) -> Self {
+        match precision {
+            EntropyPrecision::Bits12 => EncoderModels::Bits12(Vec::with_capacity(capacity)),
+            EntropyPrecision::Bits16 => EncoderModels::Bits16(Vec::with_capacity(capacity)),
+            EntropyPrecision::Bits24 => EncoderModels::Bits24(Vec::with_capacity(capacity)),
+        }
+    }
 
-fn create_and_serialize_encoder_models(
-    counts: &[HashMap<i16, u32>],
-) -> Result<(Vec<EncoderModel>, Vec<u16>), ()> {
+    /// Expects frequencies that are valid for the precision of `self`.
+    fn push(&mut self, symbols_and_frequencies: &[(T, u32)]) -> std::result::Result<(), ()> {
+        let symbols = symbols_and_frequencies.iter().map(|&(s, _)| s);
+        let frequencies = symbols_and_frequencies.iter().map(|&(_, f)| f);
+        match self {
+            EncoderModels::Bits12(models) => models.push(
+                EncoderModel12::from_symbols_and_nonzero_fixed_point_probabilities(
+                    symbols,
+                    frequencies.map(|f| f as u16),
+                    false,
+                )?,
+            ),
+            EncoderModels::Bits16(models) => models.push(
+                EncoderModel16::from_symbols_and_nonzero_fixed_point_probabilities(
+                    symbols,
+                    frequencies.map(|f| f as u16),
+                    false,
+                )?,
+            ),
+            EncoderModels::Bits24(models) => models.push(
+                EncoderModel24::from_symbols_and_nonzero_fixed_point_probabilities(
+                    symbols,
+                    frequencies,
+                    false,
+                )?,
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Creates one entropy model for each entry of `counts`, where each group in
+/// `model_groups` has `models_per_group` consecutive entries (one per model
+/// context), see [`shared_counts`].
+fn create_and_serialize_encoder_models<T: Symbol>(
+    counts: &[SymbolCounts<T>],
+    model_groups: &ModelGroups,
+    models_per_group: usize,
+    precision: EntropyPrecision,
+) -> Result<(EncoderModels<T>, Vec<u16>)> {
     let mut serialized = Vec::new();
-    let mut models = Vec::with_capacity(counts.len());
+    let mut models = EncoderModels::new(precision, counts.len());
+    let optimal = parallel::map(counts, |counts| optimal_frequencies(counts, precision));
 
-    for counts in counts {
-        let symbols_and_frequencies = optimal_frequencies_12bit(counts);
+    for (index, symbols_and_frequencies) in optimal.into_iter().enumerate() {
+        let invalid = || Error::InvalidEntropyModel {
+            timestep: model_groups.first_timestep(index / models_per_group),
+        };
+        let symbols_and_frequencies = symbols_and_frequencies.ok_or_else(invalid)?;
 
         let frequencies = symbols_and_frequencies
             .iter()
             .map(|&(_, f)| f)
             .collect::<Vec<_>>();
 
-        let num_symbols: u16 = symbols_and_frequencies.len().try_into().map_err(|_| ())?;
+        let num_symbols: u16 = symbols_and_frequencies
+            .len()
+            .try_into()
+            .map_err(|_| invalid())?;
         serialized.push(num_symbols);
         for &(symbol, _) in &symbols_and_frequencies {
-            serialized.push(symbol as u16);
+            serialize_symbol(symbol, &mut serialized);
         }
-        for frequency_encoding in pack_u12s(&frequencies[..frequencies.len() - 1]) {
-            serialized.push(frequency_encoding);
-        }
-
-        models.push(
-            EncoderModel::from_symbols_and_nonzero_fixed_point_probabilities(
-                symbols_and_frequencies.iter().map(|&(s, _)| s),
-                symbols_and_frequencies.iter().map(|&(_, f)| f),
-                false,
-            )?,
-        );
+        serialized.extend(pack_frequencies(
+            &frequencies[..frequencies.len() - 1],
+            precision,
+        ));
+
+        models
+            .push(&symbols_and_frequencies)
+            .map_err(|()| invalid())?;
     }
 
     // Add padding if necessary.
@@ -51,165 +290,1382 @@ fn create_and_serialize_encoder_models(
     Ok((models, serialized))
 }
 
-fn compress_data(
-    diffs: RankThreeTensorView<i16>,
-    models: &[EncoderModel],
+/// Serializes frequencies of the given precision into `u16`s. See
+/// `super::packed_frequencies_size` for the format.
+fn pack_frequencies(frequencies: &[u32], precision: EntropyPrecision) -> Vec<u16> {
+    match precision {
+        EntropyPrecision::Bits12 => {
+            let frequencies = frequencies.iter().map(|&f| f as u16).collect::<Vec<_>>();
+            pack_u12s(&frequencies).collect()
+        }
+        EntropyPrecision::Bits16 => frequencies.iter().map(|&f| f as u16).collect(),
+        EntropyPrecision::Bits24 => frequencies
+            .iter()
+            .flat_map(|&f| [f as u16, (f >> 16) as u16])
+            .collect(),
+    }
+}
+
+/// An ANS encoder for compressed words of the size that the precision of the
+/// entropy models requires, together with the entropy models.
+enum Encoder<'a, T: Symbol> {
+    Bits12(SmallAnsCoder, &'a [EncoderModel12<T>]),
+    Bits16(AnsCoder<u16, u32>, &'a [EncoderModel16<T>]),
+    Bits24(DefaultAnsCoder, &'a [EncoderModel24<T>]),
+}
+
+impl<'a, T: Symbol> Encoder<'a, T> {
+    fn new(models: &'a EncoderModels<T>) -> Self {
+        // Start with a `state` of `1 << word_bits` and an empty buffer, which is
+        // what readers expect.
+        match models {
+            EncoderModels::Bits12(models) => {
+                Encoder::Bits12(AnsCoder::from_binary(vec![0]).unwrap_infallible(), models)
+            }
+            EncoderModels::Bits16(models) => {
+                Encoder::Bits16(AnsCoder::from_binary(vec![0]).unwrap_infallible(), models)
+            }
+            EncoderModels::Bits24(models) => {
+                Encoder::Bits24(AnsCoder::from_binary(vec![0]).unwrap_infallible(), models)
+            }
+        }
+    }
+
+    /// Starts a new ANS stream on top of the compressed words that the encoder
+    /// accumulated so far, i.e., resets the state to what `new` sets it to. The
+    /// decoder never reads past the start of the new stream when it decodes
+    /// the words that get encoded from now on.
+    fn restart(&mut self) {
+        match self {
+            Encoder::Bits12(encoder, _) | Encoder::Bits16(encoder, _) => {
+                let (bulk, _) = std::mem::replace(encoder, AnsCoder::from_raw_parts(Vec::new(), 0))
+                    .into_raw_parts();
+                *encoder = AnsCoder::from_raw_parts(bulk, 1 << u16::BITS);
+            }
+            Encoder::Bits24(encoder, _) => {
+                let (bulk, _) = std::mem::replace(encoder, AnsCoder::from_raw_parts(Vec::new(), 0))
+                    .into_raw_parts();
+                *encoder = AnsCoder::from_raw_parts(bulk, 1 << u32::BITS);
+            }
+        }
+    }
+
+    /// Pushes the compressed words of `other`, which must have started from a fresh
+    /// state (see `new`) and which must use the same precision, on top of the
+    /// compressed words of `self`, and continues with the state of `other`. This has
+    /// the same effect as calling `restart` and then encoding the symbols of `other`.
+    fn extend(&mut self, other: Self) {
+        match (self, other) {
+            (Encoder::Bits12(encoder, _), Encoder::Bits12(other, _))
+            | (Encoder::Bits16(encoder, _), Encoder::Bits16(other, _)) => {
+                let (mut bulk, _) =
+                    std::mem::replace(encoder, AnsCoder::from_raw_parts(Vec::new(), 0))
+                        .into_raw_parts();
+                let (other_bulk, state) = other.into_raw_parts();
+                bulk.extend_from_slice(&other_bulk);
+                *encoder = AnsCoder::from_raw_parts(bulk, state);
+            }
+            (Encoder::Bits24(encoder, _), Encoder::Bits24(other, _)) => {
+                let (mut bulk, _) =
+                    std::mem::replace(encoder, AnsCoder::from_raw_parts(Vec::new(), 0))
+                        .into_raw_parts();
+                let (other_bulk, state) = other.into_raw_parts();
+                bulk.extend_from_slice(&other_bulk);
+                *encoder = AnsCoder::from_raw_parts(bulk, state);
+            }
+            _ => unreachable!("encoders for the same entropy models"),
+        }
+    }
+
+    /// Encodes `symbols`, which are the embedding vectors of consecutive words
+    /// starting at `first_word`, with the entropy models of group `group` in reverse
+    /// order. Each symbol gets encoded with the model of its context in `contexts`.
+    fn encode_reverse(
+        &mut self,
+        group: usize,
+        contexts: &ModelContexts,
+        first_word: u32,
+        symbols: &[T],
+    ) -> std::result::Result<(), ()> {
+        let num_contexts = contexts.len();
+        let embedding_dim = contexts.dimension_buckets().len();
+        macro_rules! encode {
+            ($encoder:expr, $models:expr) => {{
+                let models = &$models[group * num_contexts..(group + 1) * num_contexts];
+                if let [model] = models {
+                    $encoder
+                        .encode_iid_symbols_reverse(symbols, model)
+                        .map_err(|_| ())
+                } else {
+                    for (i, vector) in symbols.chunks_exact(embedding_dim).enumerate().rev() {
+                        let models = contexts.models_of_word(models, first_word + i as u32);
+                        for (&symbol, &bucket) in
+                            vector.iter().zip(contexts.dimension_buckets()).rev()
+                        {
+                            $encoder
+                                .encode_symbol(symbol, &models[bucket as usize])
+                                .map_err(|_| ())?;
+                        }
+                    }
+                    Ok(())
+                }
+            }};
+        }
+        match self {
+            Encoder::Bits12(encoder, models) => encode!(encoder, models),
+            Encoder::Bits16(encoder, models) => encode!(encoder, models),
+            Encoder::Bits24(encoder, models) => encode!(encoder, models),
+        }
+    }
+
+    /// Returns the current position (in units of compressed words) and state.
+    fn pos(&self) -> (usize, u64) {
+        match self {
+            Encoder::Bits12(encoder, _) | Encoder::Bits16(encoder, _) => {
+                let (pos, state) = encoder.pos();
+                (pos, state as u64)
+            }
+            Encoder::Bits24(encoder, _) => encoder.pos(),
+        }
+    }
+
+    /// Writes the compressed words that the encoder accumulated so far to `sink` (in
+    /// the order in which they were generated, in little endian byte order) and
+    /// removes them from the encoder. Returns the number of written words.
+    /// Positions that `pos` returns afterwards are relative to the spilled words.
+    fn spill(&mut self, sink: &mut impl Write) -> std::io::Result<usize> {
+        let bytes = match self {
+            Encoder::Bits12(encoder, _) | Encoder::Bits16(encoder, _) => {
+                let (_, state) = encoder.pos();
+                let (bulk, _) =
+                    std::mem::replace(encoder, AnsCoder::from_raw_parts(Vec::new(), state))
+                        .into_raw_parts();
+                bulk.iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .collect::<Vec<_>>()
+            }
+            Encoder::Bits24(encoder, _) => {
+                let (_, state) = encoder.pos();
+                let (bulk, _) =
+                    std::mem::replace(encoder, AnsCoder::from_raw_parts(Vec::new(), state))
+                        .into_raw_parts();
+                bulk.iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .collect::<Vec<_>>()
+            }
+        };
+        sink.write_all(&bytes)?;
+        Ok(bytes.len() / self.word_size())
+    }
+
+    /// Returns the size of a compressed word in bytes.
+    fn word_size(&self) -> usize {
+        match self {
+            Encoder::Bits12(..) | Encoder::Bits16(..) => 2,
+            Encoder::Bits24(..) => 4,
+        }
+    }
+
+    /// Returns the number of compressed words and the compressed data in the order
+    /// in which the decoder reads it, packed into `u32`s (with padding if
+    /// necessary).
+    fn into_compressed(self) -> (usize, Vec<u32>) {
+        match self {
+            Encoder::Bits12(encoder, _) | Encoder::Bits16(encoder, _) => {
+                let (mut compressed, _) = encoder.into_raw_parts();
+                compressed.reverse();
+                let len = compressed.len();
+                let packed = compressed
+                    .chunks(2)
+                    .map(|pair| pair[0] as u32 | (*pair.get(1).unwrap_or(&0) as u32) << 16)
+                    .collect();
+                (len, packed)
+            }
+            Encoder::Bits24(encoder, _) => {
+                let (mut compressed, _) = encoder.into_raw_parts();
+                compressed.reverse();
+                (compressed.len(), compressed)
+            }
+        }
+    }
+}
+
+/// Encodes `diffs` and returns the jump table, the compressed data (packed into
+/// `u32`s), and the number of compressed words. Starts a new ANS stream for each
+/// time step if `independent_streams` is set, which allows encoding the time steps
+/// in parallel.
+#[allow(clippy::too_many_arguments)]
+fn compress_data<T: Symbol>(
+    diffs: RankThreeTensorView<T>,
+    segments: &Segments,
+    models: &EncoderModels<T>,
+    contexts: &ModelContexts,
+    model_groups: &ModelGroups,
     jump_interval: u32,
-) -> Result<(Vec<JumpPointer>, Vec<u16>), ()> {
+    independent_streams: bool,
+) -> Result<(Vec<JumpPointer>, Vec<u32>, usize)> {
     let (num_timesteps, vocab_size, embedding_dim) = diffs.shape();
     let num_timesteps: u32 = num_timesteps.try_into().unwrap();
     let vocab_size: u32 = vocab_size.try_into().unwrap();
     let embedding_dim: u32 = embedding_dim.try_into().unwrap();
 
-    let mut tree_order = Vec::with_capacity(num_timesteps as usize);
-    tree_order.push(0);
-    tree_order.push(num_timesteps - 1);
-    traverse_subtree(
-        2,
-        0,
-        0,
-        (num_timesteps - 1) as usize,
-        1,
-        &mut |t, _, _, _, _, _| {
-            tree_order.push(t as u32);
-        },
-    );
-
     let jump_points_per_timestep = vocab_size.div_ceil(jump_interval);
     let jump_table_len = num_timesteps * jump_points_per_timestep;
     let mut jump_table_section = vec![JumpPointer::default(); jump_table_len as usize];
 
-    let mut encoder = SmallAnsCoder::from_binary(vec![0]).unwrap_infallible();
-
-    for &t in tree_order.iter().rev() {
-        let model = &models[t as usize];
-        let data = diffs.subview(t as usize).slice();
-        let chunks = data.chunks(jump_interval as usize * embedding_dim as usize);
-
-        for (i, chunk) in chunks.enumerate().rev() {
-            encoder
-                .encode_iid_symbols_reverse(chunk, model)
-                .map_err(|_| ())?;
-            let (pos, state) = encoder.pos();
-            jump_table_section[(t * jump_points_per_timestep + i as u32) as usize] = JumpPointer {
-                offset: pos as u32,
-                state,
-            };
+    // Encodes time step `t` and stores its jump pointers (with offsets relative to
+    // the beginning of `encoder`, which get fixed up below) in `jump_table`.
+    let encode_timestep =
+        |encoder: &mut Encoder<T>, t: usize, jump_table: &mut [JumpPointer]| -> Result<()> {
+            let t = t as u32;
+            let data = diffs.subview(t as usize).slice();
+            let chunks = data.chunks(jump_interval as usize * embedding_dim as usize);
+            let group = model_groups.group_of(t) as usize;
+
+            for (i, chunk) in chunks.enumerate().rev() {
+                encoder
+                    .encode_reverse(group, contexts, i as u32 * jump_interval, chunk)
+                    .map_err(|()| Error::InvalidEntropyModel { timestep: t })?;
+                let (pos, state) = encoder.pos();
+                jump_table[i] = JumpPointer {
+                    offset: pos as u64,
+                    state,
+                };
+            }
+            Ok(())
+        };
+
+    let mut encoder = Encoder::new(models);
+    let tree_order = tree_order(segments);
+    let jump_points_per_timestep = jump_points_per_timestep as usize;
+    if independent_streams {
+        // Each time step gets its own stream, so all time steps can be encoded
+        // independently and then stacked on top of each other in reverse tree order.
+        let streams = parallel::map(&tree_order, |&(t, _)| {
+            let mut encoder = Encoder::new(models);
+            let mut jump_table = vec![JumpPointer::default(); jump_points_per_timestep];
+            encode_timestep(&mut encoder, t, &mut jump_table).map(|()| (encoder, jump_table))
+        });
+        for (&(t, _), stream) in tree_order.iter().zip(streams).rev() {
+            let (stream, jump_table) = stream?;
+            let (base, _) = encoder.pos();
+            encoder.extend(stream);
+            for (dest, src) in jump_table_section[t * jump_points_per_timestep..]
+                .iter_mut()
+                .zip(jump_table)
+            {
+                *dest = JumpPointer {
+                    offset: base as u64 + src.offset,
+                    state: src.state,
+                };
+            }
+        }
+    } else {
+        for &(t, _) in tree_order.iter().rev() {
+            encode_timestep(
+                &mut encoder,
+                t,
+                &mut jump_table_section
+                    [t * jump_points_per_timestep..(t + 1) * jump_points_per_timestep],
+            )?;
         }
     }
 
-    let (mut compressed_data_section, _) = encoder.into_raw_parts();
-    compressed_data_section.reverse();
-
-    let final_compressed_size: u32 = compressed_data_section.len().try_into().map_err(|_| ())?;
+    let (final_compressed_size, compressed_data_section) = encoder.into_compressed();
     for JumpPointer { offset, .. } in jump_table_section.iter_mut() {
-        *offset = final_compressed_size - *offset;
+        *offset = final_compressed_size as u64 - *offset;
     }
 
-    // Apply padding if necessary (*after* getting `final_compressed_size`).
-    if compressed_data_section.len() % 2 == 1 {
-        compressed_data_section.push(0);
-    }
-
-    Ok((jump_table_section, compressed_data_section))
+    Ok((
+        jump_table_section,
+        compressed_data_section,
+        final_compressed_size,
+    ))
 }
 
 /// Returns the number of written *bytes* (not u32's) upon success.
-pub fn write_compressed_dwe_file(
-    uncompressed: RankThreeTensorView<i16>,
+///
+/// If `vocab` is provided, it must contain one word per word ID (i.e., its length
+/// must be equal to `uncompressed.shape().1`) and it must not contain any
+/// duplicates. The words are then stored in an optional vocabulary section.
+/// Similarly, if `timestep_labels` is provided, it must contain one label per time
+/// step (i.e., its length must be equal to `uncompressed.shape().0`), and it gets
+/// stored in an optional time step labels section.
+///
+/// The minor version of the file format depends on the features that the file
+/// uses. Files without optional sections follow version 1.0, and files whose
+/// optional sections can be skipped by readers (vocabulary, time step labels, and
+/// scale factors) follow version 1.1. Files with sections that change how they
+/// have to be decoded (segments, predictor, model contexts, or model groups, see
+/// [`CompressionOptions`]) follow version 1.2, and files with a compact jump table
+/// follow version 1.3.
+///
+/// Files that are too large for the 32-bit addresses of version 1 of the file
+/// format (i.e., files larger than 16 GiB or with more than 2^32 words of
+/// compressed data) are written in version 2 of the file format instead, as are
+/// files with entropy models of more than 12 bits of precision or with
+/// `min_major_version = 2` (see [`CompressionOptions`]). The minor versions 1.0
+/// and 1.1 both correspond to version 2.0, version 1.2 corresponds to version
+/// 2.1, and version 1.3 to version 2.2.
+///
+/// The file stores the embedding vector components as symbols of the same type `T`
+/// as `uncompressed`, i.e., `i8`, `i16`, or `i32` (see [`SymbolType`]). Files with a
+/// symbol type other than `i16` are always written in version 2 of the file format
+/// and have to be read with, e.g.,
+/// [`EmbeddingFile::new_typed`](super::EmbeddingFile::new_typed).
+///
+/// Returns `Error::ResidualOverflow` if the difference between an embedding vector
+/// component and its prediction from neighboring time steps doesn't fit into `T`.
+/// This can only happen for values close to the limits of `T`, e.g., `i16::MIN`
+/// or `i16::MAX`.
+pub fn write_compressed_dwe_file<T: Symbol>(
+    uncompressed: RankThreeTensorView<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
     jump_interval: u32,
     scale_factor: f32,
-    mut output: impl Write,
-) -> Result<usize, ()> {
+    output: impl Write,
+) -> Result<usize> {
+    write_compressed_dwe_file_with_options(
+        uncompressed,
+        vocab,
+        timestep_labels,
+        &CompressionOptions::new(jump_interval, scale_factor),
+        output,
+    )
+}
+
+/// Same as [`write_compressed_dwe_file`] but with additional settings, e.g., a
+/// higher precision of the entropy models (see [`CompressionOptions`]).
+///
+/// Returns `Error::InvalidEntropyModel` if a time step has more distinct residuals
+/// than the entropy model precision can represent (more than 4096 with the default
+/// precision of 12 bits, or more than 65535 with any precision).
+///
+/// If `options.rate_distortion_tradeoff` is set then the file stores only an
+/// approximation of `uncompressed`. Use
+/// [`write_compressed_dwe_file_with_distortion`] to find out how good the
+/// approximation is.
+///
+/// With the `rayon` feature, the residuals and entropy models of different time
+/// steps get calculated in parallel (as far as time steps don't depend on each
+/// other), and so does encoding if `options.independent_streams` is set. The
+/// written file is the same with and without the feature.
+pub fn write_compressed_dwe_file_with_options<T: Symbol>(
+    uncompressed: RankThreeTensorView<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    output: impl Write,
+) -> Result<usize> {
+    let residuals = get_residuals(uncompressed, options)?;
+    write_residuals(&residuals, vocab, timestep_labels, options, output)
+}
+
+/// Same as [`write_compressed_dwe_file_with_options`] but additionally reports the
+/// distortion of lossy compression (see
+/// [`CompressionOptions::rate_distortion_tradeoff`]).
+///
+/// The returned report compares the real numbers that `uncompressed` represents
+/// (with `options.scale_factor` and `options.scale_factors`) to the ones that
+/// readers reconstruct from the file. All errors are zero for lossless compression.
+pub fn write_compressed_dwe_file_with_distortion<T: Symbol>(
+    uncompressed: RankThreeTensorView<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    output: impl Write,
+) -> Result<(usize, QuantizationReport)> {
+    let residuals = get_residuals(uncompressed, options)?;
+    let size = write_residuals(&residuals, vocab, timestep_labels, options, output)?;
+    let report = residual_distortion(uncompressed, &residuals, options)?;
+    Ok((size, report))
+}
+
+/// Same as [`write_compressed_dwe_file_with_options`] but reports both the
+/// distortion (see [`write_compressed_dwe_file_with_distortion`]) and the cost of
+/// independent ANS streams (see [`write_compressed_dwe_file_with_stream_overhead`]).
+pub fn write_compressed_dwe_file_with_distortion_and_stream_overhead<T: Symbol>(
+    uncompressed: RankThreeTensorView<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    output: impl Write,
+) -> Result<(usize, QuantizationReport, StreamOverhead)> {
+    let residuals = get_residuals(uncompressed, options)?;
+    let (size, overhead) =
+        write_residuals_impl(&residuals, vocab, timestep_labels, options, true, output)?;
+    let report = residual_distortion(uncompressed, &residuals, options)?;
+    Ok((size, report, overhead.expect("requested")))
+}
+
+/// Compares the real numbers that `uncompressed` represents to the ones that
+/// readers reconstruct from `residuals`.
+fn residual_distortion<T: Symbol>(
+    uncompressed: RankThreeTensorView<T>,
+    residuals: &Residuals<T>,
+    options: &CompressionOptions,
+) -> Result<QuantizationReport> {
     let (num_timesteps, vocab_size, embedding_dim) = uncompressed.shape();
-    let num_timesteps: u32 = num_timesteps.try_into().unwrap();
-    let vocab_size: u32 = vocab_size.try_into().unwrap();
-    let embedding_dim: u32 = embedding_dim.try_into().unwrap();
+    let scale_factors = options.scale_factors.as_ref();
+    if let Some(scale_factors) = scale_factors {
+        scale_factors.check_shape(num_timesteps, embedding_dim)?;
+    }
+    let mut represented = Vec::with_capacity(num_timesteps * vocab_size * embedding_dim);
+    for t in 0..num_timesteps {
+        let scales = scale_factors.map_or(&[1.0][..], |scale_factors| {
+            scale_factors.at_timestep(t as u32)
+        });
+        for embedding in uncompressed.subview(t).iter_subviews() {
+            for (&u, &scale) in embedding.iter().zip(scales.iter().cycle()) {
+                represented.push((options.scale_factor * scale) * u.to_i64() as f32);
+            }
+        }
+    }
+    let represented =
+        RankThreeTensor::from_flattened(represented, num_timesteps, vocab_size, embedding_dim);
+
+    Ok(measure_distortion(
+        represented.as_view(),
+        residuals.reconstructed_or(uncompressed),
+        options.scale_factor,
+        scale_factors,
+    ))
+}
+
+/// The cost of independent ANS streams, see
+/// [`write_compressed_dwe_file_with_stream_overhead`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct StreamOverhead {
+    /// Number of independent ANS streams in the file (one per time step, or one in
+    /// total unless `independent_streams` is set).
+    pub num_streams: usize,
+
+    /// Size of the compressed data in bits (without padding).
+    pub compressed_bits: u64,
+
+    /// Size that the compressed data would have had with a single continuous ANS
+    /// stream, in bits (without padding).
+    pub continuous_compressed_bits: u64,
+}
+
+impl StreamOverhead {
+    /// Returns how many bits the compressed data is larger than with a single
+    /// continuous ANS stream.
+    ///
+    /// This is typically slightly *negative*: a continuous stream carries the final
+    /// state of each time step over into the compressed data of the next one, while
+    /// an independent stream leaves it in the jump table, which stores it anyway.
+    pub fn overhead_bits(&self) -> i64 {
+        self.compressed_bits as i64 - self.continuous_compressed_bits as i64
+    }
+}
+
+/// Same as [`write_compressed_dwe_file_with_options`] but additionally reports how
+/// [`CompressionOptions::independent_streams`] changes the size of the compressed
+/// data.
+///
+/// If `options.independent_streams` is set then this compresses the data twice, once
+/// more with a single continuous ANS stream for comparison (which doesn't get
+/// written). Otherwise, the overhead is zero.
+pub fn write_compressed_dwe_file_with_stream_overhead<T: Symbol>(
+    uncompressed: RankThreeTensorView<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    output: impl Write,
+) -> Result<(usize, StreamOverhead)> {
+    let residuals = get_residuals(uncompressed, options)?;
+    let (size, overhead) =
+        write_residuals_impl(&residuals, vocab, timestep_labels, options, true, output)?;
+    Ok((size, overhead.expect("requested")))
+}
+
+/// Writes a file with the provided residuals. See
+/// [`write_compressed_dwe_file_with_options`] for the meaning of the remaining
+/// arguments.
+fn write_residuals<T: Symbol>(
+    residuals: &Residuals<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    output: impl Write,
+) -> Result<usize> {
+    write_residuals_impl(residuals, vocab, timestep_labels, options, false, output)
+        .map(|(size, _)| size)
+}
+
+/// Same as `write_residuals` but also measures the `StreamOverhead` if
+/// `measure_stream_overhead` is set.
+fn write_residuals_impl<T: Symbol>(
+    residuals: &Residuals<T>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    measure_stream_overhead: bool,
+    output: impl Write,
+) -> Result<(usize, Option<StreamOverhead>)> {
+    let shape = residuals.diffs.as_view().shape();
+    assert_valid_shape(shape, options.jump_interval);
+    let segments = options.segments(shape.0);
+    let optional_sections = optional_sections(
+        vocab,
+        timestep_labels,
+        options.scale_factors.as_ref(),
+        &segments,
+        residuals.predictor.as_ref(),
+        &residuals.model_contexts,
+        &residuals.model_groups,
+        shape,
+    )?;
+
+    let num_contexts = residuals.model_contexts.len();
+    let (encoder_models, entropy_models_section) = create_and_serialize_encoder_models(
+        &shared_counts(&residuals.counts, &residuals.model_groups, num_contexts),
+        &residuals.model_groups,
+        num_contexts,
+        options.entropy_precision,
+    )?;
+    let compress = |independent_streams| {
+        compress_data(
+            residuals.diffs.as_view(),
+            &segments,
+            &encoder_models,
+            &residuals.model_contexts,
+            &residuals.model_groups,
+            options.jump_interval,
+            independent_streams,
+        )
+    };
+    let (jump_table_section, compressed_data_section, num_compressed_words) =
+        compress(options.independent_streams)?;
+
+    let stream_overhead = if measure_stream_overhead {
+        let word_bits = options.entropy_precision.word_bits() as u64;
+        let compressed_bits = num_compressed_words as u64 * word_bits;
+        Some(if options.independent_streams {
+            let (_, _, num_continuous_words) = compress(false)?;
+            StreamOverhead {
+                num_streams: shape.0,
+                compressed_bits,
+                continuous_compressed_bits: num_continuous_words as u64 * word_bits,
+            }
+        } else {
+            StreamOverhead {
+                num_streams: 1,
+                compressed_bits,
+                continuous_compressed_bits: compressed_bits,
+            }
+        })
+    } else {
+        None
+    };
+
+    let size = assemble_file(
+        shape,
+        options,
+        T::SYMBOL_TYPE,
+        &entropy_models_section,
+        &jump_table_section,
+        compressed_data_section.len(),
+        |output| {
+            for &word in &compressed_data_section {
+                output.write_u32::<LittleEndian>(word)?;
+            }
+            Ok(())
+        },
+        &optional_sections,
+        output,
+    )?;
+    Ok((size, stream_overhead))
+}
+
+/// Panics unless the shape and the jump interval are valid for a file.
+fn assert_valid_shape(shape: (usize, usize, usize), jump_interval: u32) {
+    let (num_timesteps, vocab_size, embedding_dim) = shape;
+    assert!(u32::try_from(num_timesteps).is_ok());
+    assert!(u32::try_from(vocab_size).is_ok());
+    assert!(u32::try_from(embedding_dim).is_ok());
 
     assert!(vocab_size > 0);
     assert!(embedding_dim > 0);
-    assert!(num_timesteps >= 2);
+    assert!(num_timesteps > 0);
     assert!(jump_interval > 0);
-    assert!(jump_interval <= vocab_size);
-
-    let (diffs, counts) = get_diffs(uncompressed);
-    let (encoder_models, entropy_models_section) = create_and_serialize_encoder_models(&counts)?;
-    let (jump_table_section, compressed_data_section) =
-        compress_data(diffs.as_view(), &encoder_models, jump_interval)?;
-
-    let entropy_model_section_size: u32 = (entropy_models_section.len() / 2)
-        .try_into()
-        .map_err(|_| ())?;
-    let compressed_data_section_size: u32 = (compressed_data_section.len() / 2)
-        .try_into()
-        .map_err(|_| ())?;
-    let jump_table_address = HEADER_SIZE + entropy_model_section_size;
-    let file_size =
-        jump_table_address + 2 * jump_table_section.len() as u32 + compressed_data_section_size;
+    assert!(jump_interval as usize <= vocab_size);
+}
 
-    let file_header = FileHeader {
-        magic: 0x6577_6400,
-        major_version: 1,
-        minor_version: 0,
-        file_size,
-        jump_table_address,
-        num_timesteps,
-        vocab_size,
-        embedding_dim,
-        jump_interval,
-        scale_factor,
+/// Writes a complete file with the provided sections and symbol type to `output`.
+///
+/// The compressed data is not passed in directly but written by
+/// `write_compressed_data`, which must write exactly `compressed_data_size` `u32`s.
+/// Returns the number of written bytes.
+#[allow(clippy::too_many_arguments)]
+fn assemble_file<W: Write>(
+    shape: (usize, usize, usize),
+    options: &CompressionOptions,
+    symbol_type: SymbolType,
+    entropy_models_section: &[u16],
+    jump_table_section: &[JumpPointer],
+    compressed_data_size: usize,
+    write_compressed_data: impl FnOnce(&mut W) -> Result<()>,
+    optional_sections: &[(u32, Vec<u32>)],
+    mut output: W,
+) -> Result<usize> {
+    let (num_timesteps, vocab_size, embedding_dim) = shape;
+    let entropy_precision = options.entropy_precision;
+    let max_offset = jump_table_section
+        .iter()
+        .map(|p| p.offset)
+        .max()
+        .unwrap_or(0);
+
+    // A compact jump table replaces the uncompressed one and comes after all other
+    // optional sections.
+    let mut sections;
+    let (jump_table_section, optional_sections) = if options.compact_jump_table {
+        let jump_points_per_timestep = vocab_size.div_ceil(options.jump_interval as usize);
+        sections = optional_sections.to_vec();
+        sections.push((
+            COMPACT_JUMP_TABLE_SECTION_TAG,
+            compact_jump_table::serialize(jump_table_section, jump_points_per_timestep),
+        ));
+        (&[][..], &sections[..])
+    } else {
+        (jump_table_section, optional_sections)
     };
 
-    let header_section = unsafe {
-        const HEADER_SIZE: usize = std::mem::size_of::<FileHeader>() / 4;
-        // SAFETY: This is safe because `FileHeader` is `repr(C)` and has the same
-        // alignment as `[u32; HEADER_SIZE]`.
-        &*(&file_header as *const FileHeader as *const [u32; HEADER_SIZE])
+    let plan = plan_file(
+        options.min_major_version,
+        entropy_precision,
+        symbol_type,
+        entropy_models_section.len() / 2,
+        jump_table_section.len(),
+        max_offset,
+        compressed_data_size,
+        optional_sections,
+    );
+
+    let file_header = FileHeader {
+        magic: MAGIC,
+        major_version: plan.major_version,
+        minor_version: plan.minor_version,
+        file_size: plan.file_size,
+        jump_table_address: plan.jump_table_address,
+        num_timesteps: num_timesteps as u32,
+        vocab_size: vocab_size as u32,
+        embedding_dim: embedding_dim as u32,
+        jump_interval: options.jump_interval,
+        scale_factor: options.scale_factor,
+        entropy_precision,
+        symbol_type,
     };
 
     // Serialize all sections to the output writer.
-    for &word in header_section {
-        output.write_u32::<LittleEndian>(word).map_err(|_| ())?;
+    for word in file_header.to_words()? {
+        output.write_u32::<LittleEndian>(word)?;
     }
-    for word in entropy_models_section {
-        output.write_u16::<LittleEndian>(word).map_err(|_| ())?;
+    for &word in entropy_models_section {
+        output.write_u16::<LittleEndian>(word)?;
     }
-    for JumpPointer { offset, state } in jump_table_section {
-        output.write_u32::<LittleEndian>(offset).map_err(|_| ())?;
-        output.write_u32::<LittleEndian>(state).map_err(|_| ())?;
+    for &JumpPointer { offset, state } in jump_table_section {
+        if plan.major_version == 1 {
+            output.write_u32::<LittleEndian>(offset as u32)?;
+        } else {
+            output.write_u64::<LittleEndian>(offset)?;
+        }
+        if entropy_precision == EntropyPrecision::Bits24 {
+            output.write_u64::<LittleEndian>(state)?;
+        } else {
+            output.write_u32::<LittleEndian>(state as u32)?;
+        }
     }
-    for word in compressed_data_section {
-        output.write_u16::<LittleEndian>(word).map_err(|_| ())?;
+    write_compressed_data(&mut output)?;
+    if let Some(section_table) = plan.section_table {
+        for (_, section) in optional_sections {
+            for &word in section {
+                output.write_u32::<LittleEndian>(word)?;
+            }
+        }
+        for word in section_table {
+            output.write_u32::<LittleEndian>(word)?;
+        }
+        output.write_u32::<LittleEndian>(optional_sections.len() as u32)?;
     }
 
-    output.flush().map_err(|_| ())?;
+    output.flush()?;
 
-    Ok(file_size as usize * 4)
+    usize::try_from(plan.file_size * 4).map_err(|_| Error::TooLarge)
 }
 
-fn optimal_frequencies_12bit(counts: &HashMap<i16, u32>) -> Vec<(i16, u16)> {
+/// Quantizes real valued embeddings and compresses them.
+///
+/// This is a combination of [`quantize`](../quantization/fn.quantize.html) and
+/// [`write_compressed_dwe_file_with_options`]. The quantization step gets stored
+/// as the `scale_factor` in the file header, so `options.scale_factor` is ignored.
+/// If `options.scale_factors` is set then each component gets divided by its scale
+/// factor before quantization.
+///
+/// Returns the number of written bytes and a report of the distortion that
+/// quantization introduced (including the distortion due to lossy compression of
+/// the residuals if `options.rate_distortion_tradeoff` is set).
+pub fn write_compressed_dwe_file_from_float(
+    embeddings: RankThreeTensorView<f32>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    quantization: &QuantizationOptions,
+    options: &CompressionOptions,
+    output: impl Write,
+) -> Result<(usize, QuantizationReport)> {
+    let scale_factors = options.scale_factors.as_ref();
+    let (quantized, mut report) = quantize(embeddings, quantization, scale_factors)?;
+    let options = CompressionOptions {
+        scale_factor: report.scale_factor,
+        ..options.clone()
+    };
+    let residuals = get_residuals(quantized.as_view(), &options)?;
+    let size = write_residuals(&residuals, vocab, timestep_labels, &options, output)?;
+    if let Some(reconstructed) = &residuals.reconstructed {
+        report = measure_distortion(
+            embeddings,
+            reconstructed.as_view(),
+            report.scale_factor,
+            scale_factors,
+        );
+    }
+    Ok((size, report))
+}
+
+/// A budget for the size of a compressed file, see
+/// [`write_compressed_dwe_file_with_target_size`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeTarget {
+    /// Maximum file size in bytes, including the header, the entropy models, the
+    /// jump table, and all optional sections.
+    FileSize(u64),
+
+    /// Maximum file size in bits divided by the number of embedding vector
+    /// components (i.e., by `num_timesteps * vocab_size * embedding_dim`).
+    BitsPerCoordinate(f64),
+}
+
+impl SizeTarget {
+    /// Returns the maximum file size in bytes for `num_coordinates` embedding
+    /// vector components.
+    fn max_file_size(self, num_coordinates: usize) -> u64 {
+        match self {
+            SizeTarget::FileSize(size) => size,
+            SizeTarget::BitsPerCoordinate(bits) => {
+                (bits.max(0.0) * num_coordinates as f64 / 8.0) as u64
+            }
+        }
+    }
+}
+
+/// What [`write_compressed_dwe_file_with_target_size`] achieved.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct TargetSizeReport {
+    /// Size of the written file in bytes.
+    pub file_size: usize,
+
+    /// `file_size` in bits divided by the number of embedding vector components.
+    pub bits_per_coordinate: f64,
+
+    /// Estimated size of the compressed data of each time step in bits, i.e., the
+    /// information content of its residuals under its entropy model.
+    pub timestep_bits: Vec<f64>,
+
+    /// The distortion due to quantization, including the RMS error of each time
+    /// step. The chosen quantization step is `quantization.scale_factor`.
+    pub quantization: QuantizationReport,
+}
+
+/// Quantizes real valued embeddings as finely as possible such that the compressed
+/// file fits into `target`, and compresses them.
+///
+/// Searches for the quantization step by bisection on a logarithmic scale. Rather
+/// than compressing the embeddings for each candidate step, the search estimates
+/// the file size from the statistics of the prediction residuals, i.e., from the
+/// entropy models that the builder would use. Once the search converged, the
+/// embeddings get compressed for real, and `output` receives the file only if it
+/// actually fits into `target`. Otherwise (which can only happen if the estimate
+/// was off by a few bytes), the search gets repeated with a correspondingly
+/// smaller budget.
+///
+/// The remaining arguments have the same meaning as for
+/// [`write_compressed_dwe_file_from_float`], where `options.scale_factor` is
+/// ignored. Returns `Error::SizeTargetUnreachable` if not even the coarsest
+/// quantization step that the search considers (which quantizes all values to
+/// zero unless `dither_seed` is set) leads to a small enough file.
+pub fn write_compressed_dwe_file_with_target_size(
+    embeddings: RankThreeTensorView<f32>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    target: SizeTarget,
+    dither_seed: Option<u64>,
+    options: &CompressionOptions,
+    mut output: impl Write,
+) -> Result<TargetSizeReport> {
+    let shape = embeddings.shape();
+    let (num_timesteps, vocab_size, embedding_dim) = shape;
+    assert_valid_shape(shape, options.jump_interval);
+    let segments = options.segments(num_timesteps);
+    // Check that all metadata fits to the shape before searching.
+    optional_sections(
+        vocab,
+        timestep_labels,
+        options.scale_factors.as_ref(),
+        &segments,
+        None,
+        &options.model_contexts(shape)?,
+        &ModelGroups::per_timestep(num_timesteps as u32),
+        shape,
+    )?;
+
+    let num_coordinates = num_timesteps * vocab_size * embedding_dim;
+    let target_size = target.max_file_size(num_coordinates);
+    let scale_factors = options.scale_factors.as_ref();
+    let statistics = analyze(embeddings, scale_factors)?;
+    let num_jump_pointers = num_timesteps * vocab_size.div_ceil(options.jump_interval as usize);
+
+    let estimate = |step: f32| -> Result<_> {
+        let (quantized, mut report) =
+            quantize_with_step(embeddings, step, dither_seed, scale_factors);
+        let options = CompressionOptions {
+            scale_factor: step,
+            ..options.clone()
+        };
+        let residuals = get_residuals(quantized.as_view(), &options)?;
+        if let Some(reconstructed) = &residuals.reconstructed {
+            report = measure_distortion(embeddings, reconstructed.as_view(), step, scale_factors);
+        }
+        let optional_sections = optional_sections(
+            vocab,
+            timestep_labels,
+            scale_factors,
+            &segments,
+            residuals.predictor.as_ref(),
+            &residuals.model_contexts,
+            &residuals.model_groups,
+            embeddings.shape(),
+        )?;
+        let estimate = estimate_file_size(
+            &residuals.counts,
+            &residuals.model_groups,
+            residuals.model_contexts.len(),
+            &options,
+            num_timesteps,
+            num_jump_pointers,
+            &optional_sections,
+        );
+        Ok((residuals, report, estimate))
+    };
+    let fits = |estimate: &Option<(u64, Vec<f64>)>, budget: u64| matches!(estimate, Some((size, _)) if *size <= budget);
+
+    let min_step = match f32_at_least(statistics.min_step) {
+        // All values are zero, so any step works.
+        0.0 => 1.0,
+        step => step,
+    };
+    // Without dithering, all values get quantized to zero with this step.
+    let max_step = f32_at_least(4.0 * statistics.max_abs).max(min_step);
+
+    let mut budget = target_size;
+    loop {
+        let mut best = estimate(max_step)?;
+        if !fits(&best.2, budget) {
+            return Err(Error::SizeTargetUnreachable {
+                target: target_size,
+                min_size: best.2.map_or(u64::MAX, |(size, _)| size),
+            });
+        }
+
+        // Invariant: `high` leads to a small enough file and `low` doesn't (unless
+        // `low == high`).
+        let (mut low, mut high) = (min_step, max_step);
+        let candidate = estimate(min_step)?;
+        if fits(&candidate.2, budget) {
+            high = min_step;
+            best = candidate;
+        }
+        while high as f64 > low as f64 * (1.0 + 1e-4) {
+            let step = (low as f64 * high as f64).sqrt() as f32;
+            if step <= low || step >= high {
+                break;
+            }
+            let candidate = estimate(step)?;
+            if fits(&candidate.2, budget) {
+                high = step;
+                best = candidate;
+            } else {
+                low = step;
+            }
+        }
+
+        let (residuals, report, estimate) = best;
+        let options = CompressionOptions {
+            scale_factor: report.scale_factor,
+            ..options.clone()
+        };
+        let mut compressed = Vec::new();
+        write_residuals(
+            &residuals,
+            vocab,
+            timestep_labels,
+            &options,
+            &mut compressed,
+        )?;
+
+        let file_size = compressed.len() as u64;
+        if file_size <= target_size {
+            output.write_all(&compressed)?;
+            output.flush()?;
+            return Ok(TargetSizeReport {
+                file_size: compressed.len(),
+                bits_per_coordinate: 8.0 * file_size as f64 / num_coordinates as f64,
+                timestep_bits: estimate.expect("checked by `fits`").1,
+                quantization: report,
+            });
+        }
+
+        // Since `budget <= target_size`, this strictly decreases the budget, so the
+        // loop terminates.
+        budget = budget.saturating_sub(file_size - target_size);
+    }
+}
+
+/// Estimates the size in bytes of a file with `num_timesteps` time steps whose
+/// residuals have the statistics `counts` (with `models_per_timestep` entries per
+/// time step) and whose time steps share their entropy models according to
+/// `model_groups`, without compressing anything.
+///
+/// The compressed data is estimated by the information content of all residuals
+/// under the entropy models that `create_and_serialize_encoder_models` would
+/// create, and a compact jump table (if `options.compact_jump_table` is set) by
+/// `compact_jump_table::estimate_size`. Returns `None` if some entropy model can't
+/// be represented, and otherwise also the estimated size of the compressed data of
+/// each time step in bits.
+fn estimate_file_size<T: Symbol>(
+    counts: &[SymbolCounts<T>],
+    model_groups: &ModelGroups,
+    models_per_timestep: usize,
+    options: &CompressionOptions,
+    num_timesteps: usize,
+    num_jump_pointers: usize,
+    optional_sections: &[(u32, Vec<u32>)],
+) -> Option<(u64, Vec<f64>)> {
+    let precision = options.entropy_precision;
+    let (entropy_models_size, timestep_bits) =
+        model_statistics(counts, model_groups, models_per_timestep, precision)?;
+
+    // The encoder starts with one compressed word, see `Encoder::new`.
+    let total_bits = timestep_bits.iter().sum::<f64>();
+    let (num_compressed_words, compressed_data_size) = if precision == EntropyPrecision::Bits24 {
+        let num_words = (total_bits / 32.0).ceil() as u64 + 1;
+        (num_words, num_words)
+    } else {
+        let num_words = (total_bits / 16.0).ceil() as u64 + 1;
+        (num_words, num_words.div_ceil(2))
+    };
+
+    let mut sections;
+    let (num_jump_pointers, optional_sections) = if options.compact_jump_table {
+        let size = compact_jump_table::estimate_size(
+            num_timesteps,
+            num_jump_pointers,
+            num_compressed_words,
+            precision.word_bits(),
+        );
+        sections = optional_sections.to_vec();
+        sections.push((COMPACT_JUMP_TABLE_SECTION_TAG, vec![0; size]));
+        (0, &sections[..])
+    } else {
+        (num_jump_pointers, optional_sections)
+    };
+
+    let plan = plan_file(
+        options.min_major_version,
+        precision,
+        T::SYMBOL_TYPE,
+        entropy_models_size.div_ceil(2),
+        num_jump_pointers,
+        num_compressed_words,
+        compressed_data_size as usize,
+        optional_sections,
+    );
+    Some((plan.file_size * 4, timestep_bits))
+}
+
+/// Returns the size (in units of `u16`s, excluding padding) of the entropy models
+/// that `create_and_serialize_encoder_models` would create for residuals with the
+/// statistics `counts` (with `models_per_timestep` entries per time step) if the
+/// time steps share their entropy models according to `model_groups`, and the
+/// information content in bits of the residuals of each time step under these
+/// models. Returns `None` if some entropy model can't be represented.
+fn model_statistics<T: Symbol>(
+    counts: &[SymbolCounts<T>],
+    model_groups: &ModelGroups,
+    models_per_timestep: usize,
+    precision: EntropyPrecision,
+) -> Option<(usize, Vec<f64>)> {
+    let mut entropy_models_size = 0;
+    let mut bits = Vec::with_capacity(model_groups.len() * models_per_timestep);
+    for counts in shared_counts(counts, model_groups, models_per_timestep) {
+        let symbols_and_frequencies = optimal_frequencies(&counts, precision)?;
+        let num_symbols = u16::try_from(symbols_and_frequencies.len()).ok()?;
+        entropy_models_size += 1
+            + num_symbols as usize * T::SYMBOL_TYPE.serialized_size()
+            + packed_frequencies_size(num_symbols - 1, precision);
+        bits.push(
+            symbols_and_frequencies
+                .into_iter()
+                .map(|(symbol, frequency)| {
+                    (symbol, precision.bits() as f64 - (frequency as f64).log2())
+                })
+                .collect::<HashMap<_, _>>(),
+        );
+    }
+
+    let timestep_bits = counts
+        .chunks(models_per_timestep)
+        .enumerate()
+        .map(|(t, counts)| {
+            let group = model_groups.group_of(t as u32) as usize;
+            let bits = &bits[group * models_per_timestep..(group + 1) * models_per_timestep];
+            counts
+                .iter()
+                .zip(bits)
+                .flat_map(|(counts, bits)| {
+                    counts
+                        .iter()
+                        .map(move |(symbol, &count)| count as f64 * bits[symbol])
+                })
+                .sum::<f64>()
+        })
+        .collect();
+    Some((entropy_models_size, timestep_bits))
+}
+
+/// Merges the `counts` of each model context of each time step (with
+/// `models_per_timestep` entries per time step) into the counts of each model
+/// context of each group in `model_groups`.
+fn shared_counts<T: Symbol>(
+    counts: &[SymbolCounts<T>],
+    model_groups: &ModelGroups,
+    models_per_timestep: usize,
+) -> Vec<SymbolCounts<T>> {
+    if model_groups.is_per_timestep() {
+        return counts.to_vec();
+    }
+    let mut shared = vec![HashMap::new(); model_groups.len() * models_per_timestep];
+    for (t, counts) in counts.chunks(models_per_timestep).enumerate() {
+        let group = model_groups.group_of(t as u32) as usize;
+        for (shared, counts) in shared[group * models_per_timestep..].iter_mut().zip(counts) {
+            for (&symbol, &count) in counts {
+                // Saturating only makes the entropy model of a gigantic group slightly
+                // suboptimal.
+                let total = shared.entry(symbol).or_insert(0u32);
+                *total = total.saturating_add(count);
+            }
+        }
+    }
+    shared
+}
+
+/// Decides which time steps share their entropy models according to `sharing`,
+/// given the `counts` of the residuals in each model context of each time step
+/// (with `num_contexts` entries per time step) and the `segments` that determine
+/// the levels of the time steps in the bisection trees.
+fn choose_model_groups<T: Symbol>(
+    counts: &[SymbolCounts<T>],
+    segments: &Segments,
+    num_contexts: usize,
+    precision: EntropyPrecision,
+    sharing: ModelSharing,
+) -> ModelGroups {
+    let num_timesteps = (counts.len() / num_contexts) as u32;
+    let per_timestep = ModelGroups::per_timestep(num_timesteps);
+    match sharing {
+        ModelSharing::PerTimestep => per_timestep,
+        ModelSharing::TreeLevels => tree_level_groups(segments),
+        ModelSharing::Automatic => {
+            // Compare the sizes of the entropy models, the compressed data, and the
+            // model groups section (including its entry in the section table).
+            let tree_levels = tree_level_groups(segments);
+            let size = |model_groups: &ModelGroups| {
+                model_statistics(counts, model_groups, num_contexts, precision).map(
+                    |(entropy_models_size, timestep_bits)| {
+                        let section_size = if model_groups.is_per_timestep() {
+                            0
+                        } else {
+                            4 * (num_timesteps as usize + 5)
+                        };
+                        (2 * entropy_models_size + section_size) as f64
+                            + timestep_bits.iter().sum::<f64>() / 8.0
+                    },
+                )
+            };
+            match (size(&per_timestep), size(&tree_levels)) {
+                (Some(unshared), Some(shared)) if shared < unshared => tree_levels,
+                (None, Some(_)) => tree_levels,
+                _ => per_timestep,
+            }
+        }
+    }
+}
+
+/// Returns the model groups in which all time steps at the same level of the
+/// bisection trees of `segments` share their entropy models (see
+/// [`ModelSharing::TreeLevels`]). Groups are numbered in the order of their levels.
+fn tree_level_groups(segments: &Segments) -> ModelGroups {
+    // Keyframes have level zero, predicted segment roots have level one, and the
+    // time steps that bisect a range have levels from two on.
+    let num_timesteps = *segments.ends().last().expect("at least one segment") as usize + 1;
+    let mut levels = vec![0; num_timesteps];
+    for i in 0..segments.len() {
+        let (left_t, right_t) = segments.roots(i);
+        let (left_t, right_t) = (left_t as usize, right_t as usize);
+        if !segments.is_keyframe(i) {
+            levels[right_t] = 1;
+        }
+        traverse_subtree(2, left_t, 0, right_t, 1, &mut |t, level, _, _, _, _| {
+            levels[t] = level as u32;
+        });
+    }
+
+    let mut used_levels = levels.clone();
+    used_levels.sort_unstable();
+    used_levels.dedup();
+    ModelGroups::new(
+        levels
+            .iter()
+            .map(|level| used_levels.binary_search(level).expect("level is used") as u32)
+            .collect(),
+    )
+}
+
+/// Serializes the optional sections (if any) for embeddings with shape `shape`.
+///
+/// The segments section gets omitted if there's only a single segment, the
+/// predictor section gets omitted if `predictor` is `None`, the model contexts
+/// section gets omitted if there's only a single context, and the model groups
+/// section gets omitted if each time step has its own entropy models.
+#[allow(clippy::too_many_arguments)]
+fn optional_sections(
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    scale_factors: Option<&ScaleFactors>,
+    segments: &Segments,
+    predictor: Option<&Predictor>,
+    model_contexts: &ModelContexts,
+    model_groups: &ModelGroups,
+    shape: (usize, usize, usize),
+) -> Result<Vec<(u32, Vec<u32>)>> {
+    let (num_timesteps, vocab_size, embedding_dim) = shape;
+    let mut optional_sections = Vec::new();
+    if let Some(vocab) = vocab {
+        if vocab.len() != vocab_size {
+            return Err(Error::LengthMismatch {
+                what: "words in the vocabulary",
+                expected: vocab_size,
+                found: vocab.len(),
+            });
+        }
+        let vocabulary = Vocabulary::new(vocab)?;
+        optional_sections.push((VOCABULARY_SECTION_TAG, vocabulary.serialize()));
+    }
+    if let Some(timestep_labels) = timestep_labels {
+        if timestep_labels.len() != num_timesteps {
+            return Err(Error::LengthMismatch {
+                what: "time step labels",
+                expected: num_timesteps,
+                found: timestep_labels.len(),
+            });
+        }
+        optional_sections.push((TIMESTEP_LABELS_SECTION_TAG, timestep_labels.serialize()));
+    }
+    if let Some(scale_factors) = scale_factors {
+        scale_factors.check_shape(num_timesteps, embedding_dim)?;
+        optional_sections.push((SCALE_FACTORS_SECTION_TAG, scale_factors.serialize()));
+    }
+    if segments.len() > 1 {
+        optional_sections.push((SEGMENTS_SECTION_TAG, segments.serialize()));
+    }
+    if let Some(predictor) = predictor {
+        optional_sections.push((PREDICTOR_SECTION_TAG, predictor.serialize()));
+    }
+    if model_contexts.len() > 1 {
+        model_contexts.check_shape(vocab_size, embedding_dim)?;
+        optional_sections.push((MODEL_CONTEXTS_SECTION_TAG, model_contexts.serialize()));
+    }
+    if !model_groups.is_per_timestep() {
+        optional_sections.push((MODEL_GROUPS_SECTION_TAG, model_groups.serialize()));
+    }
+    Ok(optional_sections)
+}
+
+/// Version dependent parts of the layout of a file that's about to be written.
+struct FilePlan {
+    major_version: u32,
+    minor_version: u32,
+    jump_table_address: u64,
+    file_size: u64,
+    /// The section table, excluding the trailing `num_sections` field, or `None`
+    /// if the file doesn't end in a section table.
+    section_table: Option<Vec<u32>>,
+}
+
+/// Uses version 1 of the file format unless `min_major_version > 1`, the entropy
+/// models don't have 12 bit precision, the symbols aren't `i16`s, or some address
+/// or offset doesn't fit into a `u32`. All sizes are in units of four bytes.
+#[allow(clippy::too_many_arguments)]
+fn plan_file(
+    min_major_version: u32,
+    precision: EntropyPrecision,
+    symbol_type: SymbolType,
+    entropy_models_size: usize,
+    num_jump_pointers: usize,
+    max_offset: u64,
+    compressed_data_size: usize,
+    optional_sections: &[(u32, Vec<u32>)],
+) -> FilePlan {
+    let plan = |major_version| {
+        plan_file_with_version(
+            major_version,
+            precision,
+            entropy_models_size,
+            num_jump_pointers,
+            compressed_data_size,
+            optional_sections,
+        )
+    };
+
+    match plan(1) {
+        plan if min_major_version <= 1
+            && precision == EntropyPrecision::Bits12
+            && symbol_type == SymbolType::I16
+            && plan.file_size <= u32::MAX as u64
+            && max_offset <= u32::MAX as u64 =>
+        {
+            plan
+        }
+        _ => plan(2),
+    }
+}
+
+fn plan_file_with_version(
+    major_version: u32,
+    precision: EntropyPrecision,
+    entropy_models_size: usize,
+    num_jump_pointers: usize,
+    compressed_data_size: usize,
+    optional_sections: &[(u32, Vec<u32>)],
+) -> FilePlan {
+    let (header_size, offset_size) = if major_version == 1 {
+        (HEADER_SIZE, 1)
+    } else {
+        (HEADER_SIZE_V2, 2)
+    };
+    let state_size = if precision == EntropyPrecision::Bits24 {
+        2
+    } else {
+        1
+    };
+    let jump_pointer_size = offset_size + state_size;
+    let jump_table_address = header_size as u64 + entropy_models_size as u64;
+    let compressed_data_end = jump_table_address
+        + jump_pointer_size * num_jump_pointers as u64
+        + compressed_data_size as u64;
+
+    // Optional sections (if any) follow the compressed data, and the section table
+    // comes at the very end of the file (see file format version 1.1). Version 2
+    // files always end in a (possibly empty) section table.
+    let mut file_size = compressed_data_end;
+    // Readers that don't know about compact jump tables can't find the compressed
+    // data (see file format versions 1.3 and 2.2).
+    let requires_version_1_3 = optional_sections
+        .iter()
+        .any(|&(tag, _)| tag == COMPACT_JUMP_TABLE_SECTION_TAG);
+    // Files with more than one segment, with a predictor, with several model
+    // contexts, or with shared entropy models can't be read correctly by readers
+    // that don't know about these sections (see file format versions 1.2 and 2.1).
+    let requires_version_1_2 = optional_sections.iter().any(|&(tag, _)| {
+        tag == SEGMENTS_SECTION_TAG
+            || tag == PREDICTOR_SECTION_TAG
+            || tag == MODEL_CONTEXTS_SECTION_TAG
+            || tag == MODEL_GROUPS_SECTION_TAG
+    });
+    let section_table = if major_version >= 2 || !optional_sections.is_empty() {
+        let mut section_table = Vec::new();
+        for (tag, section) in optional_sections {
+            let size = section.len() as u64;
+            if major_version == 1 {
+                // Truncation is detected by the caller via `file_size`.
+                section_table.extend_from_slice(&[*tag, file_size as u32, size as u32]);
+            } else {
+                section_table.push(*tag);
+                section_table.extend_from_slice(&split_u64(file_size));
+                section_table.extend_from_slice(&split_u64(size));
+            }
+            file_size += size;
+        }
+        // Account for the section table and the trailing `num_sections` field.
+        file_size += section_table.len() as u64 + 1;
+        Some(section_table)
+    } else {
+        None
+    };
+
+    FilePlan {
+        major_version,
+        minor_version: match (major_version, &section_table) {
+            _ if requires_version_1_3 => decoding_minor_version(major_version, true),
+            _ if requires_version_1_2 => decoding_minor_version(major_version, false),
+            (1, Some(_)) => 1,
+            _ => 0,
+        },
+        jump_table_address,
+        file_size,
+        section_table,
+    }
+}
+
+/// Finds the fixed point probabilities (with the given precision) that minimize the
+/// cross entropy to the empirical distribution `counts`.
+///
+/// Returns `None` if there are more distinct symbols than the precision can
+/// represent with nonzero probabilities.
+fn optimal_frequencies<T: Symbol>(
+    counts: &SymbolCounts<T>,
+    precision: EntropyPrecision,
+) -> Option<Vec<(T, u32)>> {
     assert!(!counts.is_empty());
 
-    let max_weight = (1 << 12) - 1;
+    let total_weight = 1u32 << precision.bits();
+    let max_weight = total_weight - 1;
 
     if counts.len() == 1 {
         // The file format does not support degenerate models with all probability mass
         // on a single symbol. We therefore add a token additional symbol with minimal
         // frequency.
         let only_symbol = *counts.iter().next().unwrap().0;
-        return vec![(only_symbol, max_weight), (only_symbol.wrapping_add(1), 1)];
+        return Some(vec![
+            (only_symbol, max_weight),
+            (only_symbol.wrapping_add(T::saturating_from_i64(1)), 1),
+        ]);
     }
 
     // Start by assigning each symbol weight 1 and then distributing no more than
     // the remaining weight approximately evenly across all symbols.
-    let free_weight = (1 << 12) - counts.len() as u32;
-    let total_count = counts.iter().map(|(_, &count)| count).sum::<u32>();
-    let mut remaining_weight = 1 << 12;
+    let free_weight = total_weight.checked_sub(counts.len() as u32)?;
+    let total_count = counts.iter().map(|(_, &count)| count as u64).sum::<u64>();
+    let mut remaining_weight = total_weight;
 
     let mut symbols_counts_weights_wins_losses = counts
         .iter()
         .map(|(&symbol, &count)| {
-            let weight = (1 + count as u64 * free_weight as u64 / total_count as u64) as u16;
+            let weight = (1 + count as u64 * free_weight as u64 / total_count) as u32;
             remaining_weight -= weight;
 
             // How much the cross entropy would decrease when increasing the weight by one.
@@ -285,90 +1741,487 @@ fn optimal_frequencies_12bit(counts: &HashMap<i16, u32>) -> Vec<(i16, u16)> {
             *seller_count as f64 * (*seller_weight as f64 / (*seller_weight - 1) as f64).log2()
         };
 
-        let (_, buyer_count, buyer_weight, buyer_win, buyer_loss) =
-            &mut symbols_counts_weights_wins_losses[buyer_index];
-        *buyer_weight += 1;
-        *buyer_win = if *buyer_weight == max_weight {
-            f64::NEG_INFINITY
-        } else {
-            *buyer_count as f64 * ((*buyer_weight + 1) as f64 / *buyer_weight as f64).log2()
-        };
-        *buyer_loss =
-            *buyer_count as f64 * (*buyer_weight as f64 / (*buyer_weight - 1) as f64).log2();
+        let (_, buyer_count, buyer_weight, buyer_win, buyer_loss) =
+            &mut symbols_counts_weights_wins_losses[buyer_index];
+        *buyer_weight += 1;
+        *buyer_win = if *buyer_weight == max_weight {
+            f64::NEG_INFINITY
+        } else {
+            *buyer_count as f64 * ((*buyer_weight + 1) as f64 / *buyer_weight as f64).log2()
+        };
+        *buyer_loss =
+            *buyer_count as f64 * (*buyer_weight as f64 / (*buyer_weight - 1) as f64).log2();
+    }
+
+    let mut ret = symbols_counts_weights_wins_losses
+        .into_iter()
+        .map(|(symbol, _, weight, _, _)| (symbol, weight))
+        .collect::<Vec<_>>();
+    ret.sort_by_key(|&(s, w)| (u32::MAX - w, s)); // Sort to make output deterministic.
+    Some(ret)
+}
+
+/// The residuals of the prediction from neighboring time steps that get stored in
+/// a file.
+struct Residuals<T> {
+    diffs: RankThreeTensor<T>,
+
+    /// The counts of each model context of each time step.
+    counts: Vec<SymbolCounts<T>>,
+
+    model_contexts: ModelContexts,
+
+    /// Which time steps share the entropy models that get fitted to `counts`.
+    model_groups: ModelGroups,
+
+    /// The predictor with which `diffs` were calculated, or `None` for the mean of
+    /// both parents.
+    predictor: Option<Predictor>,
+
+    /// The quantized embeddings that readers reconstruct from `diffs`, or `None` if
+    /// they are identical to the input.
+    reconstructed: Option<RankThreeTensor<T>>,
+}
+
+impl<T: Symbol> Residuals<T> {
+    fn reconstructed_or<'a>(
+        &'a self,
+        input: RankThreeTensorView<'a, T>,
+    ) -> RankThreeTensorView<'a, T> {
+        self.reconstructed
+            .as_ref()
+            .map_or(input, |reconstructed| reconstructed.as_view())
+    }
+}
+
+/// Calculates the residuals for `input`, either losslessly with [`get_diffs`] or
+/// lossily with [`get_lossy_diffs`], depending on
+/// `options.rate_distortion_tradeoff`.
+fn get_residuals<T: Symbol>(
+    input: RankThreeTensorView<T>,
+    options: &CompressionOptions,
+) -> Result<Residuals<T>> {
+    match options.rate_distortion_tradeoff {
+        None => {
+            let segments = options.segments(input.shape().0);
+            let model_contexts = options.model_contexts(input.shape())?;
+            let predictor = choose_predictor(input, &segments, options.prediction_scheme);
+            let (diffs, counts) = get_diffs(input, &segments, predictor.as_ref(), &model_contexts)?;
+            let model_groups = choose_model_groups(
+                &counts,
+                &segments,
+                model_contexts.len(),
+                options.entropy_precision,
+                options.model_sharing,
+            );
+            Ok(Residuals {
+                diffs,
+                counts,
+                model_contexts,
+                model_groups,
+                predictor,
+                reconstructed: None,
+            })
+        }
+        Some(tradeoff) => get_lossy_diffs(input, options, tradeoff as f64),
+    }
+}
+
+/// Number of times that [`get_lossy_diffs`] chooses all residuals, each time with
+/// the entropy models that fit best to the residuals chosen in the previous pass.
+const RATE_DISTORTION_PASSES: usize = 3;
+
+/// Chooses residuals that trade off rate against distortion.
+///
+/// Works like [`get_diffs`] except that each residual may deviate slightly from the
+/// exact difference between `input` and its prediction if this saves enough bits.
+/// Predictions are calculated from the reconstructed (rather than the original)
+/// values of the parent time steps, just like readers do. Starts from the entropy
+/// models of the lossless residuals (which also determine which time steps share
+/// their entropy models) and then alternates between choosing residuals and
+/// fitting the entropy models to the chosen residuals.
+fn get_lossy_diffs<T: Symbol>(
+    input: RankThreeTensorView<T>,
+    options: &CompressionOptions,
+    tradeoff: f64,
+) -> Result<Residuals<T>> {
+    let (num_timesteps, vocab_size, embedding_dim) = input.shape();
+    let slice_len = vocab_size * embedding_dim;
+    let weights = distortion_weights(options, num_timesteps, embedding_dim)?;
+    let segments = options.segments(num_timesteps);
+    let model_contexts = options.model_contexts(input.shape())?;
+    let num_contexts = model_contexts.len();
+    let predictor = choose_predictor(input, &segments, options.prediction_scheme);
+
+    let (_, mut counts) = get_diffs(input, &segments, predictor.as_ref(), &model_contexts)?;
+    let model_groups = choose_model_groups(
+        &counts,
+        &segments,
+        num_contexts,
+        options.entropy_precision,
+        options.model_sharing,
+    );
+    let input = input.slice();
+    let mut diffs = vec![T::default(); input.len()];
+    let mut reconstructed = vec![T::default(); input.len()];
+    // Each time step is predicted from the reconstructed values of its parents, so
+    // only the time steps within each wave can be processed independently.
+    let waves = parallel::dependency_waves(&segments);
+
+    for _ in 0..RATE_DISTORTION_PASSES {
+        let rate_distortion = RateDistortion::new(
+            &shared_counts(&counts, &model_groups, num_contexts),
+            options.entropy_precision,
+            tradeoff,
+        );
+        counts = vec![HashMap::new(); num_timesteps * num_contexts];
+
+        for wave in &waves {
+            let results = parallel::map(wave, |&(t, parents)| {
+                let slice = |t: usize| &reconstructed[t * slice_len..(t + 1) * slice_len];
+                let mut timestep_diffs = vec![T::default(); slice_len];
+                let mut current = vec![T::default(); slice_len];
+                let mut timestep_counts = vec![HashMap::new(); num_contexts];
+                rate_distortion.choose_residuals(
+                    model_groups.group_of(t as u32) as usize,
+                    &input[t * slice_len..(t + 1) * slice_len],
+                    parents.map(|(left_t, right_t)| (slice(left_t), slice(right_t))),
+                    Prediction::of(predictor.as_ref(), t),
+                    &weights[t],
+                    &model_contexts,
+                    &mut timestep_diffs,
+                    &mut current,
+                    &mut timestep_counts,
+                );
+                (timestep_diffs, current, timestep_counts)
+            });
+            for (&(t, _), (timestep_diffs, current, timestep_counts)) in wave.iter().zip(results) {
+                let range = t * slice_len..(t + 1) * slice_len;
+                diffs[range.clone()].copy_from_slice(&timestep_diffs);
+                reconstructed[range].copy_from_slice(&current);
+                for (dest, src) in counts[t * num_contexts..].iter_mut().zip(timestep_counts) {
+                    *dest = src;
+                }
+            }
+        }
+    }
+
+    Ok(Residuals {
+        diffs: RankThreeTensor::from_flattened(diffs, num_timesteps, vocab_size, embedding_dim),
+        counts,
+        model_contexts,
+        model_groups,
+        predictor,
+        reconstructed: Some(RankThreeTensor::from_flattened(
+            reconstructed,
+            num_timesteps,
+            vocab_size,
+            embedding_dim,
+        )),
+    })
+}
+
+/// Returns the weights that convert squared errors of quantized values into squared
+/// errors of the real numbers that they represent, either one per time step or one
+/// per dimension for each time step.
+fn distortion_weights(
+    options: &CompressionOptions,
+    num_timesteps: usize,
+    embedding_dim: usize,
+) -> Result<Vec<Vec<f64>>> {
+    if let Some(scale_factors) = &options.scale_factors {
+        scale_factors.check_shape(num_timesteps, embedding_dim)?;
+    }
+
+    Ok((0..num_timesteps)
+        .map(|t| {
+            let scales = options
+                .scale_factors
+                .as_ref()
+                .map_or(&[1.0][..], |scale_factors| {
+                    scale_factors.at_timestep(t as u32)
+                });
+            scales
+                .iter()
+                .map(|&scale| {
+                    let scale = options.scale_factor as f64 * scale as f64;
+                    scale * scale
+                })
+                .collect()
+        })
+        .collect())
+}
+
+/// The entropy models that one pass of [`get_lossy_diffs`] assumes when it trades
+/// off rate against distortion.
+struct RateDistortion<T> {
+    tradeoff: f64,
+
+    /// Information content of each symbol under the entropy model of each model
+    /// context of each group of time steps (see [`ModelGroups`]).
+    bits: Vec<HashMap<T, f64>>,
+
+    /// Information content of symbols that don't appear in `bits`.
+    unknown_symbol_bits: f64,
+}
+
+impl<T: Symbol> RateDistortion<T> {
+    fn new(counts: &[SymbolCounts<T>], precision: EntropyPrecision, tradeoff: f64) -> Self {
+        Self {
+            tradeoff,
+            bits: counts
+                .iter()
+                .map(|counts| information_contents(counts, precision))
+                .collect(),
+            unknown_symbol_bits: precision.bits() as f64,
+        }
+    }
+
+    /// Chooses the residuals of a time step in group `group`, whose original values
+    /// are `center`, given the reconstructed values of its `parents` (or `None` for
+    /// keyframes, which are predicted as zero) and how to predict from them. Counts
+    /// the chosen residuals in `counts`, which has one entry per model context.
+    #[allow(clippy::too_many_arguments)]
+    fn choose_residuals(
+        &self,
+        group: usize,
+        center: &[T],
+        parents: Option<(&[T], &[T])>,
+        prediction: Prediction,
+        weights: &[f64],
+        contexts: &ModelContexts,
+        residuals: &mut [T],
+        reconstructed: &mut [T],
+        counts: &mut [SymbolCounts<T>],
+    ) {
+        let bits = &self.bits[group * contexts.len()..(group + 1) * contexts.len()];
+        let embedding_dim = contexts.dimension_buckets().len();
+        for (i, ((&center, residual), reconstructed)) in center
+            .iter()
+            .zip(residuals.iter_mut())
+            .zip(reconstructed.iter_mut())
+            .enumerate()
+        {
+            let prediction = parents.map_or(0, |(left, right)| {
+                prediction.predict(left[i], right[i]).to_i64()
+            });
+            let weight = weights[i % weights.len()];
+            let context = contexts.context((i / embedding_dim) as u32, i % embedding_dim);
+            let center = center.to_i64();
+            let exact = center - prediction;
+
+            // Consider residuals close to the exact one, and zero. Ties are resolved in
+            // favor of the exact residual. Zero is always valid because the prediction
+            // is in the range of the symbol type.
+            let mut best = (f64::INFINITY, T::default(), T::default());
+            for candidate in [exact, exact - 1, exact + 1, exact - 2, exact + 2, 0] {
+                let (symbol, value) =
+                    match (T::from_i64(candidate), T::from_i64(prediction + candidate)) {
+                        (Some(symbol), Some(value)) => (symbol, value),
+                        _ => continue,
+                    };
+                let error = (center - value.to_i64()) as f64;
+                let cost = weight * error * error
+                    + self.tradeoff
+                        * bits[context]
+                            .get(&symbol)
+                            .copied()
+                            .unwrap_or(self.unknown_symbol_bits);
+                if cost < best.0 {
+                    best = (cost, symbol, value);
+                }
+            }
+
+            let (_, symbol, value) = best;
+            *residual = symbol;
+            *reconstructed = value;
+            counts[context]
+                .entry(symbol)
+                .and_modify(|n| *n += 1)
+                .or_insert(1);
+        }
     }
+}
 
-    let mut ret = symbols_counts_weights_wins_losses
-        .into_iter()
-        .map(|(symbol, _, weight, _, _)| (symbol, weight))
-        .collect::<Vec<_>>();
-    ret.sort_by_key(|&(s, w)| (u16::MAX - w, s)); // Sort to make output deterministic.
-    ret
+/// Returns the information content in bits of each symbol in `counts` under the
+/// entropy model that the builder would use for these counts. Falls back to the
+/// empirical distribution if the entropy model can't be represented.
+fn information_contents<T: Symbol>(
+    counts: &SymbolCounts<T>,
+    precision: EntropyPrecision,
+) -> HashMap<T, f64> {
+    match optimal_frequencies(counts, precision) {
+        Some(symbols_and_frequencies) => symbols_and_frequencies
+            .into_iter()
+            .map(|(symbol, frequency)| {
+                (symbol, precision.bits() as f64 - (frequency as f64).log2())
+            })
+            .collect(),
+        None => {
+            let total = counts.values().map(|&count| count as f64).sum::<f64>();
+            counts
+                .iter()
+                .map(|(&symbol, &count)| (symbol, (total / count as f64).log2()))
+                .collect()
+        }
+    }
 }
 
 /// Calculates checked differences and their statistics.
 ///
 /// Returns a tuple `(diffs, counts)`, where `diffs` has the same shape as
-/// `input` and contains the differences from the left and right parent, and
-/// `counts` contains a `Vec` of `HashMap`s that map from symbols in the respective
-/// slice of `diff` to their counts.
-fn get_diffs(input: RankThreeTensorView<i16>) -> (RankThreeTensor<i16>, Vec<HashMap<i16, u32>>) {
+/// `input` and contains the differences from the prediction by the left and right
+/// parent (see [`Prediction::of`]), and `counts` contains a `Vec` of `HashMap`s
+/// that map from symbols in the respective slice of `diff` to their counts, with
+/// one `HashMap` per model context in `model_contexts` for each time step.
+///
+/// Returns `Error::ResidualOverflow` if a difference doesn't fit into `T`.
+fn get_diffs<T: Symbol>(
+    input: RankThreeTensorView<T>,
+    segments: &Segments,
+    predictor: Option<&Predictor>,
+    model_contexts: &ModelContexts,
+) -> Result<(RankThreeTensor<T>, Vec<SymbolCounts<T>>)> {
     let (num_timesteps, vocab_size, embedding_dim) = input.shape();
-    let mut diffs = RankThreeTensor::new(num_timesteps, vocab_size, embedding_dim);
-    let mut diffs_view = diffs.as_view_mut();
-    let mut counts = vec![HashMap::new(); num_timesteps];
-
-    // Copy over first and last time step and create their `counts`.
-    for &t in [0, num_timesteps - 1].iter() {
-        let source_view = input.subview(t);
-        let mut target_view = diffs_view.subview_mut(t);
-        let current_counts = &mut counts[t];
-
-        for (target, source) in target_view
-            .as_mut_slice()
-            .iter_mut()
-            .zip(source_view.slice())
-        {
-            *target = *source;
-            current_counts
-                .entry(*target)
-                .and_modify(|n| *n += 1)
-                .or_insert(1);
+    let slice_len = vocab_size * embedding_dim;
+    let num_contexts = model_contexts.len();
+    let input = input.slice();
+    let mut diffs = vec![T::default(); input.len()];
+    let mut counts = vec![HashMap::new(); num_timesteps * num_contexts];
+
+    // The residuals of each time step depend only on the input, so all time steps
+    // can be processed independently.
+    let tree_order = tree_order(segments);
+    let results = parallel::map(&tree_order, |&(t, parents)| {
+        let slice = |t: usize| &input[t * slice_len..(t + 1) * slice_len];
+        let mut diffs = vec![T::default(); slice_len];
+        let mut counts = vec![HashMap::new(); num_contexts];
+        exact_residuals(
+            t,
+            slice(t),
+            parents.map(|(left_t, right_t)| (slice(left_t), slice(right_t))),
+            Prediction::of(predictor, t),
+            model_contexts,
+            &mut diffs,
+            &mut counts,
+        )
+        .map(|()| (diffs, counts))
+    });
+    for (&(t, _), result) in tree_order.iter().zip(results) {
+        let (timestep_diffs, timestep_counts) = result?;
+        diffs[t * slice_len..(t + 1) * slice_len].copy_from_slice(&timestep_diffs);
+        for (dest, src) in counts[t * num_contexts..].iter_mut().zip(timestep_counts) {
+            *dest = src;
         }
     }
 
-    // Calculate diffs of inner time steps and create their `counts`.
-    traverse_subtree(
-        2,
-        0,
-        0,
-        num_timesteps - 1,
-        1,
-        &mut |t, _level, left_t, _left_level, right_t, _right_level| {
-            let left_view = input.subview(left_t);
-            let right_view = input.subview(right_t);
-            let center_view = input.subview(t);
-            let mut target_view = diffs_view.subview_mut(t);
-            let current_counts = &mut counts[t];
+    let diffs = RankThreeTensor::from_flattened(diffs, num_timesteps, vocab_size, embedding_dim);
+    Ok((diffs, counts))
+}
 
-            for (((target, left), right), center) in target_view
-                .as_mut_slice()
-                .iter_mut()
-                .zip(left_view.slice())
-                .zip(right_view.slice())
-                .zip(center_view.slice())
-            {
-                *target = (*center as i32 - ((*left as i32 + *right as i32) / 2))
-                    .try_into()
-                    .unwrap();
-                current_counts
-                    .entry(*target)
-                    .and_modify(|n| *n += 1)
-                    .or_insert(1);
+/// Calculates the residuals of time step `t`, whose values are `center`, given the
+/// values of its `parents` (or `None` for keyframes, which get stored verbatim)
+/// and how to predict from them, and counts them in `counts`, which has one entry
+/// per model context in `contexts`.
+///
+/// Returns `Error::ResidualOverflow` if a difference doesn't fit into `T`.
+fn exact_residuals<T: Symbol>(
+    t: usize,
+    center: &[T],
+    parents: Option<(&[T], &[T])>,
+    prediction: Prediction,
+    contexts: &ModelContexts,
+    residuals: &mut [T],
+    counts: &mut [SymbolCounts<T>],
+) -> Result<()> {
+    let embedding_dim = contexts.dimension_buckets().len();
+    for (i, (&center, residual)) in center.iter().zip(residuals.iter_mut()).enumerate() {
+        *residual = match parents {
+            None => center,
+            Some((left, right)) => {
+                let diff = center.to_i64() - prediction.predict(left[i], right[i]).to_i64();
+                T::from_i64(diff).ok_or(Error::ResidualOverflow {
+                    timestep: t as u32,
+                    word_index: (i / embedding_dim) as u32,
+                    dimension: (i % embedding_dim) as u32,
+                })?
             }
-        },
-    );
+        };
+        counts[contexts.context((i / embedding_dim) as u32, i % embedding_dim)]
+            .entry(*residual)
+            .and_modify(|n| *n += 1)
+            .or_insert(1);
+    }
+    Ok(())
+}
+
+/// Chooses the weights of the predictor for `input` according to `scheme`, or
+/// returns `None` for [`PredictionScheme::Mean`], which doesn't need any.
+fn choose_predictor<T: Symbol>(
+    input: RankThreeTensorView<T>,
+    segments: &Segments,
+    scheme: PredictionScheme,
+) -> Option<Predictor> {
+    if scheme == PredictionScheme::Mean {
+        return None;
+    }
+    let mut fit = PredictorFit::new(scheme, segments, 0);
+    if fit.needs_values() {
+        for (t, parents) in tree_order(segments) {
+            let slice = |t: usize| input.subview(t).slice();
+            fit.add(
+                t,
+                slice(t),
+                parents.map(|(left_t, right_t)| (slice(left_t), slice(right_t))),
+            );
+        }
+    }
+    Some(Predictor::new(scheme, fit.weights()))
+}
+
+/// Returns all time steps in the order in which readers decode them, each together
+/// with its left and right parent (or `None` for keyframes).
+///
+/// Parents always come before their children. If there's only a single time step
+/// then it is both the first and the last one.
+fn tree_order(segments: &Segments) -> Vec<(usize, Option<(usize, usize)>)> {
+    let num_timesteps = *segments.ends().last().expect("at least one segment") as usize + 1;
+    let mut tree_order = Vec::with_capacity(num_timesteps);
+    tree_order.push((0, None));
+    for i in 0..segments.len() {
+        push_segment_tree_order(segments, i, &mut tree_order);
+    }
+    tree_order
+}
 
-    (diffs, counts)
+/// Appends the time steps of segment `i` to `tree_order` as in [`tree_order`],
+/// except for the segment's left root, which belongs to the previous segment (or is
+/// time step zero).
+fn push_segment_tree_order(
+    segments: &Segments,
+    i: usize,
+    tree_order: &mut Vec<(usize, Option<(usize, usize)>)>,
+) {
+    let (left_t, right_t) = segments.roots(i);
+    let (left_t, right_t) = (left_t as usize, right_t as usize);
+    if right_t != left_t {
+        let parents = if segments.is_keyframe(i) {
+            None
+        } else {
+            Some((left_t, left_t))
+        };
+        tree_order.push((right_t, parents));
+        traverse_subtree(
+            2,
+            left_t,
+            0,
+            right_t,
+            1,
+            &mut |t, _, left_t, _, right_t, _| {
+                tree_order.push((t, Some((left_t, right_t))));
+            },
+        );
+    }
 }
 
 fn traverse_subtree<F: FnMut(usize, usize, usize, usize, usize, usize)>(
@@ -391,22 +2244,49 @@ fn traverse_subtree<F: FnMut(usize, usize, usize, usize, usize, usize)>(
 mod test {
     use super::*;
 
-    use super::super::{EmbeddingFile, TimestepReader};
+    use super::super::{
+        has_compact_jump_table,
+        lazy::{InMemoryRangeSource, LazyEmbeddingFile},
+        timestep_labels::TimestepLabel,
+        EmbeddingFile, TimestepReader,
+    };
     use crate::tensors::RankTwoTensorView;
 
+    use rand::{rngs::StdRng, Rng, SeedableRng};
     use std::fs::File;
     use std::io::prelude::*;
 
+    /// Returns random walks that start uniformly in `-100..=100` and change each
+    /// component by at most `max_step` per time step.
+    fn random_walk(
+        seed: u64,
+        num_timesteps: usize,
+        vocab_size: usize,
+        embedding_dim: usize,
+        max_step: i16,
+    ) -> RankThreeTensor<i16> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let slice_len = vocab_size * embedding_dim;
+        let mut data = Vec::with_capacity(num_timesteps * slice_len);
+        for i in 0..num_timesteps * slice_len {
+            data.push(match i.checked_sub(slice_len) {
+                None => rng.random_range(-100..=100),
+                Some(previous) => data[previous] + rng.random_range(-max_step..=max_step),
+            });
+        }
+        RankThreeTensor::from_flattened(data, num_timesteps, vocab_size, embedding_dim)
+    }
+
     #[test]
-    fn test_optimal_frequencies_12bit() {
-        fn test(counts_and_expected_frequencies: &[(u32, u16)]) {
+    fn test_optimal_frequencies() {
+        fn test(precision: EntropyPrecision, counts_and_expected_frequencies: &[(u32, u32)]) {
             let (counts, expected) = counts_and_expected_frequencies
                 .iter()
                 .enumerate()
                 .map(|(s, &(c, f))| ((s as i16, c), (s as i16, f)))
                 .unzip();
 
-            let symbols_and_frequencies = optimal_frequencies_12bit(&counts);
+            let symbols_and_frequencies = optimal_frequencies(&counts, precision).unwrap();
             let calculated = symbols_and_frequencies
                 .into_iter()
                 .collect::<HashMap<_, _>>();
@@ -414,10 +2294,23 @@ mod test {
             assert_eq!(calculated, expected);
         }
 
-        test(&[(2, 0x0200), (5, 0x0500), (9, 0x0900)]);
-        test(&[(3, 723), (5, 1205), (9, 2168)]);
-        test(&[(3000, 723), (5000, 1204), (9008, 2169)]);
-        test(&[(3000, 722), (5000, 1204), (9009, 2170)]);
+        use EntropyPrecision::*;
+        test(Bits12, &[(2, 0x0200), (5, 0x0500), (9, 0x0900)]);
+        test(Bits12, &[(3, 723), (5, 1205), (9, 2168)]);
+        test(Bits12, &[(3000, 723), (5000, 1204), (9008, 2169)]);
+        test(Bits12, &[(3000, 722), (5000, 1204), (9009, 2170)]);
+        test(Bits16, &[(2, 0x2000), (5, 0x5000), (9, 0x9000)]);
+        test(Bits24, &[(2, 0x20_0000), (5, 0x50_0000), (9, 0x90_0000)]);
+
+        // Rare symbols get more accurate probabilities with higher precision.
+        test(Bits12, &[(1, 1), (100_000, 4095)]);
+        test(Bits16, &[(1, 1), (100_000, 0xffff)]);
+        test(Bits24, &[(1, 168), (100_000, 0xff_ff58)]);
+
+        // Each symbol needs a nonzero frequency.
+        let counts = (0..=4096).map(|s| (s as i16, 1)).collect::<HashMap<_, _>>();
+        assert!(optimal_frequencies(&counts, Bits12).is_none());
+        assert!(optimal_frequencies(&counts, Bits16).is_some());
     }
 
     #[test]
@@ -465,9 +2358,15 @@ mod test {
 
         const SCALE_FACTOR: f32 = 0.125;
         let mut compressed = Vec::<u8>::new();
-        let file_size =
-            write_compressed_dwe_file(uncompressed, JUMP_INTERVAL, SCALE_FACTOR, &mut compressed)
-                .unwrap();
+        let file_size = write_compressed_dwe_file(
+            uncompressed,
+            None,
+            None,
+            JUMP_INTERVAL,
+            SCALE_FACTOR,
+            &mut compressed,
+        )
+        .unwrap();
 
         assert_eq!(file_size, compressed.len());
         assert_eq!(file_size % 4, 0);
@@ -494,13 +2393,15 @@ mod test {
                 magic: header.magic, // Already checked above.
                 major_version: 1,
                 minor_version: 0,
-                file_size: compressed_len as u32,
+                file_size: compressed_len as u64,
                 jump_table_address: header.jump_table_address, // Checked in `EmbeddingFile::new`.
                 num_timesteps: NUM_TIMESTEPS,
                 vocab_size: VOCAB_SIZE,
                 embedding_dim: EMBEDDING_DIM,
                 jump_interval: JUMP_INTERVAL,
                 scale_factor: SCALE_FACTOR,
+                entropy_precision: EntropyPrecision::Bits12,
+                symbol_type: SymbolType::I16,
             }
         );
 
@@ -523,7 +2424,13 @@ mod test {
             }
         };
 
-        let (diffs, _) = get_diffs(uncompressed);
+        let (diffs, _) = get_diffs(
+            uncompressed,
+            &Segments::single(uncompressed.shape().0 as u32),
+            None,
+            &ModelContexts::single(uncompressed.shape().2 as u32),
+        )
+        .unwrap();
         for t in 0..NUM_TIMESTEPS {
             test_timestep(t, diffs.as_view().subview(t as usize));
         }
@@ -551,4 +2458,1400 @@ mod test {
             RankTwoTensorView::from_flattened(VOCAB_SIZE, EMBEDDING_DIM, &center_diff);
         test_timestep(center_timestep, center_diff);
     }
+
+    #[test]
+    fn create_file_with_optional_sections() {
+        let vocab = ["one", "two", "three", "thirty", "thirteen"]
+            .iter()
+            .map(|&word| word.to_string())
+            .collect::<Vec<_>>();
+        let uncompressed = (0..3 * 5 * 2).map(|x| (x * 7 % 11) as i16 - 5).collect();
+        let uncompressed = RankThreeTensor::from_flattened(uncompressed, 3, 5, 2);
+
+        let labels = TimestepLabels::from_strings(&["before", "during", "after"]).unwrap();
+
+        let mut compressed = Vec::<u8>::new();
+        write_compressed_dwe_file(
+            uncompressed.as_view(),
+            Some(&vocab),
+            Some(&labels),
+            2,
+            0.5,
+            &mut compressed,
+        )
+        .unwrap();
+        let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+
+        assert_eq!(file.header().minor_version, 1);
+        for (id, word) in vocab.iter().enumerate() {
+            assert_eq!(file.word_to_id(word), Some(id as u32));
+            assert_eq!(file.id_to_word(id as u32), Some(word.as_str()));
+        }
+        assert_eq!(file.word_to_id("thirt"), None);
+        assert_eq!(file.id_to_word(5), None);
+        assert_eq!(file.timestep_labels(), Some(&labels));
+        assert_eq!(
+            file.timestep_label(1),
+            Some(TimestepLabel::Text("during".into()))
+        );
+
+        // Simulate a reader that only understands version 1.0 of the file format,
+        // which should still be able to decode all embeddings.
+        let expected = file
+            .into_random_access_reader()
+            .get_embeddings_at(1)
+            .unwrap();
+        let mut data = EmbeddingFile::from_reader(&compressed[..])
+            .unwrap()
+            .into_inner();
+        data[2] = 0; // Set `minor_version` to zero.
+        let file = EmbeddingFile::new(data).unwrap();
+        assert!(file.vocabulary().is_none());
+        assert!(file.timestep_labels().is_none());
+        let found = file
+            .into_random_access_reader()
+            .get_embeddings_at(1)
+            .unwrap();
+        assert_eq!(found.into_inner(), expected.into_inner());
+
+        // Duplicate words are not allowed.
+        let mut vocab = vocab;
+        vocab[4] = "one".to_string();
+        assert!(matches!(
+            write_compressed_dwe_file(
+                uncompressed.as_view(),
+                Some(&vocab),
+                None,
+                2,
+                0.5,
+                Vec::new()
+            ),
+            Err(Error::DuplicateWord(word)) if word == "one"
+        ));
+
+        // The vocabulary has to match the vocabulary size.
+        assert!(matches!(
+            write_compressed_dwe_file(
+                uncompressed.as_view(),
+                Some(&vocab[..4]),
+                None,
+                2,
+                0.5,
+                Vec::new()
+            ),
+            Err(Error::LengthMismatch {
+                expected: 5,
+                found: 4,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn entropy_precisions() {
+        const NUM_TIMESTEPS: usize = 4;
+        const VOCAB_SIZE: usize = 500;
+        const EMBEDDING_DIM: usize = 20;
+
+        // A peaked distribution with a long tail, so that each time step has more
+        // distinct residuals than 12 bit entropy models can represent.
+        let mut rng = StdRng::seed_from_u64(20_201_020);
+        let uncompressed = (0..NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| {
+                if rng.random_bool(0.4) {
+                    rng.random_range(-3..=3i16)
+                } else {
+                    rng.random_range(-10_000..=10_000)
+                }
+            })
+            .collect();
+        let uncompressed =
+            RankThreeTensor::from_flattened(uncompressed, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+
+        let write = |entropy_precision| {
+            let mut options = CompressionOptions::new(7, 0.01);
+            options.entropy_precision = entropy_precision;
+            let mut compressed = Vec::<u8>::new();
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                &options,
+                &mut compressed,
+            )
+            .map(|_| compressed)
+        };
+
+        assert!(matches!(
+            write(EntropyPrecision::Bits12),
+            Err(Error::InvalidEntropyModel { .. })
+        ));
+
+        let mut expected = None;
+        for entropy_precision in [EntropyPrecision::Bits16, EntropyPrecision::Bits24] {
+            let compressed = write(entropy_precision).unwrap();
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            let header = file.header();
+            assert_eq!(header.major_version, 2);
+            assert_eq!(header.entropy_precision, entropy_precision);
+
+            let lazy =
+                LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compressed), 256, 4)
+                    .unwrap()
+                    .into_random_access_reader();
+            let file = file.into_random_access_reader();
+            let embeddings = (0..NUM_TIMESTEPS as u32)
+                .map(|t| {
+                    let embeddings = file.get_embeddings_at(t).unwrap().into_inner();
+                    assert_eq!(lazy.get_embeddings_at(t).unwrap().into_inner(), embeddings);
+                    embeddings
+                })
+                .collect::<Vec<_>>();
+            let changes = file.largest_changes_wrt(123, 10, 1, 2).unwrap();
+            assert_eq!(lazy.largest_changes_wrt(123, 10, 1, 2).unwrap(), changes);
+
+            let expected = expected.get_or_insert((embeddings.clone(), changes.clone()));
+            assert_eq!(embeddings, expected.0);
+            assert_eq!(changes, expected.1);
+        }
+    }
+
+    #[test]
+    fn any_number_of_timesteps() {
+        const VOCAB_SIZE: usize = 30;
+        const EMBEDDING_DIM: usize = 4;
+
+        let mut rng = StdRng::seed_from_u64(20_201_105);
+        for num_timesteps in 1..10 {
+            let uncompressed = (0..num_timesteps * VOCAB_SIZE * EMBEDDING_DIM)
+                .map(|_| rng.random_range(-20..=20))
+                .collect();
+            let uncompressed = RankThreeTensor::from_flattened(
+                uncompressed,
+                num_timesteps,
+                VOCAB_SIZE,
+                EMBEDDING_DIM,
+            );
+
+            let mut compressed = Vec::<u8>::new();
+            write_compressed_dwe_file(uncompressed.as_view(), None, None, 4, 0.1, &mut compressed)
+                .unwrap();
+
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            assert_eq!(file.header().num_timesteps, num_timesteps as u32);
+            let lazy =
+                LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compressed), 64, 2)
+                    .unwrap()
+                    .into_random_access_reader();
+            let file = file.into_random_access_reader();
+
+            for t in 0..num_timesteps {
+                let expected = uncompressed.as_view().subview(t).slice();
+                assert_eq!(
+                    file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                    expected
+                );
+                assert_eq!(
+                    lazy.get_embeddings_at(t as u32).unwrap().into_inner(),
+                    expected
+                );
+            }
+
+            let trajectories = file
+                .pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0])
+                .unwrap()
+                .into_inner();
+            assert_eq!(trajectories.len(), 3 * num_timesteps);
+            assert_eq!(
+                lazy.pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0])
+                    .unwrap()
+                    .into_inner(),
+                trajectories
+            );
+            for (i, (&w1, &w2)) in [3usize, 7, 29].iter().zip(&[5usize, 7, 0]).enumerate() {
+                for t in 0..num_timesteps {
+                    let embeddings = uncompressed.as_view().subview(t);
+                    let scalar_product = embeddings
+                        .subview(w1)
+                        .iter()
+                        .zip(embeddings.subview(w2))
+                        .map(|(&a, &b)| a as i32 * b as i32)
+                        .sum::<i32>();
+                    let found = trajectories[i * num_timesteps + t];
+                    assert!((found - 0.01 * scalar_product as f32).abs() < 1e-3);
+                }
+            }
+
+            let changes = file.largest_changes_wrt(3, 5, 1, 1).unwrap();
+            assert_eq!(changes.len(), 5);
+            assert_eq!(lazy.largest_changes_wrt(3, 5, 1, 1).unwrap(), changes);
+        }
+    }
+
+    #[test]
+    fn keyframes() {
+        const VOCAB_SIZE: usize = 30;
+        const EMBEDDING_DIM: usize = 4;
+
+        let mut rng = StdRng::seed_from_u64(20_201_209);
+        for num_timesteps in [1, 2, 3, 5, 8, 9, 17] {
+            let uncompressed = (0..num_timesteps * VOCAB_SIZE * EMBEDDING_DIM)
+                .map(|_| rng.random_range(-20..=20))
+                .collect();
+            let uncompressed = RankThreeTensor::from_flattened(
+                uncompressed,
+                num_timesteps,
+                VOCAB_SIZE,
+                EMBEDDING_DIM,
+            );
+            let write = |options: &CompressionOptions| {
+                let mut compressed = Vec::new();
+                write_compressed_dwe_file_with_options(
+                    uncompressed.as_view(),
+                    None,
+                    None,
+                    options,
+                    &mut compressed,
+                )
+                .unwrap();
+                compressed
+            };
+            let mut options = CompressionOptions::new(4, 0.1);
+            let expected = EmbeddingFile::from_reader(&write(&options)[..])
+                .unwrap()
+                .into_random_access_reader();
+
+            for keyframe_interval in [1, 2, 3, 4, 100] {
+                options.keyframe_interval = Some(keyframe_interval);
+                let compressed = write(&options);
+                let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+                let segments =
+                    Segments::with_keyframe_interval(num_timesteps as u32, keyframe_interval);
+                assert_eq!(file.segments(), &segments);
+                let expected_minor_version = if segments.len() == 1 { 0 } else { 2 };
+                assert_eq!(file.header().minor_version, expected_minor_version);
+
+                let lazy =
+                    LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compressed), 64, 2)
+                        .unwrap()
+                        .into_random_access_reader();
+                let file = file.into_random_access_reader();
+                for t in 0..num_timesteps {
+                    let expected = uncompressed.as_view().subview(t).slice();
+                    assert_eq!(
+                        file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                    assert_eq!(
+                        lazy.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                }
+
+                let trajectories = expected
+                    .pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0])
+                    .unwrap()
+                    .into_inner();
+                assert_eq!(
+                    file.pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0])
+                        .unwrap()
+                        .into_inner(),
+                    trajectories
+                );
+                assert_eq!(
+                    lazy.pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0])
+                        .unwrap()
+                        .into_inner(),
+                    trajectories
+                );
+                let changes = expected.largest_changes_wrt(3, 5, 1, 1).unwrap();
+                assert_eq!(file.largest_changes_wrt(3, 5, 1, 1).unwrap(), changes);
+                assert_eq!(lazy.largest_changes_wrt(3, 5, 1, 1).unwrap(), changes);
+            }
+        }
+    }
+
+    #[test]
+    fn prediction_schemes() {
+        const NUM_TIMESTEPS: usize = 11;
+        const VOCAB_SIZE: usize = 30;
+        const EMBEDDING_DIM: usize = 4;
+        const SLICE_LEN: usize = VOCAB_SIZE * EMBEDDING_DIM;
+
+        // Smooth trajectories, for which the prediction schemes actually differ.
+        let mut rng = StdRng::seed_from_u64(20_201_211);
+        let mut uncompressed = (0..SLICE_LEN)
+            .map(|_| rng.random_range(-1000..=1000))
+            .collect::<Vec<i16>>();
+        for t in 1..NUM_TIMESTEPS {
+            for i in 0..SLICE_LEN {
+                let previous = uncompressed[(t - 1) * SLICE_LEN + i];
+                uncompressed.push(previous + rng.random_range(-10..=10));
+            }
+        }
+        let uncompressed =
+            RankThreeTensor::from_flattened(uncompressed, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+        let write = |options: &CompressionOptions| {
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                options,
+                &mut compressed,
+            )
+            .unwrap();
+            compressed
+        };
+
+        let options = CompressionOptions::new(4, 0.1);
+        let expected = EmbeddingFile::from_reader(&write(&options)[..]).unwrap();
+        assert!(expected.predictor().is_none());
+        assert_eq!(expected.header().minor_version, 0);
+        let expected = expected.into_random_access_reader();
+        let expected_trajectories = expected
+            .pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0])
+            .unwrap()
+            .into_inner();
+        let expected_changes = expected.largest_changes_wrt(3, 5, 1, 1).unwrap();
+
+        for scheme in [
+            PredictionScheme::DistanceWeighted,
+            PredictionScheme::LeastSquaresPerLevel,
+            PredictionScheme::PreviousOnly,
+        ] {
+            for keyframe_interval in [None, Some(4)] {
+                let mut options = options.clone();
+                options.prediction_scheme = scheme;
+                options.keyframe_interval = keyframe_interval;
+                let compressed = write(&options);
+                let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+                assert_eq!(file.header().minor_version, 2);
+                let predictor = file.predictor().unwrap();
+                assert_eq!(predictor.scheme(), Some(scheme));
+                if scheme == PredictionScheme::DistanceWeighted && keyframe_interval.is_none() {
+                    // Time step 2 is bisected from time steps 0 and 5.
+                    let (left, right) = predictor.weights(2);
+                    assert!((left - 0.6).abs() < 1e-4 && (right - 0.4).abs() < 1e-4);
+                    assert_eq!(left + right, 1.0);
+                }
+
+                let lazy =
+                    LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compressed), 64, 2)
+                        .unwrap()
+                        .into_random_access_reader();
+                assert_eq!(lazy.file().predictor(), Some(predictor));
+                let file = file.into_random_access_reader();
+                for t in 0..NUM_TIMESTEPS {
+                    let expected = uncompressed.as_view().subview(t).slice();
+                    assert_eq!(
+                        file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                    assert_eq!(
+                        lazy.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                }
+                for reader_trajectories in [
+                    file.pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0]),
+                    lazy.pairwise_trajectories(vec![3, 7, 29], vec![5, 7, 0]),
+                ] {
+                    assert_eq!(
+                        reader_trajectories.unwrap().into_inner(),
+                        expected_trajectories
+                    );
+                }
+                assert_eq!(
+                    file.largest_changes_wrt(3, 5, 1, 1).unwrap(),
+                    expected_changes
+                );
+                assert_eq!(
+                    lazy.largest_changes_wrt(3, 5, 1, 1).unwrap(),
+                    expected_changes
+                );
+
+                // Lossy compression predicts from reconstructed values with the same
+                // predictor.
+                options.rate_distortion_tradeoff = Some(1e-4);
+                let mut lossy = Vec::new();
+                let (_, report) = write_compressed_dwe_file_with_distortion(
+                    uncompressed.as_view(),
+                    None,
+                    None,
+                    &options,
+                    &mut lossy,
+                )
+                .unwrap();
+                assert!(write(&options) == lossy);
+                let lossy = EmbeddingFile::from_reader(&lossy[..])
+                    .unwrap()
+                    .into_random_access_reader();
+                let max_error = (0..NUM_TIMESTEPS)
+                    .flat_map(|t| {
+                        let decoded = lossy.get_embeddings_at(t as u32).unwrap().into_inner();
+                        let expected = uncompressed.as_view().subview(t).slice().to_vec();
+                        decoded
+                            .into_iter()
+                            .zip(expected)
+                            .map(|(a, b)| (a as i32 - b as i32).abs())
+                            .collect::<Vec<_>>()
+                    })
+                    .max()
+                    .unwrap();
+                assert!((max_error as f32 * 0.1 - report.max_abs_error).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn model_contexts() {
+        const NUM_TIMESTEPS: usize = 5;
+        const VOCAB_SIZE: usize = 200;
+        const EMBEDDING_DIM: usize = 4;
+        const JUMP_INTERVAL: u32 = 16;
+
+        // Dimensions 1 and 3 vary much more than dimensions 0 and 2, and frequent
+        // (i.e., low index) words vary more than rare ones.
+        let mut rng = StdRng::seed_from_u64(20_201_224);
+        let uncompressed = (0..NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|i| {
+                let word_index = i / EMBEDDING_DIM % VOCAB_SIZE;
+                let range = if i % 2 == 1 { 300 } else { 3 } * if word_index < 50 { 4 } else { 1 };
+                rng.random_range(-range..=range)
+            })
+            .collect();
+        let uncompressed =
+            RankThreeTensor::from_flattened(uncompressed, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+        let model_contexts = ModelContexts::new(vec![0, 50], vec![0, 1, 0, 1]);
+
+        let write = |options: &CompressionOptions| {
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                options,
+                &mut compressed,
+            )
+            .unwrap();
+            compressed
+        };
+
+        for entropy_precision in [EntropyPrecision::Bits12, EntropyPrecision::Bits24] {
+            let mut options = CompressionOptions::new(JUMP_INTERVAL, 0.1);
+            options.entropy_precision = entropy_precision;
+            let single = write(&options);
+            options.model_contexts = Some(model_contexts.clone());
+            let compressed = write(&options);
+            if entropy_precision == EntropyPrecision::Bits12 {
+                // Larger entropy models don't pay off for this small vocabulary.
+                assert!(compressed.len() < single.len());
+            }
+
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            assert_eq!(
+                file.header().minor_version,
+                decoding_minor_version(file.header().major_version, false)
+            );
+            assert_eq!(file.model_contexts(), &model_contexts);
+            let lazy =
+                LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compressed), 64, 2)
+                    .unwrap();
+            assert_eq!(lazy.model_contexts(), &model_contexts);
+
+            // Jumping to a word has to skip over words in different contexts.
+            let (diffs, _) = get_diffs(
+                uncompressed.as_view(),
+                &Segments::single(NUM_TIMESTEPS as u32),
+                None,
+                &model_contexts,
+            )
+            .unwrap();
+            for t in 0..NUM_TIMESTEPS as u32 {
+                let expected = diffs.as_view().subview(t as usize);
+                let mut timestep = file.timestep(t).unwrap();
+                let mut lazy_timestep = lazy.timestep(t).unwrap();
+                for word_index in [0, 49, 50, 51, 3, 199, 64, 63, 120] {
+                    assert_eq!(
+                        read_vector(&mut timestep, word_index),
+                        expected.subview(word_index as usize)
+                    );
+                    assert_eq!(
+                        read_vector(&mut lazy_timestep, word_index),
+                        expected.subview(word_index as usize)
+                    );
+                }
+            }
+            let file = file.into_random_access_reader();
+            let lazy = lazy.into_random_access_reader();
+            for t in 0..NUM_TIMESTEPS {
+                let expected = uncompressed.as_view().subview(t).slice();
+                assert_eq!(
+                    file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                    expected
+                );
+                assert_eq!(
+                    lazy.get_embeddings_at(t as u32).unwrap().into_inner(),
+                    expected
+                );
+            }
+
+            // Lossy compression chooses residuals with the model of their context.
+            options.rate_distortion_tradeoff = Some(1e-3);
+            let lossy = write(&options);
+            let lossy = EmbeddingFile::from_reader(&lossy[..]).unwrap();
+            assert_eq!(lossy.model_contexts(), &model_contexts);
+            let decoded = lossy
+                .into_random_access_reader()
+                .get_embeddings_at(2)
+                .unwrap()
+                .into_inner();
+            assert_eq!(decoded.len(), VOCAB_SIZE * EMBEDDING_DIM);
+        }
+
+        // Contexts have to fit to the shape of the embeddings.
+        let mut options = CompressionOptions::new(JUMP_INTERVAL, 0.1);
+        options.model_contexts = Some(ModelContexts::new(vec![0, 200], vec![0, 1, 0, 1]));
+        assert!(matches!(
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                &options,
+                Vec::new(),
+            ),
+            Err(Error::LengthMismatch { .. })
+        ));
+        options.model_contexts = Some(ModelContexts::new(vec![0], vec![0, 1, 0]));
+        assert!(matches!(
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                &options,
+                Vec::new(),
+            ),
+            Err(Error::LengthMismatch { .. })
+        ));
+
+        fn read_vector(
+            timestep: &mut impl TimestepReader<Symbol = i16>,
+            word_index: u32,
+        ) -> Vec<i16> {
+            let mut vector = Vec::new();
+            timestep.jump_to(word_index).unwrap();
+            timestep
+                .read_single_embedding_vector(0..EMBEDDING_DIM, |symbol, _| vector.push(symbol))
+                .unwrap();
+            vector
+        }
+    }
+
+    #[test]
+    fn model_sharing() {
+        const NUM_TIMESTEPS: usize = 33;
+        const VOCAB_SIZE: usize = 8;
+        const EMBEDDING_DIM: usize = 3;
+
+        assert_eq!(
+            tree_level_groups(&Segments::single(9)).groups(),
+            &[0, 3, 2, 3, 1, 3, 2, 3, 0]
+        );
+        assert_eq!(
+            tree_level_groups(&Segments::with_keyframe_interval(9, 4)).groups(),
+            &[0, 2, 1, 2, 0, 2, 1, 2, 0]
+        );
+        assert_eq!(
+            tree_level_groups(&Segments::single(3).appended(2).unwrap()).groups(),
+            &[0, 2, 0, 2, 1]
+        );
+        assert_eq!(tree_level_groups(&Segments::single(2)).groups(), &[0, 0]);
+        assert!(tree_level_groups(&Segments::single(1)).is_per_timestep());
+
+        // A random walk with a small vocabulary, for which the entropy models make up
+        // most of the file unless time steps share them.
+        let uncompressed = random_walk(20_210_101, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM, 3);
+
+        let write = |options: &CompressionOptions| {
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                options,
+                &mut compressed,
+            )
+            .unwrap();
+            compressed
+        };
+
+        for entropy_precision in [EntropyPrecision::Bits12, EntropyPrecision::Bits24] {
+            for keyframe_interval in [None, Some(8)] {
+                let mut options = CompressionOptions::new(4, 0.1);
+                options.entropy_precision = entropy_precision;
+                options.keyframe_interval = keyframe_interval;
+                options.model_contexts = Some(ModelContexts::uniform(8, 1, 3, 3));
+                let unshared = write(&options);
+                options.model_sharing = ModelSharing::TreeLevels;
+                let shared = write(&options);
+                assert!(shared.len() < unshared.len());
+                options.model_sharing = ModelSharing::Automatic;
+                assert!(write(&options) == shared);
+
+                let segments = options.segments(NUM_TIMESTEPS);
+                let file = EmbeddingFile::from_reader(&shared[..]).unwrap();
+                assert_eq!(
+                    file.header().minor_version,
+                    decoding_minor_version(file.header().major_version, false)
+                );
+                assert_eq!(file.model_groups(), &tree_level_groups(&segments));
+                let lazy =
+                    LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&shared), 64, 2)
+                        .unwrap();
+                assert_eq!(lazy.model_groups(), file.model_groups());
+
+                let file = file.into_random_access_reader();
+                let lazy = lazy.into_random_access_reader();
+                for t in 0..NUM_TIMESTEPS {
+                    let expected = uncompressed.as_view().subview(t).slice();
+                    assert_eq!(
+                        file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                    assert_eq!(
+                        lazy.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                }
+
+                // Lossy compression chooses residuals with the shared models.
+                options.rate_distortion_tradeoff = Some(1e-3);
+                let lossy = write(&options);
+                let lossy = EmbeddingFile::from_reader(&lossy[..]).unwrap();
+                assert_eq!(lossy.model_groups(), &tree_level_groups(&segments));
+            }
+        }
+
+        // Sharing doesn't pay off if the residuals of time steps at the same level
+        // have very different distributions.
+        let uncompressed = (0..3 * 500 * 2)
+            .map(|i| ((i * 7919) % 1001) as i16 - 500 + (i / 1000) as i16 * 2000)
+            .collect();
+        let uncompressed = RankThreeTensor::from_flattened(uncompressed, 3, 500, 2);
+        let mut options = CompressionOptions::new(100, 0.1);
+        options.model_sharing = ModelSharing::Automatic;
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file_with_options(
+            uncompressed.as_view(),
+            None,
+            None,
+            &options,
+            &mut compressed,
+        )
+        .unwrap();
+        let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        assert!(file.model_groups().is_per_timestep());
+        assert_eq!(file.header().minor_version, 0);
+    }
+
+    #[test]
+    fn compact_jump_table() {
+        const NUM_TIMESTEPS: usize = 9;
+        const VOCAB_SIZE: usize = 40;
+        const EMBEDDING_DIM: usize = 4;
+
+        let uncompressed = random_walk(20_210_103, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM, 5);
+
+        let write = |options: &CompressionOptions| {
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                options,
+                &mut compressed,
+            )
+            .unwrap();
+            compressed
+        };
+
+        for entropy_precision in [
+            EntropyPrecision::Bits12,
+            EntropyPrecision::Bits16,
+            EntropyPrecision::Bits24,
+        ] {
+            for jump_interval in [1, 7] {
+                let mut options = CompressionOptions::new(jump_interval, 0.1);
+                options.entropy_precision = entropy_precision;
+                options.keyframe_interval = Some(4);
+                let uncompact = write(&options);
+                options.compact_jump_table = true;
+                let compact = write(&options);
+
+                let expected = EmbeddingFile::from_reader(&uncompact[..]).unwrap();
+                let file = EmbeddingFile::from_reader(&compact[..]).unwrap();
+                let header = file.header();
+                assert!(has_compact_jump_table(
+                    header.major_version,
+                    header.minor_version
+                ));
+                assert_eq!(
+                    file.layout.jump_table_address,
+                    file.layout.compressed_data_start
+                );
+                assert!(file.jump_table() == expected.jump_table());
+
+                // The savings of a compact jump table are about half of the size of the
+                // uncompressed one.
+                let jump_table_size = 4 * expected.jump_table().len();
+                assert!(compact.len() < uncompact.len() - jump_table_size / 3);
+
+                let lazy =
+                    LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compact), 64, 2)
+                        .unwrap()
+                        .into_random_access_reader();
+                let file = file.into_random_access_reader();
+                for t in 0..NUM_TIMESTEPS {
+                    let expected = uncompressed.as_view().subview(t).slice();
+                    assert_eq!(
+                        file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                    assert_eq!(
+                        lazy.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        expected
+                    );
+                }
+
+                // Readers reject files whose version requires a compact jump table
+                // section if it's missing.
+                let mut data = expected.into_inner();
+                data[2] = decoding_minor_version(data[1], true);
+                assert!(matches!(
+                    EmbeddingFile::new(data),
+                    Err(Error::InvalidHeader(_))
+                ));
+            }
+        }
+
+        // The estimated size of a compact jump table is close enough to hit a size
+        // target with small jump intervals.
+        let embeddings = RankThreeTensor::from_flattened(
+            uncompressed
+                .as_view()
+                .slice()
+                .iter()
+                .map(|&x| x as f32)
+                .collect(),
+            NUM_TIMESTEPS,
+            VOCAB_SIZE,
+            EMBEDDING_DIM,
+        );
+        let mut options = CompressionOptions::new(1, 1.0);
+        options.compact_jump_table = true;
+        let target_size = 4000;
+        let mut compressed = Vec::<u8>::new();
+        let report = write_compressed_dwe_file_with_target_size(
+            embeddings.as_view(),
+            None,
+            None,
+            SizeTarget::FileSize(target_size),
+            None,
+            &options,
+            &mut compressed,
+        )
+        .unwrap();
+        assert!(report.file_size as u64 <= target_size);
+        assert!(report.file_size as f64 > 0.9 * target_size as f64);
+        let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        assert_eq!(file.header().minor_version, 3);
+    }
+
+    #[test]
+    fn independent_streams() {
+        const NUM_TIMESTEPS: usize = 9;
+        const VOCAB_SIZE: usize = 40;
+        const EMBEDDING_DIM: usize = 4;
+
+        let uncompressed = random_walk(20_210_117, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM, 5);
+
+        for entropy_precision in [
+            EntropyPrecision::Bits12,
+            EntropyPrecision::Bits16,
+            EntropyPrecision::Bits24,
+        ] {
+            for jump_interval in [1, 7] {
+                let mut options = CompressionOptions::new(jump_interval, 0.1);
+                options.entropy_precision = entropy_precision;
+                options.keyframe_interval = Some(4);
+                options.model_contexts = Some(ModelContexts::new(vec![0, 10], vec![0, 0, 1, 1]));
+
+                let (continuous, report) = {
+                    let mut compressed = Vec::new();
+                    let (_, report) = write_compressed_dwe_file_with_stream_overhead(
+                        uncompressed.as_view(),
+                        None,
+                        None,
+                        &options,
+                        &mut compressed,
+                    )
+                    .unwrap();
+                    (compressed, report)
+                };
+                assert_eq!(report.num_streams, 1);
+                assert_eq!(report.overhead_bits(), 0);
+
+                options.independent_streams = true;
+                let mut independent = Vec::new();
+                let (_, report) = write_compressed_dwe_file_with_stream_overhead(
+                    uncompressed.as_view(),
+                    None,
+                    None,
+                    &options,
+                    &mut independent,
+                )
+                .unwrap();
+                assert!(independent != continuous);
+
+                // Restarting the stream changes the size by at most about two
+                // compressed words per time step.
+                let word_bits = entropy_precision.word_bits() as i64;
+                assert_eq!(report.num_streams, NUM_TIMESTEPS);
+                assert!(report.overhead_bits().abs() <= 2 * word_bits * NUM_TIMESTEPS as i64);
+                let continuous_file = EmbeddingFile::from_reader(&continuous[..]).unwrap();
+                let file = EmbeddingFile::from_reader(&independent[..]).unwrap();
+                assert_eq!(
+                    file.header().minor_version,
+                    continuous_file.header().minor_version
+                );
+
+                let file = file.into_random_access_reader();
+                for t in 0..NUM_TIMESTEPS {
+                    assert_eq!(
+                        file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                        uncompressed.as_view().subview(t).slice()
+                    );
+                }
+
+                // The compressed data is the concatenation of the time steps (in tree
+                // order), each encoded on its own.
+                let residuals = get_residuals(uncompressed.as_view(), &options).unwrap();
+                let num_contexts = residuals.model_contexts.len();
+                let (models, _) = create_and_serialize_encoder_models(
+                    &shared_counts(&residuals.counts, &residuals.model_groups, num_contexts),
+                    &residuals.model_groups,
+                    num_contexts,
+                    entropy_precision,
+                )
+                .unwrap();
+                let segments = options.segments(NUM_TIMESTEPS);
+                let (_, compressed, _) = compress_data(
+                    residuals.diffs.as_view(),
+                    &segments,
+                    &models,
+                    &residuals.model_contexts,
+                    &residuals.model_groups,
+                    jump_interval,
+                    true,
+                )
+                .unwrap();
+                let mut expected = Vec::new();
+                for (t, _) in tree_order(&segments) {
+                    let mut encoder = Encoder::new(&models);
+                    encoder
+                        .encode_reverse(
+                            residuals.model_groups.group_of(t as u32) as usize,
+                            &residuals.model_contexts,
+                            0,
+                            residuals.diffs.as_view().subview(t).slice(),
+                        )
+                        .unwrap();
+                    let mut words = Vec::new();
+                    encoder.spill(&mut words).unwrap();
+                    expected.extend(words.chunks_exact(encoder.word_size()).rev().flatten());
+                }
+                expected.resize(expected.len().next_multiple_of(4), 0);
+                let compressed = compressed
+                    .iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .collect::<Vec<_>>();
+                assert_eq!(compressed, expected);
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_compression_is_deterministic() {
+        const NUM_TIMESTEPS: usize = 13;
+        const VOCAB_SIZE: usize = 30;
+        const EMBEDDING_DIM: usize = 5;
+
+        let uncompressed = random_walk(20_210_124, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM, 5);
+
+        let write = |options: &CompressionOptions, num_threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap();
+            let mut compressed = Vec::new();
+            pool.install(|| {
+                write_compressed_dwe_file_with_options(
+                    uncompressed.as_view(),
+                    None,
+                    None,
+                    options,
+                    &mut compressed,
+                )
+            })
+            .unwrap();
+            compressed
+        };
+
+        for rate_distortion_tradeoff in [None, Some(0.01)] {
+            for independent_streams in [false, true] {
+                let mut options = CompressionOptions::new(4, 0.1);
+                options.keyframe_interval = Some(5);
+                options.rate_distortion_tradeoff = rate_distortion_tradeoff;
+                options.model_contexts = Some(ModelContexts::uniform(30, 2, 5, 2));
+                options.independent_streams = independent_streams;
+
+                let serial = write(&options, 1);
+                assert!(write(&options, 4) == serial);
+            }
+        }
+    }
+
+    #[test]
+    fn version_2_files() {
+        let vocab = (0..20).map(|i| format!("w{}", i)).collect::<Vec<_>>();
+        let uncompressed = (0..4 * 20 * 3).map(|x| (x * 5 % 13) as i16 - 6).collect();
+        let uncompressed = RankThreeTensor::from_flattened(uncompressed, 4, 20, 3);
+        let labels = TimestepLabels::from_integers(vec![1990, 2000, 2010, 2020]).unwrap();
+
+        let write = |vocab, labels, major_version| {
+            let mut compressed = Vec::<u8>::new();
+            let mut options = CompressionOptions::new(3, 0.25);
+            options.min_major_version = major_version;
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                vocab,
+                labels,
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+            compressed
+        };
+
+        let v1 = EmbeddingFile::from_reader(&write(None, None, 1)[..]).unwrap();
+        assert_eq!(v1.header().major_version, 1);
+        let expected = v1.into_random_access_reader();
+
+        for with_sections in [false, true] {
+            let (vocab, labels) = if with_sections {
+                (Some(&vocab[..]), Some(&labels))
+            } else {
+                (None, None)
+            };
+            let compressed = write(vocab, labels, 2);
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+
+            let header = file.header();
+            assert_eq!((header.major_version, header.minor_version), (2, 0));
+            assert_eq!(header.file_size * 4, compressed.len() as u64);
+            assert_eq!(file.vocabulary().is_some(), with_sections);
+            assert_eq!(file.timestep_labels(), labels);
+            if with_sections {
+                assert_eq!(file.word_to_id("w7"), Some(7));
+            }
+
+            let lazy =
+                LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compressed), 64, 2)
+                    .unwrap()
+                    .into_random_access_reader();
+            let file = file.into_random_access_reader();
+            for t in 0..4 {
+                let expected = expected.get_embeddings_at(t).unwrap().into_inner();
+                assert_eq!(file.get_embeddings_at(t).unwrap().into_inner(), expected);
+                assert_eq!(lazy.get_embeddings_at(t).unwrap().into_inner(), expected);
+            }
+            assert_eq!(
+                file.largest_changes_wrt(5, 4, 1, 1).unwrap(),
+                expected.largest_changes_wrt(5, 4, 1, 1).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn falls_back_to_version_2_for_large_files() {
+        let sections = [(VOCABULARY_SECTION_TAG, vec![0; 10])];
+
+        let plan = plan_file(
+            1,
+            EntropyPrecision::Bits12,
+            SymbolType::I16,
+            20,
+            1,
+            0,
+            1000,
+            &[],
+        );
+        assert_eq!((plan.major_version, plan.minor_version), (1, 0));
+        assert_eq!(plan.jump_table_address, HEADER_SIZE as u64 + 20);
+        assert_eq!(plan.file_size, HEADER_SIZE as u64 + 20 + 2 + 1000);
+        assert!(plan.section_table.is_none());
+
+        let plan = plan_file(
+            1,
+            EntropyPrecision::Bits12,
+            SymbolType::I16,
+            20,
+            1,
+            0,
+            1000,
+            &sections,
+        );
+        assert_eq!((plan.major_version, plan.minor_version), (1, 1));
+        assert_eq!(plan.section_table.unwrap().len(), 3);
+
+        let plan = plan_file(
+            2,
+            EntropyPrecision::Bits12,
+            SymbolType::I16,
+            20,
+            1,
+            0,
+            1000,
+            &[],
+        );
+        assert_eq!((plan.major_version, plan.minor_version), (2, 0));
+        assert_eq!(plan.jump_table_address, HEADER_SIZE_V2 as u64 + 20);
+        assert_eq!(plan.file_size, HEADER_SIZE_V2 as u64 + 20 + 3 + 1000 + 1);
+        assert_eq!(plan.section_table, Some(vec![]));
+
+        // Offsets of more than 2^32 words of compressed data don't fit into version 1.
+        let plan = plan_file(
+            1,
+            EntropyPrecision::Bits12,
+            SymbolType::I16,
+            20,
+            1,
+            1 << 32,
+            1 << 31,
+            &[],
+        );
+        assert_eq!(plan.major_version, 2);
+
+        // Files of more than 16 GiB don't fit into version 1.
+        let plan = plan_file(
+            1,
+            EntropyPrecision::Bits12,
+            SymbolType::I16,
+            20,
+            1,
+            0,
+            u32::MAX as usize,
+            &sections,
+        );
+        assert_eq!(plan.major_version, 2);
+        let section_table = plan.section_table.unwrap();
+        let address = HEADER_SIZE_V2 as u64 + 20 + 3 + u32::MAX as u64;
+        assert_eq!(
+            section_table,
+            [
+                VOCABULARY_SECTION_TAG,
+                address as u32,
+                (address >> 32) as u32,
+                10,
+                0
+            ]
+        );
+        assert_eq!(plan.file_size, address + 10 + 5 + 1);
+    }
+
+    #[test]
+    fn residual_overflow() {
+        let mut uncompressed = vec![0i16; 3 * 2 * 2];
+        uncompressed[0..4].copy_from_slice(&[i16::MIN; 4]);
+        uncompressed[4..8].copy_from_slice(&[0, 0, 0, i16::MAX]);
+        let uncompressed = RankThreeTensor::from_flattened(uncompressed, 3, 2, 2);
+
+        assert!(matches!(
+            write_compressed_dwe_file(uncompressed.as_view(), None, None, 2, 1.0, Vec::new()),
+            Err(Error::ResidualOverflow {
+                timestep: 1,
+                word_index: 1,
+                dimension: 1
+            })
+        ));
+
+        // Narrower symbols overflow at correspondingly smaller residuals.
+        let uncompressed = RankThreeTensor::from_flattened(vec![127i8, -128, 127], 3, 1, 1);
+        assert!(matches!(
+            write_compressed_dwe_file(uncompressed.as_view(), None, None, 2, 1.0, Vec::new()),
+            Err(Error::ResidualOverflow {
+                timestep: 1,
+                word_index: 0,
+                dimension: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn extreme_wide_symbols() {
+        let uncompressed = RankThreeTensor::from_flattened(
+            vec![
+                i32::MIN / 2,
+                i32::MAX / 2,
+                0,
+                1 << 20,
+                -(1 << 20),
+                i32::MAX / 2,
+            ],
+            3,
+            2,
+            1,
+        );
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file(uncompressed.as_view(), None, None, 2, 1.0, &mut compressed)
+            .unwrap();
+
+        let file = EmbeddingFile::<_, i32>::from_reader_typed(&compressed[..]).unwrap();
+        assert_eq!(file.header().symbol_type, SymbolType::I32);
+        assert_eq!(file.header().major_version, 2);
+        let reader = file.into_random_access_reader();
+        for t in 0..3 {
+            assert_eq!(
+                reader.get_embeddings_at(t as u32).unwrap().into_inner(),
+                uncompressed.as_view().subview(t).slice()
+            );
+        }
+    }
+
+    #[test]
+    fn target_size() {
+        const NUM_TIMESTEPS: usize = 5;
+        const VOCAB_SIZE: usize = 200;
+        const EMBEDDING_DIM: usize = 8;
+        const NUM_COORDINATES: usize = NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM;
+
+        // Random walks, so that neighboring time steps are correlated.
+        let mut rng = StdRng::seed_from_u64(20_201_204);
+        let mut data = vec![0.0f32; NUM_COORDINATES];
+        let slice_len = VOCAB_SIZE * EMBEDDING_DIM;
+        for i in 0..NUM_COORDINATES {
+            let previous = if i < slice_len {
+                0.0
+            } else {
+                data[i - slice_len]
+            };
+            data[i] = previous + rng.random_range(-1.0..1.0);
+        }
+        let embeddings =
+            RankThreeTensor::from_flattened(data, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+        let options = CompressionOptions::new(20, 1.0);
+
+        let mut previous_mse = f64::INFINITY;
+        for bits_per_coordinate in [2.0, 4.0, 6.0] {
+            let mut compressed = Vec::<u8>::new();
+            let report = write_compressed_dwe_file_with_target_size(
+                embeddings.as_view(),
+                None,
+                None,
+                SizeTarget::BitsPerCoordinate(bits_per_coordinate),
+                None,
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+
+            let target_size = (bits_per_coordinate * NUM_COORDINATES as f64 / 8.0) as usize;
+            assert_eq!(report.file_size, compressed.len());
+            assert!(report.file_size <= target_size);
+            assert!(report.file_size as f64 > 0.95 * target_size as f64);
+            assert!(report.bits_per_coordinate <= bits_per_coordinate);
+            assert_eq!(report.timestep_bits.len(), NUM_TIMESTEPS);
+            let estimated_bits = report.timestep_bits.iter().sum::<f64>();
+            assert!(estimated_bits < 8.0 * report.file_size as f64);
+
+            let quantization = &report.quantization;
+            assert_eq!(quantization.rms_errors.len(), NUM_TIMESTEPS);
+            let mean_squared_error = quantization
+                .rms_errors
+                .iter()
+                .map(|rms| rms * rms)
+                .sum::<f64>()
+                / NUM_TIMESTEPS as f64;
+            assert!((mean_squared_error / quantization.mean_squared_error - 1.0).abs() < 1e-9);
+            assert!(quantization.mean_squared_error < previous_mse);
+            previous_mse = quantization.mean_squared_error;
+
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            assert_eq!(file.header().scale_factor, quantization.scale_factor);
+        }
+
+        let mut compressed = Vec::<u8>::new();
+        assert!(matches!(
+            write_compressed_dwe_file_with_target_size(
+                embeddings.as_view(),
+                None,
+                None,
+                SizeTarget::FileSize(100),
+                None,
+                &options,
+                &mut compressed,
+            ),
+            Err(Error::SizeTargetUnreachable { target: 100, .. })
+        ));
+        assert!(compressed.is_empty());
+
+        // Metadata that doesn't fit to the shape gets rejected before the search.
+        let vocab = vec!["word".to_string(); 3];
+        assert!(matches!(
+            write_compressed_dwe_file_with_target_size(
+                embeddings.as_view(),
+                Some(&vocab),
+                None,
+                SizeTarget::FileSize(100_000),
+                None,
+                &options,
+                &mut compressed,
+            ),
+            Err(Error::LengthMismatch {
+                what: "words in the vocabulary",
+                ..
+            })
+        ));
+        assert!(compressed.is_empty());
+    }
+
+    #[test]
+    fn rate_distortion_optimization() {
+        const NUM_TIMESTEPS: usize = 6;
+        const VOCAB_SIZE: usize = 100;
+        const EMBEDDING_DIM: usize = 8;
+        const NUM_COORDINATES: usize = NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM;
+
+        // Random walks, so that neighboring time steps are correlated.
+        let mut rng = StdRng::seed_from_u64(20_201_205);
+        let slice_len = VOCAB_SIZE * EMBEDDING_DIM;
+        let mut data = (0..slice_len)
+            .map(|_| rng.random_range(-200..=200))
+            .collect::<Vec<i16>>();
+        for i in slice_len..NUM_COORDINATES {
+            data.push(data[i - slice_len] + rng.random_range(-10..=10));
+        }
+        let uncompressed =
+            RankThreeTensor::from_flattened(data, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+        let mut options = CompressionOptions::new(10, 0.5);
+        options.scale_factors = Some(ScaleFactors::per_dimension(vec![
+            1.0, 2.0, 1.0, 0.5, 1.0, 1.0, 3.0, 1.0,
+        ]));
+
+        let mut lossless = Vec::new();
+        let (lossless_size, report) = write_compressed_dwe_file_with_distortion(
+            uncompressed.as_view(),
+            None,
+            None,
+            &options,
+            &mut lossless,
+        )
+        .unwrap();
+        assert_eq!(report.mean_squared_error, 0.0);
+        assert_eq!(report.rms_errors, vec![0.0; NUM_TIMESTEPS]);
+
+        // Without a penalty on the rate, lossy compression is lossless.
+        options.rate_distortion_tradeoff = Some(0.0);
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file_with_options(
+            uncompressed.as_view(),
+            None,
+            None,
+            &options,
+            &mut compressed,
+        )
+        .unwrap();
+        assert_eq!(compressed, lossless);
+
+        let mut previous = (lossless_size, 0.0);
+        for tradeoff in [0.1, 1.0, 10.0] {
+            options.rate_distortion_tradeoff = Some(tradeoff);
+            let mut compressed = Vec::new();
+            let (size, report) = write_compressed_dwe_file_with_distortion(
+                uncompressed.as_view(),
+                None,
+                None,
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+            assert_eq!(size, compressed.len());
+            assert!(size < previous.0);
+            assert!(report.mean_squared_error > previous.1);
+            previous = (size, report.mean_squared_error);
+
+            // Compare the report to what readers actually reconstruct.
+            let reader = EmbeddingFile::from_reader(&compressed[..])
+                .unwrap()
+                .into_random_access_reader();
+            let mut sum_of_squared_errors = 0.0;
+            for t in 0..NUM_TIMESTEPS {
+                let expected = uncompressed.as_view().subview(t);
+                let decoded = reader.get_embeddings_at(t as u32).unwrap();
+                let mut timestep_sum_of_squared_errors = 0.0;
+                for (expected, decoded) in expected
+                    .iter_subviews()
+                    .zip(decoded.as_view().iter_subviews())
+                {
+                    for (d, (&x, &y)) in expected.iter().zip(decoded).enumerate() {
+                        let scale = 0.5 * options.scale_factors.as_ref().unwrap().get(0, d as u32);
+                        let error = (scale * (x - y) as f32) as f64;
+                        timestep_sum_of_squared_errors += error * error;
+                    }
+                }
+                let rms_error = (timestep_sum_of_squared_errors / slice_len as f64).sqrt();
+                assert!((rms_error - report.rms_errors[t]).abs() < 1e-6);
+                sum_of_squared_errors += timestep_sum_of_squared_errors;
+            }
+            let mean_squared_error = sum_of_squared_errors / NUM_COORDINATES as f64;
+            assert!((mean_squared_error / report.mean_squared_error - 1.0).abs() < 1e-6);
+        }
+
+        // Lossy compression with independent streams reports both costs.
+        options.independent_streams = true;
+        let mut distorted = Vec::new();
+        let (_, distortion) = write_compressed_dwe_file_with_distortion(
+            uncompressed.as_view(),
+            None,
+            None,
+            &options,
+            &mut distorted,
+        )
+        .unwrap();
+        let (_, overhead) = write_compressed_dwe_file_with_stream_overhead(
+            uncompressed.as_view(),
+            None,
+            None,
+            &options,
+            std::io::sink(),
+        )
+        .unwrap();
+        let mut compressed = Vec::new();
+        let (size, report, stream_overhead) =
+            write_compressed_dwe_file_with_distortion_and_stream_overhead(
+                uncompressed.as_view(),
+                None,
+                None,
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+        assert_eq!(size, compressed.len());
+        assert_eq!(compressed, distorted);
+        assert_eq!(report, distortion);
+        assert_eq!(stream_overhead, overhead);
+        assert_eq!(stream_overhead.num_streams, NUM_TIMESTEPS);
+    }
 }
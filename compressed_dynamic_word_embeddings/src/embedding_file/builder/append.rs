@@ -0,0 +1,921 @@
+//! Appending time steps to an existing file
+//!
+//! [`append_timesteps`] writes a copy of an existing file with additional time
+//! steps at the end. The existing time steps don't get recompressed: their entropy
+//! models, jump table entries, and compressed data get copied verbatim, and only
+//! the new time steps get compressed. The new time steps form a new
+//! [segment](crate::embedding_file::segments::Segments), which is predicted from
+//! the last existing time step.
+
+use super::{
+    assemble_file, compress_data, create_and_serialize_encoder_models, exact_residuals,
+    optional_sections, push_segment_tree_order, CompressionOptions, JumpPointer, PredictorFit,
+    SymbolCounts,
+};
+use crate::{
+    embedding_file::{
+        deserialize_decoder_model,
+        model_contexts::ModelContexts,
+        model_groups::ModelGroups,
+        parse_section_table, portable,
+        predictor::{Prediction, Predictor},
+        scale_factors::ScaleFactors,
+        section_table_start,
+        segments::Segments,
+        symbol::Symbol,
+        timestep_labels::TimestepLabels,
+        vocabulary::VOCABULARY_SECTION_TAG,
+        EmbeddingFile, EntropyPrecision, Layout,
+    },
+    error::{Error, Result},
+    random_access_reader::RandomAccessReader,
+    tensors::{RankThreeTensor, RankThreeTensorView},
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use std::{collections::HashMap, convert::TryFrom, io::Write};
+
+/// Writes a copy of `file` with the time steps `new_timesteps` appended to it.
+///
+/// Only the new time steps get compressed, so this is much faster than compressing
+/// all time steps from scratch, but the result is slightly larger and slower to
+/// query (see [`Segments`](crate::embedding_file::segments::Segments)). The new
+/// file keeps the vocabulary and the settings from the header of `file` (e.g., the
+/// jump interval, the entropy precision, and whether the jump table is compact).
+/// Since readers have to know about segments to decode it, the new file follows
+/// version 1.2 (or 2.1) of the file format, or version 1.3 (or 2.2) if it has a
+/// compact jump table. Optional sections with unknown tags don't get copied.
+///
+/// If `file` has time step labels then `new_timestep_labels` must contain one label
+/// for each new time step, and vice versa. If `file` has scale factors that depend
+/// on the time step then `new_scale_factors` must be provided (with either one row
+/// per new time step or a single row); otherwise, `new_scale_factors` is optional
+/// and defaults to the existing scale factors. If `file` has a
+/// [`Predictor`](crate::embedding_file::predictor::Predictor) then the weights of
+/// the new time steps get chosen with the same
+/// [`PredictionScheme`](crate::embedding_file::predictor::PredictionScheme). The
+/// new time steps use the same
+/// [`ModelContexts`](crate::embedding_file::model_contexts::ModelContexts) as the
+/// existing ones, and each new time step gets its own entropy models, even if the
+/// existing time steps share theirs (see
+/// [`ModelGroups`](crate::embedding_file::model_groups::ModelGroups)).
+///
+/// Returns the number of written bytes. Returns `Error::LengthMismatch` if the
+/// shape of a time step differs from the ones in `file`, `Error::IncompatibleAppend`
+/// if labels or scale factors are missing or of the wrong kind or if the predictor
+/// uses an unknown scheme, and
+/// `Error::ResidualOverflow` under the same conditions as
+/// [`write_compressed_dwe_file`](super::write_compressed_dwe_file).
+///
+/// The new time steps have to have the same symbol type `T` as `file`. Returns
+/// `Error::IncompatibleAppend` if `new_timesteps` contains no time steps.
+pub fn append_timesteps<D: AsRef<[u32]>, T: Symbol>(
+    file: &EmbeddingFile<D, T>,
+    new_timesteps: RankThreeTensorView<T>,
+    new_timestep_labels: Option<&TimestepLabels>,
+    new_scale_factors: Option<&ScaleFactors>,
+    output: impl Write,
+) -> Result<usize> {
+    let header = file.header();
+    let (num_new, vocab_size, embedding_dim) = new_timesteps.shape();
+    if num_new == 0 {
+        return Err(Error::IncompatibleAppend("there are no new time steps"));
+    }
+    if vocab_size != header.vocab_size as usize {
+        return Err(Error::LengthMismatch {
+            what: "words in a time step",
+            expected: header.vocab_size as usize,
+            found: vocab_size,
+        });
+    }
+    if embedding_dim != header.embedding_dim as usize {
+        return Err(Error::LengthMismatch {
+            what: "embedding dimensions in a time step",
+            expected: header.embedding_dim as usize,
+            found: embedding_dim,
+        });
+    }
+
+    let num_old = header.num_timesteps as usize;
+    let segments = file
+        .segments()
+        .appended(u32::try_from(num_new).map_err(|_| Error::TooLarge)?)?;
+    let shape = (num_old + num_new, vocab_size, embedding_dim);
+
+    let timestep_labels =
+        append_timestep_labels(file.timestep_labels(), new_timestep_labels, num_new)?;
+    let scale_factors = append_scale_factors(
+        file.scale_factors(),
+        num_old,
+        new_scale_factors,
+        num_new,
+        embedding_dim,
+    )?;
+
+    let last = RandomAccessReader::new(file).get_embeddings_at(num_old as u32 - 1)?;
+    let predictor = append_predictor(
+        file.predictor(),
+        last.as_view().slice(),
+        new_timesteps,
+        &segments,
+    )?;
+    let model_contexts = file.model_contexts();
+    let num_contexts = model_contexts.len();
+    let num_old_groups = file.model_groups().len();
+    let model_groups = file.model_groups().appended(num_new as u32)?;
+    let new_model_groups = ModelGroups::per_timestep(num_new as u32);
+    let (diffs, counts) = get_appended_diffs(
+        last.as_view().slice(),
+        new_timesteps,
+        &segments,
+        predictor.as_ref(),
+        model_contexts,
+    )?;
+
+    let precision = header.entropy_precision;
+    let (models, new_models_section) =
+        create_and_serialize_encoder_models(&counts, &new_model_groups, num_contexts, precision)
+            .map_err(|err| match err {
+                Error::InvalidEntropyModel { timestep } => Error::InvalidEntropyModel {
+                    timestep: timestep + num_old as u32,
+                },
+                err => err,
+            })?;
+    let (new_jump_table, new_compressed, _) = compress_data(
+        diffs.as_view(),
+        &Segments::single(num_new as u32),
+        &models,
+        model_contexts,
+        &new_model_groups,
+        header.jump_interval,
+        false,
+    )?;
+
+    let data = file.as_slice_u32();
+    let layout = &file.layout;
+    let old_models_section =
+        portable::u16_words(&data[layout.header_size..layout.jump_table_address]);
+    let mut entropy_models_section = old_models_section[..entropy_models_len::<T>(
+        &old_models_section,
+        num_old_groups * num_contexts,
+        precision,
+    )]
+        .to_vec();
+    entropy_models_section.extend_from_slice(
+        &new_models_section
+            [..entropy_models_len::<T>(&new_models_section, num_new * num_contexts, precision)],
+    );
+    if entropy_models_section.len() % 2 == 1 {
+        entropy_models_section.push(0); // Padding.
+    }
+
+    // The new compressed data starts right after the existing one.
+    let old_compressed = &data[layout.compressed_data_start..compressed_data_end(data, layout)?];
+    let offset_base = (old_compressed.len() * precision.words_per_u32()) as u64;
+    let jump_table_section = file
+        .jump_table()
+        .chunks_exact(layout.jump_pointer_size)
+        .map(JumpPointer::from_words)
+        .chain(new_jump_table.into_iter().map(|jump_pointer| JumpPointer {
+            offset: offset_base + jump_pointer.offset,
+            state: jump_pointer.state,
+        }))
+        .collect::<Vec<_>>();
+
+    let mut sections = Vec::new();
+    if let Some(vocabulary) = file.vocabulary() {
+        sections.push((VOCABULARY_SECTION_TAG, vocabulary.serialize()));
+    }
+    sections.extend(optional_sections(
+        None,
+        timestep_labels.as_ref(),
+        scale_factors.as_ref(),
+        &segments,
+        predictor.as_ref(),
+        model_contexts,
+        &model_groups,
+        shape,
+    )?);
+
+    let mut options = CompressionOptions::new(header.jump_interval, header.scale_factor);
+    options.entropy_precision = precision;
+    options.min_major_version = header.major_version;
+    options.compact_jump_table = layout.compact_jump_table;
+    assemble_file(
+        shape,
+        &options,
+        T::SYMBOL_TYPE,
+        &entropy_models_section,
+        &jump_table_section,
+        old_compressed.len() + new_compressed.len(),
+        |output| {
+            for &word in old_compressed.iter().chain(&new_compressed) {
+                output.write_u32::<LittleEndian>(word)?;
+            }
+            Ok(())
+        },
+        &sections,
+        output,
+    )
+}
+
+/// Returns the predictor for the file with the new time steps, which is `None` if
+/// the existing file has no predictor. Otherwise, the weights of the new time steps
+/// get chosen with the same scheme as the existing ones, but based only on the new
+/// time steps and the last existing one (which has the values `last`).
+///
+/// Returns `Error::IncompatibleAppend` if the existing predictor uses a scheme that
+/// this library doesn't know.
+fn append_predictor<T: Symbol>(
+    predictor: Option<&Predictor>,
+    last: &[T],
+    new_timesteps: RankThreeTensorView<T>,
+    segments: &Segments,
+) -> Result<Option<Predictor>> {
+    let predictor = match predictor {
+        None => return Ok(None),
+        Some(predictor) => predictor,
+    };
+    let scheme = predictor.scheme().ok_or(Error::IncompatibleAppend(
+        "the file was written with an unknown prediction scheme",
+    ))?;
+
+    let mut fit = PredictorFit::new(scheme, segments, segments.len() - 1);
+    if fit.needs_values() {
+        for_each_new_timestep(last, new_timesteps, segments, |t, center, parents| {
+            fit.add(t, center, parents);
+            Ok(())
+        })?;
+    }
+    Ok(Some(predictor.appended(&fit.weights())))
+}
+
+/// Calculates the residuals of the new time steps, which form the last one of
+/// `segments` and get appended after the existing time steps, the last one of which
+/// has the values `last`. Returns the residuals and their counts in each model
+/// context of each new time step.
+fn get_appended_diffs<T: Symbol>(
+    last: &[T],
+    new_timesteps: RankThreeTensorView<T>,
+    segments: &Segments,
+    predictor: Option<&Predictor>,
+    model_contexts: &ModelContexts,
+) -> Result<(RankThreeTensor<T>, Vec<SymbolCounts<T>>)> {
+    let (num_new, vocab_size, embedding_dim) = new_timesteps.shape();
+    let num_old = segments.roots(segments.len() - 1).0 as usize + 1;
+    let slice_len = vocab_size * embedding_dim;
+    let num_contexts = model_contexts.len();
+    let mut diffs = vec![T::default(); num_new * slice_len];
+    let mut counts = vec![HashMap::new(); num_new * num_contexts];
+
+    for_each_new_timestep(last, new_timesteps, segments, |t, center, parents| {
+        let i = t - num_old;
+        exact_residuals(
+            t,
+            center,
+            parents,
+            Prediction::of(predictor, t),
+            model_contexts,
+            &mut diffs[i * slice_len..(i + 1) * slice_len],
+            &mut counts[i * num_contexts..(i + 1) * num_contexts],
+        )
+    })?;
+
+    let diffs = RankThreeTensor::from_flattened(diffs, num_new, vocab_size, embedding_dim);
+    Ok((diffs, counts))
+}
+
+/// Calls `visit(t, center, parents)` for the new time steps in tree order, where
+/// `center` are the values of time step `t` and `parents` are the values of its
+/// parents. See [`get_appended_diffs`] for the remaining arguments.
+fn for_each_new_timestep<T: Symbol>(
+    last: &[T],
+    new_timesteps: RankThreeTensorView<T>,
+    segments: &Segments,
+    mut visit: impl FnMut(usize, &[T], Option<(&[T], &[T])>) -> Result<()>,
+) -> Result<()> {
+    let (num_new, vocab_size, embedding_dim) = new_timesteps.shape();
+    let num_old = segments.roots(segments.len() - 1).0 as usize + 1;
+    let slice_len = vocab_size * embedding_dim;
+    let input = new_timesteps.slice();
+
+    let slice = |t: usize| {
+        if t < num_old {
+            last
+        } else {
+            &input[(t - num_old) * slice_len..(t - num_old + 1) * slice_len]
+        }
+    };
+    let mut tree_order = Vec::with_capacity(num_new);
+    push_segment_tree_order(segments, segments.len() - 1, &mut tree_order);
+    for (t, parents) in tree_order {
+        visit(
+            t,
+            slice(t),
+            parents.map(|(left_t, right_t)| (slice(left_t), slice(right_t))),
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the number of `u16`s that the first `num_models` entropy models in
+/// `entropy_models_section` take up (excluding any padding), given that the
+/// models have symbols of type `T`.
+fn entropy_models_len<T: Symbol>(
+    entropy_models_section: &[u16],
+    num_models: usize,
+    precision: EntropyPrecision,
+) -> usize {
+    let mut remainder = entropy_models_section;
+    for _ in 0..num_models {
+        remainder = deserialize_decoder_model::<T>(remainder, precision)
+            .expect("entropy models have already been validated")
+            .2;
+    }
+    entropy_models_section.len() - remainder.len()
+}
+
+/// Returns the address right after the compressed data, i.e., the address of the
+/// first optional section or of the section table, or the end of the file if it
+/// has no section table.
+fn compressed_data_end(data: &[u32], layout: &Layout) -> Result<usize> {
+    if !layout.has_section_table {
+        return Ok(data.len());
+    }
+    let (&num_sections, _) = data.split_last().ok_or(Error::InvalidSectionTable)?;
+    let table_start = section_table_start(num_sections, data.len(), layout)?;
+    let sections = parse_section_table(&data[table_start..data.len() - 1], layout, table_start)?;
+    Ok(sections
+        .into_iter()
+        .map(|(_, range)| range.start)
+        .min()
+        .unwrap_or(table_start))
+}
+
+fn append_timestep_labels(
+    labels: Option<&TimestepLabels>,
+    new_labels: Option<&TimestepLabels>,
+    num_new: usize,
+) -> Result<Option<TimestepLabels>> {
+    match (labels, new_labels) {
+        (None, None) => Ok(None),
+        (Some(labels), Some(new_labels)) => {
+            if new_labels.len() != num_new {
+                return Err(Error::LengthMismatch {
+                    what: "time step labels",
+                    expected: num_new,
+                    found: new_labels.len(),
+                });
+            }
+            labels.concat(new_labels).map(Some)
+        }
+        (Some(_), None) => Err(Error::IncompatibleAppend(
+            "the file has time step labels, so the new time steps need labels too",
+        )),
+        (None, Some(_)) => Err(Error::IncompatibleAppend(
+            "the file has no time step labels, so the new time steps can't have any either",
+        )),
+    }
+}
+
+/// Returns scale factors for all time steps. Missing scale factors are equivalent
+/// to a factor of one for all time steps and dimensions.
+fn append_scale_factors(
+    scale_factors: Option<&ScaleFactors>,
+    num_old: usize,
+    new_scale_factors: Option<&ScaleFactors>,
+    num_new: usize,
+    embedding_dim: usize,
+) -> Result<Option<ScaleFactors>> {
+    if let Some(new_scale_factors) = new_scale_factors {
+        new_scale_factors.check_shape(num_new, embedding_dim)?;
+    }
+
+    match (scale_factors, new_scale_factors) {
+        (None, None) => return Ok(None),
+        (Some(scale_factors), None) if scale_factors.shape().0 == 1 => {
+            return Ok(Some(scale_factors.clone()))
+        }
+        (Some(_), None) => {
+            return Err(Error::IncompatibleAppend(
+                "the file has scale factors per time step, so the new time steps need scale \
+                    factors too",
+            ))
+        }
+        (Some(scale_factors), Some(new_scale_factors))
+            if scale_factors.shape().0 == 1 && scale_factors == new_scale_factors =>
+        {
+            return Ok(Some(scale_factors.clone()))
+        }
+        _ => {}
+    }
+
+    // Expand everything into one row per time step.
+    let num_columns = scale_factors
+        .into_iter()
+        .chain(new_scale_factors)
+        .map(|scale_factors| scale_factors.shape().1)
+        .max()
+        .unwrap_or(1);
+    let factors = rows(scale_factors, num_old)
+        .chain(rows(new_scale_factors, num_new))
+        .flat_map(|row| row.iter().cycle().take(num_columns).copied())
+        .collect();
+    Ok(Some(ScaleFactors::from_flattened(
+        factors,
+        num_old + num_new,
+        num_columns,
+    )))
+}
+
+/// Iterates over the scale factors of each time step, using a factor of one if
+/// there are no scale factors.
+fn rows(
+    scale_factors: Option<&ScaleFactors>,
+    num_timesteps: usize,
+) -> impl Iterator<Item = &[f32]> {
+    (0..num_timesteps as u32).map(move |t| {
+        scale_factors.map_or(&[1.0][..], |scale_factors| scale_factors.at_timestep(t))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::embedding_file::{
+        builder::write_compressed_dwe_file_with_options, has_compact_jump_table,
+        lazy::LazyEmbeddingFile, model_groups::ModelSharing, predictor::PredictionScheme,
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const VOCAB_SIZE: usize = 40;
+    const EMBEDDING_DIM: usize = 3;
+
+    #[test]
+    fn matches_file_written_in_one_go() {
+        let mut rng = StdRng::seed_from_u64(20_201_207);
+        let vocab = (0..VOCAB_SIZE)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<_>>();
+
+        for (num_old, num_new) in [(1, 1), (1, 4), (2, 1), (5, 3), (5, 9)] {
+            let num_timesteps = num_old + num_new;
+            let data = (0..num_timesteps * VOCAB_SIZE * EMBEDDING_DIM)
+                .map(|_| rng.random_range(-50..=50))
+                .collect();
+            let uncompressed =
+                RankThreeTensor::from_flattened(data, num_timesteps, VOCAB_SIZE, EMBEDDING_DIM);
+
+            for entropy_precision in [EntropyPrecision::Bits12, EntropyPrecision::Bits24] {
+                let mut options = CompressionOptions::new(7, 0.25);
+                options.entropy_precision = entropy_precision;
+                options.scale_factors = Some(ScaleFactors::per_dimension(vec![1.0, 2.0, 0.5]));
+                let (_, file, expected) =
+                    assert_append_matches(&options, num_old, uncompressed.as_view(), Some(&vocab));
+
+                let header = file.header();
+                let expected_version = match entropy_precision {
+                    EntropyPrecision::Bits12 => (1, 2),
+                    _ => (2, 1),
+                };
+                assert_eq!(
+                    (header.major_version, header.minor_version),
+                    expected_version
+                );
+                assert_eq!(header.num_timesteps as usize, num_timesteps);
+                assert_eq!(
+                    file.segments().ends(),
+                    &[num_old as u32 - 1, num_timesteps as u32 - 1]
+                );
+                assert_eq!(file.scale_factors(), expected.scale_factors());
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_appends() {
+        let mut rng = StdRng::seed_from_u64(20_201_208);
+        let batch_sizes = [3, 1, 2, 1, 5];
+        let num_timesteps = batch_sizes.iter().sum::<usize>();
+        let data = (0..num_timesteps * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-50..=50))
+            .collect::<Vec<_>>();
+        let batch = |start: usize, len: usize| {
+            RankThreeTensor::from_flattened(
+                data[start * VOCAB_SIZE * EMBEDDING_DIM
+                    ..(start + len) * VOCAB_SIZE * EMBEDDING_DIM]
+                    .to_vec(),
+                len,
+                VOCAB_SIZE,
+                EMBEDDING_DIM,
+            )
+        };
+
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file_with_options(
+            batch(0, num_timesteps).as_view(),
+            None,
+            None,
+            &CompressionOptions::new(4, 0.5),
+            &mut compressed,
+        )
+        .unwrap();
+        let expected = EmbeddingFile::from_reader(&compressed[..])
+            .unwrap()
+            .into_random_access_reader();
+
+        // Start from a file with keyframes to test a mix of both kinds of segments.
+        let mut keyframe_options = CompressionOptions::new(4, 0.5);
+        keyframe_options.keyframe_interval = Some(1);
+        compressed.clear();
+        write_compressed_dwe_file_with_options(
+            batch(0, batch_sizes[0]).as_view(),
+            None,
+            None,
+            &keyframe_options,
+            &mut compressed,
+        )
+        .unwrap();
+        let mut start = batch_sizes[0];
+        for &len in &batch_sizes[1..] {
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            let mut appended = Vec::new();
+            append_timesteps(
+                &file,
+                batch(start, len).as_view(),
+                None,
+                None,
+                &mut appended,
+            )
+            .unwrap();
+            compressed = appended;
+            start += len;
+        }
+
+        let appended = EmbeddingFile::from_reader(&compressed[..])
+            .unwrap()
+            .into_random_access_reader();
+        let segments = appended.file().segments();
+        assert_eq!(segments.ends(), &[1, 2, 3, 5, 6, 11]);
+        let keyframes = (0..segments.len())
+            .map(|i| segments.is_keyframe(i))
+            .collect::<Vec<_>>();
+        assert_eq!(keyframes, [true, true, false, false, false, false]);
+        assert_queries_agree(&appended, &expected, num_timesteps as u32);
+    }
+
+    #[test]
+    fn predictor() {
+        let mut rng = StdRng::seed_from_u64(20_201_210);
+        let (num_old, num_new) = (5, 4);
+        let num_timesteps = num_old + num_new;
+        let mut data = (0..VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-500..=500))
+            .collect::<Vec<i16>>();
+        for t in 1..num_timesteps {
+            let previous = (t - 1) * VOCAB_SIZE * EMBEDDING_DIM;
+            for i in 0..VOCAB_SIZE * EMBEDDING_DIM {
+                data.push(data[previous + i] + rng.random_range(-5..=5));
+            }
+        }
+        let uncompressed =
+            RankThreeTensor::from_flattened(data, num_timesteps, VOCAB_SIZE, EMBEDDING_DIM);
+
+        for scheme in [
+            PredictionScheme::DistanceWeighted,
+            PredictionScheme::LeastSquaresPerLevel,
+            PredictionScheme::PreviousOnly,
+        ] {
+            let mut options = CompressionOptions::new(4, 0.5);
+            options.prediction_scheme = scheme;
+            let (old_file, appended, _) =
+                assert_append_matches(&options, num_old, uncompressed.as_view(), None);
+            let old_predictor = old_file.predictor().unwrap();
+            let predictor = appended.predictor().unwrap();
+            assert_eq!(predictor.scheme(), Some(scheme));
+            for t in 0..num_old as u32 {
+                assert_eq!(predictor.weights(t), old_predictor.weights(t));
+            }
+            // The predicted root of the new segment copies the last existing time step.
+            assert_eq!(predictor.weights(num_timesteps as u32 - 1), (1.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn model_contexts() {
+        let mut rng = StdRng::seed_from_u64(20_201_225);
+        let data = (0..6 * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|i| match i % EMBEDDING_DIM {
+                1 => rng.random_range(-300..=300),
+                _ => rng.random_range(-3..=3),
+            })
+            .collect::<Vec<i16>>();
+        let uncompressed = RankThreeTensor::from_flattened(data, 6, VOCAB_SIZE, EMBEDDING_DIM);
+
+        for entropy_precision in [EntropyPrecision::Bits12, EntropyPrecision::Bits16] {
+            let mut options = CompressionOptions::new(4, 0.5);
+            options.entropy_precision = entropy_precision;
+            options.model_contexts = Some(ModelContexts::new(vec![0, 7, 30], vec![0, 1, 0]));
+            let (old_file, appended, _) =
+                assert_append_matches(&options, 3, uncompressed.as_view(), None);
+            assert_eq!(appended.model_contexts(), old_file.model_contexts());
+        }
+    }
+
+    #[test]
+    fn model_groups() {
+        let mut rng = StdRng::seed_from_u64(20_210_102);
+        let data = (0..8 * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-20..=20))
+            .collect::<Vec<i16>>();
+        let uncompressed = RankThreeTensor::from_flattened(data, 8, VOCAB_SIZE, EMBEDDING_DIM);
+
+        for entropy_precision in [EntropyPrecision::Bits12, EntropyPrecision::Bits24] {
+            let mut options = CompressionOptions::new(4, 0.5);
+            options.entropy_precision = entropy_precision;
+            options.model_sharing = ModelSharing::TreeLevels;
+            let (old_file, appended, _) =
+                assert_append_matches(&options, 5, uncompressed.as_view(), None);
+            assert_eq!(old_file.model_groups().groups(), &[0, 2, 1, 2, 0]);
+            // The new time steps get their own entropy models.
+            assert_eq!(appended.model_groups().groups(), &[0, 2, 1, 2, 0, 3, 4, 5]);
+        }
+    }
+
+    #[test]
+    fn compact_jump_table() {
+        let mut rng = StdRng::seed_from_u64(20_210_104);
+        let data = (0..6 * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-20..=20))
+            .collect::<Vec<i16>>();
+        let uncompressed = RankThreeTensor::from_flattened(data, 6, VOCAB_SIZE, EMBEDDING_DIM);
+
+        for min_major_version in [1, 2] {
+            let mut options = CompressionOptions::new(3, 0.5);
+            options.min_major_version = min_major_version;
+            options.compact_jump_table = true;
+            let (_, appended, _) = assert_append_matches(&options, 4, uncompressed.as_view(), None);
+
+            // The appended file keeps the compact jump table.
+            let header = appended.header();
+            assert_eq!(header.major_version, min_major_version);
+            assert!(has_compact_jump_table(
+                header.major_version,
+                header.minor_version
+            ));
+            assert!(appended.layout.compact_jump_table);
+        }
+    }
+
+    #[test]
+    fn scale_factors() {
+        let timesteps = RankThreeTensor::from_flattened(vec![3i16; 24], 4, 2, 3);
+        let timesteps = timesteps.as_view();
+        let (old, new) = timesteps.slice().split_at(12);
+        let old = RankThreeTensor::from_flattened(old.to_vec(), 2, 2, 3);
+        let new = RankThreeTensor::from_flattened(new.to_vec(), 2, 2, 3);
+
+        let append = |old_scale_factors: Option<ScaleFactors>,
+                      new_scale_factors: Option<ScaleFactors>| {
+            let mut options = CompressionOptions::new(2, 1.0);
+            options.scale_factors = old_scale_factors;
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file_with_options(
+                old.as_view(),
+                None,
+                None,
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            let mut appended = Vec::new();
+            append_timesteps(
+                &file,
+                new.as_view(),
+                None,
+                new_scale_factors.as_ref(),
+                &mut appended,
+            )?;
+            Ok::<_, Error>(
+                EmbeddingFile::from_reader(&appended[..])
+                    .unwrap()
+                    .scale_factors()
+                    .cloned(),
+            )
+        };
+
+        let per_dimension = ScaleFactors::per_dimension(vec![1.0, 2.0, 3.0]);
+        assert_eq!(append(None, None).unwrap(), None);
+        assert_eq!(
+            append(Some(per_dimension.clone()), None).unwrap(),
+            Some(per_dimension.clone())
+        );
+        assert_eq!(
+            append(Some(per_dimension.clone()), Some(per_dimension.clone())).unwrap(),
+            Some(per_dimension.clone())
+        );
+        assert_eq!(
+            append(None, Some(ScaleFactors::per_timestep(vec![2.0, 3.0]))).unwrap(),
+            Some(ScaleFactors::per_timestep(vec![1.0, 1.0, 2.0, 3.0]))
+        );
+        assert_eq!(
+            append(
+                Some(ScaleFactors::per_timestep(vec![2.0, 3.0])),
+                Some(per_dimension)
+            )
+            .unwrap(),
+            Some(ScaleFactors::from_flattened(
+                vec![2.0, 2.0, 2.0, 3.0, 3.0, 3.0, 1.0, 2.0, 3.0, 1.0, 2.0, 3.0],
+                4,
+                3
+            ))
+        );
+        assert!(matches!(
+            append(Some(ScaleFactors::per_timestep(vec![2.0, 3.0])), None),
+            Err(Error::IncompatibleAppend(_))
+        ));
+        assert!(matches!(
+            append(None, Some(ScaleFactors::per_timestep(vec![2.0, 3.0, 4.0]))),
+            Err(Error::LengthMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn incompatible_appends() {
+        let timesteps = RankThreeTensor::from_flattened(vec![3i16; 24], 4, 2, 3);
+        let mut compressed = Vec::new();
+        let labels = TimestepLabels::from_integers(vec![1, 2, 3, 4]).unwrap();
+        write_compressed_dwe_file_with_options(
+            timesteps.as_view(),
+            None,
+            Some(&labels),
+            &CompressionOptions::new(2, 1.0),
+            &mut compressed,
+        )
+        .unwrap();
+        let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        let append = |new: RankThreeTensorView<i16>, labels: Option<&TimestepLabels>| {
+            append_timesteps(&file, new, labels, None, Vec::new())
+        };
+
+        let empty = RankThreeTensor::from_flattened(Vec::new(), 0, 2, 3);
+        assert!(matches!(
+            append(empty.as_view(), None),
+            Err(Error::IncompatibleAppend("there are no new time steps"))
+        ));
+
+        let new = RankThreeTensor::from_flattened(vec![3i16; 6], 1, 2, 3);
+        let new = new.as_view();
+        assert!(matches!(
+            append(new, None),
+            Err(Error::IncompatibleAppend(_))
+        ));
+        assert!(matches!(
+            append(new, Some(&TimestepLabels::from_strings(&["5"]).unwrap())),
+            Err(Error::IncompatibleAppend(_))
+        ));
+        assert!(matches!(
+            append(
+                new,
+                Some(&TimestepLabels::from_integers(vec![5, 6]).unwrap())
+            ),
+            Err(Error::LengthMismatch { .. })
+        ));
+        assert!(append(new, Some(&TimestepLabels::from_integers(vec![5]).unwrap())).is_ok());
+
+        let wrong_vocab = RankThreeTensor::from_flattened(vec![3i16; 9], 1, 3, 3);
+        assert!(matches!(
+            append(
+                wrong_vocab.as_view(),
+                Some(&TimestepLabels::from_integers(vec![5]).unwrap())
+            ),
+            Err(Error::LengthMismatch { .. })
+        ));
+        let wrong_dim = RankThreeTensor::from_flattened(vec![3i16; 4], 1, 2, 2);
+        assert!(matches!(
+            append(
+                wrong_dim.as_view(),
+                Some(&TimestepLabels::from_integers(vec![5]).unwrap())
+            ),
+            Err(Error::LengthMismatch { .. })
+        ));
+    }
+
+    /// Writes the first `num_old` time steps of `uncompressed` with `options` (and with
+    /// `vocab` and integer time step labels), appends the remaining ones, and checks
+    /// that the result decodes to `uncompressed` and answers all queries like a file
+    /// that was written in one go, both in memory and lazily. Returns the file before
+    /// appending, after appending, and the one written in one go.
+    fn assert_append_matches(
+        options: &CompressionOptions,
+        num_old: usize,
+        uncompressed: RankThreeTensorView<i16>,
+        vocab: Option<&[String]>,
+    ) -> (EmbeddingFile, EmbeddingFile, EmbeddingFile) {
+        let (num_timesteps, vocab_size, embedding_dim) = uncompressed.shape();
+        let (old_data, new_data) = uncompressed
+            .slice()
+            .split_at(num_old * vocab_size * embedding_dim);
+        let old =
+            RankThreeTensor::from_flattened(old_data.to_vec(), num_old, vocab_size, embedding_dim);
+        let new = RankThreeTensor::from_flattened(
+            new_data.to_vec(),
+            num_timesteps - num_old,
+            vocab_size,
+            embedding_dim,
+        );
+        let labels = |range: std::ops::Range<usize>| {
+            TimestepLabels::from_integers(range.map(|t| t as i64).collect()).unwrap()
+        };
+        let write = |embeddings: RankThreeTensorView<i16>, labels: &TimestepLabels| {
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file_with_options(
+                embeddings,
+                vocab,
+                Some(labels),
+                options,
+                &mut compressed,
+            )
+            .unwrap();
+            EmbeddingFile::from_reader(&compressed[..]).unwrap()
+        };
+        let expected = write(uncompressed, &labels(0..num_timesteps));
+        let old_file = write(old.as_view(), &labels(0..num_old));
+
+        let mut compressed = Vec::new();
+        let size = append_timesteps(
+            &old_file,
+            new.as_view(),
+            Some(&labels(num_old..num_timesteps)),
+            None,
+            &mut compressed,
+        )
+        .unwrap();
+        assert_eq!(size, compressed.len());
+        let appended = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        assert_eq!(appended.vocabulary(), expected.vocabulary());
+        assert_eq!(appended.timestep_labels(), expected.timestep_labels());
+
+        let reader = RandomAccessReader::new(&appended);
+        for t in 0..num_timesteps {
+            assert_eq!(
+                reader.get_embeddings_at(t as u32).unwrap().into_inner(),
+                uncompressed.subview(t).slice()
+            );
+        }
+        let num_timesteps = num_timesteps as u32;
+        assert_queries_agree(&reader, &RandomAccessReader::new(&expected), num_timesteps);
+        let lazy = LazyEmbeddingFile::with_page_size(std::io::Cursor::new(&compressed), 64, 3)
+            .unwrap()
+            .into_random_access_reader();
+        assert_eq!(lazy.file().segments(), appended.segments());
+        assert_queries_agree(&lazy, &reader, num_timesteps);
+
+        (old_file, appended, expected)
+    }
+
+    fn assert_queries_agree<F1, F2>(
+        reader: &RandomAccessReader<F1>,
+        expected: &RandomAccessReader<F2>,
+        num_timesteps: u32,
+    ) where
+        F1: crate::embedding_file::TimestepSource,
+        F2: crate::embedding_file::TimestepSource<Symbol = F1::Symbol>,
+    {
+        for t in 0..num_timesteps {
+            assert_eq!(
+                reader.get_embeddings_at(t).unwrap().into_inner(),
+                expected.get_embeddings_at(t).unwrap().into_inner()
+            );
+            assert_eq!(
+                reader
+                    .most_related_to_at_t(vec![0, 7], t, 5)
+                    .unwrap()
+                    .into_inner(),
+                expected
+                    .most_related_to_at_t(vec![0, 7], t, 5)
+                    .unwrap()
+                    .into_inner()
+            );
+        }
+        let words1 = vec![0, 17, 39, 5];
+        let words2 = vec![39, 18, 5, 0];
+        assert_eq!(
+            reader
+                .pairwise_trajectories(words1.clone(), words2.clone())
+                .unwrap()
+                .into_inner(),
+            expected
+                .pairwise_trajectories(words1, words2)
+                .unwrap()
+                .into_inner()
+        );
+        assert_eq!(
+            reader.largest_changes_wrt(11, 6, 2, 2).unwrap(),
+            expected.largest_changes_wrt(11, 6, 2, 2).unwrap()
+        );
+    }
+}
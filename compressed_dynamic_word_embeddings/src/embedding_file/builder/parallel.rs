@@ -0,0 +1,99 @@
+//! Work that the builder may distribute across threads
+//!
+//! With the `rayon` feature, [`map`] processes its items on rayon's thread pool
+//! (so callers can limit the number of threads with
+//! `rayon::ThreadPool::install`). Without it, [`map`] processes them one after the
+//! other. Either way, the results come back in the order of the items, so the
+//! builder's output doesn't depend on the feature or on the number of threads.
+
+use super::tree_order;
+use crate::embedding_file::segments::Segments;
+
+/// Returns `items.iter().map(f)` collected into a `Vec`, calculated in parallel if
+/// the `rayon` feature is enabled.
+pub(super) fn map<T, R>(items: &[T], f: impl Fn(&T) -> R + Sync + Send) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        items.par_iter().map(f).collect()
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    {
+        items.iter().map(f).collect()
+    }
+}
+
+/// Time steps that can be processed independently, each together with its left and
+/// right parent (or `None` for keyframes) as in [`tree_order`].
+pub(super) type Wave = Vec<(usize, Option<(usize, usize)>)>;
+
+/// Splits the [`tree_order`] of `segments` into waves of time steps whose parents
+/// all belong to earlier waves, so that the time steps within each wave can be
+/// processed independently once all earlier waves are done.
+///
+/// Each wave contains the time steps whose longest chain of ancestors has the same
+/// length, in tree order.
+pub(super) fn dependency_waves(segments: &Segments) -> Vec<Wave> {
+    let tree_order = tree_order(segments);
+    let mut depths = vec![0; tree_order.len()];
+    let mut waves = Vec::<Vec<_>>::new();
+    for (t, parents) in tree_order {
+        // Parents come before their children in tree order.
+        let depth = parents.map_or(0, |(left_t, right_t)| {
+            usize::max(depths[left_t], depths[right_t]) + 1
+        });
+        depths[t] = depth;
+        if depth == waves.len() {
+            waves.push(Vec::new());
+        }
+        waves[depth].push((t, parents));
+    }
+    waves
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dependency_waves_respect_parents() {
+        for segments in [
+            Segments::single(1),
+            Segments::single(2),
+            Segments::single(9),
+            Segments::with_keyframe_interval(11, 4),
+            Segments::single(4)
+                .appended(1)
+                .and_then(|segments| segments.appended(5))
+                .unwrap(),
+        ] {
+            let waves = dependency_waves(&segments);
+            let mut wave_of = vec![None; tree_order(&segments).len()];
+            for (i, wave) in waves.iter().enumerate() {
+                assert!(!wave.is_empty());
+                for &(t, parents) in wave {
+                    assert!(wave_of[t].replace(i).is_none());
+                    if let Some((left_t, right_t)) = parents {
+                        assert!(wave_of[left_t].unwrap() < i);
+                        assert!(wave_of[right_t].unwrap() < i);
+                    }
+                }
+            }
+            assert!(wave_of.iter().all(Option::is_some));
+        }
+
+        // Keyframes and time steps that bisect the same level of a tree end up in the
+        // same wave.
+        let waves = dependency_waves(&Segments::single(9));
+        let waves = waves
+            .iter()
+            .map(|wave| wave.iter().map(|&(t, _)| t).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(waves, [vec![0, 8], vec![4], vec![2, 6], vec![1, 3, 5, 7]]);
+    }
+}
@@ -0,0 +1,219 @@
+//! Choice of the weights with which time steps get predicted from their parents
+//!
+//! Files written with a [`PredictionScheme`] other than
+//! [`Mean`](PredictionScheme::Mean) store a
+//! [`Predictor`](crate::embedding_file::predictor::Predictor), i.e., a pair of
+//! weights for each time step. The [`PredictorFit`] calculates these weights for
+//! the time steps of some segments. Most schemes depend only on the tree structure,
+//! but least squares fits also need the values of all predicted time steps and of
+//! their parents, which the caller provides via [`PredictorFit::add`].
+
+use super::traverse_subtree;
+use crate::embedding_file::{
+    predictor::{PredictionScheme, WEIGHT_ONE},
+    segments::Segments,
+    symbol::Symbol,
+};
+
+/// Weights of least squares fits can't exceed this value in magnitude, which avoids
+/// extreme predictions for degenerate data.
+const MAX_LEAST_SQUARES_WEIGHT: f64 = 4.0;
+
+/// Calculates the weights of a predictor for the time steps in some segments.
+pub(super) struct PredictorFit {
+    scheme: PredictionScheme,
+
+    /// The first time step whose weights get calculated.
+    start: usize,
+
+    /// For each time step from `start` on, its left and right parent and its level
+    /// (where predicted segment roots have level zero and the level increases by
+    /// one with each bisection), or `None` for keyframes.
+    nodes: Vec<Option<(usize, usize, usize)>>,
+
+    /// For each level, the sums of `left * left`, `left * right`, `right * right`,
+    /// `left * center`, and `right * center` over all components of all time steps
+    /// at this level (only used for least squares fits).
+    sums: Vec<[f64; 5]>,
+}
+
+impl PredictorFit {
+    /// Prepares calculating the weights for the time steps in segments
+    /// `first_segment..` of `segments`, excluding the left root of `first_segment`
+    /// unless it is time step zero.
+    pub(super) fn new(scheme: PredictionScheme, segments: &Segments, first_segment: usize) -> Self {
+        let start = match first_segment {
+            0 => 0,
+            i => segments.roots(i).0 as usize + 1,
+        };
+        let num_timesteps = *segments.ends().last().expect("at least one segment") as usize + 1;
+        let mut nodes = vec![None; num_timesteps - start];
+        for i in first_segment..segments.len() {
+            let (left_t, right_t) = segments.roots(i);
+            let (left_t, right_t) = (left_t as usize, right_t as usize);
+            if !segments.is_keyframe(i) {
+                nodes[right_t - start] = Some((left_t, left_t, 0));
+            }
+            traverse_subtree(
+                2,
+                left_t,
+                0,
+                right_t,
+                1,
+                &mut |t, level, left_t, _, right_t, _| {
+                    nodes[t - start] = Some((left_t, right_t, level - 1));
+                },
+            );
+        }
+
+        Self {
+            scheme,
+            start,
+            nodes,
+            sums: Vec::new(),
+        }
+    }
+
+    /// Returns whether the weights depend on the values of the time steps, i.e.,
+    /// whether the caller has to call [`add`](#method.add) for each time step.
+    pub(super) fn needs_values(&self) -> bool {
+        self.scheme == PredictionScheme::LeastSquaresPerLevel
+    }
+
+    /// Accounts for the values `center` of time step `t` and the values of its
+    /// `parents` (or `None` for keyframes).
+    pub(super) fn add<T: Symbol>(&mut self, t: usize, center: &[T], parents: Option<(&[T], &[T])>) {
+        let level = match t.checked_sub(self.start).and_then(|i| self.nodes[i]) {
+            Some((_, _, level)) if level != 0 => level,
+            _ => return, // Keyframes and segment roots don't get fitted.
+        };
+        let (left, right) = parents.expect("only keyframes have no parents");
+        if self.sums.len() <= level {
+            self.sums.resize(level + 1, [0.0; 5]);
+        }
+        let sums = &mut self.sums[level];
+        for ((&center, &left), &right) in center.iter().zip(left).zip(right) {
+            let (center, left, right) = (
+                center.to_i64() as f64,
+                left.to_i64() as f64,
+                right.to_i64() as f64,
+            );
+            sums[0] += left * left;
+            sums[1] += left * right;
+            sums[2] += right * right;
+            sums[3] += left * center;
+            sums[4] += right * center;
+        }
+    }
+
+    /// Returns the fixed point weights of all time steps from the first one of the
+    /// segments that were passed to [`new`](#method.new).
+    pub(super) fn weights(&self) -> Vec<[i32; 2]> {
+        let least_squares = (0..self.sums.len())
+            .map(|level| self.least_squares(level))
+            .collect::<Vec<_>>();
+
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| match *node {
+                None => [0, 0],
+                // Predicted segment roots have the same time step as both parents.
+                Some((left_t, right_t, _)) if left_t == right_t => [WEIGHT_ONE, 0],
+                Some((left_t, right_t, level)) => match self.scheme {
+                    PredictionScheme::Mean => [WEIGHT_ONE / 2, WEIGHT_ONE / 2],
+                    PredictionScheme::DistanceWeighted => {
+                        let t = self.start + i;
+                        let right = ((WEIGHT_ONE as u64 * (t - left_t) as u64
+                            + (right_t - left_t) as u64 / 2)
+                            / (right_t - left_t) as u64) as i32;
+                        [WEIGHT_ONE - right, right]
+                    }
+                    PredictionScheme::LeastSquaresPerLevel => least_squares
+                        .get(level)
+                        .copied()
+                        .unwrap_or([WEIGHT_ONE / 2, WEIGHT_ONE / 2]),
+                    PredictionScheme::PreviousOnly => [WEIGHT_ONE, 0],
+                },
+            })
+            .collect()
+    }
+
+    /// Solves the normal equations of the least squares fit for level `level`.
+    /// Falls back to the mean of both parents if they are (close to) singular.
+    fn least_squares(&self, level: usize) -> [i32; 2] {
+        let [aa, ab, bb, ac, bc] = self.sums[level];
+        let determinant = aa * bb - ab * ab;
+        if determinant.is_nan() || determinant <= 1e-9 * aa * bb {
+            return [WEIGHT_ONE / 2, WEIGHT_ONE / 2];
+        }
+        let to_fixed_point = |weight: f64| {
+            (weight.clamp(-MAX_LEAST_SQUARES_WEIGHT, MAX_LEAST_SQUARES_WEIGHT) * WEIGHT_ONE as f64)
+                .round() as i32
+        };
+        [
+            to_fixed_point((bb * ac - ab * bc) / determinant),
+            to_fixed_point((aa * bc - ab * ac) / determinant),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn weights() {
+        let segments = Segments::single(5).appended(3).unwrap();
+        let half = WEIGHT_ONE / 2;
+        let third = (WEIGHT_ONE + 1) / 3;
+
+        let fit = PredictorFit::new(PredictionScheme::DistanceWeighted, &segments, 0);
+        assert!(!fit.needs_values());
+        assert_eq!(
+            fit.weights(),
+            [
+                [0, 0],
+                [half, half],
+                [half, half],
+                [half, half],
+                [0, 0],
+                [WEIGHT_ONE - third, third],
+                [half, half],
+                [WEIGHT_ONE, 0],
+            ]
+        );
+
+        let fit = PredictorFit::new(PredictionScheme::PreviousOnly, &segments, 1);
+        assert_eq!(fit.weights(), [[WEIGHT_ONE, 0]; 3]);
+    }
+
+    #[test]
+    fn least_squares() {
+        // The time steps 0, 4, and 8 are chosen such that time step 4 is predicted
+        // exactly with weights `(1/4, 3/4)`.
+        let segments = Segments::single(9);
+        let mut values = vec![vec![0i16, 0]; 9];
+        values[0] = vec![4, 0];
+        values[8] = vec![0, 4];
+        values[4] = vec![1, 3];
+        values[2] = vec![5, -1];
+        values[6] = vec![2, 7];
+
+        let mut fit = PredictorFit::new(PredictionScheme::LeastSquaresPerLevel, &segments, 0);
+        assert!(fit.needs_values());
+        fit.add(0, &values[0], None);
+        fit.add(8, &values[8], None);
+        for (t, left_t, right_t) in [(4, 0, 8), (2, 0, 4), (6, 4, 8)] {
+            fit.add(t, &values[t], Some((&values[left_t], &values[right_t])));
+        }
+        let weights = fit.weights();
+        assert_eq!(weights[0], [0, 0]);
+        assert_eq!(weights[8], [0, 0]);
+        assert_eq!(weights[4], [WEIGHT_ONE / 4, 3 * WEIGHT_ONE / 4]);
+        assert_eq!(weights[2], weights[6]);
+        assert_ne!(weights[2], [WEIGHT_ONE / 2, WEIGHT_ONE / 2]);
+        // Levels without any data fall back to the mean.
+        assert_eq!(weights[1], [WEIGHT_ONE / 2, WEIGHT_ONE / 2]);
+    }
+}
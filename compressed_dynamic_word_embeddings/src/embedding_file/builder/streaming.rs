@@ -0,0 +1,674 @@
+//! Compression of embeddings that don't fit into memory
+//!
+//! The functions in the parent module need the entire tensor of quantized
+//! embeddings in memory, plus a tensor of residuals with the same shape. The
+//! [`StreamingBuilder`] and [`write_compressed_dwe_file_streaming`] instead hold
+//! only the time steps that are needed to predict the current one (at most about
+//! `2 * log2(num_timesteps)`) in memory. They spill residuals and compressed data
+//! to a caller provided scratch space (typically a temporary file) and assemble
+//! the final file at the end. The resulting file is identical to the one that
+//! [`write_compressed_dwe_file_with_options`](super::write_compressed_dwe_file_with_options)
+//! would write. Streaming compression only supports `i16` symbols.
+
+use super::{
+    assemble_file, assert_valid_shape, choose_model_groups, create_and_serialize_encoder_models,
+    distortion_weights, exact_residuals, optional_sections, shared_counts, tree_order,
+    CompressionOptions, Encoder, JumpPointer, PredictorFit, RateDistortion, SymbolCounts,
+    RATE_DISTORTION_PASSES,
+};
+use crate::{
+    embedding_file::{
+        model_groups::ModelGroups,
+        predictor::{Prediction, PredictionScheme, Predictor},
+        segments::Segments,
+        symbol::SymbolType,
+        timestep_labels::TimestepLabels,
+    },
+    error::{Error, Result},
+    tensors::{RankTwoTensor, RankTwoTensorView},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+/// Number of compressed words that get copied from the scratch space to the
+/// output at once.
+const COPY_BUFFER_WORDS: usize = 1 << 16;
+
+/// Compresses embeddings that get pushed one time step at a time.
+///
+/// Pushed time steps get written to `scratch` (typically a temporary file), so the
+/// memory usage doesn't grow with the number of time steps. Call
+/// [`push_timestep`](#method.push_timestep) once for each time step in order, and
+/// then [`finish`](#method.finish). If the caller can load arbitrary time steps on
+/// demand then [`write_compressed_dwe_file_streaming`] needs less scratch space.
+///
+/// # Example
+///
+/// ```
+/// use compressed_dynamic_word_embeddings::{
+///     embedding_file::{
+///         builder::{CompressionOptions, StreamingBuilder},
+///         EmbeddingFile,
+///     },
+///     tensors::RankTwoTensor,
+/// };
+///
+/// let options = CompressionOptions::new(2, 0.1);
+/// let mut builder = StreamingBuilder::new((3, 4, 2), options, std::io::Cursor::new(Vec::new()));
+/// for t in 0..3 {
+///     let embeddings = (0..8).map(|i| t * i).collect();
+///     builder.push_timestep(RankTwoTensor::from_flattened(embeddings, 4, 2).as_view()).unwrap();
+/// }
+/// let mut compressed = Vec::new();
+/// builder.finish(None, None, &mut compressed).unwrap();
+///
+/// let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+/// assert_eq!(file.header().num_timesteps, 3);
+/// ```
+pub struct StreamingBuilder<S> {
+    shape: (usize, usize, usize),
+    options: CompressionOptions,
+    scratch: S,
+    num_pushed: usize,
+}
+
+impl<S: Read + Write + Seek> StreamingBuilder<S> {
+    /// Creates a builder for embeddings with shape `(num_timesteps, vocab_size,
+    /// embedding_dim)`.
+    ///
+    /// The builder takes ownership of `scratch` and overwrites it, starting at
+    /// position zero. It needs `4 * num_timesteps * vocab_size * embedding_dim`
+    /// bytes of scratch space plus the size of the compressed data.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as
+    /// [`write_compressed_dwe_file_with_options`](super::write_compressed_dwe_file_with_options),
+    /// e.g., if any dimension is zero or if `options.jump_interval` is larger than
+    /// the vocabulary size.
+    pub fn new(shape: (usize, usize, usize), options: CompressionOptions, scratch: S) -> Self {
+        assert_valid_shape(shape, options.jump_interval);
+        Self {
+            shape,
+            options,
+            scratch,
+            num_pushed: 0,
+        }
+    }
+
+    /// Appends the quantized embeddings of the next time step, which must have
+    /// shape `(vocab_size, embedding_dim)`.
+    ///
+    /// Returns `Error::LengthMismatch` if `embeddings` has the wrong shape or if all
+    /// time steps have already been pushed.
+    pub fn push_timestep(&mut self, embeddings: RankTwoTensorView<i16>) -> Result<()> {
+        let (num_timesteps, vocab_size, embedding_dim) = self.shape;
+        if self.num_pushed == num_timesteps {
+            return Err(Error::LengthMismatch {
+                what: "time steps",
+                expected: num_timesteps,
+                found: num_timesteps + 1,
+            });
+        }
+        check_timestep_shape(embeddings, vocab_size, embedding_dim)?;
+
+        let slice_size = 2 * (vocab_size * embedding_dim) as u64;
+        self.scratch
+            .seek(SeekFrom::Start(self.num_pushed as u64 * slice_size))?;
+        write_i16s(&mut self.scratch, embeddings.slice())?;
+        self.num_pushed += 1;
+        Ok(())
+    }
+
+    /// Compresses all pushed time steps and writes the file to `output`.
+    ///
+    /// Returns the number of written bytes, or `Error::LengthMismatch` if not all
+    /// time steps have been pushed. See
+    /// [`write_compressed_dwe_file_with_options`](super::write_compressed_dwe_file_with_options)
+    /// for the remaining arguments and possible errors.
+    pub fn finish(
+        mut self,
+        vocab: Option<&[String]>,
+        timestep_labels: Option<&TimestepLabels>,
+        output: impl Write,
+    ) -> Result<usize> {
+        let (num_timesteps, vocab_size, embedding_dim) = self.shape;
+        if self.num_pushed != num_timesteps {
+            return Err(Error::LengthMismatch {
+                what: "time steps",
+                expected: num_timesteps,
+                found: self.num_pushed,
+            });
+        }
+
+        let slice_len = vocab_size * embedding_dim;
+        let slice_size = 2 * slice_len as u64;
+        compress(
+            self.shape,
+            |scratch: &mut S, t| {
+                scratch.seek(SeekFrom::Start(t as u64 * slice_size))?;
+                let mut embeddings = vec![0; slice_len];
+                scratch.read_i16_into::<LittleEndian>(&mut embeddings)?;
+                Ok(embeddings)
+            },
+            Scratch {
+                storage: &mut self.scratch,
+                start: num_timesteps as u64 * slice_size,
+            },
+            vocab,
+            timestep_labels,
+            &self.options,
+            output,
+        )
+    }
+}
+
+/// Compresses embeddings whose time steps get loaded on demand.
+///
+/// Calls `load_timestep(t)` to obtain the quantized embeddings at time step `t`,
+/// which must have shape `(vocab_size, embedding_dim)`, where `shape =
+/// (num_timesteps, vocab_size, embedding_dim)`. Each time step gets loaded once
+/// (or several times if `options.rate_distortion_tradeoff` is set or if
+/// `options.prediction_scheme` fits its weights to the data), in no particular
+/// order, and only a few time steps are held in memory at any time.
+/// Residuals and compressed data get spilled to `scratch` (typically a temporary
+/// file), which gets overwritten starting at position zero and needs `2 *
+/// num_timesteps * vocab_size * embedding_dim` bytes plus the size of the
+/// compressed data.
+///
+/// Writes the same file as
+/// [`write_compressed_dwe_file_with_options`](super::write_compressed_dwe_file_with_options),
+/// whose documentation explains the remaining arguments, the return value, and
+/// possible errors. Returns `Error::LengthMismatch` if `load_timestep` returns a
+/// tensor with the wrong shape, and passes through any errors of `load_timestep`.
+pub fn write_compressed_dwe_file_streaming<S: Read + Write + Seek>(
+    shape: (usize, usize, usize),
+    mut load_timestep: impl FnMut(usize) -> Result<RankTwoTensor<i16>>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    mut scratch: S,
+    output: impl Write,
+) -> Result<usize> {
+    assert_valid_shape(shape, options.jump_interval);
+    let (_, vocab_size, embedding_dim) = shape;
+    compress(
+        shape,
+        |_: &mut S, t| {
+            let embeddings = load_timestep(t)?;
+            check_timestep_shape(embeddings.as_view(), vocab_size, embedding_dim)?;
+            Ok(embeddings.into_inner())
+        },
+        Scratch {
+            storage: &mut scratch,
+            start: 0,
+        },
+        vocab,
+        timestep_labels,
+        options,
+        output,
+    )
+}
+
+/// The part of a scratch space that [`compress`] may use.
+struct Scratch<'a, S> {
+    storage: &'a mut S,
+    start: u64,
+}
+
+/// Compresses embeddings whose time steps get loaded on demand by `load(storage,
+/// t)`, where `storage` is the scratch space (for loaders that read from there).
+fn compress<S: Read + Write + Seek>(
+    shape: (usize, usize, usize),
+    mut load: impl FnMut(&mut S, usize) -> Result<Vec<i16>>,
+    scratch: Scratch<'_, S>,
+    vocab: Option<&[String]>,
+    timestep_labels: Option<&TimestepLabels>,
+    options: &CompressionOptions,
+    output: impl Write,
+) -> Result<usize> {
+    let (num_timesteps, vocab_size, embedding_dim) = shape;
+    let segments = options.segments(num_timesteps);
+    let model_contexts = options.model_contexts(shape)?;
+    let num_contexts = model_contexts.len();
+    let Scratch { storage, start } = scratch;
+
+    // Choose the predictor like `choose_predictor` does, which may need an extra
+    // pass over all time steps.
+    let predictor = match options.prediction_scheme {
+        PredictionScheme::Mean => None,
+        scheme => {
+            let mut fit = PredictorFit::new(scheme, &segments, 0);
+            if fit.needs_values() {
+                visit_in_tree_order(&segments, &mut |t, parents| {
+                    let center = load(storage, t)?;
+                    fit.add(t, &center, parents);
+                    Ok(center)
+                })?;
+            }
+            Some(Predictor::new(scheme, fit.weights()))
+        }
+    };
+    let slice_len = vocab_size * embedding_dim;
+    let residuals_address = |t: usize| start + t as u64 * 2 * slice_len as u64;
+    let compressed_address = residuals_address(num_timesteps);
+
+    // Calculate the residuals of all time steps in tree order, and write them to the
+    // scratch space if `write` is set. Lossy compression needs several passes, just
+    // like in `get_lossy_diffs`.
+    let mut pass = |rate_distortion: Option<(&RateDistortion<_>, &[Vec<f64>], &ModelGroups)>,
+                    write: bool|
+     -> Result<Vec<SymbolCounts<i16>>> {
+        let mut counts = vec![HashMap::new(); num_timesteps * num_contexts];
+        let mut residuals = vec![0i16; slice_len];
+        visit_in_tree_order(&segments, &mut |t, parents| {
+            let center = load(storage, t)?;
+            let prediction = Prediction::of(predictor.as_ref(), t);
+            let reconstructed = match rate_distortion {
+                None => {
+                    exact_residuals(
+                        t,
+                        &center,
+                        parents,
+                        prediction,
+                        &model_contexts,
+                        &mut residuals,
+                        &mut counts[t * num_contexts..(t + 1) * num_contexts],
+                    )?;
+                    center
+                }
+                Some((rate_distortion, weights, model_groups)) => {
+                    let mut reconstructed = vec![0i16; slice_len];
+                    rate_distortion.choose_residuals(
+                        model_groups.group_of(t as u32) as usize,
+                        &center,
+                        parents,
+                        prediction,
+                        &weights[t],
+                        &model_contexts,
+                        &mut residuals,
+                        &mut reconstructed,
+                        &mut counts[t * num_contexts..(t + 1) * num_contexts],
+                    );
+                    reconstructed
+                }
+            };
+            if write {
+                storage.seek(SeekFrom::Start(residuals_address(t)))?;
+                write_i16s(storage, &residuals)?;
+            }
+            Ok(reconstructed)
+        })?;
+        Ok(counts)
+    };
+
+    let choose_model_groups = |counts: &[SymbolCounts<i16>]| {
+        choose_model_groups(
+            counts,
+            &segments,
+            num_contexts,
+            options.entropy_precision,
+            options.model_sharing,
+        )
+    };
+    let (counts, model_groups) = match options.rate_distortion_tradeoff {
+        None => {
+            let counts = pass(None, true)?;
+            let model_groups = choose_model_groups(&counts);
+            (counts, model_groups)
+        }
+        Some(tradeoff) => {
+            let weights = distortion_weights(options, num_timesteps, embedding_dim)?;
+            let mut counts = pass(None, false)?;
+            let model_groups = choose_model_groups(&counts);
+            for i in 0..RATE_DISTORTION_PASSES {
+                let rate_distortion = RateDistortion::new(
+                    &shared_counts(&counts, &model_groups, num_contexts),
+                    options.entropy_precision,
+                    tradeoff as f64,
+                );
+                counts = pass(
+                    Some((&rate_distortion, &weights, &model_groups)),
+                    i + 1 == RATE_DISTORTION_PASSES,
+                )?;
+            }
+            (counts, model_groups)
+        }
+    };
+    let optional_sections = optional_sections(
+        vocab,
+        timestep_labels,
+        options.scale_factors.as_ref(),
+        &segments,
+        predictor.as_ref(),
+        &model_contexts,
+        &model_groups,
+        shape,
+    )?;
+
+    // Encode in reverse tree order (like `compress_data`), spilling the compressed
+    // words to the scratch space after each time step.
+    let (encoder_models, entropy_models_section) = create_and_serialize_encoder_models(
+        &shared_counts(&counts, &model_groups, num_contexts),
+        &model_groups,
+        num_contexts,
+        options.entropy_precision,
+    )?;
+    let jump_interval = options.jump_interval as usize;
+    let jump_points_per_timestep = vocab_size.div_ceil(jump_interval);
+    let mut jump_table_section =
+        vec![JumpPointer::default(); num_timesteps * jump_points_per_timestep];
+
+    let mut encoder = Encoder::new(&encoder_models);
+    let word_size = encoder.word_size();
+    let mut num_spilled = 0;
+    let mut residuals = vec![0i16; slice_len];
+    for (t, _) in tree_order(&segments).into_iter().rev() {
+        if options.independent_streams {
+            encoder.restart();
+        }
+        storage.seek(SeekFrom::Start(residuals_address(t)))?;
+        storage.read_i16_into::<LittleEndian>(&mut residuals)?;
+
+        let chunks = residuals.chunks(jump_interval * embedding_dim);
+        let group = model_groups.group_of(t as u32) as usize;
+        for (i, chunk) in chunks.enumerate().rev() {
+            encoder
+                .encode_reverse(group, &model_contexts, (i * jump_interval) as u32, chunk)
+                .map_err(|()| Error::InvalidEntropyModel { timestep: t as u32 })?;
+            let (pos, state) = encoder.pos();
+            jump_table_section[t * jump_points_per_timestep + i] = JumpPointer {
+                offset: (num_spilled + pos) as u64,
+                state,
+            };
+        }
+
+        storage.seek(SeekFrom::Start(
+            compressed_address + (num_spilled * word_size) as u64,
+        ))?;
+        num_spilled += encoder.spill(storage)?;
+    }
+    for JumpPointer { offset, .. } in jump_table_section.iter_mut() {
+        *offset = num_spilled as u64 - *offset;
+    }
+
+    assemble_file(
+        shape,
+        options,
+        SymbolType::I16,
+        &entropy_models_section,
+        &jump_table_section,
+        (num_spilled * word_size).div_ceil(4),
+        |output| {
+            // Readers expect the compressed words in reverse order of their
+            // generation, padded to a multiple of four bytes.
+            let mut buf = vec![0u8; COPY_BUFFER_WORDS * word_size];
+            let mut end = num_spilled;
+            while end != 0 {
+                let begin = end.saturating_sub(COPY_BUFFER_WORDS);
+                let bytes = &mut buf[..(end - begin) * word_size];
+                storage.seek(SeekFrom::Start(
+                    compressed_address + (begin * word_size) as u64,
+                ))?;
+                storage.read_exact(bytes)?;
+                bytes.reverse();
+                for word in bytes.chunks_exact_mut(word_size) {
+                    word.reverse();
+                }
+                output.write_all(bytes)?;
+                end = begin;
+            }
+            if !(num_spilled * word_size).is_multiple_of(4) {
+                output.write_all(&[0; 2])?;
+            }
+            Ok(())
+        },
+        &optional_sections,
+        output,
+    )
+}
+
+/// Calls `visit(t, parents)` for all time steps `t` in the order of [`tree_order`],
+/// where `parents` are the values that `visit` returned for the left and right
+/// parent of `t` (or `None` for keyframes). Holds only the values of the ancestors
+/// of the current time step in memory.
+fn visit_in_tree_order<F>(segments: &Segments, visit: &mut F) -> Result<()>
+where
+    F: FnMut(usize, Option<(&[i16], &[i16])>) -> Result<Vec<i16>>,
+{
+    let mut left = visit(0, None)?;
+    for i in 0..segments.len() {
+        let (left_t, right_t) = segments.roots(i);
+        let (left_t, right_t) = (left_t as usize, right_t as usize);
+        if right_t != left_t {
+            let parents = if segments.is_keyframe(i) {
+                None
+            } else {
+                Some((&left[..], &left[..]))
+            };
+            let right = visit(right_t, parents)?;
+            visit_subtree((left_t, &left), (right_t, &right), visit)?;
+            left = right;
+        }
+    }
+    Ok(())
+}
+
+/// Recursive part of [`visit_in_tree_order`], analogous to `traverse_subtree`.
+fn visit_subtree<F>(left: (usize, &[i16]), right: (usize, &[i16]), visit: &mut F) -> Result<()>
+where
+    F: FnMut(usize, Option<(&[i16], &[i16])>) -> Result<Vec<i16>>,
+{
+    let t = (left.0 + right.0) / 2;
+    if t != left.0 {
+        let center = visit(t, Some((left.1, right.1)))?;
+        visit_subtree(left, (t, &center), visit)?;
+        visit_subtree((t, &center), right, visit)?;
+    }
+    Ok(())
+}
+
+/// Returns `Error::LengthMismatch` unless `embeddings` has shape `(vocab_size,
+/// embedding_dim)`.
+fn check_timestep_shape(
+    embeddings: RankTwoTensorView<i16>,
+    vocab_size: usize,
+    embedding_dim: usize,
+) -> Result<()> {
+    let (num_rows, num_columns) = embeddings.shape();
+    if num_columns != embedding_dim {
+        Err(Error::LengthMismatch {
+            what: "embedding dimensions in a time step",
+            expected: embedding_dim,
+            found: num_columns,
+        })
+    } else if num_rows != vocab_size {
+        Err(Error::LengthMismatch {
+            what: "words in a time step",
+            expected: vocab_size,
+            found: num_rows,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn write_i16s(sink: &mut impl Write, values: &[i16]) -> Result<()> {
+    let bytes = values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect::<Vec<_>>();
+    sink.write_all(&bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        embedding_file::{
+            builder::write_compressed_dwe_file_with_options, model_contexts::ModelContexts,
+            model_groups::ModelSharing, predictor::PredictionScheme, scale_factors::ScaleFactors,
+            EntropyPrecision,
+        },
+        tensors::RankThreeTensor,
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::io::Cursor;
+
+    #[test]
+    fn matches_in_memory_builder() {
+        const VOCAB_SIZE: usize = 30;
+        const EMBEDDING_DIM: usize = 3;
+        let mut rng = StdRng::seed_from_u64(20_201_206);
+
+        // Options that change the structure of the file, which the streaming builder
+        // has to reproduce exactly.
+        let structures: [fn(&mut CompressionOptions); 8] = [
+            |_| {},
+            |options| options.keyframe_interval = Some(2),
+            |options| options.prediction_scheme = PredictionScheme::LeastSquaresPerLevel,
+            |options| {
+                options.model_contexts = Some(ModelContexts::uniform(
+                    VOCAB_SIZE as u32,
+                    3,
+                    EMBEDDING_DIM as u32,
+                    2,
+                ))
+            },
+            |options| options.model_sharing = ModelSharing::TreeLevels,
+            |options| options.model_sharing = ModelSharing::Automatic,
+            |options| options.compact_jump_table = true,
+            |options| options.independent_streams = true,
+        ];
+
+        for num_timesteps in [1, 2, 3, 6, 9] {
+            let data = (0..num_timesteps * VOCAB_SIZE * EMBEDDING_DIM)
+                .map(|_| rng.random_range(-50..=50))
+                .collect();
+            let uncompressed =
+                RankThreeTensor::from_flattened(data, num_timesteps, VOCAB_SIZE, EMBEDDING_DIM);
+            let shape = uncompressed.as_view().shape();
+            let vocab = (0..VOCAB_SIZE)
+                .map(|i| format!("word{}", i))
+                .collect::<Vec<_>>();
+
+            for entropy_precision in [EntropyPrecision::Bits12, EntropyPrecision::Bits24] {
+                for rate_distortion_tradeoff in [None, Some(0.5)] {
+                    for configure in structures {
+                        let mut options = CompressionOptions::new(7, 0.25);
+                        options.entropy_precision = entropy_precision;
+                        options.rate_distortion_tradeoff = rate_distortion_tradeoff;
+                        options.scale_factors =
+                            Some(ScaleFactors::per_dimension(vec![1.0, 2.0, 0.5]));
+                        configure(&mut options);
+
+                        let mut expected = Vec::new();
+                        let expected_size = write_compressed_dwe_file_with_options(
+                            uncompressed.as_view(),
+                            Some(&vocab),
+                            None,
+                            &options,
+                            &mut expected,
+                        )
+                        .unwrap();
+
+                        let mut loaded = Vec::new();
+                        let mut compressed = Vec::new();
+                        let size = write_compressed_dwe_file_streaming(
+                            shape,
+                            |t| {
+                                loaded.push(t);
+                                let embeddings = uncompressed.as_view().subview(t).slice().to_vec();
+                                Ok(RankTwoTensor::from_flattened(
+                                    embeddings,
+                                    VOCAB_SIZE,
+                                    EMBEDDING_DIM,
+                                ))
+                            },
+                            Some(&vocab),
+                            None,
+                            &options,
+                            Cursor::new(Vec::new()),
+                            &mut compressed,
+                        )
+                        .unwrap();
+                        assert_eq!(size, expected_size);
+                        assert!(compressed == expected);
+                        let mut num_passes = if rate_distortion_tradeoff.is_some() {
+                            1 + RATE_DISTORTION_PASSES
+                        } else {
+                            1
+                        };
+                        if options.prediction_scheme == PredictionScheme::LeastSquaresPerLevel {
+                            num_passes += 1; // Fitting the weights needs an extra pass.
+                        }
+                        assert_eq!(loaded.len(), num_passes * num_timesteps);
+
+                        let mut builder =
+                            StreamingBuilder::new(shape, options.clone(), Cursor::new(Vec::new()));
+                        for t in 0..num_timesteps {
+                            builder
+                                .push_timestep(uncompressed.as_view().subview(t))
+                                .unwrap();
+                        }
+                        let mut compressed = Vec::new();
+                        builder.finish(Some(&vocab), None, &mut compressed).unwrap();
+                        assert!(compressed == expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_shapes() {
+        let embeddings = RankTwoTensor::from_flattened(vec![1i16; 12], 4, 3);
+        let options = CompressionOptions::new(2, 1.0);
+
+        let mut builder =
+            StreamingBuilder::new((2, 4, 3), options.clone(), Cursor::new(Vec::new()));
+        builder.push_timestep(embeddings.as_view()).unwrap();
+        let transposed = RankTwoTensor::from_flattened(vec![1i16; 12], 3, 4);
+        assert!(matches!(
+            builder.push_timestep(transposed.as_view()),
+            Err(Error::LengthMismatch {
+                expected: 3,
+                found: 4,
+                ..
+            })
+        ));
+        assert!(matches!(
+            builder.finish(None, None, Vec::new()),
+            Err(Error::LengthMismatch {
+                what: "time steps",
+                expected: 2,
+                found: 1
+            })
+        ));
+
+        let result = write_compressed_dwe_file_streaming(
+            (2, 5, 3),
+            |_| Ok(RankTwoTensor::from_flattened(vec![1; 12], 4, 3)),
+            None,
+            None,
+            &options,
+            Cursor::new(Vec::new()),
+            Vec::new(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::LengthMismatch {
+                expected: 5,
+                found: 4,
+                ..
+            })
+        ));
+    }
+}
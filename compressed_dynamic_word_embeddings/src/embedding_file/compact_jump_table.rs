@@ -0,0 +1,298 @@
+//! The optional section that stores the jump table in a compact form
+
+use super::{FileHeader, JumpPointer, Layout};
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that replaces the jump table in files of versions
+/// 1.3 and 2.2.
+pub const COMPACT_JUMP_TABLE_SECTION_TAG: u32 = u32::from_le_bytes(*b"jtbl");
+
+/// Number of `u32`s before the packed bits, which hold the bit widths of the fields.
+const NUM_WIDTHS: usize = 3;
+
+/// Serializes the jump table `jump_pointers` (with `jump_points_per_timestep`
+/// entries per time step, in the order of the uncompressed jump table) into the
+/// payload of a compact jump table section.
+///
+/// The payload starts with three `u32`s, which are the bit widths of the fields
+/// that follow: the `offset` of the first jump pointer of each time step, the
+/// difference (modulo 2^64) between the `offset`s of subsequent jump pointers of
+/// the same time step, and the `state` of each jump pointer. The fields of all jump
+/// pointers follow, in the order of the uncompressed jump table, packed into `u32`s
+/// starting at the least significant bit (with zero padding at the end). Since the
+/// offsets within a time step don't decrease, the differences are much smaller than
+/// the offsets themselves.
+pub(super) fn serialize(
+    jump_pointers: &[JumpPointer],
+    jump_points_per_timestep: usize,
+) -> Vec<u32> {
+    let fields = || {
+        jump_pointers
+            .chunks_exact(jump_points_per_timestep)
+            .flat_map(|jump_table| {
+                let offsets = std::iter::once(0)
+                    .chain(jump_table.iter().map(|jump_pointer| jump_pointer.offset));
+                jump_table
+                    .iter()
+                    .zip(offsets)
+                    .map(|(jump_pointer, previous)| {
+                        (
+                            jump_pointer.offset.wrapping_sub(previous),
+                            jump_pointer.state,
+                        )
+                    })
+            })
+            .enumerate()
+            .map(|(i, (offset, state))| (i % jump_points_per_timestep == 0, offset, state))
+    };
+
+    let mut widths = [0; NUM_WIDTHS];
+    for (first, offset, state) in fields() {
+        let offset_width = &mut widths[if first { 0 } else { 1 }];
+        *offset_width = u32::max(*offset_width, bit_width(offset));
+        widths[2] = u32::max(widths[2], bit_width(state));
+    }
+
+    let mut writer = BitWriter {
+        words: widths.to_vec(),
+        buffer: 0,
+        len: 0,
+    };
+    for (first, offset, state) in fields() {
+        writer.write(offset, widths[if first { 0 } else { 1 }]);
+        writer.write(state, widths[2]);
+    }
+    writer.finish()
+}
+
+/// Inverse of [`serialize`], which returns the jump table in the layout of the
+/// uncompressed jump table for the version of the file format in `header`.
+///
+/// Returns `Error::InvalidSection` if the payload doesn't have the size that its
+/// bit widths imply for the number of jump pointers in `header`, or if some jump
+/// pointer doesn't fit into the uncompressed jump table. Doesn't check whether the
+/// jump pointers point into the compressed data.
+pub(super) fn deserialize(payload: &[u32], header: &FileHeader) -> Result<Box<[u32]>> {
+    let invalid = || Error::InvalidSection {
+        tag: COMPACT_JUMP_TABLE_SECTION_TAG,
+    };
+    let (widths, packed) = payload.split_at_checked(NUM_WIDTHS).ok_or_else(invalid)?;
+    let [first_offset_width, offset_width, state_width] = *widths else {
+        unreachable!("split at `NUM_WIDTHS`");
+    };
+    // States are never zero, so each jump pointer takes up at least one bit. This
+    // bounds the number of jump pointers by the size of the payload.
+    if first_offset_width > 64 || offset_width > 64 || state_width == 0 || state_width > 64 {
+        return Err(invalid());
+    }
+
+    // Calculate in `u128` so that this can't overflow.
+    let num_timesteps = header.num_timesteps as u128;
+    let jump_points_per_timestep = header.vocab_size.div_ceil(header.jump_interval) as u128;
+    let num_jump_pointers = num_timesteps * jump_points_per_timestep;
+    let num_bits = num_timesteps * first_offset_width as u128
+        + (num_jump_pointers - num_timesteps) * offset_width as u128
+        + num_jump_pointers * state_width as u128;
+    if num_bits.div_ceil(32) != packed.len() as u128 {
+        return Err(invalid());
+    }
+
+    let jump_pointer_size = Layout::jump_pointer_size(header);
+    let jump_points_per_timestep = jump_points_per_timestep as usize;
+    let mut jump_table = vec![0; num_jump_pointers as usize * jump_pointer_size];
+    let mut reader = BitReader {
+        words: packed,
+        buffer: 0,
+        len: 0,
+    };
+    let mut offset = 0u64;
+    for (i, words) in jump_table.chunks_exact_mut(jump_pointer_size).enumerate() {
+        if i % jump_points_per_timestep == 0 {
+            offset = reader.read(first_offset_width).ok_or_else(invalid)?;
+        } else {
+            offset = offset.wrapping_add(reader.read(offset_width).ok_or_else(invalid)?);
+        }
+        let state = reader.read(state_width).ok_or_else(invalid)?;
+        JumpPointer { offset, state }
+            .to_words(words)
+            .ok_or_else(invalid)?;
+    }
+
+    Ok(jump_table.into())
+}
+
+/// Returns the size of the compact jump table section, in units of four bytes, that
+/// the builder estimates for a file with `num_timesteps` time steps,
+/// `num_jump_pointers` jump pointers, and `num_compressed_words` words of
+/// compressed data (each with `word_bits` bits).
+///
+/// Assumes that the offsets of subsequent jump pointers differ by at most four
+/// times the average and that states use all available bits.
+pub(super) fn estimate_size(
+    num_timesteps: usize,
+    num_jump_pointers: usize,
+    num_compressed_words: u64,
+    word_bits: u32,
+) -> usize {
+    let first_offset_width = bit_width(num_compressed_words) as u64;
+    let offset_width = bit_width(4 * num_compressed_words / num_jump_pointers as u64) as u64;
+    let state_width = 2 * word_bits as u64;
+    let num_bits = num_timesteps as u64 * first_offset_width
+        + (num_jump_pointers - num_timesteps) as u64 * offset_width
+        + num_jump_pointers as u64 * state_width;
+    NUM_WIDTHS + num_bits.div_ceil(32) as usize
+}
+
+/// Returns the number of bits that are needed to represent `value`.
+fn bit_width(value: u64) -> u32 {
+    u64::BITS - value.leading_zeros()
+}
+
+struct BitWriter {
+    words: Vec<u32>,
+    /// Bits that don't fill a whole word yet (the lowest `len` bits).
+    buffer: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    /// Appends the lowest `width` bits of `value`, which must be zero above them.
+    fn write(&mut self, value: u64, width: u32) {
+        if width > 32 {
+            self.write_at_most_32(value & u32::MAX as u64, 32);
+            self.write_at_most_32(value >> 32, width - 32);
+        } else {
+            self.write_at_most_32(value, width);
+        }
+    }
+
+    fn write_at_most_32(&mut self, value: u64, width: u32) {
+        // Can't overflow since `self.len < 32` and `value < 1 << 32`.
+        self.buffer |= value << self.len;
+        self.len += width;
+        if self.len >= 32 {
+            self.words.push(self.buffer as u32);
+            self.buffer >>= 32;
+            self.len -= 32;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u32> {
+        if self.len != 0 {
+            self.words.push(self.buffer as u32);
+        }
+        self.words
+    }
+}
+
+struct BitReader<'a> {
+    words: &'a [u32],
+    /// Bits that were read from `words` but not yet returned (the lowest `len` bits).
+    buffer: u64,
+    len: u32,
+}
+
+impl BitReader<'_> {
+    /// Reads a value with `width` bits, or returns `None` if `words` ends first.
+    fn read(&mut self, width: u32) -> Option<u64> {
+        if width > 32 {
+            let low = self.read_at_most_32(32)?;
+            let high = self.read_at_most_32(width - 32)?;
+            Some(high << 32 | low)
+        } else {
+            self.read_at_most_32(width)
+        }
+    }
+
+    fn read_at_most_32(&mut self, width: u32) -> Option<u64> {
+        if self.len < width {
+            let (&word, rest) = self.words.split_first()?;
+            self.buffer |= (word as u64) << self.len;
+            self.len += 32;
+            self.words = rest;
+        }
+        let value = self.buffer & ((1 << width) - 1);
+        self.buffer >>= width;
+        self.len -= width;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::embedding_file::{symbol::SymbolType, EntropyPrecision, MAGIC};
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let header = |major_version, entropy_precision| FileHeader {
+            magic: MAGIC,
+            major_version,
+            minor_version: 0,
+            file_size: 0,
+            jump_table_address: 0,
+            num_timesteps: 3,
+            vocab_size: 5,
+            embedding_dim: 2,
+            jump_interval: 2,
+            scale_factor: 1.0,
+            entropy_precision,
+            symbol_type: SymbolType::I16,
+        };
+        let jump_pointer = |offset, state| JumpPointer { offset, state };
+        let jump_pointers = [
+            jump_pointer(7, 0x1_0000),
+            jump_pointer(12, 0x1234_5678),
+            jump_pointer(13, 0xffff_ffff),
+            jump_pointer(0, 0x2_0000),
+            jump_pointer(0, 0x3_0000),
+            jump_pointer(5, 0x4_0000),
+            jump_pointer(1000, 0x5_0000),
+            jump_pointer(1040, 0x6_0000),
+            jump_pointer(1041, 0x7_0000),
+        ];
+
+        let serialized = serialize(&jump_pointers, 3);
+        assert_eq!(&serialized[..NUM_WIDTHS], &[10, 6, 32]);
+        assert_eq!(
+            serialized.len(),
+            NUM_WIDTHS + (3 * 10 + 6 * 6 + 9 * 32usize).div_ceil(32)
+        );
+
+        let expected = |jump_pointer_size| {
+            let mut words = vec![0; 9 * jump_pointer_size];
+            for (jump_pointer, words) in jump_pointers
+                .iter()
+                .zip(words.chunks_exact_mut(jump_pointer_size))
+            {
+                jump_pointer.to_words(words).unwrap();
+            }
+            words
+        };
+        let version_1 = header(1, EntropyPrecision::Bits12);
+        let version_2 = header(2, EntropyPrecision::Bits24);
+        assert_eq!(&*deserialize(&serialized, &version_1).unwrap(), expected(2));
+        assert_eq!(&*deserialize(&serialized, &version_2).unwrap(), expected(4));
+
+        // Wrong number of jump pointers.
+        let mut other = header(1, EntropyPrecision::Bits12);
+        other.num_timesteps = 4;
+        assert!(deserialize(&serialized, &other).is_err());
+        assert!(deserialize(&serialized[..serialized.len() - 1], &version_1).is_err());
+        assert!(deserialize(&[0, 0, 0], &version_1).is_err());
+        assert!(deserialize(&[], &version_1).is_err());
+
+        // Offsets that only fit into version 2 and states that only fit into 24 bit
+        // entropy models.
+        let large = [jump_pointer(1 << 32, 1 << 32); 9];
+        let serialized = serialize(&large, 3);
+        assert_eq!(&serialized[..NUM_WIDTHS], &[33, 0, 33]);
+        assert!(deserialize(&serialized, &version_1).is_err());
+        assert!(deserialize(&serialized, &header(2, EntropyPrecision::Bits12)).is_err());
+        let deserialized = deserialize(&serialized, &version_2).unwrap();
+        for words in deserialized.chunks_exact(4) {
+            assert_eq!(JumpPointer::from_words(words).offset, 1 << 32);
+            assert_eq!(JumpPointer::from_words(words).state, 1 << 32);
+        }
+    }
+}
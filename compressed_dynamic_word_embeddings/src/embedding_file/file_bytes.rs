@@ -0,0 +1,81 @@
+//! Access to a file that is held in a byte buffer or a memory map
+
+use std::convert::TryInto;
+
+use crate::error::{Error, Result};
+
+/// A byte buffer whose contents can be viewed as a slice of `u32`s.
+///
+/// Use this as the storage of an [`EmbeddingFile`](../struct.EmbeddingFile.html)
+/// to read a file directly out of a memory map or a borrowed byte slice. Several
+/// processes can then share the same page cached file. See
+/// [`EmbeddingFile::from_bytes`](../struct.EmbeddingFile.html#method.from_bytes).
+///
+/// On little endian platforms, a buffer that is aligned to a four byte boundary
+/// is used in place (memory maps are always suitably aligned). Otherwise, `new`
+/// decodes the buffer into a copy, which still works but doesn't save any memory
+/// (see [`is_zero_copy`](#method.is_zero_copy)).
+#[derive(Debug, Clone)]
+pub struct FileBytes<B> {
+    bytes: B,
+    copy: Option<Box<[u32]>>,
+}
+
+impl<B: AsRef<[u8]>> FileBytes<B> {
+    /// Wraps a byte buffer after checking that its length is a multiple of four
+    /// bytes.
+    ///
+    /// `bytes.as_ref()` must return the same slice each time it's called, which is
+    /// the case for all common buffer types (e.g., `&[u8]`, `Vec<u8>`, or memory
+    /// maps).
+    pub fn new(bytes: B) -> Result<Self> {
+        let slice = bytes.as_ref();
+        if !slice.len().is_multiple_of(4) {
+            return Err(Error::Truncated);
+        }
+
+        let in_place = cfg!(target_endian = "little") && slice.as_ptr().cast::<u32>().is_aligned();
+        let copy = if in_place {
+            None
+        } else {
+            Some(
+                slice
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_le_bytes(chunk.try_into().expect("chunk of four")))
+                    .collect(),
+            )
+        };
+
+        Ok(Self { bytes, copy })
+    }
+
+    /// Returns `true` if the buffer is used in place, and `false` if `new` had to
+    /// decode it into a copy because the buffer isn't aligned to a four byte
+    /// boundary or because the platform is big endian.
+    pub fn is_zero_copy(&self) -> bool {
+        self.copy.is_none()
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bytes
+    }
+}
+
+impl<B: AsRef<[u8]>> AsRef<[u32]> for FileBytes<B> {
+    fn as_ref(&self) -> &[u32] {
+        if let Some(copy) = &self.copy {
+            return copy;
+        }
+
+        // SAFETY: Every bit pattern is a valid `u32`, and `align_to` takes care of
+        // alignment. The prefix is empty unless `B` violates the contract of `new`,
+        // in which case we return an empty slice. This may make later queries panic
+        // but it can't lead to undefined behavior.
+        let (prefix, words, _) = unsafe { self.bytes.as_ref().align_to::<u32>() };
+        if prefix.is_empty() {
+            words
+        } else {
+            &[]
+        }
+    }
+}
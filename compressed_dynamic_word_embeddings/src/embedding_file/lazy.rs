@@ -0,0 +1,765 @@
+//! Lazy loading of compressed dynamic word embeddings files
+//!
+//! A [`LazyEmbeddingFile`] reads only the header, the entropy models, and the
+//! optional sections (including a compact jump table, if any) when it is created.
+//! The (uncompressed) jump table and the compressed data are fetched in pages of a
+//! fixed size only when a query actually needs them, and a bounded number of pages
+//! is kept in a cache. This allows answering queries that
+//! touch only a few words (e.g., `pairwise_trajectories`) on huge files with a tiny
+//! memory footprint.
+//!
+//! The file can be read from anything that implements [`RangeSource`], i.e., from
+//! any `Read + Seek` (e.g., a `std::fs::File`) or from a remote file that is accessed
+//! with HTTP range requests. [`InMemoryRangeSource`] is an in-memory stand-in for
+//! the latter that keeps track of the fetched ranges.
+
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::convert::{Infallible, TryFrom, TryInto};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use constriction::{backends::ReadWords, CoderError, PosSeek, Stack};
+
+use super::{
+    compact_jump_table, deserialize_decoder_models, model_contexts::ModelContexts,
+    model_groups::ModelGroups, parse_section_table, portable, predictor::Predictor,
+    scale_factors::ScaleFactors, section_table_start, segments::Segments, symbol::Symbol,
+    timestep_labels::TimestepLabels, vocabulary::Vocabulary, DecoderModels, FileHeader,
+    JumpPointer, Layout, OptionalSections, TimestepDecoder, TimestepReader, TimestepSource,
+    HEADER_SIZE_V2,
+};
+use crate::error::{Error, Result};
+use crate::random_access_reader::RandomAccessReader;
+
+/// Default size of the pages in which a [`LazyEmbeddingFile`] fetches data (in
+/// bytes).
+pub const DEFAULT_PAGE_SIZE: usize = 1 << 16;
+
+/// Default number of pages that a [`LazyEmbeddingFile`] keeps in its cache.
+pub const DEFAULT_MAX_CACHED_PAGES: usize = 64;
+
+/// A source of byte ranges of a file.
+///
+/// This is implemented for all types that implement `Read + Seek`. Implement it
+/// for other types to load files, e.g., with HTTP range requests.
+pub trait RangeSource {
+    /// Fills `buf` with the bytes of the file starting at byte offset `start`.
+    ///
+    /// Returns an error of kind `UnexpectedEof` if the file ends before `buf` is
+    /// full.
+    fn read_range(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Returns the size of the file in bytes.
+    fn size(&mut self) -> io::Result<u64>;
+}
+
+impl<R: Read + Seek> RangeSource for R {
+    fn read_range(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(start))?;
+        self.read_exact(buf)
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+}
+
+/// An in-memory stand-in for a remote file that is loaded with range requests.
+///
+/// Counts the number of requests and the number of fetched bytes, e.g., to check
+/// how much data a query would download.
+#[derive(Debug, Clone)]
+pub struct InMemoryRangeSource<B> {
+    bytes: B,
+    num_requests: usize,
+    num_fetched_bytes: u64,
+}
+
+impl<B: AsRef<[u8]>> InMemoryRangeSource<B> {
+    pub fn new(bytes: B) -> Self {
+        Self {
+            bytes,
+            num_requests: 0,
+            num_fetched_bytes: 0,
+        }
+    }
+
+    /// Returns the number of calls to `read_range` so far.
+    pub fn num_requests(&self) -> usize {
+        self.num_requests
+    }
+
+    /// Returns the total number of bytes fetched by `read_range` so far.
+    pub fn num_fetched_bytes(&self) -> u64 {
+        self.num_fetched_bytes
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bytes
+    }
+}
+
+impl<B: AsRef<[u8]>> RangeSource for InMemoryRangeSource<B> {
+    fn read_range(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        let bytes = self.bytes.as_ref();
+        let range = usize::try_from(start)
+            .ok()
+            .and_then(|start| Some(start..start.checked_add(buf.len())?))
+            .and_then(|range| bytes.get(range))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(range);
+        self.num_requests += 1;
+        self.num_fetched_bytes += buf.len() as u64;
+        Ok(())
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.bytes.as_ref().len() as u64)
+    }
+}
+
+/// A compressed dynamic word embeddings file that is loaded lazily from a
+/// [`RangeSource`].
+///
+/// Provides the same queries as an [`EmbeddingFile`](../struct.EmbeddingFile.html)
+/// (in particular, it can be turned into a [`RandomAccessReader`]), but it only
+/// fetches the parts of the file that a query actually touches. See the
+/// [module level documentation](index.html) for details. The type parameter `T`
+/// is the [`Symbol`] type of the file, as for an `EmbeddingFile`.
+pub struct LazyEmbeddingFile<S, T = i16> {
+    header: FileHeader,
+    decoder_models: DecoderModels<T>,
+    layout: Layout,
+    vocabulary: Option<Vocabulary>,
+    timestep_labels: Option<TimestepLabels>,
+    scale_factors: Option<ScaleFactors>,
+    segments: Segments,
+    predictor: Option<Predictor>,
+    model_contexts: ModelContexts,
+    model_groups: ModelGroups,
+    /// The decoded jump table if the file stores it in a compact jump table section.
+    compact_jump_table: Option<Box<[u32]>>,
+    pages: PageCache<S>,
+}
+
+impl<S: RangeSource> LazyEmbeddingFile<S> {
+    /// Reads the header, the entropy models, and the optional sections from
+    /// `source`, and prepares for fetching the rest of the file lazily with default
+    /// page size and cache capacity.
+    ///
+    /// Returns `Error::SymbolTypeMismatch` if the file doesn't have `i16` symbols,
+    /// see [`new_typed`](#method.new_typed).
+    pub fn new(source: S) -> Result<Self> {
+        Self::new_typed(source)
+    }
+
+    /// Same as [`new`](#method.new) but with a custom size of the pages in which data
+    /// gets fetched (in bytes, must be a nonzero multiple of four) and a custom
+    /// maximum number of pages that are kept in the cache (must be nonzero).
+    pub fn with_page_size(source: S, page_size: usize, max_cached_pages: usize) -> Result<Self> {
+        Self::with_page_size_typed(source, page_size, max_cached_pages)
+    }
+}
+
+impl<S: RangeSource, T: Symbol> LazyEmbeddingFile<S, T> {
+    /// Same as [`new`](#method.new) but for files whose `symbol_type` is `T`.
+    pub fn new_typed(source: S) -> Result<Self> {
+        Self::with_page_size_typed(source, DEFAULT_PAGE_SIZE, DEFAULT_MAX_CACHED_PAGES)
+    }
+
+    /// Same as [`with_page_size`](#method.with_page_size) but for files whose
+    /// `symbol_type` is `T`.
+    pub fn with_page_size_typed(
+        mut source: S,
+        page_size: usize,
+        max_cached_pages: usize,
+    ) -> Result<Self> {
+        assert!(page_size != 0 && page_size.is_multiple_of(4));
+        assert!(max_cached_pages != 0);
+
+        let file_size = source.size()?;
+        if !file_size.is_multiple_of(4) {
+            return Err(Error::Truncated);
+        }
+        let file_len = usize::try_from(file_size / 4).map_err(|_| Error::TooLarge)?;
+
+        // Fetch enough words for the header of any version, which `from_words` then
+        // parses according to the `major_version` field.
+        let header = read_words(
+            &mut source,
+            0,
+            usize::min(file_len, HEADER_SIZE_V2 as usize),
+        )?;
+        let header = FileHeader::from_words(&header)?;
+        let layout = header.validate(file_len)?;
+        header.check_symbol_type::<T>()?;
+
+        let mut sections = OptionalSections::default();
+        if layout.has_section_table {
+            let num_sections = read_words(&mut source, file_len - 1, 1)?[0];
+            let table_start = section_table_start(num_sections, file_len, &layout)?;
+            let table = read_words(&mut source, table_start, file_len - 1 - table_start)?;
+            for (tag, range) in parse_section_table(&table, &layout, table_start)? {
+                if OptionalSections::is_known(tag) {
+                    let payload = read_words(&mut source, range.start, range.len())?;
+                    sections.insert(tag, &payload, &header)?;
+                }
+            }
+        }
+
+        // The model contexts and groups determine how many entropy models there are.
+        let model_contexts = sections
+            .model_contexts
+            .unwrap_or_else(|| ModelContexts::single(header.embedding_dim));
+        let model_groups = sections
+            .model_groups
+            .unwrap_or_else(|| ModelGroups::per_timestep(header.num_timesteps));
+        let entropy_models_section = read_words(
+            &mut source,
+            layout.header_size,
+            layout.jump_table_address - layout.header_size,
+        )?;
+        let decoder_models = deserialize_decoder_models(
+            &header,
+            &model_groups,
+            model_contexts.len(),
+            &portable::u16_words(&entropy_models_section),
+        )?;
+
+        let compact_jump_table = compact_jump_table(&layout, sections.compact_jump_table)?;
+        let segments = sections
+            .segments
+            .unwrap_or_else(|| Segments::single(header.num_timesteps));
+        Ok(Self {
+            header,
+            decoder_models,
+            layout,
+            vocabulary: sections.vocabulary,
+            timestep_labels: sections.timestep_labels,
+            scale_factors: sections.scale_factors,
+            segments,
+            predictor: sections.predictor,
+            model_contexts,
+            model_groups,
+            compact_jump_table,
+            pages: PageCache {
+                source: RefCell::new(source),
+                page_len: page_size / 4,
+                file_len,
+                max_pages: max_cached_pages,
+                pages: Default::default(),
+            },
+        })
+    }
+
+    pub fn into_random_access_reader(self) -> RandomAccessReader<Self> {
+        RandomAccessReader::new(self)
+    }
+
+    #[inline(always)]
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    pub fn timestep(&self, t: u32) -> Result<LazyTimestep<'_, S, T>> {
+        if t >= self.header.num_timesteps {
+            return Err(Error::TimestepOutOfRange {
+                timestep: t,
+                num_timesteps: self.header.num_timesteps,
+            });
+        }
+
+        let start = self.layout.compressed_data_start;
+        let decoder = TimestepDecoder::new(
+            &self.decoder_models,
+            self.model_contexts.len(),
+            self.model_groups.group_of(t) as usize,
+            self.jump_pointer(t, 0)?,
+            |pos| PagedWords::new(&self.pages, start, pos),
+            |pos| PagedWords::new(&self.pages, start, pos),
+        )
+        .ok_or(Error::InconsistentJumpTable)?;
+
+        Ok(LazyTimestep {
+            file: self,
+            decoder,
+            t,
+            word_index: 0,
+        })
+    }
+
+    /// Returns the vocabulary, or `None` if the file doesn't contain one.
+    pub fn vocabulary(&self) -> Option<&Vocabulary> {
+        self.vocabulary.as_ref()
+    }
+
+    /// Returns the labels of all time steps, or `None` if the file doesn't contain
+    /// any.
+    pub fn timestep_labels(&self) -> Option<&TimestepLabels> {
+        self.timestep_labels.as_ref()
+    }
+
+    /// Returns the scale factors per dimension and/or per time step, or `None` if
+    /// the file doesn't contain any.
+    pub fn scale_factors(&self) -> Option<&ScaleFactors> {
+        self.scale_factors.as_ref()
+    }
+
+    /// Returns the segments into which the time steps are split.
+    pub fn segments(&self) -> &Segments {
+        &self.segments
+    }
+
+    /// Returns the weights with which time steps are predicted from their parents,
+    /// or `None` if the file doesn't contain any.
+    pub fn predictor(&self) -> Option<&Predictor> {
+        self.predictor.as_ref()
+    }
+
+    /// Returns the contexts that determine which entropy model decodes each
+    /// embedding vector component.
+    pub fn model_contexts(&self) -> &ModelContexts {
+        &self.model_contexts
+    }
+
+    /// Returns the groups of time steps that share their entropy models.
+    pub fn model_groups(&self) -> &ModelGroups {
+        &self.model_groups
+    }
+
+    /// Builds the lookup tables of all entropy models that haven't been used yet
+    /// (see [`EmbeddingFile::precompute_entropy_models`](../struct.EmbeddingFile.html#method.precompute_entropy_models)).
+    pub fn precompute_entropy_models(&self) {
+        self.decoder_models.precompute();
+    }
+
+    /// Provides access to the underlying source, e.g., to inspect the statistics of
+    /// an [`InMemoryRangeSource`].
+    pub fn source(&self) -> Ref<'_, S> {
+        self.pages.source.borrow()
+    }
+
+    pub fn into_source(self) -> S {
+        self.pages.source.into_inner()
+    }
+
+    /// Fetches (unless the file has a compact jump table) and validates the jump
+    /// pointer with index `jump_point` of time step `t`.
+    fn jump_pointer(&self, t: u32, jump_point: u32) -> Result<JumpPointer> {
+        let jump_pointer_size = self.layout.jump_pointer_size;
+        let start = self.layout.jump_table_range(t).start + jump_pointer_size * jump_point as usize;
+        let range = start..start + jump_pointer_size;
+        let words = match &self.compact_jump_table {
+            Some(jump_table) => jump_table[range].to_vec(),
+            None => range
+                .map(|index| self.pages.word(self.layout.jump_table_address + index))
+                .collect::<io::Result<Vec<_>>>()?,
+        };
+        let jump_pointer = JumpPointer::from_words(&words);
+        let precision = self.header.entropy_precision;
+        let compressed_len =
+            precision.words_per_u32() * (self.pages.file_len - self.layout.compressed_data_start);
+        if jump_pointer.is_valid(compressed_len, precision) {
+            Ok(jump_pointer)
+        } else {
+            Err(Error::InconsistentJumpTable)
+        }
+    }
+}
+
+impl<S: RangeSource, T: Symbol> TimestepSource for LazyEmbeddingFile<S, T> {
+    type Symbol = T;
+
+    type Timestep<'a>
+        = LazyTimestep<'a, S, T>
+    where
+        Self: 'a;
+
+    fn header(&self) -> &FileHeader {
+        self.header()
+    }
+
+    fn timestep(&self, t: u32) -> Result<Self::Timestep<'_>> {
+        self.timestep(t)
+    }
+
+    fn vocabulary(&self) -> Option<&Vocabulary> {
+        self.vocabulary()
+    }
+
+    fn timestep_labels(&self) -> Option<&TimestepLabels> {
+        self.timestep_labels()
+    }
+
+    fn scale_factors(&self) -> Option<&ScaleFactors> {
+        self.scale_factors()
+    }
+
+    fn segments(&self) -> &Segments {
+        self.segments()
+    }
+
+    fn predictor(&self) -> Option<&Predictor> {
+        self.predictor()
+    }
+}
+
+/// Decoder for a single time step of a [`LazyEmbeddingFile`].
+pub struct LazyTimestep<'a, S, T = i16> {
+    file: &'a LazyEmbeddingFile<S, T>,
+    decoder: TimestepDecoder<'a, PagedWords<'a, S, u16>, PagedWords<'a, S, u32>, T>,
+    t: u32,
+    word_index: u32,
+}
+
+impl<S: RangeSource, T: Symbol> TimestepReader for LazyTimestep<'_, S, T> {
+    type Symbol = T;
+
+    fn read_single_embedding_vector<I: Iterator>(
+        &mut self,
+        dest_iter: I,
+        callback: impl FnMut(T, I::Item),
+    ) -> Result<()> {
+        self.decoder
+            .decode_vector(
+                &self.file.model_contexts,
+                self.word_index,
+                dest_iter,
+                callback,
+            )
+            .map_err(backend_error)?;
+        self.word_index += 1;
+        Ok(())
+    }
+
+    fn jump_to(&mut self, word_index: u32) -> Result<()> {
+        let header = &self.file.header;
+        if word_index >= header.vocab_size {
+            return Err(Error::WordIndexOutOfRange {
+                word_index,
+                vocab_size: header.vocab_size,
+            });
+        }
+
+        let jump_point = word_index / header.jump_interval;
+        if word_index < self.word_index || jump_point != self.word_index / header.jump_interval {
+            let jump_pointer = self.file.jump_pointer(self.t, jump_point)?;
+            self.decoder
+                .seek(jump_pointer)
+                .map_err(|()| Error::InconsistentJumpTable)?;
+            self.word_index = jump_point * header.jump_interval;
+        }
+
+        self.decoder
+            .skip(
+                &self.file.model_contexts,
+                self.word_index,
+                word_index - self.word_index,
+            )
+            .map_err(backend_error)?;
+        self.word_index = word_index;
+
+        Ok(())
+    }
+}
+
+fn backend_error(err: CoderError<Infallible, io::Error>) -> Error {
+    match err {
+        CoderError::Frontend(infallible) => match infallible {},
+        CoderError::Backend(err) => err.into(),
+    }
+}
+
+/// Reads `len` words of four bytes each, starting at `address` (in units of four
+/// bytes).
+fn read_words(source: &mut impl RangeSource, address: usize, len: usize) -> io::Result<Vec<u32>> {
+    let mut bytes = vec![0; 4 * len];
+    source.read_range(4 * address as u64, &mut bytes)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+/// A bounded cache of pages of a file, which evicts the oldest page when it's full.
+struct PageCache<S> {
+    source: RefCell<S>,
+
+    /// Number of words of four bytes per page.
+    page_len: usize,
+
+    /// Length of the entire file in units of four bytes.
+    file_len: usize,
+
+    max_pages: usize,
+    pages: RefCell<Pages>,
+}
+
+#[derive(Default)]
+struct Pages {
+    by_index: HashMap<usize, Rc<[u32]>>,
+    insertion_order: VecDeque<usize>,
+}
+
+impl<S: RangeSource> PageCache<S> {
+    fn page(&self, index: usize) -> io::Result<Rc<[u32]>> {
+        if let Some(page) = self.pages.borrow().by_index.get(&index) {
+            return Ok(Rc::clone(page));
+        }
+
+        let start = index * self.page_len;
+        let end = usize::min(start + self.page_len, self.file_len);
+        let page: Rc<[u32]> =
+            read_words(&mut *self.source.borrow_mut(), start, end - start)?.into();
+
+        let mut pages = self.pages.borrow_mut();
+        if pages.insertion_order.len() >= self.max_pages {
+            let evicted = pages.insertion_order.pop_front().unwrap();
+            pages.by_index.remove(&evicted);
+        }
+        pages.insertion_order.push_back(index);
+        pages.by_index.insert(index, Rc::clone(&page));
+
+        Ok(page)
+    }
+
+    fn word(&self, address: usize) -> io::Result<u32> {
+        let page = self.page(address / self.page_len)?;
+        page.get(address % self.page_len)
+            .copied()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+}
+
+/// Backend for the ANS decoder that reads the compressed data from a `PageCache`.
+///
+/// The type parameter `Word` is the type of the compressed words (`u16` or `u32`,
+/// depending on the precision of the entropy models), and positions are measured
+/// in units of `Word`s. Seek positions are relative to the start of the compressed
+/// data (`start`), so they can be taken directly from the jump table.
+struct PagedWords<'a, S, Word> {
+    pages: &'a PageCache<S>,
+    start: usize,
+    end: usize,
+    pos: usize,
+
+    /// The most recently used page, which starts at position `page_start`.
+    page: Rc<[u32]>,
+    page_start: usize,
+
+    phantom: PhantomData<Word>,
+}
+
+/// A type of compressed words, several of which may be packed into each `u32` of
+/// the file.
+trait PackedWord {
+    const PER_U32: usize;
+
+    /// Extracts the word with index `index` (lower half first) from `word`.
+    fn unpack(word: u32, index: usize) -> Self;
+}
+
+impl PackedWord for u16 {
+    const PER_U32: usize = 2;
+
+    fn unpack(word: u32, index: usize) -> Self {
+        // Each `u32` holds two `u16`s in little endian byte order.
+        (word >> (16 * index)) as u16
+    }
+}
+
+impl PackedWord for u32 {
+    const PER_U32: usize = 1;
+
+    fn unpack(word: u32, _index: usize) -> Self {
+        word
+    }
+}
+
+impl<'a, S, Word: PackedWord> PagedWords<'a, S, Word> {
+    /// Returns a backend positioned at `pos` relative to the compressed data, which
+    /// starts at `compressed_data_start` (in units of four bytes), or `None` if
+    /// `pos` is out of bounds.
+    fn new(pages: &'a PageCache<S>, compressed_data_start: usize, pos: usize) -> Option<Self> {
+        let mut words = PagedWords {
+            pages,
+            start: Word::PER_U32 * compressed_data_start,
+            end: Word::PER_U32 * pages.file_len,
+            pos: 0,
+            page: Rc::new([]),
+            page_start: 0,
+            phantom: PhantomData,
+        };
+        constriction::Seek::seek(&mut words, pos).ok()?;
+        Some(words)
+    }
+}
+
+impl<S: RangeSource, Word: PackedWord> ReadWords<Word, Stack> for PagedWords<'_, S, Word> {
+    type ReadError = io::Error;
+
+    fn read(&mut self) -> io::Result<Option<Word>> {
+        if self.pos >= self.end {
+            return Ok(None);
+        }
+
+        let mut index = self.pos.wrapping_sub(self.page_start);
+        if index >= Word::PER_U32 * self.page.len() {
+            let page_index = self.pos / (Word::PER_U32 * self.pages.page_len);
+            self.page = self.pages.page(page_index)?;
+            self.page_start = Word::PER_U32 * self.pages.page_len * page_index;
+            index = self.pos - self.page_start;
+        }
+        self.pos += 1;
+
+        let word = self.page[index / Word::PER_U32];
+        Ok(Some(Word::unpack(word, index % Word::PER_U32)))
+    }
+}
+
+impl<S, Word> PosSeek for PagedWords<'_, S, Word> {
+    type Position = usize;
+}
+
+impl<S, Word> constriction::Seek for PagedWords<'_, S, Word> {
+    fn seek(&mut self, pos: usize) -> std::result::Result<(), ()> {
+        match self.start.checked_add(pos) {
+            Some(pos) if pos <= self.end => {
+                self.pos = pos;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::embedding_file::{
+        builder::write_compressed_dwe_file, timestep_labels::TimestepLabel, EmbeddingFile,
+    };
+    use crate::tensors::RankThreeTensor;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const NUM_TIMESTEPS: usize = 9;
+    const VOCAB_SIZE: usize = 300;
+    const EMBEDDING_DIM: usize = 8;
+
+    fn create_sample_file() -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(20_201_019);
+        let uncompressed = (0..NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-30..=30i16))
+            .collect();
+        let uncompressed =
+            RankThreeTensor::from_flattened(uncompressed, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+        let vocab = (0..VOCAB_SIZE)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<_>>();
+        let labels = TimestepLabels::from_integers((1990..1999).collect()).unwrap();
+
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file(
+            uncompressed.as_view(),
+            Some(&vocab),
+            Some(&labels),
+            10,
+            0.1,
+            &mut compressed,
+        )
+        .unwrap();
+        compressed
+    }
+
+    #[test]
+    fn lazy_queries_match_in_memory() {
+        let compressed = create_sample_file();
+        let in_memory = EmbeddingFile::from_reader(&compressed[..])
+            .unwrap()
+            .into_random_access_reader();
+
+        // Use tiny pages and a tiny cache so that pages get evicted all the time.
+        let lazy = LazyEmbeddingFile::with_page_size(std::io::Cursor::new(&compressed), 64, 3)
+            .unwrap()
+            .into_random_access_reader();
+
+        assert_eq!(lazy.file().header(), in_memory.file().header());
+        assert_eq!(lazy.file().vocabulary(), in_memory.file().vocabulary());
+        assert_eq!(
+            lazy.file().timestep_labels(),
+            in_memory.file().timestep_labels()
+        );
+
+        for t in 0..NUM_TIMESTEPS as u32 {
+            assert_eq!(
+                lazy.get_embeddings_at(t).unwrap().into_inner(),
+                in_memory.get_embeddings_at(t).unwrap().into_inner()
+            );
+            assert_eq!(
+                lazy.most_related_to_at_t(vec![3, 299], t, 5)
+                    .unwrap()
+                    .into_inner(),
+                in_memory
+                    .most_related_to_at_t(vec![3, 299], t, 5)
+                    .unwrap()
+                    .into_inner()
+            );
+        }
+        let label = TimestepLabel::Integer(1995);
+        assert_eq!(
+            lazy.get_embeddings_at(&label).unwrap().into_inner(),
+            in_memory.get_embeddings_at(5).unwrap().into_inner()
+        );
+
+        let words1 = vec![0, 17, 150, 299];
+        let words2 = vec![299, 18, 42, 0];
+        assert_eq!(
+            lazy.pairwise_trajectories(words1.clone(), words2.clone())
+                .unwrap()
+                .into_inner(),
+            in_memory
+                .pairwise_trajectories(words1, words2)
+                .unwrap()
+                .into_inner()
+        );
+        assert_eq!(
+            lazy.largest_changes_wrt(42, 6, 2, 2).unwrap(),
+            in_memory.largest_changes_wrt(42, 6, 2, 2).unwrap()
+        );
+
+        assert!(matches!(
+            lazy.get_embeddings_at(NUM_TIMESTEPS as u32),
+            Err(Error::TimestepOutOfRange { .. })
+        ));
+        assert!(matches!(
+            lazy.largest_changes_wrt(VOCAB_SIZE as u32, 6, 2, 2),
+            Err(Error::WordIndexOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn fetches_only_what_queries_touch() {
+        let compressed = create_sample_file();
+        let file =
+            LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&compressed), 256, 16)
+                .unwrap();
+        let initially_fetched = file.source().num_fetched_bytes();
+        assert!(initially_fetched < compressed.len() as u64 / 4);
+
+        let reader = file.into_random_access_reader();
+        reader.pairwise_trajectories(vec![5], vec![250]).unwrap();
+        let fetched = reader.file().source().num_fetched_bytes();
+        assert!(fetched > initially_fetched);
+        assert!(fetched < compressed.len() as u64 / 2);
+
+        // Truncated files get rejected, and reading pages past the end fails.
+        let truncated = InMemoryRangeSource::new(&compressed[..compressed.len() - 4]);
+        assert!(matches!(
+            LazyEmbeddingFile::new(truncated),
+            Err(Error::FileSizeMismatch { .. })
+        ));
+    }
+}
@@ -1,168 +1,641 @@
 use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::OnceLock;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use constriction::{stream::Decode, Seek, UnwrapInfallible};
+use std::convert::{Infallible, TryFrom, TryInto};
+
+use constriction::{
+    backends::ReadWords,
+    stream::{
+        model::{
+            DefaultNonContiguousCategoricalDecoderModel, NonContiguousCategoricalDecoderModel,
+            SmallNonContiguousLookupDecoderModel,
+        },
+        stack::AnsCoder,
+        Decode,
+    },
+    CoderError, PosSeek, Seek, Stack, UnwrapInfallible,
+};
 
 use super::random_access_reader::RandomAccessReader;
+use crate::error::{Error, Result};
 use crate::u12::unpack_u12s;
+use compact_jump_table::COMPACT_JUMP_TABLE_SECTION_TAG;
+use file_bytes::FileBytes;
+use model_contexts::{ModelContexts, MODEL_CONTEXTS_SECTION_TAG};
+use model_groups::{ModelGroups, MODEL_GROUPS_SECTION_TAG};
+use predictor::{Predictor, PREDICTOR_SECTION_TAG};
+use scale_factors::{ScaleFactors, SCALE_FACTORS_SECTION_TAG};
+use segments::{Segments, SEGMENTS_SECTION_TAG};
+use symbol::{deserialize_symbols, Symbol, SymbolType};
+use timestep_labels::{TimestepLabel, TimestepLabels, TimestepRef, TIMESTEP_LABELS_SECTION_TAG};
+use vocabulary::{Vocabulary, VOCABULARY_SECTION_TAG};
 
 pub mod builder;
+pub mod compact_jump_table;
+pub mod file_bytes;
+pub mod lazy;
+pub mod model_contexts;
+pub mod model_groups;
+mod portable;
+pub mod predictor;
+pub mod quantization;
+pub mod scale_factors;
+pub mod segments;
+pub mod symbol;
+pub mod timestep_labels;
+pub mod vocabulary;
 
+#[cfg(target_endian = "little")]
 type Cursor<'data> = constriction::backends::Cursor<u16, &'data [u16]>;
-type ReversedCursor<'data> = constriction::backends::Reverse<Cursor<'data>>;
-type DecoderModel = constriction::stream::model::SmallNonContiguousLookupDecoderModel<i16>;
-type DecoderModelView<'a> = constriction::stream::model::SmallNonContiguousLookupDecoderModel<
-    i16,
-    &'a [(u16, i16)],
-    &'a [u16],
->;
-type Decoder<'data> = constriction::stream::stack::SmallAnsCoder<ReversedCursor<'data>>;
-
-pub const HEADER_SIZE: u32 = (std::mem::size_of::<FileHeader>() / 4) as u32;
-
-pub struct EmbeddingFile {
-    raw_data: Box<[u32]>,
-    decoder_models: Box<[DecoderModel]>,
-    jump_points_per_timestep: usize,
-    compressed_data_start: usize,
+#[cfg(target_endian = "little")]
+type CompressedWords<'data> = constriction::backends::Reverse<Cursor<'data>>;
+#[cfg(not(target_endian = "little"))]
+type CompressedWords<'data> = portable::SplitWords<'data>;
+type CompressedWords32<'data> =
+    constriction::backends::Reverse<constriction::backends::Cursor<u32, &'data [u32]>>;
+
+type DecoderModel12<T> = SmallNonContiguousLookupDecoderModel<T>;
+type DecoderModel16<T> = NonContiguousCategoricalDecoderModel<T, u16, Vec<(u16, T)>, 16>;
+type DecoderModel24<T> = DefaultNonContiguousCategoricalDecoderModel<T>;
+
+/// Size of the file header in version 1 of the file format, in units of 4 bytes.
+pub const HEADER_SIZE: u32 = 10;
+
+/// Size of the file header in version 2 of the file format, in units of 4 bytes.
+pub const HEADER_SIZE_V2: u32 = 13;
+
+/// The `magic` field of the file header, i.e., `"\0dwe"` in little endian byte order.
+pub const MAGIC: u32 = 0x6577_6400;
+
+/// A parsed compressed dynamic word embeddings file.
+///
+/// The type parameter `D` is the storage that holds the raw file contents. It
+/// defaults to an owned buffer (as created by [`from_reader`](#method.from_reader))
+/// but it can also be a borrowed `&[u32]` or a [`FileBytes`] wrapper around a
+/// byte buffer or a memory map (see [`from_bytes`](#method.from_bytes)). Parsing
+/// the file only reads the header, the entropy models, the jump table, and the
+/// optional sections, so constructing an `EmbeddingFile` from a memory map doesn't
+/// touch the compressed data.
+///
+/// Parsing also validates all entropy models, but it builds the lookup tables that
+/// the decoder needs for the entropy models of a time step only once the time step
+/// gets decoded for the first time, so that loading a file with many time steps
+/// stays fast. Call [`precompute_entropy_models`](#method.precompute_entropy_models)
+/// to build all lookup tables up front, e.g., in a long running server.
+///
+/// The type parameter `T` is the [`Symbol`] type of the quantized embedding vector
+/// components, which has to match the `symbol_type` in the file header. It defaults
+/// to `i16`, which is what most files use. Files with other symbol types can be
+/// parsed with [`new_typed`](#method.new_typed) and friends.
+pub struct EmbeddingFile<D = Box<[u32]>, T = i16> {
+    raw_data: D,
+    header: FileHeader,
+    decoder_models: DecoderModels<T>,
+    layout: Layout,
+    vocabulary: Option<Vocabulary>,
+    timestep_labels: Option<TimestepLabels>,
+    scale_factors: Option<ScaleFactors>,
+    segments: Segments,
+    predictor: Option<Predictor>,
+    model_contexts: ModelContexts,
+    model_groups: ModelGroups,
+    /// The decoded jump table if the file stores it in a compact jump table section.
+    compact_jump_table: Option<Box<[u32]>>,
 }
 
-#[derive(Debug, PartialEq)]
-#[repr(C)]
+/// The parsed file header.
+///
+/// Version 2 of the file format stores `file_size` and `jump_table_address` as
+/// `u64`s and adds the fields `entropy_precision` and `symbol_type`, which are
+/// always 12 bits and `i16`, respectively, in version 1 (see
+/// [`from_words`](#method.from_words)).
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileHeader {
     pub magic: u32,
     pub major_version: u32,
     pub minor_version: u32,
-    pub file_size: u32,
-    pub jump_table_address: u32,
+    pub file_size: u64,
+    pub jump_table_address: u64,
     pub num_timesteps: u32,
     pub vocab_size: u32,
     pub embedding_dim: u32,
     pub jump_interval: u32,
     pub scale_factor: f32,
+    pub entropy_precision: EntropyPrecision,
+    pub symbol_type: SymbolType,
 }
 
 impl FileHeader {
-    /// # Safety
+    /// Parses the header at the beginning of a file, whose layout depends on the
+    /// `major_version` field.
     ///
-    /// `data.len()` must be at least `HEADER_SIZE`
-    #[inline(always)]
-    pub unsafe fn memory_map_unsafe(data: &[u32]) -> &Self {
-        #[allow(unused_unsafe)] // See Rust RFC #2585.
-        unsafe {
-            // SAFETY: safe according to contract of this method
-            let header_slice = data.get_unchecked(0..HEADER_SIZE as usize);
+    /// Returns `Error::Truncated` if `data` is too short to hold the header.
+    /// `data` may be longer than the header, in which case the rest is ignored.
+    /// Doesn't check whether the header is consistent with the rest of the file.
+    pub fn from_words(data: &[u32]) -> Result<Self> {
+        let (magic, major_version, minor_version) = match *data {
+            [magic, major_version, minor_version, ..] => (magic, major_version, minor_version),
+            _ => return Err(Error::Truncated),
+        };
+        if magic == MAGIC.swap_bytes() {
+            return Err(Error::UnsupportedByteOrder);
+        }
+        if magic != MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
 
-            // SAFETY: `FileHeader` is `repr(C)` and has the same size and alignment as
-            // `[u32; HEADER_SIZE]`
-            let ptr = header_slice.as_ptr();
-            &*(ptr as *const FileHeader)
+        let header_size = Layout::header_size(major_version, minor_version)?;
+        let data = data.get(..header_size).ok_or(Error::Truncated)?;
+        let (file_size, jump_table_address, rest) = match major_version {
+            1 => (data[3] as u64, data[4] as u64, &data[5..]),
+            _ => (
+                join_u64(data[3], data[4]),
+                join_u64(data[5], data[6]),
+                &data[7..],
+            ),
+        };
+
+        // Version 2 stores the precision in the lower half of the last word and the
+        // number of bits of the symbol type in the upper half (where zero means 16).
+        let (entropy_precision, symbol_type) = match rest.get(5) {
+            None => (EntropyPrecision::Bits12, SymbolType::I16),
+            Some(&word) => (
+                EntropyPrecision::from_bits(word & 0xffff)
+                    .ok_or(Error::InvalidHeader("unsupported entropy_precision"))?,
+                match word >> 16 {
+                    0 => SymbolType::I16,
+                    bits => SymbolType::from_bits(bits)
+                        .ok_or(Error::InvalidHeader("unsupported symbol_type"))?,
+                },
+            ),
+        };
+
+        Ok(FileHeader {
+            magic,
+            major_version,
+            minor_version,
+            file_size,
+            jump_table_address,
+            num_timesteps: rest[0],
+            vocab_size: rest[1],
+            embedding_dim: rest[2],
+            jump_interval: rest[3],
+            scale_factor: f32::from_bits(rest[4]),
+            entropy_precision,
+            symbol_type,
+        })
+    }
+
+    /// Decodes and parses the header at the beginning of a file.
+    ///
+    /// Unlike `from_words`, this expects the raw file contents (in little endian
+    /// byte order), and it works for unaligned buffers.
+    pub fn from_le_bytes(bytes: &[u8]) -> Result<Self> {
+        let words = bytes
+            .chunks_exact(4)
+            .take(HEADER_SIZE_V2 as usize)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().expect("chunk of four")))
+            .collect::<Vec<_>>();
+        Self::from_words(&words)
+    }
+
+    /// Serializes the header in the layout of its `major_version`.
+    ///
+    /// Returns `Error::TooLarge` if `file_size` or `jump_table_address` don't fit
+    /// into version 1 of the file format, and `Error::InvalidHeader` if version 1
+    /// is requested with an `entropy_precision` other than 12 bits or a
+    /// `symbol_type` other than `i16`.
+    fn to_words(&self) -> Result<Vec<u32>> {
+        let mut words = vec![self.magic, self.major_version, self.minor_version];
+        if self.major_version == 1 {
+            if self.entropy_precision != EntropyPrecision::Bits12 {
+                return Err(Error::InvalidHeader(
+                    "version 1 only supports 12 bit entropy models",
+                ));
+            }
+            if self.symbol_type != SymbolType::I16 {
+                return Err(Error::InvalidHeader("version 1 only supports i16 symbols"));
+            }
+            for field in [self.file_size, self.jump_table_address] {
+                words.push(field.try_into().map_err(|_| Error::TooLarge)?);
+            }
+        } else {
+            words.extend_from_slice(&split_u64(self.file_size));
+            words.extend_from_slice(&split_u64(self.jump_table_address));
+        }
+        words.extend_from_slice(&[
+            self.num_timesteps,
+            self.vocab_size,
+            self.embedding_dim,
+            self.jump_interval,
+            self.scale_factor.to_bits(),
+        ]);
+        if self.major_version != 1 {
+            // Files with `i16` symbols store zero so that they remain readable by
+            // readers that don't know about symbol types.
+            let symbol_bits = match self.symbol_type {
+                SymbolType::I16 => 0,
+                symbol_type => symbol_type.bits(),
+            };
+            words.push(self.entropy_precision.bits() | symbol_bits << 16);
+        }
+        Ok(words)
+    }
+
+    /// Checks that the header is valid for a file of length `file_len` (in units of
+    /// four bytes), and returns the layout of the file.
+    fn validate(&self, file_len: usize) -> Result<Layout> {
+        let header_size = Layout::header_size(self.major_version, self.minor_version)?;
+        if self.file_size != file_len as u64 {
+            return Err(Error::FileSizeMismatch {
+                declared: self.file_size,
+                actual: file_len,
+            });
+        }
+        if self.jump_table_address <= header_size as u64 || self.jump_table_address > self.file_size
+        {
+            return Err(Error::InvalidHeader("jump_table_address out of bounds"));
+        }
+        if self.num_timesteps == 0 {
+            return Err(Error::InvalidHeader("num_timesteps must be nonzero"));
+        }
+        if self.vocab_size == 0 || self.embedding_dim == 0 {
+            return Err(Error::InvalidHeader(
+                "vocab_size and embedding_dim must be nonzero",
+            ));
+        }
+        if self.vocab_size.checked_mul(self.embedding_dim).is_none() {
+            // Readers index into time steps with `u32`s.
+            return Err(Error::InvalidHeader(
+                "vocab_size * embedding_dim must fit into a u32",
+            ));
+        }
+        if self.jump_interval == 0 {
+            return Err(Error::InvalidHeader("jump_interval must be nonzero"));
+        }
+
+        Layout::new(self, header_size)
+    }
+
+    /// Returns `Error::SymbolTypeMismatch` unless the file has symbols of type `T`.
+    fn check_symbol_type<T: Symbol>(&self) -> Result<()> {
+        if self.symbol_type == T::SYMBOL_TYPE {
+            Ok(())
+        } else {
+            Err(Error::SymbolTypeMismatch {
+                expected: T::SYMBOL_TYPE,
+                found: self.symbol_type,
+            })
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Default)]
-#[repr(C)]
-struct JumpPointer {
-    offset: u32,
-    state: u32,
+/// Precision of the fixed point probabilities in the entropy models.
+///
+/// Higher precisions approximate peaked distributions and rare symbols more
+/// closely and allow for larger alphabets, but they make the entropy models
+/// section larger and decoding slightly slower. Version 1 of the file format
+/// supports only 12 bit precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EntropyPrecision {
+    /// 12 bit probabilities, which get decoded with a lookup table. This limits the
+    /// alphabet to 4096 symbols per time step.
+    #[default]
+    Bits12,
+
+    /// 16 bit probabilities.
+    Bits16,
+
+    /// 24 bit probabilities. The compressed data then consists of 32 bit words.
+    Bits24,
 }
 
-pub struct Timestep<'data, 'model> {
-    decoder: Decoder<'data>,
-    model: DecoderModelView<'model>,
-    jump_table: &'data [JumpPointer],
-    word_index: u32,
-    embedding_dim: u32,
-    jump_interval: u32,
+impl EntropyPrecision {
+    /// Returns the number of bits of each fixed point probability.
+    pub fn bits(self) -> u32 {
+        match self {
+            EntropyPrecision::Bits12 => 12,
+            EntropyPrecision::Bits16 => 16,
+            EntropyPrecision::Bits24 => 24,
+        }
+    }
+
+    /// Returns `None` unless `bits` is 12, 16, or 24.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            12 => Some(EntropyPrecision::Bits12),
+            16 => Some(EntropyPrecision::Bits16),
+            24 => Some(EntropyPrecision::Bits24),
+            _ => None,
+        }
+    }
+
+    /// Size of the words of compressed data, in bits.
+    fn word_bits(self) -> u32 {
+        match self {
+            EntropyPrecision::Bits24 => 32,
+            _ => 16,
+        }
+    }
+
+    /// Number of words of compressed data that are packed into each `u32`.
+    fn words_per_u32(self) -> usize {
+        (32 / self.word_bits()) as usize
+    }
 }
 
-impl EmbeddingFile {
-    pub fn new(data: Box<[u32]>) -> Result<Self, ()> {
-        if data.len() < HEADER_SIZE as usize {
-            return Err(());
+/// Returns the minor version that files with major version `major_version` need if
+/// they contain sections that change decoding (segments, predictor, model contexts,
+/// or model groups) and, if `compact_jump_table` is set, a compact jump table.
+fn decoding_minor_version(major_version: u32, compact_jump_table: bool) -> u32 {
+    match (major_version, compact_jump_table) {
+        (1, false) => 2,
+        (1, true) => 3,
+        (_, false) => 1,
+        (_, true) => 2,
+    }
+}
+
+/// Returns whether files of the given version store their jump table in a compact
+/// jump table section, i.e., whether they follow version 1.3 or 2.2.
+fn has_compact_jump_table(major_version: u32, minor_version: u32) -> bool {
+    matches!((major_version, minor_version), (1, 3) | (2, 2))
+}
+
+/// Positions and sizes of the parts of a file, in units of four bytes, as far as
+/// they can be inferred from the header alone.
+#[derive(Debug, Clone)]
+struct Layout {
+    header_size: usize,
+    jump_table_address: usize,
+    jump_pointer_size: usize,
+    jump_points_per_timestep: usize,
+    compressed_data_start: usize,
+    /// Whether the jump table is stored in a compact jump table section rather
+    /// than right before the compressed data (since versions 1.3 and 2.2).
+    compact_jump_table: bool,
+    /// Whether the file ends in a section table (since version 1.1).
+    has_section_table: bool,
+    section_table_entry_size: usize,
+}
+
+impl Layout {
+    /// Returns `Error::UnsupportedVersion` also for minor versions that are newer than
+    /// this reader since they may change how the file has to be decoded.
+    fn header_size(major_version: u32, minor_version: u32) -> Result<usize> {
+        match (major_version, minor_version) {
+            (1, 0..=3) => Ok(HEADER_SIZE as usize),
+            (2, 0..=2) => Ok(HEADER_SIZE_V2 as usize),
+            _ => Err(Error::UnsupportedVersion {
+                major: major_version,
+                minor: minor_version,
+            }),
         }
+    }
 
-        let header = unsafe {
-            // SAFETY: We checked above that data.len() >= HEADER_SIZE
-            FileHeader::memory_map_unsafe(&data)
-        };
+    /// Returns the size of a jump pointer in units of four bytes.
+    fn jump_pointer_size(header: &FileHeader) -> usize {
+        let offset_size = if header.major_version >= 2 { 2 } else { 1 };
+        // The ANS coder's state has twice as many bits as a compressed word.
+        let state_size = (header.entropy_precision.word_bits() / 16) as usize;
+        offset_size + state_size
+    }
 
-        let embeddings_size = header.vocab_size * header.embedding_dim;
+    /// Returns an error if the jump table doesn't fit into the file. Assumes that
+    /// the rest of the header has already been validated.
+    fn new(header: &FileHeader, header_size: usize) -> Result<Self> {
+        let wide = header.major_version >= 2;
+        let jump_pointer_size = Self::jump_pointer_size(header);
+        let compact_jump_table = has_compact_jump_table(header.major_version, header.minor_version);
 
-        if header.magic != 0x6577_6400
-            || header.major_version != 1
-            || header.file_size as usize != data.len()
-            || header.jump_table_address <= HEADER_SIZE
-            || header.jump_table_address > header.file_size
-            || header.num_timesteps < 2
-            || embeddings_size == 0
-            || header.jump_interval == 0
-        {
-            return Err(());
+        // Calculate in `u128` so that this can't overflow.
+        let jump_points_per_timestep = header.vocab_size.div_ceil(header.jump_interval);
+        let jump_table_size = if compact_jump_table {
+            0
+        } else {
+            jump_pointer_size as u128
+                * header.num_timesteps as u128
+                * jump_points_per_timestep as u128
+        };
+        let compressed_data_start = header.jump_table_address as u128 + jump_table_size;
+        if compressed_data_start > header.file_size as u128 {
+            return Err(Error::Truncated);
         }
 
-        let entropy_models_section =
-            get_u16_slice(&data[HEADER_SIZE as usize..header.jump_table_address as usize]);
+        Ok(Layout {
+            header_size,
+            // Both fit into a `usize` because they're bounded by the file size.
+            jump_table_address: header.jump_table_address as usize,
+            jump_pointer_size,
+            jump_points_per_timestep: jump_points_per_timestep as usize,
+            compressed_data_start: compressed_data_start as usize,
+            compact_jump_table,
+            has_section_table: wide || header.minor_version >= 1,
+            section_table_entry_size: if wide { 5 } else { 3 },
+        })
+    }
+
+    /// Returns the range of the jump table for time step `t`, relative to the start
+    /// of the (uncompressed) jump table.
+    fn jump_table_range(&self, t: u32) -> Range<usize> {
+        let len = self.jump_pointer_size * self.jump_points_per_timestep;
+        let start = len * t as usize;
+        start..start + len
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct JumpPointer {
+    /// Position in the compressed data, in units of compressed words.
+    offset: u64,
+    state: u64,
+}
+
+/// The jump table for a single time step.
+#[derive(Debug, Clone, Copy)]
+struct JumpTable<'data> {
+    data: &'data [u32],
+    jump_pointer_size: usize,
+}
+
+impl JumpTable<'_> {
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.data.len() / self.jump_pointer_size
+    }
+
+    /// Panics if `jump_point` is out of bounds.
+    fn get(&self, jump_point: usize) -> JumpPointer {
+        let start = jump_point * self.jump_pointer_size;
+        JumpPointer::from_words(&self.data[start..start + self.jump_pointer_size])
+    }
+}
 
-        let mut remainder = entropy_models_section;
-        let mut decoder_models = Vec::with_capacity(header.num_timesteps as usize);
-        for _ in 0..header.num_timesteps {
-            let (model, r) = deserialize_decoder_model(remainder)?;
-            remainder = r;
-            decoder_models.push(model);
+impl JumpPointer {
+    /// Parses a jump pointer from two words (version 1 of the file format), from
+    /// three words (version 2 of the file format, where `offset` is a `u64`), or
+    /// from four words (version 2 with 24 bit entropy models, where `state` is a
+    /// `u64` as well).
+    fn from_words(words: &[u32]) -> Self {
+        match *words {
+            [offset, state] => JumpPointer {
+                offset: offset as u64,
+                state: state as u64,
+            },
+            [offset_low, offset_high, state] => JumpPointer {
+                offset: join_u64(offset_low, offset_high),
+                state: state as u64,
+            },
+            [offset_low, offset_high, state_low, state_high] => JumpPointer {
+                offset: join_u64(offset_low, offset_high),
+                state: join_u64(state_low, state_high),
+            },
+            _ => panic!("jump pointers have two to four words"),
         }
-        if remainder.len() > 1 {
-            // At most one padding entry allowed.
-            Err(())?
+    }
+
+    /// Inverse of [`from_words`](#method.from_words), which writes the jump pointer
+    /// into `words`. Returns `None` if `offset` or `state` don't fit.
+    fn to_words(self, words: &mut [u32]) -> Option<()> {
+        match words {
+            [offset, state] => {
+                *offset = self.offset.try_into().ok()?;
+                *state = self.state.try_into().ok()?;
+            }
+            [offset_low, offset_high, state] => {
+                [*offset_low, *offset_high] = split_u64(self.offset);
+                *state = self.state.try_into().ok()?;
+            }
+            [offset_low, offset_high, state_low, state_high] => {
+                [*offset_low, *offset_high] = split_u64(self.offset);
+                [*state_low, *state_high] = split_u64(self.state);
+            }
+            _ => panic!("jump pointers have two to four words"),
         }
+        Some(())
+    }
 
-        let jump_points_per_timestep = header.vocab_size.div_ceil(header.jump_interval) as usize;
-        let compressed_data_start = header.jump_table_address as usize
-            + 2 * header.num_timesteps as usize * jump_points_per_timestep;
+    /// Every jump pointer has to point into the compressed data (of length
+    /// `compressed_len` in units of compressed words), and its `state` has to
+    /// satisfy the invariant `state >= 1 << word_bits` of the ANS coder (the
+    /// encoder starts with `state == 1 << word_bits` and never goes below).
+    fn is_valid(&self, compressed_len: usize, precision: EntropyPrecision) -> bool {
+        self.offset <= compressed_len as u64 && self.state >> precision.word_bits() != 0
+    }
+}
 
-        Ok(EmbeddingFile {
-            raw_data: data,
-            decoder_models: decoder_models.into(),
-            jump_points_per_timestep,
-            compressed_data_start,
-        })
+/// Decoder for a single time step.
+///
+/// The type parameter `W` is the backend from which the decoder reads compressed
+/// data that consists of `u16` words (i.e., for entropy models with a precision of
+/// 12 or 16 bits). The default reinterprets the compressed data in place on
+/// little endian platforms and splits it into `u16`s on the fly on other
+/// platforms. The type parameter `T` is the [`Symbol`] type of the file.
+pub struct Timestep<'data, 'model, W = CompressedWords<'data>, T = i16> {
+    decoder: TimestepDecoder<'model, W, CompressedWords32<'data>, T>,
+    model_contexts: &'model ModelContexts,
+    jump_table: JumpTable<'data>,
+    word_index: u32,
+    vocab_size: u32,
+    jump_interval: u32,
+}
+
+impl<D: AsRef<[u32]>> EmbeddingFile<D> {
+    /// Parses a file that follows version 1 or version 2 of the file format.
+    ///
+    /// The two major versions differ only in the sizes of fields that hold
+    /// addresses, which are 32 bits wide in version 1 and 64 bits wide in version
+    /// 2. This method reads the `major_version` field of the header and parses
+    /// the header, the jump table, and the section table accordingly.
+    ///
+    /// Returns `Error::SymbolTypeMismatch` if the file doesn't have `i16` symbols,
+    /// see [`new_typed`](#method.new_typed).
+    pub fn new(raw_data: D) -> Result<Self> {
+        Self::new_typed(raw_data)
     }
+}
 
-    pub fn from_reader(mut reader: impl Read) -> Result<EmbeddingFile, ()> {
-        let mut buf = vec![0; HEADER_SIZE as usize];
-        reader
-            .read_u32_into::<LittleEndian>(&mut buf[..])
-            .map_err(|_| ())?;
+impl<D: AsRef<[u32]>, T: Symbol> EmbeddingFile<D, T> {
+    /// Same as [`new`](#method.new) but for files whose `symbol_type` is `T`, e.g.,
+    /// `EmbeddingFile::<_, i8>::new_typed(data)`.
+    ///
+    /// Returns `Error::SymbolTypeMismatch` if the file has a different symbol type.
+    pub fn new_typed(raw_data: D) -> Result<Self> {
+        let data = raw_data.as_ref();
+        let header = FileHeader::from_words(data)?;
+        let layout = header.validate(data.len())?;
+        header.check_symbol_type::<T>()?;
 
-        let header = unsafe {
-            // SAFETY: We made sure that buf.len() == HEADER_SIZE
-            FileHeader::memory_map_unsafe(&buf)
-        };
-        let file_size = header.file_size;
+        let mut sections = OptionalSections::default();
+        if layout.has_section_table {
+            let (&num_sections, _) = data.split_last().ok_or(Error::InvalidSectionTable)?;
+            let table_start = section_table_start(num_sections, data.len(), &layout)?;
+            let table = &data[table_start..data.len() - 1];
+            for (tag, range) in parse_section_table(table, &layout, table_start)? {
+                sections.insert(tag, &data[range], &header)?;
+            }
+        }
+
+        // The model contexts and groups determine how many entropy models there are.
+        let model_contexts = sections
+            .model_contexts
+            .unwrap_or_else(|| ModelContexts::single(header.embedding_dim));
+        let model_groups = sections
+            .model_groups
+            .unwrap_or_else(|| ModelGroups::per_timestep(header.num_timesteps));
+        let decoder_models = deserialize_decoder_models(
+            &header,
+            &model_groups,
+            model_contexts.len(),
+            &portable::u16_words(&data[layout.header_size..layout.jump_table_address]),
+        )?;
 
-        buf.reserve_exact((file_size - HEADER_SIZE) as usize);
-        for _ in HEADER_SIZE..file_size {
-            buf.push(reader.read_u32::<LittleEndian>().map_err(|_| ())?);
+        let compact_jump_table = compact_jump_table(&layout, sections.compact_jump_table)?;
+        let precision = header.entropy_precision;
+        let compressed_len =
+            precision.words_per_u32() * (data.len() - layout.compressed_data_start);
+        let jump_table = compact_jump_table
+            .as_deref()
+            .unwrap_or(&data[layout.jump_table_address..layout.compressed_data_start]);
+        if jump_table
+            .chunks_exact(layout.jump_pointer_size)
+            .any(|words| !JumpPointer::from_words(words).is_valid(compressed_len, precision))
+        {
+            return Err(Error::InconsistentJumpTable);
         }
 
-        Self::new(buf.into())
+        let segments = sections
+            .segments
+            .unwrap_or_else(|| Segments::single(header.num_timesteps));
+        Ok(EmbeddingFile {
+            raw_data,
+            header,
+            decoder_models,
+            layout,
+            vocabulary: sections.vocabulary,
+            timestep_labels: sections.timestep_labels,
+            scale_factors: sections.scale_factors,
+            segments,
+            predictor: sections.predictor,
+            model_contexts,
+            model_groups,
+            compact_jump_table,
+        })
     }
 
-    pub fn into_random_access_reader(self) -> RandomAccessReader {
+    pub fn into_random_access_reader(self) -> RandomAccessReader<Self> {
         RandomAccessReader::new(self)
     }
 
-    pub fn into_inner(self) -> Box<[u32]> {
+    pub fn into_inner(self) -> D {
         self.raw_data
     }
 
     #[inline(always)]
     pub fn header(&self) -> &FileHeader {
-        unsafe {
-            // SAFETY: the ensures checks that `self.raw_data` is big enough.
-            FileHeader::memory_map_unsafe(&self.raw_data)
-        }
+        &self.header
     }
 
     /// Writes the compressed data to a writer and flushes it.
@@ -176,141 +649,953 @@ impl EmbeddingFile {
         }
         writer.flush()
     }
+}
+
+impl EmbeddingFile {
+    pub fn from_reader(reader: impl Read) -> Result<Self> {
+        Self::from_reader_typed(reader)
+    }
+}
+
+impl<T: Symbol> EmbeddingFile<Box<[u32]>, T> {
+    /// Same as [`from_reader`](#method.from_reader) but for files whose
+    /// `symbol_type` is `T`, see [`new_typed`](#method.new_typed).
+    pub fn from_reader_typed(mut reader: impl Read) -> Result<Self> {
+        // Read the beginning of the header to find out how large the full header is.
+        let mut buf = vec![0; 3];
+        reader.read_u32_into::<LittleEndian>(&mut buf[..])?;
+        if buf[0] == MAGIC {
+            let header_size = Layout::header_size(buf[1], buf[2])?;
+            buf.resize(header_size, 0);
+            reader.read_u32_into::<LittleEndian>(&mut buf[3..])?;
+        }
+
+        // This checks the magic number before we allocate memory based on `file_size`.
+        let header = FileHeader::from_words(&buf)?;
+        let file_size = usize::try_from(header.file_size).map_err(|_| Error::TooLarge)?;
+        if file_size < buf.len() {
+            return Err(Error::InvalidHeader("file_size smaller than the header"));
+        }
+
+        // Don't trust `file_size` blindly for the initial allocation since the file may
+        // be truncated. The buffer grows as needed while we read the file.
+        const MAX_INITIAL_CAPACITY: usize = 1 << 24;
+        buf.reserve_exact(usize::min(file_size - buf.len(), MAX_INITIAL_CAPACITY));
+        for _ in buf.len()..file_size {
+            buf.push(reader.read_u32::<LittleEndian>()?);
+        }
+
+        Self::new_typed(buf.into())
+    }
+}
+
+impl<B: AsRef<[u8]>> EmbeddingFile<FileBytes<B>> {
+    /// Parses a file from a byte buffer, without copying it if possible.
+    ///
+    /// The buffer can be anything that dereferences to a byte slice, e.g., a
+    /// `&[u8]`, a `Vec<u8>`, or a memory map. On little endian platforms, the file
+    /// is used in place if the buffer is aligned to a four byte boundary (memory
+    /// maps are always suitably aligned). Otherwise, the file is decoded into a
+    /// copy, see [`FileBytes`].
+    pub fn from_bytes(bytes: B) -> Result<Self> {
+        Self::from_bytes_typed(bytes)
+    }
+}
+
+impl<B: AsRef<[u8]>, T: Symbol> EmbeddingFile<FileBytes<B>, T> {
+    /// Same as [`from_bytes`](#method.from_bytes) but for files whose `symbol_type`
+    /// is `T`, see [`new_typed`](#method.new_typed).
+    pub fn from_bytes_typed(bytes: B) -> Result<Self> {
+        Self::new_typed(FileBytes::new(bytes)?)
+    }
+}
 
-    pub fn timestep(&self, t: u32) -> Result<Timestep<'_, '_>, ()> {
+impl<D: AsRef<[u32]>, T: Symbol> EmbeddingFile<D, T> {
+    pub fn timestep(&self, t: u32) -> Result<Timestep<'_, '_, CompressedWords<'_>, T>> {
         let header = self.header();
-        if t as usize >= self.decoder_models.len() {
-            Err(())
+        if t >= header.num_timesteps {
+            Err(Error::TimestepOutOfRange {
+                timestep: t,
+                num_timesteps: header.num_timesteps,
+            })
         } else {
-            let jump_table_start =
-                header.jump_table_address as usize + 2 * self.jump_points_per_timestep * t as usize;
-
-            let jump_table = unsafe {
-                // SAFETY: Transmuting from `&[u32]` of even length to `&[JumpPointer]` is safe,
-                // because `JumpPointer` is `repr(C)` and contains exactly two `u32`s.
-                // See also https://internals.rust-lang.org/t/pre-rfc-v2-safe-transmute/11431
-                let jump_table_data = &self.raw_data
-                    [jump_table_start..jump_table_start + 2 * self.jump_points_per_timestep];
-                let ptr = jump_table_data.as_ptr();
-                std::slice::from_raw_parts(ptr as *const JumpPointer, self.jump_points_per_timestep)
+            let jump_table = JumpTable {
+                data: &self.jump_table()[self.layout.jump_table_range(t)],
+                jump_pointer_size: self.layout.jump_pointer_size,
             };
 
-            let compressed = get_u16_slice(&self.raw_data[self.compressed_data_start..]);
+            let compressed = &self.raw_data.as_ref()[self.layout.compressed_data_start..];
+            let decoder = TimestepDecoder::new(
+                &self.decoder_models,
+                self.model_contexts.len(),
+                self.model_groups.group_of(t) as usize,
+                jump_table.get(0),
+                |pos| compressed_words(compressed, pos),
+                |pos| compressed_words32(compressed, pos),
+            )
+            .ok_or(Error::InconsistentJumpTable)?;
 
             Ok(Timestep::new(
-                &self.decoder_models[t as usize],
+                decoder,
+                &self.model_contexts,
                 jump_table,
-                compressed,
-                header.embedding_dim,
+                header.vocab_size,
                 header.jump_interval,
             ))
         }
     }
 
     pub fn as_slice_u32(&self) -> &[u32] {
-        &self.raw_data
+        self.raw_data.as_ref()
+    }
+
+    /// Returns the jump pointers of all time steps, in the layout of the
+    /// uncompressed jump table (decoded if the file has a compact jump table).
+    fn jump_table(&self) -> &[u32] {
+        self.compact_jump_table.as_deref().unwrap_or(
+            &self.raw_data.as_ref()
+                [self.layout.jump_table_address..self.layout.compressed_data_start],
+        )
+    }
+
+    /// Returns the vocabulary, or `None` if the file doesn't contain one.
+    ///
+    /// Only files with `minor_version >= 1` can contain a vocabulary.
+    pub fn vocabulary(&self) -> Option<&Vocabulary> {
+        self.vocabulary.as_ref()
+    }
+
+    /// Looks up the word ID of `word` in the file's vocabulary.
+    ///
+    /// Returns `None` if the file doesn't contain a vocabulary or if `word` is not
+    /// in the vocabulary.
+    pub fn word_to_id(&self, word: &str) -> Option<u32> {
+        self.vocabulary.as_ref()?.word_to_id(word)
+    }
+
+    /// Looks up the word with ID `id` in the file's vocabulary.
+    ///
+    /// Returns `None` if the file doesn't contain a vocabulary or if `id` is out of
+    /// bounds.
+    pub fn id_to_word(&self, id: u32) -> Option<&str> {
+        self.vocabulary.as_ref()?.id_to_word(id)
+    }
+
+    /// Returns the labels of all time steps, or `None` if the file doesn't contain
+    /// any.
+    ///
+    /// Only files with `minor_version >= 1` can contain time step labels.
+    pub fn timestep_labels(&self) -> Option<&TimestepLabels> {
+        self.timestep_labels.as_ref()
+    }
+
+    /// Returns the label of time step `t`, or `None` if the file doesn't contain
+    /// time step labels or if `t` is out of bounds.
+    pub fn timestep_label(&self, t: u32) -> Option<TimestepLabel> {
+        self.timestep_labels.as_ref()?.get(t)
+    }
+
+    /// Returns the scale factors per dimension and/or per time step, or `None` if
+    /// the file doesn't contain any (in which case `header().scale_factor` applies
+    /// to all embedding vector components).
+    pub fn scale_factors(&self) -> Option<&ScaleFactors> {
+        self.scale_factors.as_ref()
+    }
+
+    /// Returns the segments into which the time steps are split. Files to which no
+    /// time steps were appended have a single segment.
+    pub fn segments(&self) -> &Segments {
+        &self.segments
+    }
+
+    /// Returns the weights with which time steps are predicted from their parents,
+    /// or `None` if the file doesn't contain any (in which case each time step is
+    /// predicted by the integer mean of its parents).
+    pub fn predictor(&self) -> Option<&Predictor> {
+        self.predictor.as_ref()
+    }
+
+    /// Returns the contexts that determine which entropy model decodes each
+    /// embedding vector component. Files without a model contexts section have a
+    /// single context.
+    pub fn model_contexts(&self) -> &ModelContexts {
+        &self.model_contexts
+    }
+
+    /// Returns the groups of time steps that share their entropy models. In files
+    /// without a model groups section, each time step forms its own group.
+    pub fn model_groups(&self) -> &ModelGroups {
+        &self.model_groups
+    }
+
+    /// Builds the lookup tables of all entropy models that haven't been used yet.
+    ///
+    /// This is never necessary since the lookup tables of a time step get built when
+    /// the time step gets decoded for the first time. But it avoids this delay in
+    /// the first queries, at the cost of a slower start and more memory.
+    pub fn precompute_entropy_models(&self) {
+        self.decoder_models.precompute();
+    }
+
+    /// Resolves a time step index or label to a time step index.
+    ///
+    /// Returns an error if the index is out of bounds, or if there's no time step
+    /// with the provided label.
+    pub fn resolve_timestep<'a>(&self, t: impl Into<TimestepRef<'a>>) -> Result<u32> {
+        TimestepSource::resolve_timestep(self, t)
+    }
+}
+
+impl<D: AsRef<[u32]>, T: Symbol> TimestepSource for EmbeddingFile<D, T> {
+    type Symbol = T;
+
+    type Timestep<'a>
+        = Timestep<'a, 'a, CompressedWords<'a>, T>
+    where
+        Self: 'a;
+
+    fn header(&self) -> &FileHeader {
+        self.header()
+    }
+
+    fn timestep(&self, t: u32) -> Result<Self::Timestep<'_>> {
+        self.timestep(t)
+    }
+
+    fn vocabulary(&self) -> Option<&Vocabulary> {
+        self.vocabulary()
+    }
+
+    fn timestep_labels(&self) -> Option<&TimestepLabels> {
+        self.timestep_labels()
+    }
+
+    fn scale_factors(&self) -> Option<&ScaleFactors> {
+        self.scale_factors()
+    }
+
+    fn segments(&self) -> &Segments {
+        self.segments()
+    }
+
+    fn predictor(&self) -> Option<&Predictor> {
+        self.predictor()
     }
 }
 
-fn deserialize_decoder_model(serialized: &[u16]) -> Result<(DecoderModel, &[u16]), ()> {
-    let num_symbols = serialized[0];
-    let packed_size = 3 * num_symbols as usize / 4;
+/// Returns the decoded compact jump table if `layout` requires one, or
+/// `Error::InvalidHeader` if it's missing. Files whose version doesn't have compact
+/// jump tables ignore compact jump table sections.
+fn compact_jump_table(layout: &Layout, decoded: Option<Box<[u32]>>) -> Result<Option<Box<[u32]>>> {
+    if !layout.compact_jump_table {
+        Ok(None)
+    } else if decoded.is_some() {
+        Ok(decoded)
+    } else {
+        Err(Error::InvalidHeader(
+            "minor_version requires a compact jump table section",
+        ))
+    }
+}
+
+/// Returns the address of the section table of a file of length `file_len`, given
+/// the number of sections (the last entry of the file).
+fn section_table_start(num_sections: u32, file_len: usize, layout: &Layout) -> Result<usize> {
+    (num_sections as usize)
+        .checked_mul(layout.section_table_entry_size)
+        .and_then(|table_len| (file_len - 1).checked_sub(table_len))
+        .filter(|&table_start| table_start >= layout.compressed_data_start)
+        .ok_or(Error::InvalidSectionTable)
+}
+
+/// Parses the entries of the section table, which starts at address `table_start`.
+///
+/// Returns the tags and the address ranges of all optional sections. All optional
+/// sections must lie between the beginning of the compressed data and the section
+/// table.
+fn parse_section_table(
+    table: &[u32],
+    layout: &Layout,
+    table_start: usize,
+) -> Result<Vec<(u32, Range<usize>)>> {
+    table
+        .chunks_exact(layout.section_table_entry_size)
+        .map(|entry| {
+            let (tag, address, size) = match *entry {
+                [tag, address, size] => (tag, address as u64, size as u64),
+                [tag, address_low, address_high, size_low, size_high] => (
+                    tag,
+                    join_u64(address_low, address_high),
+                    join_u64(size_low, size_high),
+                ),
+                _ => unreachable!("section table entries have three or five words"),
+            };
+            address
+                .checked_add(size)
+                .filter(|&end| {
+                    address >= layout.compressed_data_start as u64 && end <= table_start as u64
+                })
+                // Both fit into a `usize` because they're bounded by `table_start`.
+                .map(|end| (tag, address as usize..end as usize))
+                .ok_or(Error::InvalidSectionTable)
+        })
+        .collect()
+}
+
+/// The optional sections (since version 1.1) that this library understands.
+#[derive(Default)]
+struct OptionalSections {
+    vocabulary: Option<Vocabulary>,
+    timestep_labels: Option<TimestepLabels>,
+    scale_factors: Option<ScaleFactors>,
+    segments: Option<Segments>,
+    predictor: Option<Predictor>,
+    model_contexts: Option<ModelContexts>,
+    model_groups: Option<ModelGroups>,
+    /// The decoded jump table, in the layout of the uncompressed jump table.
+    compact_jump_table: Option<Box<[u32]>>,
+}
+
+impl OptionalSections {
+    fn is_known(tag: u32) -> bool {
+        tag == VOCABULARY_SECTION_TAG
+            || tag == TIMESTEP_LABELS_SECTION_TAG
+            || tag == SCALE_FACTORS_SECTION_TAG
+            || tag == SEGMENTS_SECTION_TAG
+            || tag == PREDICTOR_SECTION_TAG
+            || tag == MODEL_CONTEXTS_SECTION_TAG
+            || tag == MODEL_GROUPS_SECTION_TAG
+            || tag == COMPACT_JUMP_TABLE_SECTION_TAG
+    }
+
+    fn insert(&mut self, tag: u32, payload: &[u32], header: &FileHeader) -> Result<()> {
+        match tag {
+            VOCABULARY_SECTION_TAG => {
+                self.vocabulary = Some(Vocabulary::deserialize(payload, header.vocab_size)?)
+            }
+            TIMESTEP_LABELS_SECTION_TAG => {
+                self.timestep_labels =
+                    Some(TimestepLabels::deserialize(payload, header.num_timesteps)?)
+            }
+            SCALE_FACTORS_SECTION_TAG => {
+                self.scale_factors = Some(ScaleFactors::deserialize(
+                    payload,
+                    header.num_timesteps,
+                    header.embedding_dim,
+                )?)
+            }
+            SEGMENTS_SECTION_TAG => {
+                self.segments = Some(Segments::deserialize(payload, header.num_timesteps)?)
+            }
+            PREDICTOR_SECTION_TAG => {
+                self.predictor = Some(Predictor::deserialize(payload, header.num_timesteps)?)
+            }
+            MODEL_CONTEXTS_SECTION_TAG => {
+                self.model_contexts = Some(ModelContexts::deserialize(
+                    payload,
+                    header.vocab_size,
+                    header.embedding_dim,
+                )?)
+            }
+            MODEL_GROUPS_SECTION_TAG => {
+                self.model_groups = Some(ModelGroups::deserialize(payload, header.num_timesteps)?)
+            }
+            COMPACT_JUMP_TABLE_SECTION_TAG => {
+                self.compact_jump_table = Some(compact_jump_table::deserialize(payload, header)?)
+            }
+            _ => {} // Readers must ignore optional sections with unknown tags.
+        }
+        Ok(())
+    }
+}
+
+/// The entropy models of all groups of time steps (see [`ModelGroups`]) and
+/// contexts (see [`ModelContexts`]), in the precision that the file header
+/// declares.
+///
+/// All entropy models get parsed and validated when the file is loaded, but the
+/// lookup tables that the decoder needs get built only when a time step of the
+/// respective group gets decoded for the first time (or in
+/// [`precompute`](#method.precompute)). This keeps loading files with many time
+/// steps fast.
+struct DecoderModels<T> {
+    precision: EntropyPrecision,
+    models_per_group: usize,
+
+    /// The serialized entropy models, see [`deserialize_decoder_model`].
+    serialized: Box<[u16]>,
+
+    /// The position of each entropy model in `serialized`.
+    offsets: Box<[usize]>,
+
+    /// The lookup tables of each group, which get built on first use.
+    lookup_tables: LookupTables<T>,
+}
+
+enum LookupTables<T> {
+    Bits12(GroupTables<DecoderModel12<T>>),
+    Bits16(GroupTables<DecoderModel16<T>>),
+    Bits24(GroupTables<DecoderModel24<T>>),
+}
+
+/// One slot per group of time steps for the entropy models of that group.
+type GroupTables<M> = Box<[OnceLock<Box<[M]>>]>;
+
+/// An entropy model that can be constructed from the symbols and frequencies that
+/// [`deserialize_decoder_model`] returns.
+trait DecoderModel<T>: Sized {
+    /// Returns `None` if the frequencies aren't valid for the precision of the model.
+    fn from_symbols_and_frequencies(symbols: &[T], frequencies: &[u32]) -> Option<Self>;
+}
+
+impl<T: Symbol> DecoderModel<T> for DecoderModel12<T> {
+    fn from_symbols_and_frequencies(symbols: &[T], frequencies: &[u32]) -> Option<Self> {
+        Self::from_symbols_and_nonzero_fixed_point_probabilities(
+            symbols.iter().copied(),
+            frequencies.iter().map(|&f| f as u16),
+            false,
+        )
+        .ok()
+    }
+}
+
+impl<T: Symbol> DecoderModel<T> for DecoderModel16<T> {
+    fn from_symbols_and_frequencies(symbols: &[T], frequencies: &[u32]) -> Option<Self> {
+        Self::from_symbols_and_nonzero_fixed_point_probabilities(
+            symbols.iter().copied(),
+            frequencies.iter().map(|&f| f as u16),
+            false,
+        )
+        .ok()
+    }
+}
+
+impl<T: Symbol> DecoderModel<T> for DecoderModel24<T> {
+    fn from_symbols_and_frequencies(symbols: &[T], frequencies: &[u32]) -> Option<Self> {
+        Self::from_symbols_and_nonzero_fixed_point_probabilities(
+            symbols.iter().copied(),
+            frequencies,
+            false,
+        )
+        .ok()
+    }
+}
+
+impl<T: Symbol> DecoderModels<T> {
+    fn num_groups(&self) -> usize {
+        self.offsets.len() / self.models_per_group
+    }
+
+    /// Returns the entropy models of group `group` (one per context), building
+    /// their lookup tables first if this hasn't happened yet. `lookup_tables` must
+    /// be the cells in `self.lookup_tables`.
+    fn group<'a, M: DecoderModel<T>>(
+        &'a self,
+        lookup_tables: &'a [OnceLock<Box<[M]>>],
+        group: usize,
+    ) -> &'a [M] {
+        lookup_tables[group].get_or_init(|| {
+            let models_per_group = self.models_per_group;
+            self.offsets[group * models_per_group..(group + 1) * models_per_group]
+                .iter()
+                .map(|&offset| {
+                    // `deserialize_decoder_models` checked that this succeeds.
+                    let (symbols, frequencies, _) =
+                        deserialize_decoder_model(&self.serialized[offset..], self.precision)
+                            .expect("validated when loading the file");
+                    M::from_symbols_and_frequencies(&symbols, &frequencies)
+                        .expect("validated when loading the file")
+                })
+                .collect()
+        })
+    }
+
+    /// Builds the lookup tables of all groups that haven't been built yet.
+    fn precompute(&self) {
+        for group in 0..self.num_groups() {
+            match &self.lookup_tables {
+                LookupTables::Bits12(lookup_tables) => {
+                    self.group(lookup_tables, group);
+                }
+                LookupTables::Bits16(lookup_tables) => {
+                    self.group(lookup_tables, group);
+                }
+                LookupTables::Bits24(lookup_tables) => {
+                    self.group(lookup_tables, group);
+                }
+            }
+        }
+    }
+}
+
+/// Parses and validates the entropy models of all groups in `model_groups`,
+/// `models_per_group` models for each group, without building their lookup tables
+/// (see [`DecoderModels`]).
+fn deserialize_decoder_models<T: Symbol>(
+    header: &FileHeader,
+    model_groups: &ModelGroups,
+    models_per_group: usize,
+    entropy_models_section: &[u16],
+) -> Result<DecoderModels<T>> {
+    // Each entropy model takes up at least four `u16`s (`num_symbols`, at least two
+    // symbols, and at least one packed frequency). Checking this before allocating
+    // `offsets` prevents excessive allocations for malformed headers.
+    let num_models = model_groups.len() as u64 * models_per_group as u64;
+    if num_models > (entropy_models_section.len() / 4) as u64 {
+        return Err(Error::InvalidHeader(
+            "entropy models section too small for num_timesteps",
+        ));
+    }
+
+    let precision = header.entropy_precision;
+    let mut remainder = entropy_models_section;
+    let mut offsets = Vec::with_capacity(num_models as usize);
+    for index in 0..num_models as usize {
+        let invalid = || Error::InvalidEntropyModel {
+            timestep: model_groups.first_timestep(index / models_per_group),
+        };
+        offsets.push(entropy_models_section.len() - remainder.len());
+        let (_, _, r) = deserialize_decoder_model::<T>(remainder, precision).ok_or_else(invalid)?;
+        // Building the lookup tables (see `DecoderModels::group`) can't fail for
+        // frequencies that pass the checks in `deserialize_decoder_model`.
+        remainder = r;
+    }
+    if remainder.len() > 1 {
+        // At most one padding entry allowed.
+        return Err(Error::InvalidHeader(
+            "jump_table_address doesn't match the size of the entropy models section",
+        ));
+    }
+
+    let num_groups = model_groups.len();
+    let lookup_tables = match precision {
+        EntropyPrecision::Bits12 => LookupTables::Bits12(empty_cells(num_groups)),
+        EntropyPrecision::Bits16 => LookupTables::Bits16(empty_cells(num_groups)),
+        EntropyPrecision::Bits24 => LookupTables::Bits24(empty_cells(num_groups)),
+    };
+    Ok(DecoderModels {
+        precision,
+        models_per_group,
+        serialized: entropy_models_section[..entropy_models_section.len() - remainder.len()].into(),
+        offsets: offsets.into(),
+        lookup_tables,
+    })
+}
+
+fn empty_cells<T>(len: usize) -> Box<[OnceLock<T>]> {
+    (0..len).map(|_| OnceLock::new()).collect()
+}
+
+/// Parses the entropy model with symbols of type `T` at the beginning of
+/// `serialized`.
+///
+/// Returns the symbols, the frequencies of all symbols (including the last one,
+/// which isn't serialized), and the remainder of `serialized`, or `None` if
+/// `serialized` doesn't start with a valid entropy model.
+fn deserialize_decoder_model<T: Symbol>(
+    serialized: &[u16],
+    precision: EntropyPrecision,
+) -> Option<(Vec<T>, Vec<u32>, &[u16])> {
+    let num_symbols = *serialized.first()?;
+    if num_symbols < 2 {
+        // Degenerate models with all probability mass on a single symbol are not
+        // supported by the file format.
+        return None;
+    }
+    let symbols_size = num_symbols as usize * T::SYMBOL_TYPE.serialized_size();
+    let packed_size = packed_frequencies_size(num_symbols - 1, precision);
 
     // Extract remainder first to check most constrained bounds.
-    let remainder = serialized
-        .get(1 + num_symbols as usize + packed_size..)
-        .ok_or(())?;
-    let symbols = &serialized[1..1 + num_symbols as usize];
-    let packed_frequencies =
-        &serialized[1 + num_symbols as usize..1 + num_symbols as usize + packed_size];
+    let remainder = serialized.get(1 + symbols_size + packed_size..)?;
+    let symbols = deserialize_symbols(&serialized[1..1 + symbols_size])?;
+    let packed_frequencies = &serialized[1 + symbols_size..1 + symbols_size + packed_size];
+    let mut frequencies = unpack_frequencies(packed_frequencies, num_symbols - 1, precision);
+
+    // Check that all frequencies are nonzero and that they leave some probability
+    // mass for the last symbol before constructing the model. Otherwise, a malformed
+    // model could make the lookup table grow way beyond `1 << 12` entries.
+    let mut total = 0u32;
+    for &frequency in &frequencies {
+        if frequency == 0 {
+            return None;
+        }
+        total = total.checked_add(frequency)?;
+        if total >= 1 << precision.bits() {
+            return None;
+        }
+    }
+    // Don't let constriction infer the last frequency since it would overflow the
+    // `u16` probabilities of 16 bit models.
+    frequencies.push((1 << precision.bits()) - total);
+
+    Some((symbols, frequencies, remainder))
+}
+
+/// Number of `u16`s that hold `amt` packed frequencies: 12 bit frequencies are
+/// packed with [`pack_u12s`](../u12/fn.pack_u12s.html), 16 bit frequencies take up
+/// one `u16` each, and 24 bit frequencies take up two `u16`s each (lower half
+/// first).
+fn packed_frequencies_size(amt: u16, precision: EntropyPrecision) -> usize {
+    match precision {
+        EntropyPrecision::Bits12 => 3 * (amt as usize + 1) / 4,
+        EntropyPrecision::Bits16 => amt as usize,
+        EntropyPrecision::Bits24 => 2 * amt as usize,
+    }
+}
+
+/// Inverse of `builder::pack_frequencies`. Expects `packed` to have the length
+/// returned by `packed_frequencies_size`.
+fn unpack_frequencies(packed: &[u16], amt: u16, precision: EntropyPrecision) -> Vec<u32> {
+    match precision {
+        EntropyPrecision::Bits12 => unpack_u12s(packed, amt).map(u32::from).collect(),
+        EntropyPrecision::Bits16 => packed.iter().map(|&f| f as u32).collect(),
+        EntropyPrecision::Bits24 => packed
+            .chunks_exact(2)
+            .map(|f| f[0] as u32 | (f[1] as u32) << 16)
+            .collect(),
+    }
+}
 
-    let model = DecoderModel::from_symbols_and_nonzero_fixed_point_probabilities(
-        symbols.iter().map(|&s| s as i16),
-        unpack_u12s(packed_frequencies, num_symbols - 1),
-        true,
-    )
-    .expect("Invalid entropy model.");
+/// An ANS decoder that's positioned in the compressed data of a time step, together
+/// with the entropy models of all contexts of the time step.
+///
+/// The types of both depend on the precision of the entropy models. `B16` and `B32`
+/// are the backends from which the decoder reads compressed data that consists of
+/// `u16` and `u32` words, respectively.
+enum TimestepDecoder<'model, B16, B32, T> {
+    Bits12(AnsCoder<u16, u32, B16>, &'model [DecoderModel12<T>]),
+    Bits16(AnsCoder<u16, u32, B16>, &'model [DecoderModel16<T>]),
+    Bits24(AnsCoder<u32, u64, B32>, &'model [DecoderModel24<T>]),
+}
 
-    Ok((model, remainder))
+/// Evaluates `$body` with `$decoder` and `$models` bound to the ANS decoder and the
+/// entropy models of a `TimestepDecoder`. This compiles `$body` separately for each
+/// precision so that the hot decoding loops don't dispatch on the precision.
+macro_rules! with_decoder {
+    ($timestep_decoder:expr, |$decoder:ident, $models:ident| $body:expr) => {
+        match $timestep_decoder {
+            TimestepDecoder::Bits12($decoder, $models) => $body,
+            TimestepDecoder::Bits16($decoder, $models) => $body,
+            TimestepDecoder::Bits24($decoder, $models) => $body,
+        }
+    };
 }
 
-impl<'data, 'model> Timestep<'data, 'model> {
+impl<'model, B16, B32, T: Symbol> TimestepDecoder<'model, B16, B32, T> {
+    /// Creates a decoder that starts at `jump_pointer` and uses the entropy models of
+    /// group `group` (which must be in bounds) of a file with `num_contexts`
+    /// entropy models per group. The closures create a backend positioned at a
+    /// given offset, and they're called only for the matching precision.
+    ///
+    /// Returns `None` if the backend can't be created or if the state is invalid.
     fn new(
-        decoder_model: &'model DecoderModel,
-        jump_table: &'data [JumpPointer],
-        compressed: &'data [u16],
-        embedding_dim: u32,
-        jump_interval: u32,
-    ) -> Self {
-        let JumpPointer { offset, state } = jump_table[0];
-        let cursor =
-            Cursor::new_at_pos(compressed, offset as usize).expect("Jump position out of bounds");
+        models: &'model DecoderModels<T>,
+        num_contexts: usize,
+        group: usize,
+        jump_pointer: JumpPointer,
+        words16: impl FnOnce(usize) -> Option<B16>,
+        words32: impl FnOnce(usize) -> Option<B32>,
+    ) -> Option<Self> {
+        debug_assert_eq!(num_contexts, models.models_per_group);
+        let offset = usize::try_from(jump_pointer.offset).ok()?;
+        match &models.lookup_tables {
+            LookupTables::Bits12(lookup_tables) => Some(TimestepDecoder::Bits12(
+                AnsCoder::from_raw_parts(words16(offset)?, jump_pointer.state.try_into().ok()?),
+                models.group(lookup_tables, group),
+            )),
+            LookupTables::Bits16(lookup_tables) => Some(TimestepDecoder::Bits16(
+                AnsCoder::from_raw_parts(words16(offset)?, jump_pointer.state.try_into().ok()?),
+                models.group(lookup_tables, group),
+            )),
+            LookupTables::Bits24(lookup_tables) => Some(TimestepDecoder::Bits24(
+                AnsCoder::from_raw_parts(words32(offset)?, jump_pointer.state),
+                models.group(lookup_tables, group),
+            )),
+        }
+    }
+}
 
-        let decoder = Decoder::from_raw_parts(constriction::backends::Reverse(cursor), state);
+impl<B16, B32, E, T: Symbol> TimestepDecoder<'_, B16, B32, T>
+where
+    B16: ReadWords<u16, Stack, ReadError = E> + Seek + PosSeek<Position = usize>,
+    B32: ReadWords<u32, Stack, ReadError = E> + Seek + PosSeek<Position = usize>,
+{
+    /// Decodes the embedding vector of the word with index `word_index`.
+    fn decode_vector<I: Iterator>(
+        &mut self,
+        contexts: &ModelContexts,
+        word_index: u32,
+        dest_iter: I,
+        mut callback: impl FnMut(T, I::Item),
+    ) -> std::result::Result<(), CoderError<Infallible, E>> {
+        with_decoder!(self, |decoder, models| {
+            if let [model] = models {
+                let model = model.as_view();
+                for dest in dest_iter {
+                    callback(decoder.decode_symbol(model)?, dest);
+                }
+            } else {
+                let models = contexts.models_of_word(models, word_index);
+                for (dest, &bucket) in dest_iter.zip(contexts.dimension_buckets()) {
+                    callback(
+                        decoder.decode_symbol(models[bucket as usize].as_view())?,
+                        dest,
+                    );
+                }
+            }
+            Ok(())
+        })
+    }
 
+    /// Decodes and discards the embedding vectors of `num_words` words, starting at
+    /// the word with index `word_index`.
+    fn skip(
+        &mut self,
+        contexts: &ModelContexts,
+        word_index: u32,
+        num_words: u32,
+    ) -> std::result::Result<(), CoderError<Infallible, E>> {
+        // Note that just calling `decode_iid_symbols` won't do anything because it's lazy.
+        // We actually actually have to drain the iterator.
+        with_decoder!(self, |decoder, models| {
+            if let [model] = models {
+                let amt = num_words as usize * contexts.dimension_buckets().len();
+                for symbol in decoder.decode_iid_symbols(amt, model.as_view()) {
+                    symbol?;
+                }
+            } else {
+                for word_index in word_index..word_index + num_words {
+                    let models = contexts.models_of_word(models, word_index);
+                    for &bucket in contexts.dimension_buckets() {
+                        decoder.decode_symbol(models[bucket as usize].as_view())?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn seek(&mut self, jump_pointer: JumpPointer) -> std::result::Result<(), ()> {
+        let offset = usize::try_from(jump_pointer.offset).map_err(|_| ())?;
+        match self {
+            TimestepDecoder::Bits12(decoder, _) | TimestepDecoder::Bits16(decoder, _) => {
+                let state = u32::try_from(jump_pointer.state).map_err(|_| ())?;
+                decoder.seek((offset, state))
+            }
+            TimestepDecoder::Bits24(decoder, _) => decoder.seek((offset, jump_pointer.state)),
+        }
+    }
+}
+
+impl<'data, 'model, W, T> Timestep<'data, 'model, W, T> {
+    /// Expects `decoder` to be positioned at the first jump pointer.
+    fn new(
+        decoder: TimestepDecoder<'model, W, CompressedWords32<'data>, T>,
+        model_contexts: &'model ModelContexts,
+        jump_table: JumpTable<'data>,
+        vocab_size: u32,
+        jump_interval: u32,
+    ) -> Self {
         Timestep {
             decoder,
-            model: decoder_model.as_view(),
+            model_contexts,
             jump_table,
             word_index: 0,
-            embedding_dim,
+            vocab_size,
             jump_interval,
         }
     }
-
-    pub fn into_inner(self) -> (Decoder<'data>, DecoderModelView<'model>) {
-        (self.decoder, self.model)
-    }
 }
 
 pub trait TimestepReader {
+    /// The type of the decoded embedding vector components.
+    type Symbol: Symbol;
+
     fn read_single_embedding_vector<I: Iterator>(
         &mut self,
         dest_iter: I,
-        callback: impl FnMut(i16, I::Item),
-    ) -> Result<(), ()>;
+        callback: impl FnMut(Self::Symbol, I::Item),
+    ) -> Result<()>;
+
+    fn jump_to(&mut self, word_index: u32) -> Result<()>;
+}
+
+/// A compressed dynamic word embeddings file whose time steps can be decoded.
+///
+/// This is implemented by [`EmbeddingFile`], which holds the entire file in memory,
+/// and by [`LazyEmbeddingFile`](lazy/struct.LazyEmbeddingFile.html), which loads
+/// only those parts of the file that a query actually touches. A
+/// [`RandomAccessReader`] can answer queries on either one.
+pub trait TimestepSource {
+    /// The type of the quantized embedding vector components, see
+    /// [`FileHeader::symbol_type`].
+    type Symbol: Symbol;
+
+    type Timestep<'a>: TimestepReader<Symbol = Self::Symbol>
+    where
+        Self: 'a;
+
+    fn header(&self) -> &FileHeader;
+
+    fn timestep(&self, t: u32) -> Result<Self::Timestep<'_>>;
+
+    /// Returns the vocabulary, or `None` if the file doesn't contain one.
+    fn vocabulary(&self) -> Option<&Vocabulary>;
+
+    /// Returns the labels of all time steps, or `None` if the file doesn't contain
+    /// any.
+    fn timestep_labels(&self) -> Option<&TimestepLabels>;
+
+    /// Returns the scale factors per dimension and/or per time step, or `None` if
+    /// the file doesn't contain any.
+    fn scale_factors(&self) -> Option<&ScaleFactors>;
+
+    /// Returns the segments into which the time steps are split, which determine
+    /// how time steps are predicted from each other.
+    fn segments(&self) -> &Segments;
+
+    /// Returns the weights with which time steps are predicted from their parents,
+    /// or `None` if each time step is predicted by the integer mean of its parents.
+    fn predictor(&self) -> Option<&Predictor>;
+
+    /// Resolves a time step index or label to a time step index.
+    ///
+    /// Returns an error if the index is out of bounds, or if there's no time step
+    /// with the provided label.
+    fn resolve_timestep<'a>(&self, t: impl Into<TimestepRef<'a>>) -> Result<u32> {
+        let num_timesteps = self.header().num_timesteps;
+        match t.into() {
+            TimestepRef::Index(t) if t < num_timesteps => Ok(t),
+            TimestepRef::Index(t) => Err(Error::TimestepOutOfRange {
+                timestep: t,
+                num_timesteps,
+            }),
+            TimestepRef::Label(label) => self
+                .timestep_labels()
+                .and_then(|labels| labels.position(label))
+                .ok_or_else(|| Error::UnknownTimestepLabel(label.to_string())),
+        }
+    }
+}
+
+/// Allows querying a borrowed file, e.g., with a `RandomAccessReader<&EmbeddingFile>`.
+impl<F: TimestepSource + ?Sized> TimestepSource for &F {
+    type Symbol = F::Symbol;
+
+    type Timestep<'a>
+        = F::Timestep<'a>
+    where
+        Self: 'a;
+
+    fn header(&self) -> &FileHeader {
+        (**self).header()
+    }
+
+    fn timestep(&self, t: u32) -> Result<Self::Timestep<'_>> {
+        (**self).timestep(t)
+    }
 
-    fn jump_to(&mut self, word_index: u32) -> Result<(), ()>;
+    fn vocabulary(&self) -> Option<&Vocabulary> {
+        (**self).vocabulary()
+    }
+
+    fn timestep_labels(&self) -> Option<&TimestepLabels> {
+        (**self).timestep_labels()
+    }
+
+    fn scale_factors(&self) -> Option<&ScaleFactors> {
+        (**self).scale_factors()
+    }
+
+    fn segments(&self) -> &Segments {
+        (**self).segments()
+    }
+
+    fn predictor(&self) -> Option<&Predictor> {
+        (**self).predictor()
+    }
 }
 
-impl TimestepReader for Timestep<'_, '_> {
+impl<W, T: Symbol> TimestepReader for Timestep<'_, '_, W, T>
+where
+    W: ReadWords<u16, Stack, ReadError = Infallible> + Seek + PosSeek<Position = usize>,
+{
+    type Symbol = T;
+
     fn read_single_embedding_vector<I: Iterator>(
         &mut self,
         dest_iter: I,
-        mut callback: impl FnMut(i16, I::Item),
-    ) -> Result<(), ()> {
-        let decoder = &mut self.decoder;
-        let model = self.model;
-        for dest in dest_iter {
-            let symbol = decoder.decode_symbol(model).unwrap_infallible();
-            callback(symbol, dest);
-        }
+        callback: impl FnMut(T, I::Item),
+    ) -> Result<()> {
+        self.decoder
+            .decode_vector(self.model_contexts, self.word_index, dest_iter, callback)
+            .unwrap_infallible();
         self.word_index += 1;
         Ok(())
     }
 
-    fn jump_to(&mut self, word_index: u32) -> Result<(), ()> {
+    fn jump_to(&mut self, word_index: u32) -> Result<()> {
+        if word_index >= self.vocab_size {
+            return Err(Error::WordIndexOutOfRange {
+                word_index,
+                vocab_size: self.vocab_size,
+            });
+        }
+
         let jump_point = word_index / self.jump_interval;
         if word_index < self.word_index || jump_point != self.word_index / self.jump_interval {
-            let JumpPointer { offset, state } = self.jump_table[jump_point as usize];
-            self.decoder.seek((offset as usize, state))?;
+            self.decoder
+                .seek(self.jump_table.get(jump_point as usize))
+                .map_err(|()| Error::InconsistentJumpTable)?;
             self.word_index = jump_point * self.jump_interval;
         }
 
-        // Note that just calling `decode_iid_symbols` won't do anything because it's lazy.
-        // We actually actually have to drain the iterator.
-        for symbol in self.decoder.decode_iid_symbols(
-            self.embedding_dim as usize * (word_index - self.word_index) as usize,
-            self.model,
-        ) {
-            symbol.unwrap_infallible();
-        }
+        self.decoder
+            .skip(
+                self.model_contexts,
+                self.word_index,
+                word_index - self.word_index,
+            )
+            .unwrap_infallible();
         self.word_index = word_index;
 
         Ok(())
     }
 }
 
+/// Combines two `u32` words of the file (lower half first) into a `u64`.
+fn join_u64(low: u32, high: u32) -> u64 {
+    (high as u64) << 32 | low as u64
+}
+
+/// Splits a `u64` into two `u32` words (lower half first).
+fn split_u64(value: u64) -> [u32; 2] {
+    [value as u32, (value >> 32) as u32]
+}
+
+/// Returns a decoder backend for the compressed data `words`, positioned at `pos`
+/// (in units of two bytes), or `None` if `pos` is out of bounds.
+#[cfg(target_endian = "little")]
+fn compressed_words(words: &[u32], pos: usize) -> Option<CompressedWords<'_>> {
+    let cursor = Cursor::new_at_pos(get_u16_slice(words), pos).ok()?;
+    Some(constriction::backends::Reverse(cursor))
+}
+
+#[cfg(not(target_endian = "little"))]
+fn compressed_words(words: &[u32], pos: usize) -> Option<CompressedWords<'_>> {
+    portable::SplitWords::new_at_pos(words, pos)
+}
+
+/// Returns a decoder backend for compressed data that consists of `u32` words,
+/// positioned at `pos` (in units of four bytes), or `None` if `pos` is out of
+/// bounds.
+fn compressed_words32(words: &[u32], pos: usize) -> Option<CompressedWords32<'_>> {
+    let cursor = constriction::backends::Cursor::new_at_pos(words, pos).ok()?;
+    Some(constriction::backends::Reverse(cursor))
+}
+
+/// Reinterprets `u32`s as pairs of `u16`s. This matches the file format only on
+/// little endian platforms, see module `portable`.
 fn get_u16_slice(data: &[u32]) -> &[u16] {
     unsafe {
         // Transmuting from `&[u32]` to `&[u16]` is always safe, see, e.g.:
@@ -323,6 +1608,15 @@ fn get_u16_slice(data: &[u32]) -> &[u16] {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tensors::RankThreeTensor;
+    use builder::{
+        write_compressed_dwe_file, write_compressed_dwe_file_with_options, CompressionOptions,
+    };
+    use lazy::{InMemoryRangeSource, LazyEmbeddingFile};
+    use model_groups::ModelSharing;
+    use predictor::PredictionScheme;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     #[test]
     fn construct_file() {
@@ -388,11 +1682,510 @@ mod test {
             data.push(chunk[0] as u32 | ((chunk[1] as u32) << 16));
         }
 
-        let file = EmbeddingFile::new(data.into()).unwrap();
+        let file = EmbeddingFile::new(data.into_boxed_slice()).unwrap();
         assert_eq!(file.header().file_size, 35);
 
         let mut data = file.into_inner();
         data[4] -= 1; // Invalidate the jump_table address.
-        assert!(EmbeddingFile::new(data).is_err());
+        assert!(matches!(
+            EmbeddingFile::new(data.clone()),
+            Err(Error::InvalidEntropyModel { timestep: 2 })
+        ));
+        data[4] += 1;
+
+        data[0] = 0x1234_5678;
+        assert!(matches!(
+            EmbeddingFile::new(data.clone()),
+            Err(Error::BadMagic(0x1234_5678))
+        ));
+        data[0] = MAGIC;
+
+        data[1] = 7; // Unsupported major version.
+        assert!(matches!(
+            EmbeddingFile::new(data.clone()),
+            Err(Error::UnsupportedVersion { major: 7, minor: 0 })
+        ));
+        data[1] = 1;
+
+        // Newer minor versions may change decoding, so they have to be rejected too.
+        data[2] = 4;
+        assert!(matches!(
+            EmbeddingFile::new(data.clone()),
+            Err(Error::UnsupportedVersion { major: 1, minor: 4 })
+        ));
+        data[1] = 2;
+        data[2] = 3;
+        assert!(matches!(
+            FileHeader::from_words(&data),
+            Err(Error::UnsupportedVersion { major: 2, minor: 3 })
+        ));
+        data[1] = 1;
+        data[2] = 0;
+
+        data[21] = 9; // Point the jump table outside of the compressed data.
+        assert!(matches!(
+            EmbeddingFile::new(data.clone()),
+            Err(Error::InconsistentJumpTable)
+        ));
+        data[21] = 2;
+
+        let truncated = data[..34]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<u8>>();
+        assert!(matches!(
+            EmbeddingFile::from_reader(&truncated[..]),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn zero_copy_loading() {
+        let uncompressed = RankThreeTensor::from_flattened(
+            (0..3 * 5 * 4).map(|i| (i % 7) as i16 - 3).collect(),
+            3,
+            5,
+            4,
+        );
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file(uncompressed.as_view(), None, None, 2, 0.5, &mut compressed)
+            .unwrap();
+        let owned = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        let words = owned.as_slice_u32().to_vec();
+        assert_eq!(
+            &FileHeader::from_le_bytes(&compressed).unwrap(),
+            owned.header()
+        );
+
+        // Store the words in little endian byte order so that `le_bytes` is the
+        // file contents on all platforms.
+        let le_words = words.iter().map(|word| word.to_le()).collect::<Vec<_>>();
+        let le_bytes = as_bytes(&le_words);
+        assert_eq!(le_bytes, &compressed[..]);
+
+        // Construct a buffer whose bytes at offset 1 are the file contents, which is
+        // guaranteed to be misaligned.
+        let padded = std::iter::once(0)
+            .chain(compressed.iter().copied())
+            .chain([0; 3])
+            .collect::<Vec<u8>>()
+            .chunks_exact(4)
+            .map(|chunk| u32::from_ne_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let misaligned = &as_bytes(&padded)[1..1 + compressed.len()];
+
+        let borrowed = EmbeddingFile::new(&words[..]).unwrap();
+        let from_bytes = EmbeddingFile::from_bytes(le_bytes).unwrap();
+        let from_misaligned = EmbeddingFile::from_bytes(misaligned).unwrap();
+        assert_eq!(borrowed.header(), owned.header());
+        assert_eq!(from_bytes.header(), owned.header());
+        assert_eq!(from_misaligned.header(), owned.header());
+        assert!(!FileBytes::new(misaligned).unwrap().is_zero_copy());
+        assert_eq!(
+            FileBytes::new(le_bytes).unwrap().is_zero_copy(),
+            cfg!(target_endian = "little")
+        );
+        if cfg!(target_endian = "little") {
+            assert_eq!(from_bytes.as_slice_u32().as_ptr(), le_words.as_ptr());
+        }
+
+        let owned = owned.into_random_access_reader();
+        let borrowed = borrowed.into_random_access_reader();
+        let from_bytes = from_bytes.into_random_access_reader();
+        let from_misaligned = from_misaligned.into_random_access_reader();
+        for t in 0..3 {
+            let expected = owned.get_embeddings_at(t).unwrap().into_inner();
+            assert_eq!(
+                borrowed.get_embeddings_at(t).unwrap().into_inner(),
+                expected
+            );
+            assert_eq!(
+                from_bytes.get_embeddings_at(t).unwrap().into_inner(),
+                expected
+            );
+            assert_eq!(
+                from_misaligned.get_embeddings_at(t).unwrap().into_inner(),
+                expected
+            );
+        }
+
+        assert!(matches!(
+            EmbeddingFile::from_bytes(&le_bytes[..le_bytes.len() - 1]),
+            Err(Error::Truncated)
+        ));
+
+        let swapped = le_words
+            .iter()
+            .map(|word| word.swap_bytes())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            EmbeddingFile::from_bytes(as_bytes(&swapped)),
+            Err(Error::UnsupportedByteOrder)
+        ));
+    }
+
+    /// Checks that the lookup tables of the entropy models get built only for the
+    /// groups of time steps that get decoded, also when several threads decode the
+    /// same time step concurrently.
+    #[test]
+    fn lazy_decoder_models() {
+        let uncompressed = RankThreeTensor::from_flattened(
+            (0..4 * 6 * 3).map(|i| (i * 5 % 11) as i16 - 5).collect(),
+            4,
+            6,
+            3,
+        );
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file(uncompressed.as_view(), None, None, 2, 0.5, &mut compressed)
+            .unwrap();
+        let expected = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        expected.precompute_entropy_models();
+        assert_eq!(built_groups(&expected.decoder_models), [true; 4]);
+
+        let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        assert_eq!(built_groups(&file.decoder_models), [false; 4]);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| read_timestep(&file, 2));
+            }
+        });
+        assert_eq!(
+            built_groups(&file.decoder_models),
+            [false, false, true, false]
+        );
+
+        file.precompute_entropy_models();
+        assert_eq!(built_groups(&file.decoder_models), [true; 4]);
+        for t in 0..4 {
+            assert_eq!(read_timestep(&file, t), read_timestep(&expected, t));
+        }
+
+        fn built_groups(models: &DecoderModels<i16>) -> Vec<bool> {
+            match &models.lookup_tables {
+                LookupTables::Bits12(cells) => cells.iter().map(|c| c.get().is_some()).collect(),
+                LookupTables::Bits16(cells) => cells.iter().map(|c| c.get().is_some()).collect(),
+                LookupTables::Bits24(cells) => cells.iter().map(|c| c.get().is_some()).collect(),
+            }
+        }
+
+        fn read_timestep(file: &EmbeddingFile, t: u32) -> Vec<i16> {
+            let mut timestep = file.timestep(t).unwrap();
+            let mut symbols = Vec::new();
+            for _ in 0..6 {
+                timestep
+                    .read_single_embedding_vector(0..3, |symbol, _| symbols.push(symbol))
+                    .unwrap();
+            }
+            symbols
+        }
+    }
+
+    /// Round trips files with each symbol type, both in memory and lazily, and checks
+    /// that files can only be opened with their own symbol type.
+    #[test]
+    fn symbol_types() {
+        let narrow = round_trip(|i| (i * 7 % 101) as i8 - 50);
+        let standard = round_trip(|i| (i * 5 % 11) as i16 - 5);
+        let wide = round_trip(|i| (i as i32 * 7919 % 200_001 - 100_000) * 1000);
+
+        assert_eq!(
+            FileHeader::from_le_bytes(&standard).unwrap().major_version,
+            1
+        );
+        assert_eq!(
+            u32::from_le_bytes(narrow[48..52].try_into().unwrap()),
+            12 | 8 << 16
+        );
+        assert_eq!(
+            u32::from_le_bytes(wide[48..52].try_into().unwrap()),
+            12 | 32 << 16
+        );
+
+        assert!(matches!(
+            EmbeddingFile::from_reader(&narrow[..]),
+            Err(Error::SymbolTypeMismatch {
+                expected: SymbolType::I16,
+                found: SymbolType::I8
+            })
+        ));
+        assert!(matches!(
+            EmbeddingFile::<_, i8>::from_reader_typed(&wide[..]),
+            Err(Error::SymbolTypeMismatch {
+                expected: SymbolType::I8,
+                found: SymbolType::I32
+            })
+        ));
+        assert!(matches!(
+            LazyEmbeddingFile::<_, i32>::new_typed(InMemoryRangeSource::new(&standard[..])),
+            Err(Error::SymbolTypeMismatch {
+                expected: SymbolType::I32,
+                found: SymbolType::I16
+            })
+        ));
+
+        let mut unknown = narrow.clone();
+        unknown[50] = 64;
+        assert!(matches!(
+            FileHeader::from_le_bytes(&unknown),
+            Err(Error::InvalidHeader(_))
+        ));
+
+        let mut header = FileHeader::from_le_bytes(&narrow).unwrap();
+        header.major_version = 1;
+        assert!(header.to_words().is_err());
+
+        fn round_trip<T: Symbol>(value: impl Fn(usize) -> T) -> Vec<u8> {
+            let uncompressed =
+                RankThreeTensor::from_flattened((0..5 * 6 * 3).map(value).collect(), 5, 6, 3);
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file(uncompressed.as_view(), None, None, 2, 0.5, &mut compressed)
+                .unwrap();
+            let header = FileHeader::from_le_bytes(&compressed).unwrap();
+            assert_eq!(header.symbol_type, T::SYMBOL_TYPE);
+
+            let file = EmbeddingFile::<_, T>::from_reader_typed(&compressed[..])
+                .unwrap()
+                .into_random_access_reader();
+            let lazy = LazyEmbeddingFile::<_, T>::with_page_size_typed(
+                InMemoryRangeSource::new(&compressed[..]),
+                64,
+                2,
+            )
+            .unwrap()
+            .into_random_access_reader();
+            for t in 0..5 {
+                let expected = uncompressed.as_view().subview(t).slice();
+                assert_eq!(
+                    file.get_embeddings_at(t as u32).unwrap().into_inner(),
+                    expected
+                );
+                assert_eq!(
+                    lazy.get_embeddings_at(t as u32).unwrap().into_inner(),
+                    expected
+                );
+            }
+            compressed
+        }
+    }
+
+    fn as_bytes(words: &[u32]) -> &[u8] {
+        unsafe {
+            // SAFETY: Viewing any memory as bytes is safe.
+            std::slice::from_raw_parts(words.as_ptr() as *const u8, 4 * words.len())
+        }
+    }
+
+    /// Loads lots of randomly corrupted files and checks that they either get
+    /// rejected or that all queries on them succeed (with garbage results), but
+    /// that nothing ever panics or reads out of bounds.
+    #[test]
+    fn mutated_files_never_panic() {
+        const NUM_TIMESTEPS: usize = 5;
+        const VOCAB_SIZE: usize = 12;
+        const EMBEDDING_DIM: usize = 4;
+
+        let mut rng = StdRng::seed_from_u64(20_201_018);
+        let uncompressed = (0..NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-20..=20i16))
+            .collect();
+        let uncompressed =
+            RankThreeTensor::from_flattened(uncompressed, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+        let vocab = (0..VOCAB_SIZE)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<_>>();
+        let labels = TimestepLabels::from_integers((2000..2005).collect()).unwrap();
+
+        for (major_version, entropy_precision, compact_jump_table, decoding_sections) in [
+            (1, EntropyPrecision::Bits12, false, false),
+            (2, EntropyPrecision::Bits12, false, false),
+            (2, EntropyPrecision::Bits16, false, false),
+            (2, EntropyPrecision::Bits24, false, false),
+            (1, EntropyPrecision::Bits12, true, false),
+            (2, EntropyPrecision::Bits24, true, false),
+            (1, EntropyPrecision::Bits12, false, true),
+            (2, EntropyPrecision::Bits16, true, true),
+        ] {
+            let mut options = CompressionOptions::new(5, 0.1);
+            options.min_major_version = major_version;
+            options.entropy_precision = entropy_precision;
+            options.compact_jump_table = compact_jump_table;
+            if decoding_sections {
+                options.keyframe_interval = Some(2);
+                options.prediction_scheme = PredictionScheme::LeastSquaresPerLevel;
+                options.model_contexts = Some(ModelContexts::uniform(
+                    VOCAB_SIZE as u32,
+                    2,
+                    EMBEDDING_DIM as u32,
+                    2,
+                ));
+                options.model_sharing = ModelSharing::TreeLevels;
+            }
+            let mut compressed = Vec::new();
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                Some(&vocab),
+                Some(&labels),
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+            mutate_and_exercise(&compressed, &mut rng);
+        }
+
+        fn mutate_and_exercise(compressed: &[u8], rng: &mut StdRng) {
+            let original = compressed
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect::<Vec<_>>();
+            assert!(exercise(original.clone()));
+
+            let special_values = [
+                0,
+                1,
+                2,
+                0xffff,
+                1 << 16,
+                u32::MAX,
+                u32::MAX - 1,
+                original.len() as u32,
+                original.len() as u32 - 1,
+                original.len() as u32 + 1,
+            ];
+
+            // Set each header field to each special value.
+            let header_size = Layout::header_size(original[1], original[2]).unwrap();
+            for index in 1..header_size {
+                for &value in &special_values {
+                    let mut data = original.clone();
+                    data[index] = value;
+                    exercise(data);
+                }
+            }
+
+            // Set each word after the header to a huge value, so that sizes and indices
+            // in all sections get tested before they're used for allocations.
+            for index in header_size..original.len() {
+                let mut data = original.clone();
+                data[index] = u32::MAX - 1;
+                exercise(data);
+            }
+
+            let mut num_accepted = 0;
+            for _ in 0..3000 {
+                let mut data = original.clone();
+                for _ in 0..rng.random_range(1..=3) {
+                    let index = rng.random_range(0..data.len());
+                    match rng.random_range(0..3) {
+                        0 => data[index] ^= 1 << rng.random_range(0..32),
+                        1 => {
+                            data[index] = special_values[rng.random_range(0..special_values.len())]
+                        }
+                        _ => data[index] = rng.random(),
+                    }
+                }
+                num_accepted += exercise(data) as usize;
+            }
+            // Mutations of the compressed data are undetectable, so some files should
+            // still be accepted.
+            assert!(num_accepted > 0);
+
+            // Truncate files, both with and without adjusting `file_size` accordingly.
+            for len in 0..original.len() {
+                let data = original[..len].to_vec();
+                exercise(data.clone());
+                if len >= header_size {
+                    let mut data = data;
+                    data[3] = len as u32; // Also correct for version 2 since `len < 2^32`.
+                    exercise(data);
+                }
+            }
+        }
+
+        /// Returns `true` if the file was accepted.
+        fn exercise(data: Vec<u32>) -> bool {
+            let bytes = data
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect::<Vec<u8>>();
+            let _ = EmbeddingFile::from_reader(&bytes[..]);
+            let _ = EmbeddingFile::from_bytes(as_bytes(&data));
+
+            // The lazy reader checks jump pointers only when it needs them, so it may
+            // accept files that `EmbeddingFile::new` rejects, but queries on these
+            // files must return errors rather than panic.
+            let lazy = LazyEmbeddingFile::with_page_size(InMemoryRangeSource::new(&bytes), 64, 4)
+                .ok()
+                .filter(|lazy| is_small(lazy.header()))
+                .map(LazyEmbeddingFile::into_random_access_reader);
+
+            let file = match EmbeddingFile::new(&data[..]) {
+                Ok(file) => file,
+                Err(_) => {
+                    if let Some(lazy) = lazy {
+                        let _ = run_queries(&lazy);
+                    }
+                    return false;
+                }
+            };
+
+            file.precompute_entropy_models();
+            if !is_small(file.header()) {
+                // Don't decode files that claim to have huge embeddings (to keep memory
+                // consumption of the test low). These are valid as far as `new` can tell.
+                return true;
+            }
+
+            for t in 0..file.header().num_timesteps {
+                let _ = file.timestep_label(t);
+            }
+            if let Some(vocabulary) = file.vocabulary() {
+                for word in vocabulary.iter() {
+                    assert!(file.word_to_id(word).is_some());
+                }
+            }
+
+            let reader = file.into_random_access_reader();
+            let results = run_queries(&reader).unwrap();
+            assert_eq!(run_queries(&lazy.unwrap()).unwrap(), results);
+
+            true
+        }
+
+        fn is_small(header: &FileHeader) -> bool {
+            header.num_timesteps as u64 * header.vocab_size as u64 * header.embedding_dim as u64
+                <= 1 << 16
+        }
+
+        #[allow(clippy::type_complexity)]
+        fn run_queries<F: TimestepSource<Symbol = i16>>(
+            reader: &RandomAccessReader<F>,
+        ) -> Result<(Vec<Vec<i16>>, Vec<Vec<u32>>, Vec<u32>, Vec<u32>)> {
+            let header = reader.file().header();
+            let (num_timesteps, vocab_size) = (header.num_timesteps, header.vocab_size);
+            let last = vocab_size - 1;
+
+            let mut embeddings = Vec::new();
+            let mut most_related = Vec::new();
+            for t in 0..num_timesteps {
+                embeddings.push(reader.get_embeddings_at(t)?.into_inner());
+                most_related.push(
+                    reader
+                        .most_related_to_at_t(vec![0, last], t, 3)?
+                        .into_inner(),
+                );
+            }
+            // Compare bit patterns since mutated scale factors may be NaN.
+            let trajectories = reader
+                .pairwise_trajectories(vec![0, last], vec![last, 0])?
+                .into_inner()
+                .into_iter()
+                .map(f32::to_bits)
+                .collect();
+            let largest_changes = reader.largest_changes_wrt(last, 3, 1, 1)?;
+            assert!(reader.get_embeddings_at(num_timesteps).is_err());
+            assert!(reader.largest_changes_wrt(vocab_size, 3, 1, 1).is_err());
+
+            Ok((embeddings, most_related, trajectories, largest_changes))
+        }
     }
 }
@@ -0,0 +1,253 @@
+//! The optional section that splits each time step into several entropy model
+//! contexts
+
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that splits each time step into several entropy
+/// model contexts (since versions 1.2 and 2.1).
+pub const MODEL_CONTEXTS_SECTION_TAG: u32 = u32::from_le_bytes(*b"mctx");
+
+/// Assignment of the embedding vector components of each time step to separate
+/// entropy models.
+///
+/// By default, all residuals of a time step are coded with a single entropy model.
+/// Since residuals of some embedding dimensions (or of frequent vs. rare words)
+/// usually have a much larger variance than others, a file can instead have one
+/// entropy model per time step and *context*, where the context of a component is
+/// determined by the *word range* of its word and by the *dimension bucket* of its
+/// dimension. Word ranges are consecutive ranges of word indices (e.g., frequency
+/// bands if words are sorted by frequency), and dimension buckets are arbitrary
+/// groups of embedding dimensions.
+///
+/// Each additional context costs one entropy model per time step, so it only pays
+/// off for large vocabularies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelContexts {
+    /// The first word index of each word range, starting at zero and strictly
+    /// increasing.
+    word_range_starts: Box<[u32]>,
+
+    /// The bucket of each embedding dimension. Each bucket from zero to the largest
+    /// one contains at least one dimension.
+    dimension_buckets: Box<[u32]>,
+
+    num_dimension_buckets: u32,
+}
+
+impl ModelContexts {
+    /// Creates a single context for all components, which is what files without a
+    /// model contexts section use.
+    ///
+    /// Panics if `embedding_dim == 0`.
+    pub fn single(embedding_dim: u32) -> Self {
+        Self::new(vec![0], vec![0; embedding_dim as usize])
+    }
+
+    /// Creates contexts from the first word index of each word range and the bucket
+    /// of each embedding dimension.
+    ///
+    /// Panics unless `word_range_starts` starts with zero and is strictly
+    /// increasing, `dimension_buckets` is not empty, and each bucket between zero
+    /// and the largest one contains at least one dimension.
+    pub fn new(word_range_starts: Vec<u32>, dimension_buckets: Vec<u32>) -> Self {
+        Self::validate(word_range_starts, dimension_buckets)
+            .expect("invalid word ranges or dimension buckets")
+    }
+
+    /// Splits the words into `num_word_ranges` ranges and the dimensions into
+    /// `num_dimension_buckets` buckets of consecutive dimensions, all of
+    /// approximately equal size.
+    ///
+    /// Panics if any argument is zero or if there are more ranges or buckets than
+    /// words or dimensions, respectively.
+    pub fn uniform(
+        vocab_size: u32,
+        num_word_ranges: u32,
+        embedding_dim: u32,
+        num_dimension_buckets: u32,
+    ) -> Self {
+        assert!(num_word_ranges != 0 && num_word_ranges <= vocab_size);
+        assert!(num_dimension_buckets != 0 && num_dimension_buckets <= embedding_dim);
+        let word_range_starts = (0..num_word_ranges)
+            .map(|i| (i as u64 * vocab_size as u64 / num_word_ranges as u64) as u32)
+            .collect();
+        let dimension_buckets = (0..embedding_dim)
+            .map(|d| (d as u64 * num_dimension_buckets as u64 / embedding_dim as u64) as u32)
+            .collect();
+        Self::new(word_range_starts, dimension_buckets)
+    }
+
+    fn validate(word_range_starts: Vec<u32>, dimension_buckets: Vec<u32>) -> Option<Self> {
+        if word_range_starts.first() != Some(&0)
+            || word_range_starts.windows(2).any(|pair| pair[0] >= pair[1])
+        {
+            return None;
+        }
+        let num_dimension_buckets = dimension_buckets.iter().max()?.checked_add(1)?;
+        if num_dimension_buckets as usize > dimension_buckets.len() {
+            return None;
+        }
+        let mut used = vec![false; num_dimension_buckets as usize];
+        for &bucket in &dimension_buckets {
+            used[bucket as usize] = true;
+        }
+        if used.contains(&false) {
+            return None;
+        }
+        Some(Self {
+            word_range_starts: word_range_starts.into(),
+            dimension_buckets: dimension_buckets.into(),
+            num_dimension_buckets,
+        })
+    }
+
+    /// Returns the first word index of each word range.
+    pub fn word_range_starts(&self) -> &[u32] {
+        &self.word_range_starts
+    }
+
+    /// Returns the bucket of each embedding dimension.
+    pub fn dimension_buckets(&self) -> &[u32] {
+        &self.dimension_buckets
+    }
+
+    /// Returns the number of dimension buckets.
+    pub fn num_dimension_buckets(&self) -> u32 {
+        self.num_dimension_buckets
+    }
+
+    /// Returns the number of contexts, i.e., the number of entropy models per time
+    /// step, which is the number of word ranges times the number of dimension
+    /// buckets.
+    pub fn len(&self) -> usize {
+        self.word_range_starts.len() * self.num_dimension_buckets as usize
+    }
+
+    /// Always returns `false` since there is at least one context.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns `LengthMismatch` unless the contexts fit to embeddings with the
+    /// provided vocabulary size and embedding dimension.
+    pub(crate) fn check_shape(&self, vocab_size: usize, embedding_dim: usize) -> Result<()> {
+        if self.dimension_buckets.len() != embedding_dim {
+            return Err(Error::LengthMismatch {
+                what: "dimension buckets",
+                expected: embedding_dim,
+                found: self.dimension_buckets.len(),
+            });
+        }
+        let last_start = *self.word_range_starts.last().expect("at least one range");
+        if last_start as usize >= vocab_size {
+            return Err(Error::LengthMismatch {
+                what: "words covered by the word ranges",
+                expected: vocab_size,
+                found: last_start as usize + 1,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the entropy models for the word with index `word_index`, one per
+    /// dimension bucket, given the entropy models `models` of all contexts of a
+    /// time step (in the order of the file format).
+    #[inline(always)]
+    pub(crate) fn models_of_word<'a, M>(&self, models: &'a [M], word_index: u32) -> &'a [M] {
+        let num_buckets = self.num_dimension_buckets as usize;
+        let range = self
+            .word_range_starts
+            .partition_point(|&start| start <= word_index)
+            - 1;
+        &models[range * num_buckets..(range + 1) * num_buckets]
+    }
+
+    /// Returns the index of the context of the component with the given word index
+    /// and dimension.
+    #[inline(always)]
+    pub(crate) fn context(&self, word_index: u32, dimension: usize) -> usize {
+        let range = self
+            .word_range_starts
+            .partition_point(|&start| start <= word_index)
+            - 1;
+        range * self.num_dimension_buckets as usize + self.dimension_buckets[dimension] as usize
+    }
+
+    /// Serializes the contexts into the payload of a model contexts section, which
+    /// consists of the number of word ranges, the first word index of each word
+    /// range, and the bucket of each embedding dimension.
+    pub(crate) fn serialize(&self) -> Vec<u32> {
+        std::iter::once(self.word_range_starts.len() as u32)
+            .chain(self.word_range_starts.iter().copied())
+            .chain(self.dimension_buckets.iter().copied())
+            .collect()
+    }
+
+    /// Inverse of [`serialize`](#method.serialize).
+    ///
+    /// Returns `Error::InvalidSection` unless `serialized` describes valid contexts
+    /// for a file with the provided vocabulary size and embedding dimension.
+    pub(crate) fn deserialize(
+        serialized: &[u32],
+        vocab_size: u32,
+        embedding_dim: u32,
+    ) -> Result<Self> {
+        let invalid = || Error::InvalidSection {
+            tag: MODEL_CONTEXTS_SECTION_TAG,
+        };
+        let (&num_word_ranges, rest) = serialized.split_first().ok_or_else(invalid)?;
+        if rest.len() as u64 != num_word_ranges as u64 + embedding_dim as u64 {
+            return Err(invalid());
+        }
+        let (word_range_starts, dimension_buckets) = rest.split_at(num_word_ranges as usize);
+        let contexts = Self::validate(word_range_starts.to_vec(), dimension_buckets.to_vec())
+            .ok_or_else(invalid)?;
+        contexts
+            .check_shape(vocab_size as usize, embedding_dim as usize)
+            .map_err(|_| invalid())?;
+        Ok(contexts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let contexts = ModelContexts::new(vec![0, 3, 10], vec![1, 0, 0, 1, 2]);
+        assert_eq!(contexts.len(), 9);
+        assert_eq!(contexts.num_dimension_buckets(), 3);
+        assert_eq!(contexts.serialize(), [3, 0, 3, 10, 1, 0, 0, 1, 2]);
+        assert_eq!(
+            ModelContexts::deserialize(&contexts.serialize(), 11, 5).unwrap(),
+            contexts
+        );
+        assert!(ModelContexts::deserialize(&contexts.serialize(), 10, 5).is_err());
+        assert!(ModelContexts::deserialize(&contexts.serialize(), 11, 4).is_err());
+        assert!(ModelContexts::deserialize(&[], 11, 5).is_err());
+        assert!(ModelContexts::deserialize(&[1, 1, 0, 0, 0, 0, 0], 11, 5).is_err());
+        assert!(ModelContexts::deserialize(&[2, 0, 0, 0, 0, 0, 0, 0], 11, 5).is_err());
+        assert!(ModelContexts::deserialize(&[1, 0, 0, 2, 0, 0, 0], 11, 5).is_err());
+        assert!(ModelContexts::deserialize(&[u32::MAX, 0, 0, 0, 0, 0, 0], 11, 5).is_err());
+        // Huge bucket indices get rejected without allocating anything for them.
+        assert!(ModelContexts::deserialize(&[1, 0, 0, 0, 0, 0, u32::MAX - 1], 11, 5).is_err());
+
+        assert_eq!(contexts.context(0, 0), 1);
+        assert_eq!(contexts.context(2, 4), 2);
+        assert_eq!(contexts.context(3, 1), 3);
+        assert_eq!(contexts.context(10, 3), 7);
+        let models = (0..9).collect::<Vec<_>>();
+        assert_eq!(contexts.models_of_word(&models, 9), &[3, 4, 5]);
+        assert_eq!(contexts.models_of_word(&models, 10), &[6, 7, 8]);
+    }
+
+    #[test]
+    fn uniform() {
+        let contexts = ModelContexts::uniform(10, 3, 5, 2);
+        assert_eq!(contexts.word_range_starts(), &[0, 3, 6]);
+        assert_eq!(contexts.dimension_buckets(), &[0, 0, 0, 1, 1]);
+        assert_eq!(ModelContexts::single(3), ModelContexts::uniform(7, 1, 3, 1));
+        assert_eq!(ModelContexts::single(3).len(), 1);
+    }
+}
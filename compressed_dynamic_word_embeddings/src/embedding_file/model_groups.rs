@@ -0,0 +1,194 @@
+//! The optional section that lets several time steps share their entropy models
+
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that assigns the time steps to groups with shared
+/// entropy models (since versions 1.2 and 2.1).
+pub const MODEL_GROUPS_SECTION_TAG: u32 = u32::from_le_bytes(*b"mgrp");
+
+/// The rule by which the builder decides which time steps share their entropy
+/// models (see
+/// [`CompressionOptions::model_sharing`](../builder/struct.CompressionOptions.html#structfield.model_sharing)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum ModelSharing {
+    /// Each time step has its own entropy models. This is what files without a
+    /// model groups section do, so the builder doesn't write one in this case.
+    #[default]
+    PerTimestep,
+
+    /// Time steps at the same level of the bisection tree share their entropy
+    /// models. Keyframes form one level, predicted roots of appended segments
+    /// another one, and the time steps that bisect a range form one level per
+    /// bisection depth. Residuals at the same level tend to have similar
+    /// distributions since their parents are equally far away in time.
+    TreeLevels,
+
+    /// Uses [`TreeLevels`](#variant.TreeLevels) if this is estimated to lead to a
+    /// smaller file than [`PerTimestep`](#variant.PerTimestep), and
+    /// `PerTimestep` otherwise. Sharing usually pays off for small vocabularies or
+    /// many time steps, where the entropy models make up a noticeable part of the
+    /// file.
+    Automatic,
+}
+
+/// Assignment of the time steps to groups that share their entropy models.
+///
+/// By default, each time step has its own entropy models (one per
+/// [model context](../model_contexts/struct.ModelContexts.html)). A file can
+/// instead store the entropy models only once per *group* of time steps, which
+/// makes the entropy models section smaller and the file faster to load. Each time
+/// step references its group by index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelGroups {
+    /// The group of each time step. Each group from zero to the largest one contains
+    /// at least one time step.
+    groups: Box<[u32]>,
+
+    num_groups: u32,
+}
+
+impl ModelGroups {
+    /// Creates one group per time step, which is what files without a model groups
+    /// section use.
+    ///
+    /// Panics if `num_timesteps == 0`.
+    pub fn per_timestep(num_timesteps: u32) -> Self {
+        Self::new((0..num_timesteps).collect())
+    }
+
+    /// Creates groups from the group index of each time step.
+    ///
+    /// Panics if `groups` is empty or if some group between zero and the largest one
+    /// contains no time step.
+    pub fn new(groups: Vec<u32>) -> Self {
+        Self::validate(groups).expect("invalid model groups")
+    }
+
+    fn validate(groups: Vec<u32>) -> Option<Self> {
+        let num_groups = groups.iter().max()?.checked_add(1)?;
+        if num_groups as usize > groups.len() {
+            return None;
+        }
+        let mut used = vec![false; num_groups as usize];
+        for &group in &groups {
+            used[group as usize] = true;
+        }
+        if used.contains(&false) {
+            return None;
+        }
+        Some(Self {
+            groups: groups.into(),
+            num_groups,
+        })
+    }
+
+    /// Returns the group of each time step.
+    pub fn groups(&self) -> &[u32] {
+        &self.groups
+    }
+
+    /// Returns the group of time step `t`.
+    ///
+    /// Panics if `t` is out of bounds.
+    pub fn group_of(&self, t: u32) -> u32 {
+        self.groups[t as usize]
+    }
+
+    /// Returns the number of groups, i.e., the number of sets of entropy models in
+    /// the file.
+    pub fn len(&self) -> usize {
+        self.num_groups as usize
+    }
+
+    /// Always returns `false` since there is at least one group.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns whether each time step forms its own group, in the order of the
+    /// time steps (in which case the file doesn't need a model groups section).
+    pub fn is_per_timestep(&self) -> bool {
+        self.groups
+            .iter()
+            .enumerate()
+            .all(|(t, &group)| group as usize == t)
+    }
+
+    /// Returns the first time step in group `group` (for error messages).
+    pub(crate) fn first_timestep(&self, group: usize) -> u32 {
+        self.groups
+            .iter()
+            .position(|&g| g as usize == group)
+            .expect("every group contains a time step") as u32
+    }
+
+    /// Returns the groups after appending `num_timesteps` time steps, each of which
+    /// forms a new group.
+    pub(crate) fn appended(&self, num_timesteps: u32) -> Result<Self> {
+        let num_groups = self
+            .num_groups
+            .checked_add(num_timesteps)
+            .ok_or(Error::TooLarge)?;
+        let groups = self
+            .groups
+            .iter()
+            .copied()
+            .chain(self.num_groups..num_groups)
+            .collect();
+        Ok(Self { groups, num_groups })
+    }
+
+    /// Serializes the groups into the payload of a model groups section, which
+    /// consists of the group index of each time step.
+    pub(crate) fn serialize(&self) -> Vec<u32> {
+        self.groups.to_vec()
+    }
+
+    /// Inverse of [`serialize`](#method.serialize).
+    ///
+    /// Returns `Error::InvalidSection` unless `serialized` assigns each of
+    /// `num_timesteps` time steps to a group without leaving out any group index.
+    pub(crate) fn deserialize(serialized: &[u32], num_timesteps: u32) -> Result<Self> {
+        let invalid = || Error::InvalidSection {
+            tag: MODEL_GROUPS_SECTION_TAG,
+        };
+        if serialized.len() as u64 != num_timesteps as u64 {
+            return Err(invalid());
+        }
+        Self::validate(serialized.to_vec()).ok_or_else(invalid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let groups = ModelGroups::new(vec![0, 0, 2, 1, 2]);
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups.group_of(3), 1);
+        assert_eq!(groups.first_timestep(2), 2);
+        assert!(!groups.is_per_timestep());
+        assert_eq!(groups.serialize(), [0, 0, 2, 1, 2]);
+        assert_eq!(
+            ModelGroups::deserialize(&groups.serialize(), 5).unwrap(),
+            groups
+        );
+        assert!(ModelGroups::deserialize(&groups.serialize(), 6).is_err());
+        assert!(ModelGroups::deserialize(&[], 0).is_err());
+        assert!(ModelGroups::deserialize(&[0, 2, 2], 3).is_err());
+        assert!(ModelGroups::deserialize(&[0, 3], 2).is_err());
+        assert!(ModelGroups::deserialize(&[u32::MAX], 1).is_err());
+
+        let appended = groups.appended(2).unwrap();
+        assert_eq!(appended.groups(), &[0, 0, 2, 1, 2, 3, 4]);
+        assert_eq!(appended.len(), 5);
+        assert!(ModelGroups::per_timestep(4).is_per_timestep());
+        assert!(ModelGroups::per_timestep(4)
+            .appended(3)
+            .unwrap()
+            .is_per_timestep());
+    }
+}
@@ -0,0 +1,220 @@
+//! Decoding that doesn't depend on the byte order of the platform
+//!
+//! The file format stores all `u32` and `u16` words in little endian byte order and
+//! packs pairs of `u16`s into `u32`s, lower half first. Once a file is loaded into
+//! a slice of (native) `u32`s, little endian platforms can therefore simply
+//! reinterpret it as a slice of `u16`s, which is what the fast path does. The
+//! functions and types in this module split each `u32` arithmetically instead,
+//! which works on all platforms. They are used on big endian platforms, and they
+//! are tested on all platforms.
+
+use std::borrow::Cow;
+use std::convert::Infallible;
+
+use constriction::{backends::ReadWords, PosSeek, Seek, Stack};
+
+use super::get_u16_slice;
+
+/// Returns the `u16`s packed into `words`, without copying on little endian
+/// platforms.
+pub(super) fn u16_words(words: &[u32]) -> Cow<'_, [u16]> {
+    if cfg!(target_endian = "little") {
+        Cow::Borrowed(get_u16_slice(words))
+    } else {
+        Cow::Owned(split_u16s(words))
+    }
+}
+
+/// Returns the `u16`s packed into `words` (lower half of each `u32` first).
+pub(super) fn split_u16s(words: &[u32]) -> Vec<u16> {
+    words
+        .iter()
+        .flat_map(|&word| [word as u16, (word >> 16) as u16])
+        .collect()
+}
+
+/// Backend for the ANS decoder that reads the `u16`s packed into a slice of `u32`s.
+///
+/// Positions are measured in units of two bytes, like offsets in the jump table.
+#[cfg_attr(target_endian = "little", allow(dead_code))] // Only used in tests.
+#[derive(Debug, Clone)]
+pub struct SplitWords<'data> {
+    words: &'data [u32],
+    pos: usize,
+}
+
+#[cfg_attr(target_endian = "little", allow(dead_code))]
+impl<'data> SplitWords<'data> {
+    /// Returns `None` if `pos` is out of bounds.
+    pub(super) fn new_at_pos(words: &'data [u32], pos: usize) -> Option<Self> {
+        if pos <= 2 * words.len() {
+            Some(Self { words, pos })
+        } else {
+            None
+        }
+    }
+}
+
+impl ReadWords<u16, Stack> for SplitWords<'_> {
+    type ReadError = Infallible;
+
+    #[inline(always)]
+    fn read(&mut self) -> Result<Option<u16>, Infallible> {
+        let word = self
+            .words
+            .get(self.pos / 2)
+            .map(|&word| (word >> (16 * (self.pos % 2))) as u16);
+        if word.is_some() {
+            self.pos += 1;
+        }
+        Ok(word)
+    }
+}
+
+impl PosSeek for SplitWords<'_> {
+    type Position = usize;
+}
+
+impl Seek for SplitWords<'_> {
+    fn seek(&mut self, pos: usize) -> Result<(), ()> {
+        if pos <= 2 * self.words.len() {
+            self.pos = pos;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        builder::{write_compressed_dwe_file_with_options, CompressionOptions},
+        compressed_words32, deserialize_decoder_models,
+        model_contexts::ModelContexts,
+        model_groups::ModelSharing,
+        EmbeddingFile, EntropyPrecision, Timestep, TimestepDecoder, TimestepReader,
+    };
+    use super::*;
+    use crate::tensors::RankThreeTensor;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Decodes every time step with the byte order independent backend (which is
+    /// what big endian platforms use) and compares to the default backend, for both
+    /// precisions whose compressed data consists of `u16`s (the latter with several
+    /// model contexts).
+    #[test]
+    fn split_words_match_default_backend() {
+        split_words_match_default_backend_with(EntropyPrecision::Bits12);
+        split_words_match_default_backend_with(EntropyPrecision::Bits16);
+    }
+
+    fn split_words_match_default_backend_with(entropy_precision: EntropyPrecision) {
+        const NUM_TIMESTEPS: usize = 6;
+        const VOCAB_SIZE: usize = 50;
+        const EMBEDDING_DIM: usize = 7;
+        const JUMP_INTERVAL: u32 = 8;
+
+        let mut rng = StdRng::seed_from_u64(20_201_018);
+        let uncompressed = (0..NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-30..=30i16))
+            .collect();
+        let uncompressed =
+            RankThreeTensor::from_flattened(uncompressed, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+        let mut options = CompressionOptions::new(JUMP_INTERVAL, 0.1);
+        options.entropy_precision = entropy_precision;
+        if entropy_precision == EntropyPrecision::Bits16 {
+            options.model_contexts = Some(ModelContexts::uniform(
+                VOCAB_SIZE as u32,
+                3,
+                EMBEDDING_DIM as u32,
+                2,
+            ));
+            options.model_sharing = ModelSharing::TreeLevels;
+        }
+        let mut compressed = Vec::new();
+        write_compressed_dwe_file_with_options(
+            uncompressed.as_view(),
+            None,
+            None,
+            &options,
+            &mut compressed,
+        )
+        .unwrap();
+        let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+        let words = file.as_slice_u32();
+
+        let split = split_u16s(words);
+        assert_eq!(split.len(), 2 * words.len());
+        assert_eq!(&split[..], &*u16_words(words));
+        if cfg!(target_endian = "little") {
+            assert_eq!(&split[..], get_u16_slice(words));
+        }
+
+        let header = file.header();
+        let models = deserialize_decoder_models::<i16>(
+            header,
+            file.model_groups(),
+            file.model_contexts().len(),
+            &split_u16s(&words[file.layout.header_size..file.layout.jump_table_address]),
+        )
+        .unwrap();
+        let compressed_words = &words[file.layout.compressed_data_start..];
+
+        let word_indices = [0, 1, 17, 16, 49, 3, 31, 32, 24];
+        for t in 0..NUM_TIMESTEPS as u32 {
+            let mut expected = file.timestep(t).unwrap();
+            let jump_table = expected.jump_table;
+            let decoder = TimestepDecoder::new(
+                &models,
+                file.model_contexts().len(),
+                file.model_groups().group_of(t) as usize,
+                jump_table.get(0),
+                |pos| SplitWords::new_at_pos(compressed_words, pos),
+                |pos| compressed_words32(compressed_words, pos),
+            )
+            .unwrap();
+            let mut found = Timestep::new(
+                decoder,
+                file.model_contexts(),
+                jump_table,
+                VOCAB_SIZE as u32,
+                JUMP_INTERVAL,
+            );
+
+            for _ in 0..VOCAB_SIZE {
+                assert_eq!(read_vector(&mut found), read_vector(&mut expected));
+            }
+            for &word_index in &word_indices {
+                found.jump_to(word_index).unwrap();
+                expected.jump_to(word_index).unwrap();
+                assert_eq!(read_vector(&mut found), read_vector(&mut expected));
+            }
+        }
+    }
+
+    fn read_vector(reader: &mut impl TimestepReader<Symbol = i16>) -> Vec<i16> {
+        let mut vector = Vec::new();
+        reader
+            .read_single_embedding_vector(0..7, |symbol, _| vector.push(symbol))
+            .unwrap();
+        vector
+    }
+
+    #[test]
+    fn split_words_seek_bounds() {
+        let words = [0x2222_1111, 0x4444_3333];
+        let mut backend = SplitWords::new_at_pos(&words, 1).unwrap();
+        assert_eq!(backend.read(), Ok(Some(0x2222)));
+        assert_eq!(backend.read(), Ok(Some(0x3333)));
+        assert_eq!(backend.read(), Ok(Some(0x4444)));
+        assert_eq!(backend.read(), Ok(None));
+
+        assert!(backend.seek(0).is_ok());
+        assert_eq!(backend.read(), Ok(Some(0x1111)));
+        assert!(backend.seek(5).is_err());
+        assert!(SplitWords::new_at_pos(&words, 4).is_some());
+        assert!(SplitWords::new_at_pos(&words, 5).is_none());
+    }
+}
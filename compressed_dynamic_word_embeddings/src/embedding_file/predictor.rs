@@ -0,0 +1,276 @@
+//! The optional section that specifies how time steps get predicted from their
+//! parents in the bisection tree
+
+use super::symbol::Symbol;
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that stores the weights with which time steps get
+/// predicted from their parents (since versions 1.2 and 2.1).
+pub const PREDICTOR_SECTION_TAG: u32 = u32::from_le_bytes(*b"pred");
+
+/// Number of fractional bits of the fixed point weights in a [`Predictor`].
+pub const WEIGHT_FRACTIONAL_BITS: u32 = 16;
+
+/// A weight of one in fixed point representation.
+pub(crate) const WEIGHT_ONE: i32 = 1 << WEIGHT_FRACTIONAL_BITS;
+
+/// The rule by which the builder chooses how each time step gets predicted from its
+/// two parents in the bisection tree (see
+/// [`CompressionOptions::prediction_scheme`](../builder/struct.CompressionOptions.html#structfield.prediction_scheme)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum PredictionScheme {
+    /// Predicts each time step as the integer mean of its two parents, rounded
+    /// toward zero. This is what files without a predictor section do, so the
+    /// builder doesn't write one for this scheme.
+    #[default]
+    Mean,
+
+    /// Interpolates linearly between the two parents according to their distance
+    /// in time from the predicted time step.
+    DistanceWeighted,
+
+    /// Fits one pair of weights per level of the bisection tree by least squares,
+    /// i.e., so that the weighted sum of the parents is as close as possible to
+    /// the predicted time step (before quantization of the weights).
+    LeastSquaresPerLevel,
+
+    /// Predicts each time step by its left (i.e., earlier) parent only.
+    PreviousOnly,
+}
+
+impl PredictionScheme {
+    fn to_u32(self) -> u32 {
+        match self {
+            PredictionScheme::Mean => 0,
+            PredictionScheme::DistanceWeighted => 1,
+            PredictionScheme::LeastSquaresPerLevel => 2,
+            PredictionScheme::PreviousOnly => 3,
+        }
+    }
+
+    fn from_u32(scheme: u32) -> Option<Self> {
+        match scheme {
+            0 => Some(PredictionScheme::Mean),
+            1 => Some(PredictionScheme::DistanceWeighted),
+            2 => Some(PredictionScheme::LeastSquaresPerLevel),
+            3 => Some(PredictionScheme::PreviousOnly),
+            _ => None,
+        }
+    }
+}
+
+/// Weights with which each time step gets predicted from its two parents.
+///
+/// A time step with parent values `left` and `right` and weights `(w_left,
+/// w_right)` (in fixed point with [`WEIGHT_FRACTIONAL_BITS`] fractional bits) gets
+/// predicted as `(w_left * left + w_right * right + 2^15) >> 16`, clamped to the
+/// range of the file's [`SymbolType`](../symbol/enum.SymbolType.html). Readers then
+/// add the decoded residual with wraparound.
+///
+/// The weights of keyframes are irrelevant since keyframes don't get predicted.
+/// Predicted segment roots (see
+/// [`Segments`](../segments/struct.Segments.html)) have the same time step as both
+/// parents, so only the sum of their weights matters. Files without a predictor
+/// section use the integer mean of the two parents instead (see
+/// [`PredictionScheme::Mean`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Predictor {
+    /// The [`PredictionScheme`] with which the weights were chosen, which readers
+    /// don't need. Unknown schemes are allowed for forward compatibility.
+    scheme: u32,
+
+    /// The fixed point weights `[w_left, w_right]` of each time step.
+    weights: Box<[[i32; 2]]>,
+}
+
+impl Predictor {
+    /// Creates a predictor from fixed point weights, one pair per time step.
+    pub(crate) fn new(scheme: PredictionScheme, weights: Vec<[i32; 2]>) -> Self {
+        Self {
+            scheme: scheme.to_u32(),
+            weights: weights.into(),
+        }
+    }
+
+    /// Returns the scheme with which the weights were chosen, or `None` if the
+    /// file was written by a newer library that uses a scheme unknown to this one.
+    /// Readers don't need to know the scheme to decode the file.
+    pub fn scheme(&self) -> Option<PredictionScheme> {
+        PredictionScheme::from_u32(self.scheme)
+    }
+
+    /// Returns the weights `(w_left, w_right)` of the left and right parent of time
+    /// step `t`, converted to floating point.
+    ///
+    /// Panics if `t` is out of bounds.
+    pub fn weights(&self, t: u32) -> (f64, f64) {
+        let [left, right] = self.weights[t as usize];
+        (
+            left as f64 / WEIGHT_ONE as f64,
+            right as f64 / WEIGHT_ONE as f64,
+        )
+    }
+
+    /// Returns the predictor after appending time steps with the weights `weights`.
+    pub(crate) fn appended(&self, weights: &[[i32; 2]]) -> Self {
+        Self {
+            scheme: self.scheme,
+            weights: self.weights.iter().chain(weights).copied().collect(),
+        }
+    }
+
+    /// Serializes the predictor into the payload of a predictor section, which
+    /// consists of the scheme followed by the two weights of each time step (as
+    /// two's complement `i32`s).
+    pub(crate) fn serialize(&self) -> Vec<u32> {
+        std::iter::once(self.scheme)
+            .chain(
+                self.weights
+                    .iter()
+                    .flat_map(|&[left, right]| [left as u32, right as u32]),
+            )
+            .collect()
+    }
+
+    /// Inverse of [`serialize`](#method.serialize).
+    ///
+    /// Returns `Error::InvalidSection` unless `serialized` contains exactly two
+    /// weights per time step of a file with `num_timesteps` time steps.
+    pub(crate) fn deserialize(serialized: &[u32], num_timesteps: u32) -> Result<Self> {
+        match serialized.split_first() {
+            Some((&scheme, weights)) if weights.len() as u64 == 2 * num_timesteps as u64 => {
+                Ok(Self {
+                    scheme,
+                    weights: weights
+                        .chunks_exact(2)
+                        .map(|pair| [pair[0] as i32, pair[1] as i32])
+                        .collect(),
+                })
+            }
+            _ => Err(Error::InvalidSection {
+                tag: PREDICTOR_SECTION_TAG,
+            }),
+        }
+    }
+}
+
+/// How a single time step gets predicted from its parents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Prediction {
+    /// The integer mean of both parents, rounded toward zero.
+    Mean,
+
+    /// A weighted sum of both parents, see [`Predictor`].
+    Weighted([i32; 2]),
+}
+
+impl Prediction {
+    /// Returns the prediction of time step `t` of a file with the (optional)
+    /// predictor `predictor`.
+    pub(crate) fn of(predictor: Option<&Predictor>, t: usize) -> Self {
+        predictor.map_or(Prediction::Mean, |predictor| {
+            Prediction::Weighted(predictor.weights[t])
+        })
+    }
+
+    #[inline(always)]
+    pub(crate) fn predict<T: Symbol>(self, left: T, right: T) -> T {
+        match self {
+            Prediction::Mean => T::saturating_from_i64((left.to_i64() + right.to_i64()) / 2),
+            Prediction::Weighted([w_left, w_right]) => {
+                // The products fit into an `i64` even for `i32` symbols. Their sum may
+                // not, but then the result gets clamped anyway.
+                let sum = (w_left as i64 * left.to_i64())
+                    .saturating_add(w_right as i64 * right.to_i64())
+                    .saturating_add(WEIGHT_ONE as i64 >> 1);
+                T::saturating_from_i64(sum >> WEIGHT_FRACTIONAL_BITS)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let predictor = Predictor::new(
+            PredictionScheme::LeastSquaresPerLevel,
+            vec![[0, 0], [WEIGHT_ONE, 0], [-3, WEIGHT_ONE + 3]],
+        );
+        let serialized = predictor.serialize();
+        assert_eq!(serialized.len(), 7);
+        assert_eq!(serialized[0], 2);
+        assert_eq!(Predictor::deserialize(&serialized, 3).unwrap(), predictor);
+        assert!(Predictor::deserialize(&serialized, 2).is_err());
+        assert!(Predictor::deserialize(&serialized[..6], 3).is_err());
+        assert!(Predictor::deserialize(&[], 0).is_err());
+        assert_eq!(predictor.weights(2), (-3.0 / 65536.0, 1.0 + 3.0 / 65536.0));
+
+        let mut unknown = serialized.clone();
+        unknown[0] = 100;
+        let unknown = Predictor::deserialize(&unknown, 3).unwrap();
+        assert_eq!(unknown.scheme(), None);
+        assert_eq!(
+            predictor.scheme(),
+            Some(PredictionScheme::LeastSquaresPerLevel)
+        );
+
+        let appended = predictor.appended(&[[1, 2]]);
+        assert_eq!(appended.scheme(), predictor.scheme());
+        assert_eq!(
+            Predictor::deserialize(&appended.serialize(), 4).unwrap(),
+            appended
+        );
+        assert_eq!(
+            Prediction::of(Some(&appended), 3),
+            Prediction::Weighted([1, 2])
+        );
+        assert_eq!(Prediction::of(None, 3), Prediction::Mean);
+    }
+
+    #[test]
+    fn predict() {
+        assert_eq!(Prediction::Mean.predict(3, 6), 4);
+        assert_eq!(Prediction::Mean.predict(-3, -6), -4);
+        assert_eq!(Prediction::Mean.predict(i16::MAX, i16::MAX), i16::MAX);
+
+        let half = WEIGHT_ONE / 2;
+        assert_eq!(Prediction::Weighted([half, half]).predict(3, 6), 5);
+        assert_eq!(Prediction::Weighted([half, half]).predict(-3, -6), -4);
+        assert_eq!(Prediction::Weighted([WEIGHT_ONE, 0]).predict(-7, 100), -7);
+        assert_eq!(
+            Prediction::Weighted([WEIGHT_ONE / 4, 3 * WEIGHT_ONE / 4]).predict(0, 100),
+            75
+        );
+        assert_eq!(
+            Prediction::Weighted([2 * WEIGHT_ONE, 0]).predict(i16::MAX, 0),
+            i16::MAX
+        );
+        assert_eq!(
+            Prediction::Weighted([2 * WEIGHT_ONE, -WEIGHT_ONE]).predict(i16::MIN, i16::MAX),
+            i16::MIN
+        );
+
+        // Other symbol types clamp to their own range.
+        assert_eq!(Prediction::Mean.predict(i8::MIN, i8::MIN), i8::MIN);
+        assert_eq!(
+            Prediction::Weighted([2 * WEIGHT_ONE, 0]).predict(100i8, 0),
+            i8::MAX
+        );
+        assert_eq!(
+            Prediction::Weighted([half, half]).predict(i32::MAX, i32::MAX - 2),
+            i32::MAX - 1
+        );
+        assert_eq!(
+            Prediction::Weighted([i32::MIN, i32::MIN]).predict(i32::MIN, i32::MIN),
+            i32::MAX
+        );
+        assert_eq!(
+            Prediction::Weighted([i32::MAX, i32::MAX]).predict(i32::MIN, i32::MIN),
+            i32::MIN
+        );
+    }
+}
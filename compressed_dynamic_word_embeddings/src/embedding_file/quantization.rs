@@ -0,0 +1,473 @@
+//! Quantization of real valued embeddings into the integers that the file stores
+//!
+//! A compressed dynamic word embeddings file stores each embedding vector component
+//! as an integer `u` (of the file's [`SymbolType`](super::symbol::SymbolType)),
+//! which represents the real number `scale_factor * u` (possibly refined by
+//! [`ScaleFactors`]). The function [`quantize`] turns `f32` embeddings into 16-bit
+//! integers and reports the distortion that this introduces.
+//! The builder function
+//! [`write_compressed_dwe_file_from_float`](../builder/fn.write_compressed_dwe_file_from_float.html)
+//! combines quantization and compression, and
+//! [`write_compressed_dwe_file_with_target_size`](../builder/fn.write_compressed_dwe_file_with_target_size.html)
+//! additionally chooses the quantization step such that the file fits into a size
+//! budget.
+
+use super::{scale_factors::ScaleFactors, symbol::Symbol};
+use crate::{
+    error::{Error, Result},
+    tensors::{RankThreeTensor, RankThreeTensorView},
+};
+
+/// Largest magnitude of a quantized value. Staying within half of the range of
+/// an `i16` guarantees that the residuals of the prediction from neighboring time
+/// steps always fit into an `i16` as well.
+const MAX_ABS_QUANTIZED: f64 = (i16::MAX / 2) as f64;
+
+/// How [`quantize`] chooses the quantization step (i.e., the `scale_factor` in the
+/// file header).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuantizationStep {
+    /// Use exactly the provided step. Returns `Error::QuantizationStepTooSmall` if
+    /// the quantized values would violate the limits of the file format.
+    Fixed(f32),
+
+    /// Use the provided factor times the standard deviation of all (normalized)
+    /// embedding vector components, but never a step so small that the quantized
+    /// values would violate the limits of the file format. Smaller factors lead to
+    /// smaller distortion but larger files.
+    RelativeToStdDev(f32),
+}
+
+/// Settings for [`quantize`].
+///
+/// Create with [`new`](#method.new), which sets all fields that aren't arguments of
+/// `new` to their defaults, and then modify individual fields as needed.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct QuantizationOptions {
+    /// How to choose the quantization step.
+    pub step: QuantizationStep,
+
+    /// Seed for dithering (defaults to `None`, i.e., rounding to the nearest
+    /// integer). If set, a pseudorandom offset that is uniformly distributed in
+    /// `[-0.5, 0.5)` gets added to each value before rounding, which makes the
+    /// quantization error independent of the signal at the cost of a larger mean
+    /// squared error. The same seed always leads to the same result.
+    pub dither_seed: Option<u64>,
+}
+
+impl QuantizationOptions {
+    pub fn new(step: QuantizationStep) -> Self {
+        Self {
+            step,
+            dither_seed: None,
+        }
+    }
+}
+
+/// The distortion that [`quantize`] introduced.
+///
+/// All errors are measured between the original embedding vector components and
+/// the values that readers reconstruct from the quantized ones (e.g., with
+/// [`RandomAccessReader::get_dequantized_embeddings_at`](../../random_access_reader/struct.RandomAccessReader.html#method.get_dequantized_embeddings_at)).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct QuantizationReport {
+    /// The chosen quantization step, which has to be stored as `scale_factor` in
+    /// the file header.
+    pub scale_factor: f32,
+
+    /// Mean of the squared quantization errors over all components.
+    pub mean_squared_error: f64,
+
+    /// Largest magnitude of the quantization error of any component.
+    pub max_abs_error: f32,
+
+    /// Mean of the squares of all original components, e.g., for calculating a
+    /// signal to noise ratio `mean_squared_value / mean_squared_error`.
+    pub mean_squared_value: f64,
+
+    /// Root mean squared quantization error of each time step.
+    pub rms_errors: Vec<f64>,
+}
+
+/// Quantizes real valued embeddings with shape `(num_timesteps, vocab_size,
+/// embedding_dim)`.
+///
+/// If `scale_factors` is provided, each component gets first divided by its scale
+/// factor and then quantized with a step that is common to all components, so that
+/// the quantized values are meant to be stored together with `scale_factors` (see
+/// [`CompressionOptions::scale_factors`](../builder/struct.CompressionOptions.html#structfield.scale_factors)).
+///
+/// The quantized values are small enough that the squared norm of each quantized
+/// embedding vector fits into an `i32` and that the builder never fails with
+/// `Error::ResidualOverflow`. Returns `Error::NonFiniteEmbedding` if any component
+/// is infinite or NaN, `Error::QuantizationStepTooSmall` if a fixed quantization
+/// step violates these limits, and `Error::LengthMismatch` if `scale_factors` has
+/// the wrong shape.
+pub fn quantize(
+    embeddings: RankThreeTensorView<f32>,
+    options: &QuantizationOptions,
+    scale_factors: Option<&ScaleFactors>,
+) -> Result<(RankThreeTensor<i16>, QuantizationReport)> {
+    let statistics = analyze(embeddings, scale_factors)?;
+
+    let step = match options.step {
+        QuantizationStep::Fixed(step) => {
+            if !(step > 0.0 && step.is_finite() && step as f64 >= statistics.min_step) {
+                return Err(Error::QuantizationStepTooSmall {
+                    step,
+                    min_step: f32_at_least(statistics.min_step),
+                });
+            }
+            step
+        }
+        QuantizationStep::RelativeToStdDev(factor) => {
+            match f32_at_least(f64::max(
+                factor as f64 * statistics.std_dev,
+                statistics.min_step,
+            )) {
+                // All values are zero, so any step works.
+                0.0 => 1.0,
+                step => step,
+            }
+        }
+    };
+
+    Ok(quantize_with_step(
+        embeddings,
+        step,
+        options.dither_seed,
+        scale_factors,
+    ))
+}
+
+/// Statistics of the (normalized) embedding vector components that determine which
+/// quantization steps are admissible.
+pub(crate) struct Statistics {
+    /// Smallest quantization step that respects the limits of the file format.
+    pub(crate) min_step: f64,
+
+    /// Standard deviation of all normalized components.
+    pub(crate) std_dev: f64,
+
+    /// Largest magnitude of any normalized component.
+    pub(crate) max_abs: f64,
+}
+
+/// Checks that all values are finite and that `scale_factors` has the right shape,
+/// and collects statistics of the values after dividing them by their scale factors.
+pub(crate) fn analyze(
+    embeddings: RankThreeTensorView<f32>,
+    scale_factors: Option<&ScaleFactors>,
+) -> Result<Statistics> {
+    let (num_timesteps, vocab_size, embedding_dim) = embeddings.shape();
+    if let Some(scale_factors) = scale_factors {
+        scale_factors.check_shape(num_timesteps, embedding_dim)?;
+    }
+
+    let (mut sum, mut sum_of_squares, mut max_abs, mut max_norm) = (0.0, 0.0, 0.0f64, 0.0f64);
+    for t in 0..num_timesteps {
+        let scales = scales_at(scale_factors, t);
+        for (i, embedding) in embeddings.subview(t).iter_subviews().enumerate() {
+            let mut square_norm = 0.0;
+            for (d, (&x, &scale)) in embedding.iter().zip(scales.iter().cycle()).enumerate() {
+                let y = x as f64 / scale as f64;
+                if !y.is_finite() {
+                    return Err(Error::NonFiniteEmbedding {
+                        timestep: t as u32,
+                        word_index: i as u32,
+                        dimension: d as u32,
+                    });
+                }
+                sum += y;
+                square_norm += y * y;
+                max_abs = max_abs.max(y.abs());
+            }
+            sum_of_squares += square_norm;
+            max_norm = max_norm.max(square_norm.sqrt());
+        }
+    }
+
+    // Rounding changes each value by less than one (also with dithering), so it
+    // increases the norm of an embedding vector by less than `sqrt(embedding_dim)`.
+    let max_quantized_norm = (i32::MAX as f64).sqrt() - (embedding_dim as f64).sqrt();
+    if max_quantized_norm <= 0.0 {
+        return Err(Error::TooLarge);
+    }
+    let min_step = f64::max(
+        max_abs / (MAX_ABS_QUANTIZED - 1.0),
+        max_norm / max_quantized_norm,
+    );
+
+    let len = (num_timesteps * vocab_size * embedding_dim) as f64;
+    let mean = sum / len;
+    Ok(Statistics {
+        min_step,
+        std_dev: (sum_of_squares / len - mean * mean).max(0.0).sqrt(),
+        max_abs,
+    })
+}
+
+/// Quantizes with the provided `step`, which must be at least the `min_step` that
+/// [`analyze`] returns for the same `embeddings` and `scale_factors`.
+pub(crate) fn quantize_with_step(
+    embeddings: RankThreeTensorView<f32>,
+    step: f32,
+    dither_seed: Option<u64>,
+    scale_factors: Option<&ScaleFactors>,
+) -> (RankThreeTensor<i16>, QuantizationReport) {
+    let (num_timesteps, vocab_size, embedding_dim) = embeddings.shape();
+    let mut dither = dither_seed.map(SplitMix64);
+    let mut quantized = Vec::with_capacity(num_timesteps * vocab_size * embedding_dim);
+    for t in 0..num_timesteps {
+        let scales = scales_at(scale_factors, t);
+        for embedding in embeddings.subview(t).iter_subviews() {
+            for (&x, &scale) in embedding.iter().zip(scales.iter().cycle()) {
+                let offset = dither.as_mut().map_or(0.0, SplitMix64::next_offset);
+                quantized.push((x as f64 / scale as f64 / step as f64 + offset).round() as i16);
+            }
+        }
+    }
+
+    let quantized =
+        RankThreeTensor::from_flattened(quantized, num_timesteps, vocab_size, embedding_dim);
+    let report = measure_distortion(embeddings, quantized.as_view(), step, scale_factors);
+    (quantized, report)
+}
+
+/// Compares `embeddings` to the values that readers reconstruct from `quantized`
+/// if the file header has `scale_factor = step`.
+pub(crate) fn measure_distortion<T: Symbol>(
+    embeddings: RankThreeTensorView<f32>,
+    quantized: RankThreeTensorView<T>,
+    step: f32,
+    scale_factors: Option<&ScaleFactors>,
+) -> QuantizationReport {
+    let (num_timesteps, vocab_size, embedding_dim) = embeddings.shape();
+    let (mut sum_of_squared_errors, mut max_abs_error) = (0.0, 0.0f32);
+    let mut sum_of_squared_values = 0.0;
+    let mut rms_errors = Vec::with_capacity(num_timesteps);
+    for t in 0..num_timesteps {
+        let scales = scales_at(scale_factors, t);
+        let mut timestep_sum_of_squared_errors = 0.0;
+        for (embedding, quantized) in embeddings
+            .subview(t)
+            .iter_subviews()
+            .zip(quantized.subview(t).iter_subviews())
+        {
+            for ((&x, &q), &scale) in embedding.iter().zip(quantized).zip(scales.iter().cycle()) {
+                // Reconstruct the value in the same way as `RandomAccessReader` does.
+                let error = x - (step * scale) * q.to_i64() as f32;
+                timestep_sum_of_squared_errors += error as f64 * error as f64;
+                max_abs_error = max_abs_error.max(error.abs());
+                sum_of_squared_values += x as f64 * x as f64;
+            }
+        }
+        sum_of_squared_errors += timestep_sum_of_squared_errors;
+        rms_errors
+            .push((timestep_sum_of_squared_errors / (vocab_size * embedding_dim) as f64).sqrt());
+    }
+
+    let len = (num_timesteps * vocab_size * embedding_dim) as f64;
+    QuantizationReport {
+        scale_factor: step,
+        mean_squared_error: sum_of_squared_errors / len,
+        max_abs_error,
+        mean_squared_value: sum_of_squared_values / len,
+        rms_errors,
+    }
+}
+
+/// Returns the scale factors of all dimensions at time step `t`, or a slice of
+/// length one if they don't depend on the dimension.
+fn scales_at(scale_factors: Option<&ScaleFactors>, t: usize) -> &[f32] {
+    scale_factors.map_or(&[1.0], |scale_factors| scale_factors.at_timestep(t as u32))
+}
+
+/// Returns the smallest `f32` that is not smaller than `x`.
+pub(crate) fn f32_at_least(x: f64) -> f32 {
+    let rounded = x as f32;
+    if (rounded as f64) < x {
+        rounded.next_up()
+    } else {
+        rounded
+    }
+}
+
+/// A minimal pseudorandom number generator for dithering, so that the crate
+/// doesn't need to depend on `rand`.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    /// Returns a value that is uniformly distributed in `[-0.5, 0.5)`.
+    fn next_offset(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 * (1.0 / (1u64 << 53) as f64) - 0.5
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::embedding_file::{
+        builder::{write_compressed_dwe_file_from_float, CompressionOptions},
+        EmbeddingFile,
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_embeddings(
+        rng: &mut StdRng,
+        shape: (usize, usize, usize),
+        magnitude: f32,
+    ) -> RankThreeTensor<f32> {
+        let (num_timesteps, vocab_size, embedding_dim) = shape;
+        let data = (0..num_timesteps * vocab_size * embedding_dim)
+            .map(|_| rng.random_range(-magnitude..magnitude))
+            .collect();
+        RankThreeTensor::from_flattened(data, num_timesteps, vocab_size, embedding_dim)
+    }
+
+    #[test]
+    fn quantization_error() {
+        let mut rng = StdRng::seed_from_u64(20_201_201);
+        let embeddings = random_embeddings(&mut rng, (3, 50, 8), 2.0);
+        let embeddings = embeddings.as_view();
+
+        let options = QuantizationOptions::new(QuantizationStep::Fixed(0.01));
+        let (quantized, report) = quantize(embeddings, &options, None).unwrap();
+        assert_eq!(report.scale_factor, 0.01);
+        assert!(report.max_abs_error <= 0.005 * 1.001);
+        assert!(report.mean_squared_error > 0.0);
+        // Uniformly distributed errors have a mean squared error of `step^2 / 12`.
+        assert!((report.mean_squared_error / (0.01f64 * 0.01 / 12.0) - 1.0).abs() < 0.1);
+        for (&x, &q) in embeddings.slice().iter().zip(quantized.as_view().slice()) {
+            assert_eq!(q, (x as f64 / 0.01f32 as f64).round() as i16);
+        }
+
+        let mut options = options;
+        options.dither_seed = Some(123);
+        let (dithered, dithered_report) = quantize(embeddings, &options, None).unwrap();
+        assert!(dithered_report.max_abs_error <= 0.01 * 1.001);
+        assert!(dithered_report.mean_squared_error > report.mean_squared_error);
+        assert_ne!(dithered.as_view().slice(), quantized.as_view().slice());
+        let (dithered_again, _) = quantize(embeddings, &options, None).unwrap();
+        assert_eq!(dithered_again.as_view().slice(), dithered.as_view().slice());
+
+        let options = QuantizationOptions::new(QuantizationStep::RelativeToStdDev(0.1));
+        let (_, report) = quantize(embeddings, &options, None).unwrap();
+        // The standard deviation of `U(-2, 2)` is `4 / sqrt(12)`.
+        assert!((report.scale_factor / (0.4 / 12f32.sqrt()) - 1.0).abs() < 0.05);
+        assert!((report.mean_squared_value / (16.0 / 12.0) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn limits_of_the_file_format() {
+        let mut rng = StdRng::seed_from_u64(20_201_202);
+        let embeddings = random_embeddings(&mut rng, (2, 20, 100), 1000.0);
+        let embeddings = embeddings.as_view();
+
+        let options = QuantizationOptions::new(QuantizationStep::Fixed(0.001));
+        let min_step = match quantize(embeddings, &options, None) {
+            Err(Error::QuantizationStepTooSmall { step, min_step }) => {
+                assert_eq!(step, 0.001);
+                min_step
+            }
+            _ => panic!("expected `QuantizationStepTooSmall`"),
+        };
+
+        for step in [
+            QuantizationStep::Fixed(min_step),
+            QuantizationStep::RelativeToStdDev(1e-9),
+        ] {
+            let (quantized, report) =
+                quantize(embeddings, &QuantizationOptions::new(step), None).unwrap();
+            assert_eq!(report.scale_factor, min_step);
+            for embedding in quantized.as_view().subview(0).iter_subviews() {
+                let square_norm = embedding.iter().map(|&q| q as i64 * q as i64).sum::<i64>();
+                assert!(square_norm <= i32::MAX as i64);
+                assert!(embedding.iter().all(|&q| q.unsigned_abs() <= 16383));
+            }
+        }
+
+        let mut data = embeddings.slice().to_vec();
+        data[2 * 100 + 7] = f32::NAN;
+        let embeddings = RankThreeTensor::from_flattened(data, 2, 20, 100);
+        assert!(matches!(
+            quantize(embeddings.as_view(), &options, None),
+            Err(Error::NonFiniteEmbedding {
+                timestep: 0,
+                word_index: 2,
+                dimension: 7
+            })
+        ));
+    }
+
+    #[test]
+    fn compress_from_float() {
+        const NUM_TIMESTEPS: usize = 4;
+        const EMBEDDING_DIM: usize = 5;
+
+        let mut rng = StdRng::seed_from_u64(20_201_203);
+        let embeddings = random_embeddings(&mut rng, (NUM_TIMESTEPS, 30, EMBEDDING_DIM), 1.0);
+        let scale_factors = ScaleFactors::per_dimension(vec![0.5, 1.0, 2.0, 4.0, 8.0]);
+
+        let quantization = QuantizationOptions::new(QuantizationStep::RelativeToStdDev(0.05));
+        for rate_distortion_tradeoff in [None, Some(1e-2)] {
+            let mut options = CompressionOptions::new(10, 123.0);
+            options.scale_factors = Some(scale_factors.clone());
+            options.rate_distortion_tradeoff = rate_distortion_tradeoff;
+            let mut compressed = Vec::<u8>::new();
+            let (_, report) = write_compressed_dwe_file_from_float(
+                embeddings.as_view(),
+                None,
+                None,
+                &quantization,
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+
+            let (_, expected_report) =
+                quantize(embeddings.as_view(), &quantization, Some(&scale_factors)).unwrap();
+            if rate_distortion_tradeoff.is_none() {
+                assert_eq!(report, expected_report);
+            } else {
+                // Lossy compression of the residuals adds to the distortion.
+                assert_eq!(report.scale_factor, expected_report.scale_factor);
+                assert!(report.mean_squared_error > expected_report.mean_squared_error);
+            }
+
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            assert_eq!(file.header().scale_factor, report.scale_factor);
+            let reader = file.into_random_access_reader();
+            let mut sum_of_squared_errors = 0.0;
+            let mut max_abs_error = 0.0f32;
+            for t in 0..NUM_TIMESTEPS {
+                let dequantized = reader
+                    .get_dequantized_embeddings_at(t as u32)
+                    .unwrap()
+                    .into_inner();
+                for (&x, &y) in embeddings
+                    .as_view()
+                    .subview(t)
+                    .slice()
+                    .iter()
+                    .zip(&dequantized)
+                {
+                    sum_of_squared_errors += (x - y) as f64 * (x - y) as f64;
+                    max_abs_error = max_abs_error.max((x - y).abs());
+                }
+            }
+            assert_eq!(max_abs_error, report.max_abs_error);
+            let mean_squared_error =
+                sum_of_squared_errors / embeddings.as_view().slice().len() as f64;
+            assert!((mean_squared_error / report.mean_squared_error - 1.0).abs() < 1e-9);
+        }
+    }
+}
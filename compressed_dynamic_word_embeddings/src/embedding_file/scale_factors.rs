@@ -0,0 +1,189 @@
+//! The optional section that refines the scale factor per dimension and/or per time step
+
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that holds scale factors per embedding dimension
+/// and/or per time step (since version 1.1).
+pub const SCALE_FACTORS_SECTION_TAG: u32 = u32::from_le_bytes(*b"scal");
+
+/// Scale factors that may depend on the time step, the embedding dimension, or both.
+///
+/// The factors form a table with either one row per time step or a single row for
+/// all time steps, and with either one column per embedding dimension or a single
+/// column for all dimensions (like broadcasting in numpy). A quantized embedding
+/// vector component `u` at time step `t` and dimension `d` represents the value
+/// `header.scale_factor * scale_factors.get(t, d) * u`, where `header.scale_factor`
+/// is the global scale factor from the [`FileHeader`](../struct.FileHeader.html).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaleFactors {
+    factors: Box<[f32]>,
+    num_rows: usize,
+    num_columns: usize,
+}
+
+impl ScaleFactors {
+    /// Creates a table with `num_rows` rows and `num_columns` columns from its
+    /// entries in row major order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `factors.len() != num_rows * num_columns` or if the table is empty.
+    pub fn from_flattened(factors: Vec<f32>, num_rows: usize, num_columns: usize) -> Self {
+        assert!(num_rows != 0 && num_columns != 0);
+        assert_eq!(Some(factors.len()), num_rows.checked_mul(num_columns));
+        Self {
+            factors: factors.into(),
+            num_rows,
+            num_columns,
+        }
+    }
+
+    /// Creates scale factors that depend only on the time step (one per time step).
+    pub fn per_timestep(factors: Vec<f32>) -> Self {
+        let len = factors.len();
+        Self::from_flattened(factors, len, 1)
+    }
+
+    /// Creates scale factors that depend only on the embedding dimension (one per
+    /// dimension).
+    pub fn per_dimension(factors: Vec<f32>) -> Self {
+        let len = factors.len();
+        Self::from_flattened(factors, 1, len)
+    }
+
+    /// Returns `(num_rows, num_columns)`.
+    pub fn shape(&self) -> (usize, usize) {
+        (self.num_rows, self.num_columns)
+    }
+
+    /// Returns the factors in row major order.
+    pub fn as_slice(&self) -> &[f32] {
+        &self.factors
+    }
+
+    /// Returns the scale factor for dimension `dimension` at time step `t`.
+    ///
+    /// Panics if `t` or `dimension` is out of bounds for a table that depends on it.
+    pub fn get(&self, t: u32, dimension: u32) -> f32 {
+        let row = self.at_timestep(t);
+        if row.len() == 1 {
+            row[0]
+        } else {
+            row[dimension as usize]
+        }
+    }
+
+    /// Returns the scale factors of all dimensions at time step `t`, or a slice of
+    /// length one if the scale factors don't depend on the dimension.
+    ///
+    /// Panics if the table has one row per time step and `t` is out of bounds.
+    pub fn at_timestep(&self, t: u32) -> &[f32] {
+        let row = if self.num_rows == 1 { 0 } else { t as usize };
+        &self.factors[row * self.num_columns..(row + 1) * self.num_columns]
+    }
+
+    /// Returns `Error::LengthMismatch` unless the table has a valid shape for
+    /// embeddings with `num_timesteps` time steps and dimension `embedding_dim`.
+    pub(crate) fn check_shape(&self, num_timesteps: usize, embedding_dim: usize) -> Result<()> {
+        if self.num_rows != 1 && self.num_rows != num_timesteps {
+            Err(Error::LengthMismatch {
+                what: "rows of scale factors",
+                expected: num_timesteps,
+                found: self.num_rows,
+            })
+        } else if self.num_columns != 1 && self.num_columns != embedding_dim {
+            Err(Error::LengthMismatch {
+                what: "columns of scale factors",
+                expected: embedding_dim,
+                found: self.num_columns,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Serializes the table into the payload of a scale factors section.
+    ///
+    /// The payload consists of the number of rows, the number of columns, and the
+    /// bit patterns of all factors (as `f32`s in row major order).
+    pub(crate) fn serialize(&self) -> Vec<u32> {
+        let mut serialized = Vec::with_capacity(2 + self.factors.len());
+        serialized.push(self.num_rows as u32);
+        serialized.push(self.num_columns as u32);
+        serialized.extend(self.factors.iter().map(|factor| factor.to_bits()));
+        serialized
+    }
+
+    /// Inverse of [`serialize`](#method.serialize).
+    ///
+    /// Returns `Error::InvalidSection` if `serialized` is not a valid scale factors
+    /// section for a file with `num_timesteps` time steps and embedding dimension
+    /// `embedding_dim`.
+    pub(crate) fn deserialize(
+        serialized: &[u32],
+        num_timesteps: u32,
+        embedding_dim: u32,
+    ) -> Result<Self> {
+        let invalid = || Error::InvalidSection {
+            tag: SCALE_FACTORS_SECTION_TAG,
+        };
+
+        let (num_rows, num_columns, payload) = match serialized {
+            [num_rows, num_columns, payload @ ..] => {
+                (*num_rows as usize, *num_columns as usize, payload)
+            }
+            _ => return Err(invalid()),
+        };
+        if num_rows == 0
+            || num_columns == 0
+            || num_rows.checked_mul(num_columns) != Some(payload.len())
+        {
+            return Err(invalid());
+        }
+
+        let scale_factors = Self::from_flattened(
+            payload.iter().map(|&bits| f32::from_bits(bits)).collect(),
+            num_rows,
+            num_columns,
+        );
+        scale_factors
+            .check_shape(num_timesteps as usize, embedding_dim as usize)
+            .map_err(|_| invalid())?;
+        Ok(scale_factors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let all_scale_factors = [
+            ScaleFactors::per_timestep(vec![0.5, 0.25, 2.0]),
+            ScaleFactors::per_dimension(vec![1.5, -0.5]),
+            ScaleFactors::from_flattened(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3, 2),
+            ScaleFactors::from_flattened(vec![0.125], 1, 1),
+        ];
+
+        for scale_factors in &all_scale_factors {
+            let serialized = scale_factors.serialize();
+            assert_eq!(
+                &ScaleFactors::deserialize(&serialized, 3, 2).unwrap(),
+                scale_factors
+            );
+            assert!(ScaleFactors::deserialize(&serialized[..serialized.len() - 1], 3, 2).is_err());
+        }
+
+        assert!(ScaleFactors::deserialize(&all_scale_factors[0].serialize(), 4, 2).is_err());
+        assert!(ScaleFactors::deserialize(&all_scale_factors[1].serialize(), 3, 3).is_err());
+        assert!(ScaleFactors::deserialize(&[0, 0], 3, 2).is_err());
+
+        let table = &all_scale_factors[2];
+        assert_eq!(table.get(1, 0), 3.0);
+        assert_eq!(table.at_timestep(2), &[5.0, 6.0]);
+        assert_eq!(all_scale_factors[0].get(1, 1), 0.25);
+        assert_eq!(all_scale_factors[0].at_timestep(2), &[2.0]);
+        assert_eq!(all_scale_factors[1].get(2, 1), -0.5);
+    }
+}
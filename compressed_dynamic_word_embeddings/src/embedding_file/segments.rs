@@ -0,0 +1,241 @@
+//! The optional section that splits the time steps into separately predicted segments
+
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that splits the time steps into segments (since
+/// versions 1.2 and 2.1).
+pub const SEGMENTS_SECTION_TAG: u32 = u32::from_le_bytes(*b"segm");
+
+/// Marks a segment whose last time step is a keyframe in the serialized section.
+const KEYFRAME: u32 = 0;
+
+/// Marks a segment whose last time step is predicted by the last time step of the
+/// previous segment in the serialized section.
+const PREDICTED: u32 = 1;
+
+/// Partition of the time steps into consecutive segments, each of which has its
+/// own bisection tree for predicting time steps from each other.
+///
+/// A file that was written in one go has a single segment by default, which spans
+/// all time steps (files without a segments section are treated this way). The
+/// first and the last time step of the first segment are keyframes, i.e., they are
+/// coded independently of all other time steps. The time steps in between are
+/// predicted by recursively bisecting the range between these two.
+///
+/// Each further segment starts right after the last time step of the previous
+/// segment and bisects the range between these two time steps in the same way. Its
+/// last time step is either a keyframe or it is predicted by the last time step of
+/// the previous segment:
+/// - Files written with a
+///   [`keyframe_interval`](../builder/struct.CompressionOptions.html#structfield.keyframe_interval)
+///   have a keyframe every `keyframe_interval` time steps (see
+///   [`with_keyframe_interval`](#method.with_keyframe_interval)). Decoding any
+///   time step then requires decoding at most two keyframes and a logarithmic (in
+///   `keyframe_interval`) number of predicted time steps.
+/// - Each [appended](../builder/fn.append_timesteps.html) batch of time steps
+///   forms a new segment whose last time step is predicted. Decoding a time step in
+///   such a segment requires decoding the last time step of each preceding segment
+///   up to the closest keyframe, so files with many appended segments are slower to
+///   query than files that were written in one go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segments {
+    /// The last time step of each segment, in increasing order.
+    ends: Box<[u32]>,
+
+    /// Whether the last time step of each segment is a keyframe (always `true` for
+    /// the first segment).
+    keyframes: Box<[bool]>,
+}
+
+impl Segments {
+    /// Creates a single segment that spans `num_timesteps` time steps.
+    ///
+    /// Panics if `num_timesteps == 0`.
+    pub fn single(num_timesteps: u32) -> Self {
+        assert!(num_timesteps != 0);
+        Self {
+            ends: vec![num_timesteps - 1].into(),
+            keyframes: vec![true].into(),
+        }
+    }
+
+    /// Creates segments of `keyframe_interval` time steps each (except for the last
+    /// segment, which may be shorter), so that the time steps `0`,
+    /// `keyframe_interval`, `2 * keyframe_interval`, and so on, as well as the last
+    /// time step, are keyframes.
+    ///
+    /// Panics if `num_timesteps == 0` or `keyframe_interval == 0`.
+    pub fn with_keyframe_interval(num_timesteps: u32, keyframe_interval: u32) -> Self {
+        assert!(num_timesteps != 0 && keyframe_interval != 0);
+        let last = num_timesteps - 1;
+        let mut ends = (1..)
+            .map_while(|i| keyframe_interval.checked_mul(i).filter(|&end| end < last))
+            .collect::<Vec<_>>();
+        ends.push(last);
+        Self {
+            keyframes: vec![true; ends.len()].into(),
+            ends: ends.into(),
+        }
+    }
+
+    /// Returns the last time step of each segment, in increasing order.
+    pub fn ends(&self) -> &[u32] {
+        &self.ends
+    }
+
+    /// Returns the number of segments, which is always at least one.
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// Always returns `false` since there is at least one segment.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns whether the last time step of segment `i` is a keyframe, i.e., whether
+    /// it is coded independently rather than predicted by the last time step of the
+    /// previous segment. Always returns `true` for the first segment.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn is_keyframe(&self, i: usize) -> bool {
+        self.keyframes[i]
+    }
+
+    /// Returns the index of the segment that contains time step `t`.
+    ///
+    /// Returns the index of the last segment if `t` is out of bounds.
+    pub fn segment_of(&self, t: u32) -> usize {
+        self.ends
+            .partition_point(|&end| end < t)
+            .min(self.ends.len() - 1)
+    }
+
+    /// Returns the roots of the bisection tree of segment `i`, i.e., the first
+    /// and the last time step for the first segment, and the last time steps of
+    /// the previous and of the current segment for all other segments.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn roots(&self, i: usize) -> (u32, u32) {
+        let left = if i == 0 { 0 } else { self.ends[i - 1] };
+        (left, self.ends[i])
+    }
+
+    /// Returns the segments after appending a segment of `num_timesteps` time steps
+    /// whose last time step is predicted.
+    pub(crate) fn appended(&self, num_timesteps: u32) -> Result<Self> {
+        let last = *self.ends.last().expect("there's at least one segment");
+        let end = last.checked_add(num_timesteps).ok_or(Error::TooLarge)?;
+        let mut ends = self.ends.to_vec();
+        let mut keyframes = self.keyframes.to_vec();
+        ends.push(end);
+        keyframes.push(false);
+        Ok(Self {
+            ends: ends.into(),
+            keyframes: keyframes.into(),
+        })
+    }
+
+    /// Serializes the segments into the payload of a segments section, which
+    /// consists of two `u32`s per segment: its last time step, and either `0` if
+    /// that time step is a keyframe or `1` if it is predicted.
+    pub(crate) fn serialize(&self) -> Vec<u32> {
+        self.ends
+            .iter()
+            .zip(self.keyframes.iter())
+            .flat_map(|(&end, &keyframe)| [end, if keyframe { KEYFRAME } else { PREDICTED }])
+            .collect()
+    }
+
+    /// Inverse of [`serialize`](#method.serialize).
+    ///
+    /// Returns `Error::InvalidSection` unless `serialized` lists increasing time
+    /// steps that end at the last one of a file with `num_timesteps` time steps,
+    /// each followed by a valid kind, where the first segment must end in a keyframe.
+    pub(crate) fn deserialize(serialized: &[u32], num_timesteps: u32) -> Result<Self> {
+        let invalid = || Error::InvalidSection {
+            tag: SEGMENTS_SECTION_TAG,
+        };
+        if !serialized.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let mut ends = Vec::with_capacity(serialized.len() / 2);
+        let mut keyframes = Vec::with_capacity(serialized.len() / 2);
+        for entry in serialized.chunks_exact(2) {
+            ends.push(entry[0]);
+            keyframes.push(match entry[1] {
+                KEYFRAME => true,
+                PREDICTED => false,
+                _ => return Err(invalid()),
+            });
+        }
+        if ends.last().map(|&last| last as u64 + 1) != Some(num_timesteps as u64)
+            || ends.windows(2).any(|pair| pair[0] >= pair[1])
+            || keyframes.first() != Some(&true)
+        {
+            return Err(invalid());
+        }
+        Ok(Self {
+            ends: ends.into(),
+            keyframes: keyframes.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let segments = Segments::single(5)
+            .appended(1)
+            .unwrap()
+            .appended(3)
+            .unwrap();
+        assert_eq!(segments.ends(), &[4, 5, 8]);
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments.serialize(), [4, 0, 5, 1, 8, 1]);
+        assert_eq!(
+            Segments::deserialize(&segments.serialize(), 9).unwrap(),
+            segments
+        );
+        assert!(Segments::deserialize(&segments.serialize(), 10).is_err());
+        assert!(Segments::deserialize(&[], 9).is_err());
+        assert!(Segments::deserialize(&[4, 0, 4, 1, 8, 1], 9).is_err());
+        assert!(Segments::deserialize(&[5, 0, 4, 1, 8, 1], 9).is_err());
+        assert!(Segments::deserialize(&[4, 1, 5, 1, 8, 1], 9).is_err());
+        assert!(Segments::deserialize(&[4, 0, 5, 2, 8, 1], 9).is_err());
+        assert!(Segments::deserialize(&[4, 0, 5, 1, 8], 9).is_err());
+
+        let segment_of = (0..10).map(|t| segments.segment_of(t)).collect::<Vec<_>>();
+        assert_eq!(segment_of, [0, 0, 0, 0, 0, 1, 2, 2, 2, 2]);
+        assert_eq!(segments.roots(0), (0, 4));
+        assert_eq!(segments.roots(1), (4, 5));
+        assert_eq!(segments.roots(2), (5, 8));
+        assert_eq!(Segments::single(1).roots(0), (0, 0));
+        assert!(segments.is_keyframe(0));
+        assert!(!segments.is_keyframe(1));
+    }
+
+    #[test]
+    fn keyframe_interval() {
+        let ends = |num_timesteps, keyframe_interval| {
+            let segments = Segments::with_keyframe_interval(num_timesteps, keyframe_interval);
+            assert!((0..segments.len()).all(|i| segments.is_keyframe(i)));
+            assert_eq!(
+                Segments::deserialize(&segments.serialize(), num_timesteps).unwrap(),
+                segments
+            );
+            segments.ends().to_vec()
+        };
+        assert_eq!(ends(1, 4), [0]);
+        assert_eq!(ends(4, 4), [3]);
+        assert_eq!(ends(5, 4), [4]);
+        assert_eq!(ends(6, 4), [4, 5]);
+        assert_eq!(ends(13, 4), [4, 8, 12]);
+        assert_eq!(ends(14, 4), [4, 8, 12, 13]);
+        assert_eq!(ends(3, 1), [1, 2]);
+        assert_eq!(ends(u32::MAX, u32::MAX / 2), [u32::MAX / 2, u32::MAX - 1]);
+    }
+}
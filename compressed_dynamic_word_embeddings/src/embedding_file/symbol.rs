@@ -0,0 +1,197 @@
+//! The integer types in which files store quantized embedding vector components
+
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display};
+use std::hash::Hash;
+
+/// The integer type of the quantized embedding vector components and of the
+/// prediction residuals in a file, which is also the alphabet of the entropy
+/// models.
+///
+/// Narrower types make decoded time steps smaller (and scalar products between
+/// them faster), and wider types allow for a finer quantization. Types other than
+/// [`I16`](#variant.I16) require version 2 of the file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SymbolType {
+    /// Signed 8 bit integers (`i8`).
+    I8,
+
+    /// Signed 16 bit integers (`i16`), which is what all files in version 1 of the
+    /// file format use.
+    #[default]
+    I16,
+
+    /// Signed 32 bit integers (`i32`).
+    I32,
+}
+
+impl SymbolType {
+    /// Returns the number of bits of the integer type.
+    pub fn bits(self) -> u32 {
+        match self {
+            SymbolType::I8 => 8,
+            SymbolType::I16 => 16,
+            SymbolType::I32 => 32,
+        }
+    }
+
+    /// Returns `None` unless `bits` is 8, 16, or 32.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            8 => Some(SymbolType::I8),
+            16 => Some(SymbolType::I16),
+            32 => Some(SymbolType::I32),
+            _ => None,
+        }
+    }
+
+    /// Number of `u16`s that each symbol takes up in the entropy models section.
+    pub(crate) fn serialized_size(self) -> usize {
+        match self {
+            SymbolType::I32 => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "i{}", self.bits())
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for i8 {}
+    impl Sealed for i16 {}
+    impl Sealed for i32 {}
+}
+
+/// An integer type that can hold quantized embedding vector components, i.e.,
+/// `i8`, `i16`, or `i32` (see [`SymbolType`]).
+///
+/// This trait is sealed, i.e., it can't be implemented outside of this crate.
+pub trait Symbol:
+    Copy + Default + Ord + Hash + Debug + Display + Send + Sync + 'static + private::Sealed
+{
+    /// The [`SymbolType`] that files with symbols of this type declare in their
+    /// header.
+    const SYMBOL_TYPE: SymbolType;
+
+    /// Converts the symbol into an `i64` without loss.
+    fn to_i64(self) -> i64;
+
+    /// Returns `None` if `value` doesn't fit into the symbol type.
+    fn from_i64(value: i64) -> Option<Self>;
+
+    /// Converts `value` into the symbol type, clamping it to the range of the type.
+    fn saturating_from_i64(value: i64) -> Self;
+
+    /// Adds two symbols with wraparound, as readers do when they add a residual to
+    /// its prediction.
+    fn wrapping_add(self, other: Self) -> Self;
+}
+
+macro_rules! impl_symbol {
+    ($ty:ty, $symbol_type:expr) => {
+        impl Symbol for $ty {
+            const SYMBOL_TYPE: SymbolType = $symbol_type;
+
+            #[inline(always)]
+            fn to_i64(self) -> i64 {
+                self as i64
+            }
+
+            #[inline(always)]
+            fn from_i64(value: i64) -> Option<Self> {
+                <$ty>::try_from(value).ok()
+            }
+
+            #[inline(always)]
+            fn saturating_from_i64(value: i64) -> Self {
+                value.clamp(<$ty>::MIN as i64, <$ty>::MAX as i64) as $ty
+            }
+
+            #[inline(always)]
+            fn wrapping_add(self, other: Self) -> Self {
+                <$ty>::wrapping_add(self, other)
+            }
+        }
+    };
+}
+
+impl_symbol!(i8, SymbolType::I8);
+impl_symbol!(i16, SymbolType::I16);
+impl_symbol!(i32, SymbolType::I32);
+
+/// Appends the serialization of `symbol` to the entropy models section `dest`:
+/// `i8`s and `i16`s take up one `u16` each (in two's complement, with `i8`s sign
+/// extended to 16 bits), and `i32`s take up two `u16`s (lower half first).
+pub(crate) fn serialize_symbol<T: Symbol>(symbol: T, dest: &mut Vec<u16>) {
+    let value = symbol.to_i64() as u32;
+    dest.push(value as u16);
+    if T::SYMBOL_TYPE.serialized_size() == 2 {
+        dest.push((value >> 16) as u16);
+    }
+}
+
+/// Inverse of [`serialize_symbol`] for a sequence of symbols. Returns `None` if
+/// some symbol doesn't fit into `T` (which can only happen for `i8`s).
+pub(crate) fn deserialize_symbols<T: Symbol>(serialized: &[u16]) -> Option<Vec<T>> {
+    match T::SYMBOL_TYPE.serialized_size() {
+        1 => serialized
+            .iter()
+            .map(|&word| T::from_i64(word as i16 as i64))
+            .collect(),
+        _ => serialized
+            .chunks_exact(2)
+            .map(|words| T::from_i64((words[0] as u32 | (words[1] as u32) << 16) as i32 as i64))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize() {
+        fn round_trip<T: Symbol>(symbols: &[T], expected: &[u16]) {
+            let mut serialized = Vec::new();
+            for &symbol in symbols {
+                serialize_symbol(symbol, &mut serialized);
+            }
+            assert_eq!(serialized, expected);
+            assert_eq!(deserialize_symbols::<T>(&serialized).unwrap(), symbols);
+        }
+
+        round_trip::<i8>(&[0, 1, -1, i8::MIN, i8::MAX], &[0, 1, 0xffff, 0xff80, 0x7f]);
+        round_trip::<i16>(&[0, -2, i16::MIN], &[0, 0xfffe, 0x8000]);
+        round_trip::<i32>(
+            &[-2, 0x1234_5678, i32::MIN],
+            &[0xfffe, 0xffff, 0x5678, 0x1234, 0, 0x8000],
+        );
+
+        // Values that don't fit into an `i8` are invalid.
+        assert!(deserialize_symbols::<i8>(&[0x80]).is_none());
+        assert!(deserialize_symbols::<i8>(&[0xff7f]).is_none());
+        assert_eq!(deserialize_symbols::<i16>(&[0x80]).unwrap(), [0x80]);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(i8::saturating_from_i64(200), i8::MAX);
+        assert_eq!(i16::saturating_from_i64(-40000), i16::MIN);
+        assert_eq!(i32::saturating_from_i64(-5), -5);
+        assert_eq!(i8::from_i64(128), None);
+        assert_eq!(i32::from_i64(1 << 31), None);
+        assert_eq!(Symbol::wrapping_add(i8::MAX, 1), i8::MIN);
+
+        for symbol_type in [SymbolType::I8, SymbolType::I16, SymbolType::I32] {
+            assert_eq!(SymbolType::from_bits(symbol_type.bits()), Some(symbol_type));
+        }
+        assert_eq!(SymbolType::from_bits(0), None);
+        assert_eq!(SymbolType::I32.to_string(), "i32");
+    }
+}
@@ -0,0 +1,397 @@
+//! The optional section that assigns a label (e.g., a year) to each time step
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use super::vocabulary::Vocabulary;
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that holds the time step labels (since version 1.1).
+pub const TIMESTEP_LABELS_SECTION_TAG: u32 = u32::from_le_bytes(*b"tlab");
+
+const KIND_INTEGER: u32 = 0;
+const KIND_DATE: u32 = 1;
+const KIND_TEXT: u32 = 2;
+
+/// Label of a single time step.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TimestepLabel {
+    /// An integer label, typically a year.
+    Integer(i64),
+
+    /// A calendar date.
+    Date(Date),
+
+    /// A free form label.
+    Text(String),
+}
+
+impl Display for TimestepLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestepLabel::Integer(n) => n.fmt(f),
+            TimestepLabel::Date(date) => date.fmt(f),
+            TimestepLabel::Text(text) => text.fmt(f),
+        }
+    }
+}
+
+/// A date in the proleptic Gregorian calendar.
+///
+/// Formats and parses as an ISO 8601 calendar date (`YYYY-MM-DD`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i32,
+    month: u8,
+    day: u8,
+}
+
+impl Date {
+    /// Returns `None` if `month` or `day` are out of range.
+    pub fn new(year: i32, month: u8, day: u8) -> Option<Self> {
+        let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days_in_month = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if is_leap_year => 29,
+            2 => 28,
+            _ => return None,
+        };
+        if day == 0 || day > days_in_month {
+            None
+        } else {
+            Some(Self { year, month, day })
+        }
+    }
+
+    pub fn year(self) -> i32 {
+        self.year
+    }
+
+    pub fn month(self) -> u8 {
+        self.month
+    }
+
+    pub fn day(self) -> u8 {
+        self.day
+    }
+}
+
+impl Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.year < 0 {
+            f.write_str("-")?;
+        }
+        write!(
+            f,
+            "{:04}-{:02}-{:02}",
+            self.year.unsigned_abs(),
+            self.month,
+            self.day
+        )
+    }
+}
+
+impl FromStr for Date {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = || {
+            // Split at the last two dashes since the year may be negative.
+            let mut parts = s.rsplitn(3, '-');
+            let day = parts.next()?;
+            let month = parts.next()?;
+            let year = parts.next()?;
+            if month.len() != 2 || day.len() != 2 {
+                return None;
+            }
+            Date::new(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)
+        };
+        parse().ok_or_else(|| Error::InvalidDate(s.into()))
+    }
+}
+
+/// Refers to a time step either by its zero based index or by its label.
+///
+/// Query methods of a [`RandomAccessReader`](crate::random_access_reader::RandomAccessReader)
+/// that refer to a single time step accept anything that converts into a
+/// `TimestepRef`, i.e., either a `u32` index or a `&TimestepLabel`.
+#[derive(Debug, Clone, Copy)]
+pub enum TimestepRef<'a> {
+    Index(u32),
+    Label(&'a TimestepLabel),
+}
+
+impl From<u32> for TimestepRef<'_> {
+    fn from(index: u32) -> Self {
+        TimestepRef::Index(index)
+    }
+}
+
+impl<'a> From<&'a TimestepLabel> for TimestepRef<'a> {
+    fn from(label: &'a TimestepLabel) -> Self {
+        TimestepRef::Label(label)
+    }
+}
+
+/// One label per time step, all of the same kind and without any duplicates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimestepLabels {
+    inner: Labels,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Labels {
+    Integers(Box<[i64]>),
+    Dates(Box<[Date]>),
+    Text(Vocabulary),
+}
+
+impl TimestepLabels {
+    /// Returns `Error::DuplicateTimestepLabel` if `labels` contains duplicates.
+    pub fn from_integers(labels: Vec<i64>) -> Result<Self> {
+        check_unique(&labels)?;
+        Ok(Self {
+            inner: Labels::Integers(labels.into()),
+        })
+    }
+
+    /// Returns `Error::DuplicateTimestepLabel` if `labels` contains duplicates.
+    pub fn from_dates(labels: Vec<Date>) -> Result<Self> {
+        check_unique(&labels)?;
+        Ok(Self {
+            inner: Labels::Dates(labels.into()),
+        })
+    }
+
+    /// Returns `Error::DuplicateTimestepLabel` if `labels` contains duplicates.
+    pub fn from_strings<S: AsRef<str>>(labels: &[S]) -> Result<Self> {
+        let labels = Vocabulary::new(labels).map_err(|err| match err {
+            Error::DuplicateWord(label) => Error::DuplicateTimestepLabel(label),
+            err => err,
+        })?;
+        Ok(Self {
+            inner: Labels::Text(labels),
+        })
+    }
+
+    /// Returns the number of labels, which is the number of time steps.
+    pub fn len(&self) -> usize {
+        match &self.inner {
+            Labels::Integers(labels) => labels.len(),
+            Labels::Dates(labels) => labels.len(),
+            Labels::Text(labels) => labels.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the label of time step `t`, or `None` if `t` is out of bounds.
+    pub fn get(&self, t: u32) -> Option<TimestepLabel> {
+        match &self.inner {
+            Labels::Integers(labels) => labels.get(t as usize).map(|&n| TimestepLabel::Integer(n)),
+            Labels::Dates(labels) => labels.get(t as usize).map(|&d| TimestepLabel::Date(d)),
+            Labels::Text(labels) => labels
+                .id_to_word(t)
+                .map(|text| TimestepLabel::Text(text.into())),
+        }
+    }
+
+    /// Iterates over the labels of all time steps in order.
+    pub fn iter(&self) -> impl Iterator<Item = TimestepLabel> + '_ {
+        (0..self.len() as u32).map(move |t| self.get(t).expect("`t` is in bounds"))
+    }
+
+    /// Returns the index of the time step with label `label`, or `None` if there is
+    /// no such time step.
+    pub fn position(&self, label: &TimestepLabel) -> Option<u32> {
+        match (&self.inner, label) {
+            (Labels::Integers(labels), TimestepLabel::Integer(n)) => {
+                labels.iter().position(|l| l == n).map(|t| t as u32)
+            }
+            (Labels::Dates(labels), TimestepLabel::Date(d)) => {
+                labels.iter().position(|l| l == d).map(|t| t as u32)
+            }
+            (Labels::Text(labels), TimestepLabel::Text(text)) => labels.word_to_id(text),
+            _ => None,
+        }
+    }
+
+    /// Parses `label` according to the kind of labels stored in `self`, and then
+    /// returns the index of the time step with this label (if any).
+    ///
+    /// This is useful for user facing applications where labels are entered as text.
+    pub fn position_of_str(&self, label: &str) -> Option<u32> {
+        match &self.inner {
+            Labels::Integers(_) => self.position(&TimestepLabel::Integer(label.parse().ok()?)),
+            Labels::Dates(_) => self.position(&TimestepLabel::Date(label.parse().ok()?)),
+            Labels::Text(labels) => labels.word_to_id(label),
+        }
+    }
+
+    /// Returns the labels of `self` followed by the labels of `other`.
+    ///
+    /// Returns `Error::IncompatibleAppend` if the labels are of different kinds, and
+    /// `Error::DuplicateTimestepLabel` if `other` repeats a label of `self`.
+    pub(crate) fn concat(&self, other: &Self) -> Result<Self> {
+        match (&self.inner, &other.inner) {
+            (Labels::Integers(labels), Labels::Integers(other)) => {
+                Self::from_integers([&labels[..], &other[..]].concat())
+            }
+            (Labels::Dates(labels), Labels::Dates(other)) => {
+                Self::from_dates([&labels[..], &other[..]].concat())
+            }
+            (Labels::Text(labels), Labels::Text(other)) => {
+                Self::from_strings(&labels.iter().chain(other.iter()).collect::<Vec<_>>())
+            }
+            _ => Err(Error::IncompatibleAppend(
+                "the new time step labels are of a different kind than the existing ones",
+            )),
+        }
+    }
+
+    /// Serializes the labels into the payload of a time step labels section.
+    ///
+    /// The first `u32` identifies the kind of labels. It is followed by either
+    /// - for integer labels: one `i64` per time step, split into its lower and upper
+    ///   halves (in this order);
+    /// - for dates: two `u32`s per time step, where the first one holds the year
+    ///   (as an `i32`) and the second one holds `month << 8 | day`; or
+    /// - for text labels: the labels in the same format as the vocabulary section.
+    pub(crate) fn serialize(&self) -> Vec<u32> {
+        let mut serialized = Vec::new();
+        match &self.inner {
+            Labels::Integers(labels) => {
+                serialized.push(KIND_INTEGER);
+                for &label in labels.iter() {
+                    serialized.push(label as u32);
+                    serialized.push((label >> 32) as u32);
+                }
+            }
+            Labels::Dates(labels) => {
+                serialized.push(KIND_DATE);
+                for label in labels.iter() {
+                    serialized.push(label.year as u32);
+                    serialized.push((label.month as u32) << 8 | label.day as u32);
+                }
+            }
+            Labels::Text(labels) => {
+                serialized.push(KIND_TEXT);
+                serialized.extend(labels.serialize());
+            }
+        }
+        serialized
+    }
+
+    /// Inverse of [`serialize`](#method.serialize).
+    ///
+    /// Returns `Error::InvalidSection` if `serialized` is not a valid time step
+    /// labels section with exactly `num_timesteps` labels.
+    pub(crate) fn deserialize(serialized: &[u32], num_timesteps: u32) -> Result<Self> {
+        let invalid = || Error::InvalidSection {
+            tag: TIMESTEP_LABELS_SECTION_TAG,
+        };
+
+        let (&kind, payload) = serialized.split_first().ok_or_else(invalid)?;
+        match kind {
+            KIND_INTEGER | KIND_DATE if payload.len() != 2 * num_timesteps as usize => {
+                Err(invalid())
+            }
+            KIND_INTEGER => Self::from_integers(
+                payload
+                    .chunks_exact(2)
+                    .map(|chunk| (chunk[0] as u64 | (chunk[1] as u64) << 32) as i64)
+                    .collect(),
+            )
+            .map_err(|_| invalid()),
+            KIND_DATE => Self::from_dates(
+                payload
+                    .chunks_exact(2)
+                    .map(|chunk| {
+                        if chunk[1] >> 16 != 0 {
+                            return None;
+                        }
+                        Date::new(chunk[0] as i32, (chunk[1] >> 8) as u8, chunk[1] as u8)
+                    })
+                    .collect::<Option<_>>()
+                    .ok_or_else(invalid)?,
+            )
+            .map_err(|_| invalid()),
+            KIND_TEXT => Ok(Self {
+                inner: Labels::Text(
+                    Vocabulary::deserialize(payload, num_timesteps).map_err(|_| invalid())?,
+                ),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn check_unique<T: Ord + Clone + Display>(labels: &[T]) -> Result<()> {
+    let mut sorted = labels.to_vec();
+    sorted.sort_unstable();
+    match sorted.windows(2).find(|pair| pair[0] == pair[1]) {
+        Some(pair) => Err(Error::DuplicateTimestepLabel(pair[0].to_string())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_and_format_dates() {
+        let date = "1969-07-20".parse::<Date>().unwrap();
+        assert_eq!(date, Date::new(1969, 7, 20).unwrap());
+        assert_eq!(date.to_string(), "1969-07-20");
+        let date = "-0044-03-15".parse::<Date>().unwrap();
+        assert_eq!(date.year(), -44);
+        assert_eq!(date.to_string(), "-0044-03-15");
+
+        assert!("2000-02-29".parse::<Date>().is_ok());
+        assert!("1900-02-29".parse::<Date>().is_err());
+        assert!("2001-13-01".parse::<Date>().is_err());
+        assert!("2001-1-01".parse::<Date>().is_err());
+        assert!("20010101".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let all_labels = [
+            TimestepLabels::from_integers(vec![1800, -5, 1 << 40]).unwrap(),
+            TimestepLabels::from_dates(vec![
+                Date::new(2020, 1, 31).unwrap(),
+                Date::new(2020, 2, 29).unwrap(),
+                Date::new(-1, 12, 1).unwrap(),
+            ])
+            .unwrap(),
+            TimestepLabels::from_strings(&["early", "middle", "late"]).unwrap(),
+        ];
+
+        for labels in &all_labels {
+            assert_eq!(labels.len(), 3);
+            let serialized = labels.serialize();
+            assert_eq!(
+                &TimestepLabels::deserialize(&serialized, 3).unwrap(),
+                labels
+            );
+            assert!(TimestepLabels::deserialize(&serialized, 4).is_err());
+
+            for (t, label) in labels.iter().enumerate() {
+                assert_eq!(labels.position(&label), Some(t as u32));
+                assert_eq!(labels.position_of_str(&label.to_string()), Some(t as u32));
+            }
+            assert_eq!(labels.get(3), None);
+        }
+
+        assert_eq!(
+            all_labels[0].position(&TimestepLabel::Text("-5".into())),
+            None
+        );
+        assert_eq!(all_labels[0].position_of_str("1801"), None);
+        assert!(TimestepLabels::from_integers(vec![1, 2, 1]).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+//! The optional vocabulary section of a compressed dynamic word embeddings file
+//!
+//! The vocabulary is stored in front coded form: each word is represented by the
+//! number of leading bytes it shares with the previous word, followed by the
+//! remaining bytes. Both lengths are encoded as LEB128 variable length integers.
+//! See section "Optional Sections" of the file format specification in
+//! `file-format.html` for details.
+
+use std::convert::TryInto;
+
+use crate::error::{Error, Result};
+
+/// Tag of the optional section that holds the vocabulary (since version 1.1).
+pub const VOCABULARY_SECTION_TAG: u32 = u32::from_le_bytes(*b"voca");
+
+/// Bidirectional mapping between words and their (zero based) word IDs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vocabulary {
+    /// Concatenation of all words, ordered by their word IDs.
+    text: String,
+
+    /// `ends[i]` is the position in `text` right after the word with ID `i`.
+    ends: Box<[u32]>,
+
+    /// All word IDs, sorted by the words they refer to.
+    sorted_ids: Box<[u32]>,
+}
+
+impl Vocabulary {
+    /// Creates a vocabulary where the word at index `i` of `words` gets word ID `i`.
+    ///
+    /// Returns an error if `words` contains duplicates or if the concatenation of
+    /// all words is longer than `u32::MAX` bytes.
+    pub fn new<S: AsRef<str>>(words: &[S]) -> Result<Self> {
+        let mut text = String::new();
+        let mut ends = Vec::with_capacity(words.len());
+        for word in words {
+            text.push_str(word.as_ref());
+            ends.push(text.len().try_into().map_err(|_| Error::TooLarge)?);
+        }
+
+        Self::from_raw_parts(text, ends)
+    }
+
+    fn from_raw_parts(text: String, ends: Vec<u32>) -> Result<Self> {
+        let mut vocabulary = Self {
+            text,
+            ends: ends.into(),
+            sorted_ids: Default::default(),
+        };
+
+        let mut sorted_ids = (0..vocabulary.ends.len() as u32).collect::<Vec<_>>();
+        sorted_ids.sort_unstable_by_key(|&id| vocabulary.word(id));
+        if let Some(pair) = sorted_ids
+            .windows(2)
+            .find(|pair| vocabulary.word(pair[0]) == vocabulary.word(pair[1]))
+        {
+            return Err(Error::DuplicateWord(vocabulary.word(pair[0]).into()));
+        }
+        vocabulary.sorted_ids = sorted_ids.into();
+
+        Ok(vocabulary)
+    }
+
+    /// Returns the number of words in the vocabulary.
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// Returns the word with ID `id`, or `None` if `id` is out of bounds.
+    pub fn id_to_word(&self, id: u32) -> Option<&str> {
+        if (id as usize) < self.ends.len() {
+            Some(self.word(id))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the ID of the word `word`, or `None` if `word` is not in the
+    /// vocabulary.
+    pub fn word_to_id(&self, word: &str) -> Option<u32> {
+        self.sorted_ids
+            .binary_search_by(|&id| self.word(id).cmp(word))
+            .ok()
+            .map(|index| self.sorted_ids[index])
+    }
+
+    /// Iterates over all words, ordered by their word IDs.
+    pub fn iter(&self) -> impl Iterator<Item = &str> + '_ {
+        (0..self.ends.len() as u32).map(move |id| self.word(id))
+    }
+
+    fn word(&self, id: u32) -> &str {
+        let end = self.ends[id as usize] as usize;
+        let start = match id.checked_sub(1) {
+            Some(previous) => self.ends[previous as usize] as usize,
+            None => 0,
+        };
+        &self.text[start..end]
+    }
+
+    /// Serializes the vocabulary into the payload of a vocabulary section.
+    ///
+    /// The first `u32` holds the number of words. It is followed by the front coded
+    /// words, packed into `u32`s in little endian byte order and padded with zero
+    /// bytes to a multiple of four bytes.
+    pub(crate) fn serialize(&self) -> Vec<u32> {
+        let mut bytes = Vec::new();
+        let mut previous: &[u8] = &[];
+        for word in self.iter() {
+            let word = word.as_bytes();
+            let prefix_len = previous
+                .iter()
+                .zip(word)
+                .take_while(|(a, b)| a == b)
+                .count();
+            write_leb128(&mut bytes, prefix_len as u32);
+            write_leb128(&mut bytes, (word.len() - prefix_len) as u32);
+            bytes.extend_from_slice(&word[prefix_len..]);
+            previous = word;
+        }
+
+        let mut serialized = Vec::with_capacity(1 + bytes.len().div_ceil(4));
+        serialized.push(self.len() as u32);
+        serialized.extend(bytes.chunks(4).map(|chunk| {
+            let mut padded = [0u8; 4];
+            padded[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(padded)
+        }));
+        serialized
+    }
+
+    /// Inverse of [`serialize`](#method.serialize).
+    ///
+    /// Returns `Error::InvalidSection` if `serialized` is not a valid vocabulary
+    /// section with exactly `expected_len` words.
+    pub(crate) fn deserialize(serialized: &[u32], expected_len: u32) -> Result<Self> {
+        let invalid = || Error::InvalidSection {
+            tag: VOCABULARY_SECTION_TAG,
+        };
+
+        let (&num_words, payload) = serialized.split_first().ok_or_else(invalid)?;
+        // Each word takes up at least two bytes (for its two lengths). Checking this
+        // before allocating any memory protects against malformed `num_words`.
+        if num_words != expected_len || num_words as usize > 2 * payload.len() {
+            return Err(invalid());
+        }
+
+        let payload = payload
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let mut remainder = &payload[..];
+
+        let mut text = Vec::new();
+        let mut ends = Vec::with_capacity(num_words as usize);
+        let mut previous_start = 0;
+        for _ in 0..num_words {
+            let prefix_len = read_leb128(&mut remainder).ok_or_else(invalid)? as usize;
+            let suffix_len = read_leb128(&mut remainder).ok_or_else(invalid)? as usize;
+            let previous_len = text.len() - previous_start;
+            if prefix_len > previous_len || suffix_len > remainder.len() {
+                return Err(invalid());
+            }
+            // Check the total length before growing `text` since front coding lets a
+            // short payload describe a huge text. Repeating the previous word in full
+            // would be a duplicate, which gets rejected right away for the same reason.
+            if text.len() + prefix_len + suffix_len > u32::MAX as usize
+                || (!ends.is_empty() && prefix_len == previous_len && suffix_len == 0)
+            {
+                return Err(invalid());
+            }
+
+            let start = text.len();
+            text.extend_from_within(previous_start..previous_start + prefix_len);
+            text.extend_from_slice(&remainder[..suffix_len]);
+            remainder = &remainder[suffix_len..];
+            std::str::from_utf8(&text[start..]).map_err(|_| invalid())?;
+
+            ends.push(text.len().try_into().map_err(|_| invalid())?);
+            previous_start = start;
+        }
+
+        // Only zero padding up to the next multiple of four bytes is allowed.
+        if remainder.len() >= 4 || remainder.iter().any(|&byte| byte != 0) {
+            return Err(invalid());
+        }
+
+        let text = String::from_utf8(text).map_err(|_| invalid())?;
+        Self::from_raw_parts(text, ends).map_err(|_| invalid())
+    }
+}
+
+fn write_leb128(dest: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        dest.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    dest.push(value as u8);
+}
+
+fn read_leb128(src: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..32).step_by(7) {
+        let (&byte, remainder) = src.split_first()?;
+        *src = remainder;
+        if shift == 28 && byte & 0x70 != 0 {
+            return None; // Overflow.
+        }
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize() {
+        let words = ["the", "there", "their", "a", "", "thé", "théâtre", "z"];
+        let vocabulary = Vocabulary::new(&words).unwrap();
+
+        assert_eq!(vocabulary.len(), words.len());
+        assert!(vocabulary.iter().eq(words.iter().cloned()));
+        for (id, &word) in words.iter().enumerate() {
+            assert_eq!(vocabulary.id_to_word(id as u32), Some(word));
+            assert_eq!(vocabulary.word_to_id(word), Some(id as u32));
+        }
+        assert_eq!(vocabulary.id_to_word(words.len() as u32), None);
+        assert_eq!(vocabulary.word_to_id("then"), None);
+
+        let serialized = vocabulary.serialize();
+        assert_eq!(serialized[0], words.len() as u32);
+
+        let deserialized = Vocabulary::deserialize(&serialized, words.len() as u32).unwrap();
+        assert_eq!(deserialized, vocabulary);
+
+        assert!(Vocabulary::deserialize(&serialized, words.len() as u32 + 1).is_err());
+        assert!(Vocabulary::deserialize(&serialized[..serialized.len() - 1], 8).is_err());
+    }
+
+    #[test]
+    fn reject_mutated_sections() {
+        let serialized = Vocabulary::new(&["ab", "ac"]).unwrap().serialize();
+        assert_eq!(
+            &serialized[1..],
+            [
+                u32::from_le_bytes([0, 2, b'a', b'b']),
+                u32::from_le_bytes([1, 1, b'c', 0])
+            ]
+        );
+        assert!(Vocabulary::deserialize(&serialized, 2).is_ok());
+
+        // The second word repeats the first one in full.
+        let mut mutated = serialized.clone();
+        mutated[2] = u32::from_le_bytes([2, 0, 0, 0]);
+        assert!(Vocabulary::deserialize(&mutated, 2).is_err());
+
+        // Two empty words are duplicates too.
+        let mut mutated = serialized;
+        mutated[1] = u32::from_le_bytes([0, 0, 0, 0]);
+        mutated[2] = 0;
+        assert!(Vocabulary::deserialize(&mutated, 2).is_err());
+    }
+
+    #[test]
+    fn reject_duplicates() {
+        match Vocabulary::new(&["a", "b", "a"]) {
+            Err(Error::DuplicateWord(word)) => assert_eq!(word, "a"),
+            _ => panic!("duplicate word not detected"),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Error type shared by all fallible operations of this crate
+
+use std::fmt::{self, Display};
+
+use crate::embedding_file::symbol::SymbolType;
+
+/// Shorthand for `std::result::Result<T, Error>`.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Reasons why reading, writing, or querying a compressed dynamic word embeddings
+/// file can fail.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading from or writing to the underlying reader or writer failed.
+    Io(std::io::Error),
+
+    /// The file doesn't start with the magic number of the file format, so it's
+    /// probably not a compressed dynamic word embeddings file at all.
+    BadMagic(u32),
+
+    /// The file is stored in big endian byte order (e.g., because it was written
+    /// in native byte order on a big endian platform). The file format always
+    /// uses little endian byte order, independent of the platform.
+    UnsupportedByteOrder,
+
+    /// The file was written for a version of the file format that this library
+    /// can't read.
+    UnsupportedVersion { major: u32, minor: u32 },
+
+    /// The file stores its quantized embedding vectors with a different symbol type
+    /// than the one it was opened with.
+    SymbolTypeMismatch {
+        expected: SymbolType,
+        found: SymbolType,
+    },
+
+    /// The file ends before all data announced in its header could be read.
+    Truncated,
+
+    /// The `file_size` field in the header doesn't match the actual file size (both
+    /// in units of four bytes).
+    FileSizeMismatch { declared: u64, actual: usize },
+
+    /// A field in the header has a value that violates the file format.
+    InvalidHeader(&'static str),
+
+    /// The entropy model of time step `timestep` is malformed (when reading) or
+    /// can't be represented (when writing).
+    InvalidEntropyModel { timestep: u32 },
+
+    /// The jump table points outside of the compressed data.
+    InconsistentJumpTable,
+
+    /// The section table at the end of the file (since version 1.1) is malformed.
+    InvalidSectionTable,
+
+    /// The optional section with tag `tag` is malformed.
+    InvalidSection { tag: u32 },
+
+    /// A time step index was out of bounds.
+    TimestepOutOfRange { timestep: u32, num_timesteps: u32 },
+
+    /// No time step has the provided label (or the file has no time step labels).
+    UnknownTimestepLabel(String),
+
+    /// A word index was out of bounds.
+    WordIndexOutOfRange { word_index: u32, vocab_size: u32 },
+
+    /// The difference between an embedding vector component and its prediction
+    /// doesn't fit into the symbol type of the entropy coder.
+    ResidualOverflow {
+        timestep: u32,
+        word_index: u32,
+        dimension: u32,
+    },
+
+    /// An embedding vector component that should be quantized is infinite or NaN
+    /// (or becomes so when divided by its scale factor).
+    NonFiniteEmbedding {
+        timestep: u32,
+        word_index: u32,
+        dimension: u32,
+    },
+
+    /// The quantization step is so small (or not positive) that quantized
+    /// embedding vectors would violate the limits of the file format. Any step of
+    /// at least `min_step` is fine.
+    QuantizationStepTooSmall { step: f32, min_step: f32 },
+
+    /// Compressed embeddings can't be made to fit into a requested file size of
+    /// `target` bytes. Even the coarsest quantization leads to a file of about
+    /// `min_size` bytes.
+    SizeTargetUnreachable { target: u64, min_size: u64 },
+
+    /// A vocabulary contains the same word more than once.
+    DuplicateWord(String),
+
+    /// The labels for the time steps contain the same label more than once.
+    DuplicateTimestepLabel(String),
+
+    /// A string could not be parsed as an ISO 8601 calendar date (`YYYY-MM-DD`).
+    InvalidDate(String),
+
+    /// Time steps can't be appended to a file, e.g., because they lack time step
+    /// labels that the file has for its existing time steps.
+    IncompatibleAppend(&'static str),
+
+    /// An argument (e.g., a vocabulary) has the wrong length for the embeddings it
+    /// refers to.
+    LengthMismatch {
+        what: &'static str,
+        expected: usize,
+        found: usize,
+    },
+
+    /// The data is too large to be represented in the file format.
+    TooLarge,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::BadMagic(magic) => write!(
+                f,
+                "not a compressed dynamic word embeddings file (bad magic number 0x{:08x})",
+                magic
+            ),
+            Error::UnsupportedByteOrder => {
+                f.write_str("file is stored in big endian instead of little endian byte order")
+            }
+            Error::UnsupportedVersion { major, minor } => {
+                write!(f, "unsupported file format version {}.{}", major, minor)
+            }
+            Error::SymbolTypeMismatch { expected, found } => write!(
+                f,
+                "file stores {} symbols but was opened with symbol type {}",
+                found, expected
+            ),
+            Error::Truncated => f.write_str("file is truncated"),
+            Error::FileSizeMismatch { declared, actual } => write!(
+                f,
+                "file header declares a size of {} bytes but the file has {} bytes",
+                *declared as u128 * 4,
+                *actual as u128 * 4
+            ),
+            Error::InvalidHeader(reason) => write!(f, "invalid file header: {}", reason),
+            Error::InvalidEntropyModel { timestep } => {
+                write!(f, "invalid entropy model for time step {}", timestep)
+            }
+            Error::InconsistentJumpTable => {
+                f.write_str("jump table points outside of the compressed data")
+            }
+            Error::InvalidSectionTable => f.write_str("malformed section table"),
+            Error::InvalidSection { tag } => {
+                write!(f, "malformed optional section \"{}\"", tag_to_string(*tag))
+            }
+            Error::TimestepOutOfRange {
+                timestep,
+                num_timesteps,
+            } => write!(
+                f,
+                "time step {} out of range (there are {} time steps)",
+                timestep, num_timesteps
+            ),
+            Error::UnknownTimestepLabel(label) => {
+                write!(f, "no time step with label \"{}\"", label)
+            }
+            Error::WordIndexOutOfRange {
+                word_index,
+                vocab_size,
+            } => write!(
+                f,
+                "word index {} out of range (vocabulary size is {})",
+                word_index, vocab_size
+            ),
+            Error::ResidualOverflow {
+                timestep,
+                word_index,
+                dimension,
+            } => write!(
+                f,
+                "prediction residual overflows at time step {}, word index {}, dimension {}",
+                timestep, word_index, dimension
+            ),
+            Error::NonFiniteEmbedding {
+                timestep,
+                word_index,
+                dimension,
+            } => write!(
+                f,
+                "non-finite embedding vector component at time step {}, word index {}, \
+                    dimension {}",
+                timestep, word_index, dimension
+            ),
+            Error::QuantizationStepTooSmall { step, min_step } => write!(
+                f,
+                "quantization step {} is too small (must be at least {})",
+                step, min_step
+            ),
+            Error::SizeTargetUnreachable { target, min_size } => write!(
+                f,
+                "can't compress into {} bytes (the smallest possible file has about {} bytes)",
+                target, min_size
+            ),
+            Error::DuplicateWord(word) => write!(f, "duplicate word \"{}\" in vocabulary", word),
+            Error::DuplicateTimestepLabel(label) => {
+                write!(f, "duplicate time step label \"{}\"", label)
+            }
+            Error::InvalidDate(date) => {
+                write!(f, "invalid date \"{}\" (expected YYYY-MM-DD)", date)
+            }
+            Error::IncompatibleAppend(reason) => write!(f, "can't append time steps: {}", reason),
+            Error::LengthMismatch {
+                what,
+                expected,
+                found,
+            } => write!(f, "expected {} {} but found {}", expected, what, found),
+            Error::TooLarge => f.write_str("data too large for the file format"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            Error::Truncated
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
+/// Formats a section tag as the four ASCII characters it was made of.
+fn tag_to_string(tag: u32) -> String {
+    tag.to_le_bytes()
+        .iter()
+        .flat_map(|&byte| std::ascii::escape_default(byte))
+        .map(char::from)
+        .collect()
+}
@@ -1,5 +1,5 @@
-#[allow(clippy::result_unit_err)]
 pub mod embedding_file;
+pub mod error;
 pub mod random_access_reader;
 pub mod tensors;
 pub mod u12;
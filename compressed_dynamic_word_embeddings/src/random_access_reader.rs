@@ -1,29 +1,42 @@
-use std::cmp::Ordering::*;
+use std::cmp::Ordering::{self, *};
 use std::collections::BinaryHeap;
 
-use constriction::{stream::Decode, UnwrapInfallible};
-
+use crate::error::{Error, Result};
 use crate::tensors::RankTwoTensorViewMut;
 
-use super::embedding_file::{EmbeddingFile, TimestepReader};
+use super::embedding_file::{
+    predictor::Prediction, symbol::Symbol, timestep_labels::TimestepRef, EmbeddingFile,
+    TimestepReader, TimestepSource,
+};
 use super::tensors::{RankThreeTensor, RankTwoTensor, RankTwoTensorView};
 
-pub struct RandomAccessReader {
-    file: EmbeddingFile,
-
-    /// The height of the tree. The first and last time step each count as one
-    /// toward the tree height.
+/// Answers queries on a compressed dynamic word embeddings file.
+///
+/// The type parameter `F` is usually an [`EmbeddingFile`], but it can also be a
+/// [`LazyEmbeddingFile`](../embedding_file/lazy/struct.LazyEmbeddingFile.html) or
+/// any other [`TimestepSource`].
+pub struct RandomAccessReader<F = EmbeddingFile> {
+    file: F,
+
+    /// The height of the highest tree among all segments. The two roots of each
+    /// tree count as one each toward its height (unless they coincide, which can
+    /// only happen in the first segment).
     tree_height: u32,
 }
 
-impl RandomAccessReader {
-    pub fn new(embedding_file: EmbeddingFile) -> Self {
-        let num_timesteps = embedding_file.header().num_timesteps;
-        let tree_height = if num_timesteps <= 2 {
-            2
-        } else {
-            34 - (num_timesteps - 2).leading_zeros()
-        };
+impl<F: TimestepSource> RandomAccessReader<F> {
+    pub fn new(embedding_file: F) -> Self {
+        let segments = embedding_file.segments();
+        let tree_height = (0..segments.len())
+            .map(|i| {
+                let (left_t, right_t) = segments.roots(i);
+                match right_t - left_t {
+                    span @ (0 | 1) => span + 1,
+                    span => 34 - (span - 1).leading_zeros(),
+                }
+            })
+            .max()
+            .expect("there's at least one segment");
 
         Self {
             file: embedding_file,
@@ -31,49 +44,105 @@ impl RandomAccessReader {
         }
     }
 
+    /// Returns the underlying file, e.g., to look up words in its vocabulary.
+    pub fn file(&self) -> &F {
+        &self.file
+    }
+
+    /// Returns the scale factors of all embedding dimensions at time step `t`
+    /// relative to the global `scale_factor` from the header, or a single factor if
+    /// they don't depend on the dimension.
+    fn relative_scales_at(&self, t: u32) -> &[f32] {
+        self.file
+            .scale_factors()
+            .map_or(&[1.0], |scale_factors| scale_factors.at_timestep(t))
+    }
+
+    /// Returns how time step `t` is predicted from its parents.
+    fn prediction_at(&self, t: u32) -> Prediction {
+        Prediction::of(self.file.predictor(), t as usize)
+    }
+
+    fn check_word_indices(&self, words: &[u32]) -> Result<()> {
+        let vocab_size = self.file.header().vocab_size;
+        match words.iter().find(|&&word| word >= vocab_size) {
+            Some(&word_index) => Err(Error::WordIndexOutOfRange {
+                word_index,
+                vocab_size,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Calculates the scalar products between the embeddings of `words1[i]` and
+    /// `words2[i]` for all `i` and all time steps.
+    ///
+    /// Returns an error if `words1` and `words2` have different lengths or if any
+    /// word index is out of bounds.
     #[allow(clippy::too_many_arguments)]
     pub fn pairwise_trajectories(
         &self,
         mut words1: Vec<u32>,
         mut words2: Vec<u32>,
-    ) -> RankTwoTensor<f32> {
-        fn process_timestep(
-            mut reader: impl TimestepReader,
-            mut embeddings: RankTwoTensorViewMut<i16>,
+    ) -> Result<RankTwoTensor<f32>> {
+        fn process_timestep<R: TimestepReader>(
+            mut reader: R,
+            mut embeddings: RankTwoTensorViewMut<R::Symbol>,
             output: &mut [f32],
             unique_words: &[u32],
             words1: &[u32],
             words2: &[u32],
             embedding_dim: u32,
-            scale_factor_square: f32,
-        ) {
+            squared_scales: &[f32],
+        ) -> Result<()> {
             let mut embeddings_iter_mut = embeddings.as_mut_slice().iter_mut();
             for &word in unique_words {
-                reader.jump_to(word).unwrap();
-                reader
-                    .read_single_embedding_vector(
-                        (&mut embeddings_iter_mut).take(embedding_dim as usize),
-                        |n, dest| *dest = n,
-                    )
-                    .unwrap();
+                reader.jump_to(word)?;
+                reader.read_single_embedding_vector(
+                    (&mut embeddings_iter_mut).take(embedding_dim as usize),
+                    |n, dest| *dest = n,
+                )?;
             }
 
             for ((&w1, &w2), dest) in words1.iter().zip(words2).zip(output.iter_mut()) {
                 let embedding1 = embeddings.subview(w1 as usize);
                 let embedding2 = embeddings.subview(w2 as usize);
-                let scalar_product = embedding1
-                    .iter()
-                    .zip(embedding2)
-                    .map(|(&a, &b)| a as i32 * b as i32)
-                    .sum::<i32>();
-                *dest = scale_factor_square * scalar_product as f32;
+                *dest = match *squared_scales {
+                    [squared_scale] => {
+                        let scalar_product = embedding1
+                            .iter()
+                            .zip(embedding2)
+                            .map(|(&a, &b)| a.to_i64() as f64 * b.to_i64() as f64)
+                            .sum::<f64>();
+                        squared_scale * scalar_product as f32
+                    }
+                    _ => embedding1
+                        .iter()
+                        .zip(embedding2)
+                        .zip(squared_scales)
+                        .map(|((&a, &b), &s)| s * (a.to_i64() * b.to_i64()) as f32)
+                        .sum(),
+                };
             }
+
+            Ok(())
         }
 
-        if words1.is_empty() || words1.len() != words2.len() {
-            // TODO: handle error if words1.len() != words2.len()
-            return RankTwoTensor::new(0, self.file.header().num_timesteps as usize);
+        if words1.len() != words2.len() {
+            return Err(Error::LengthMismatch {
+                what: "words in `words2`",
+                expected: words1.len(),
+                found: words2.len(),
+            });
         }
+        if words1.is_empty() {
+            return Ok(RankTwoTensor::new(
+                0,
+                self.file.header().num_timesteps as usize,
+            ));
+        }
+        self.check_word_indices(&words1)?;
+        self.check_word_indices(&words2)?;
 
         let mut unique_words = words1
             .iter()
@@ -91,9 +160,17 @@ impl RandomAccessReader {
 
         let header = self.file.header();
         let embedding_dim = header.embedding_dim;
-        let scale_factor_square = header.scale_factor * header.scale_factor;
+        let squared_scales_at = |t| {
+            self.relative_scales_at(t)
+                .iter()
+                .map(|&relative_scale| {
+                    let scale = header.scale_factor * relative_scale;
+                    scale * scale
+                })
+                .collect::<Vec<f32>>()
+        };
 
-        let mut extracted_embeddings = RankThreeTensor::<i16>::new(
+        let mut extracted_embeddings = RankThreeTensor::<F::Symbol>::new(
             self.tree_height as usize,
             unique_words.len(),
             header.embedding_dim as usize,
@@ -103,60 +180,110 @@ impl RandomAccessReader {
         let mut output = RankTwoTensor::<f32>::new(header.num_timesteps as usize, words1.len());
         let mut output = output.as_view_mut();
 
-        // Extract relevant embeddings and calculate scalar products for first and last
-        // time step (levels 0 and 1).
-        for &(t, level) in &[(0, 0), (header.num_timesteps - 1, 1)] {
-            process_timestep(
-                self.file.timestep(t).unwrap(),
-                extracted_embeddings.subview_mut(level as usize),
-                output.subview_mut(t as usize),
-                &unique_words,
-                &words1,
-                &words2,
-                embedding_dim,
-                scale_factor_square,
-            );
-        }
-
-        traverse_subtree(
-            2,
-            0,
-            0,
-            header.num_timesteps - 1,
-            1,
-            &mut |t, level, _left_t, left_level, _right_t, right_level| {
-                let (left_parent, right_parent, target) = extracted_embeddings.subviews_rrw(
-                    left_level as usize,
-                    right_level as usize,
-                    level as usize,
-                );
-
-                let timestep = self.file.timestep(t).unwrap();
-                let reader = AccumulatingReader::new(left_parent, right_parent, timestep);
-                process_timestep(
-                    reader,
-                    target,
+        // Extracts the relevant embeddings at time step `t` into `level` and
+        // calculates their scalar products. Keyframes (`parents == None`) are read
+        // directly from the file, all other time steps are predicted from the
+        // embeddings at the levels `parents`.
+        let mut result = Ok(());
+        let mut process = |t, level, parents: Option<(u32, u32)>| {
+            if result.is_err() {
+                return false;
+            }
+            result = self.file.timestep(t).and_then(|timestep| match parents {
+                None => process_timestep(
+                    timestep,
+                    extracted_embeddings.subview_mut(level as usize),
                     output.subview_mut(t as usize),
                     &unique_words,
                     &words1,
                     &words2,
                     embedding_dim,
-                    scale_factor_square,
-                );
-                (true, true)
-            },
-        );
+                    &squared_scales_at(t),
+                ),
+                Some((left_level, right_level)) => {
+                    let (left_parent, right_parent, target) = extracted_embeddings.subviews_rrw(
+                        left_level as usize,
+                        right_level as usize,
+                        level as usize,
+                    );
+                    process_timestep(
+                        AccumulatingReader::new(
+                            left_parent,
+                            right_parent,
+                            timestep,
+                            self.prediction_at(t),
+                        ),
+                        target,
+                        output.subview_mut(t as usize),
+                        &unique_words,
+                        &words1,
+                        &words2,
+                        embedding_dim,
+                        &squared_scales_at(t),
+                    )
+                }
+            });
+            result.is_ok()
+        };
+
+        // Process segment by segment. The roots of all segments alternate between
+        // levels 0 and 1, and the remaining time steps of each segment use the
+        // levels from 2 upwards.
+        let segments = self.file.segments();
+        process(0, 0, None);
+        let mut left_level = 0;
+        for i in 0..segments.len() {
+            let (left_t, right_t) = segments.roots(i);
+            if right_t == left_t {
+                continue; // Can only happen in the first segment.
+            }
+            let right_level = 1 - left_level;
+            let parents = if segments.is_keyframe(i) {
+                None
+            } else {
+                Some((left_level, left_level))
+            };
+            if !process(right_t, right_level, parents) {
+                break;
+            }
+            traverse_subtree(
+                2,
+                left_t,
+                left_level,
+                right_t,
+                right_level,
+                &mut |t, level, _, left_level, _, right_level| {
+                    let ok = process(t, level, Some((left_level, right_level)));
+                    (ok, ok)
+                },
+            );
+            left_level = right_level;
+        }
+        result?;
 
-        output.downgrade().to_transposed()
+        Ok(output.downgrade().to_transposed())
     }
 
-    pub fn most_related_to_at_t(
+    /// Finds the `amt` words whose embeddings have the largest scalar product with
+    /// the embeddings of each one of the `target_words` at time step `t`.
+    ///
+    /// The time step can be specified either by its index or by its label. Returns
+    /// an error if `t` or any of the `target_words` is out of bounds, or if no time
+    /// step has the provided label. Scalar products take the file's
+    /// [`ScaleFactors`](../embedding_file/scale_factors/struct.ScaleFactors.html)
+    /// into account, if any.
+    pub fn most_related_to_at_t<'a>(
         &self,
         target_words: Vec<u32>,
-        t: u32,
+        t: impl Into<TimestepRef<'a>>,
         amt: u32,
-    ) -> RankTwoTensor<u32> {
-        let embeddings = self.get_embeddings_at(t);
+    ) -> Result<RankTwoTensor<u32>> {
+        self.check_word_indices(&target_words)?;
+        let t = self.file.resolve_timestep(t)?;
+        let embeddings = self.get_embeddings_at(t)?;
+        if amt == 0 {
+            return Ok(RankTwoTensor::new(target_words.len(), 0));
+        }
         let embeddings = embeddings.as_view();
 
         let mut unique_words = target_words
@@ -170,17 +297,27 @@ impl RandomAccessReader {
         let header = self.file.header();
         let embedding_dim = header.embedding_dim;
 
+        // Weight the target embeddings with the squared scale factors so that a plain
+        // scalar product with another (quantized) embedding yields the scalar product
+        // of the scaled embeddings, up to the constant factor `header.scale_factor^2`.
+        let scales = self.relative_scales_at(t);
         let mut target_embeddings = RankTwoTensor::new(unique_words.len(), embedding_dim as usize);
         for (&word, target) in unique_words
             .iter()
             .zip(target_embeddings.as_view_mut().iter_mut_subviews())
         {
-            target.copy_from_slice(embeddings.subview(word as usize));
+            for ((dest, &component), &scale) in target
+                .iter_mut()
+                .zip(embeddings.subview(word as usize))
+                .zip(scales.iter().cycle())
+            {
+                *dest = component.to_i64() as f64 * (scale as f64 * scale as f64);
+            }
         }
         let target_embeddings = target_embeddings.as_view();
 
         let mut front_runners =
-            RankTwoTensor::<FrontRunnerCandidate<i32>>::new(unique_words.len(), amt as usize);
+            RankTwoTensor::<FrontRunnerCandidate<f64>>::new(unique_words.len(), amt as usize);
         let mut front_runners = front_runners.as_view_mut();
 
         for (word, embedding) in embeddings.iter_subviews().enumerate() {
@@ -192,8 +329,8 @@ impl RandomAccessReader {
                 let scalar_product = embedding
                     .iter()
                     .zip(target_embedding)
-                    .map(|(&a, &b)| a as i32 * b as i32)
-                    .sum::<i32>();
+                    .map(|(&a, &b)| a.to_i64() as f64 * b)
+                    .sum::<f64>();
 
                 let (mut last_fr, remaining_fr) = front_runners.split_last_mut().unwrap();
 
@@ -234,111 +371,222 @@ impl RandomAccessReader {
             }
         }
 
-        reordered_top_k
+        Ok(reordered_top_k)
     }
 
-    pub fn get_embeddings_at(&self, t: u32) -> RankTwoTensor<i16> {
+    /// Decodes the (quantized) embedding vectors of all words at time step `t`.
+    ///
+    /// The time step can be specified either by its index or by its label. Returns
+    /// an error if `t` is out of bounds or if no time step has the provided label.
+    pub fn get_embeddings_at<'a>(
+        &self,
+        t: impl Into<TimestepRef<'a>>,
+    ) -> Result<RankTwoTensor<F::Symbol>> {
+        let t = self.file.resolve_timestep(t)?;
         let header = self.file.header();
-        let timestep_size = header.vocab_size * header.embedding_dim;
-
-        let extract_timestep = |t| {
-            let (mut decoder, model) = self.file.timestep(t).unwrap().into_inner();
-            decoder
-                .decode_iid_symbols(timestep_size as usize, model)
-                .map(UnwrapInfallible::unwrap_infallible)
-                .collect::<Vec<_>>()
+        let (vocab_size, embedding_dim) = (header.vocab_size, header.embedding_dim);
+        let timestep_size = (vocab_size * embedding_dim) as usize;
+
+        let segments = self.file.segments();
+        let segment = segments.segment_of(t);
+        let (mut t_left, mut t_right) = segments.roots(segment);
+
+        let decode = |t, buf: &mut [F::Symbol], parent: Option<&[F::Symbol]>| match parent {
+            None => read_timestep(self.file.timestep(t)?, buf, embedding_dim),
+            Some(parent) => {
+                let parent = RankTwoTensorView::from_flattened(vocab_size, embedding_dim, parent);
+                let reader = AccumulatingReader::new(
+                    parent,
+                    parent,
+                    self.file.timestep(t)?,
+                    self.prediction_at(t),
+                );
+                read_timestep(reader, buf, embedding_dim)
+            }
         };
 
-        let result = if t == 0 || t == header.num_timesteps - 1 {
-            extract_timestep(t)
+        let result = if (t == t_right && segments.is_keyframe(segment)) || t == 0 {
+            let mut buf = vec![F::Symbol::default(); timestep_size];
+            decode(t, &mut buf, None)?;
+            buf
         } else {
-            let mut t_left = 0;
-            let mut t_right = header.num_timesteps - 1;
-            let mut buf_left = extract_timestep(t_left);
-            let mut buf_right = extract_timestep(t_right);
-            let mut buf = vec![0; timestep_size as usize]; // TODO: use MaybeUninit
-
-            loop {
-                let t_center = (t_left + t_right) / 2;
-                let (mut decoder, model) = self.file.timestep(t_center).unwrap().into_inner();
-                let symbols = decoder.decode_iid_symbols(timestep_size as usize, model);
-                for (((s, target), &l), &r) in
-                    symbols.zip(buf.iter_mut()).zip(&buf_left).zip(&buf_right)
-                {
-                    *target = s
-                        .unwrap_infallible()
-                        .wrapping_add(((l as i32 + r as i32) / 2) as i16);
+            let mut buf_left = vec![F::Symbol::default(); timestep_size];
+            let mut buf_right = vec![F::Symbol::default(); timestep_size];
+
+            // Decode the left root, which is either time step zero or the end of the
+            // previous segment. In the latter case, walk along the ends of all
+            // preceding segments, starting from the closest keyframe.
+            if segment == 0 {
+                decode(0, &mut buf_left, None)?;
+            } else {
+                let keyframe = (0..segment)
+                    .rev()
+                    .find(|&i| segments.is_keyframe(i))
+                    .expect("the first segment ends in a keyframe");
+                let ends = segments.ends();
+                decode(ends[keyframe], &mut buf_left, None)?;
+                for &end in &ends[keyframe + 1..segment] {
+                    decode(end, &mut buf_right, Some(&buf_left))?;
+                    std::mem::swap(&mut buf_left, &mut buf_right);
                 }
+            }
 
-                match t_center.cmp(&t) {
-                    Equal => break buf,
-                    Less => {
-                        t_left = t_center;
-                        std::mem::swap(&mut buf, &mut buf_left);
-                    }
-                    Greater => {
-                        t_right = t_center;
-                        std::mem::swap(&mut buf, &mut buf_right);
+            let parent = if segments.is_keyframe(segment) {
+                None
+            } else {
+                Some(&buf_left[..])
+            };
+            decode(t_right, &mut buf_right, parent)?;
+
+            if t == t_right {
+                buf_right
+            } else {
+                let mut buf = vec![F::Symbol::default(); timestep_size]; // TODO: use MaybeUninit
+                loop {
+                    let t_center = (t_left + t_right) / 2;
+                    let reader = AccumulatingReader::new(
+                        RankTwoTensorView::from_flattened(vocab_size, embedding_dim, &buf_left),
+                        RankTwoTensorView::from_flattened(vocab_size, embedding_dim, &buf_right),
+                        self.file.timestep(t_center)?,
+                        self.prediction_at(t_center),
+                    );
+                    read_timestep(reader, &mut buf, embedding_dim)?;
+
+                    match t_center.cmp(&t) {
+                        Equal => break buf,
+                        Less => {
+                            t_left = t_center;
+                            std::mem::swap(&mut buf, &mut buf_left);
+                        }
+                        Greater => {
+                            t_right = t_center;
+                            std::mem::swap(&mut buf, &mut buf_right);
+                        }
                     }
                 }
             }
         };
 
-        RankTwoTensor::from_flattened(
+        Ok(RankTwoTensor::from_flattened(
             result,
+            vocab_size as usize,
+            embedding_dim as usize,
+        ))
+    }
+
+    /// Decodes the embedding vectors of all words at time step `t` and scales them
+    /// back to real numbers.
+    ///
+    /// Each quantized component gets multiplied with the global `scale_factor` from
+    /// the file header and, if the file contains
+    /// [`ScaleFactors`](../embedding_file/scale_factors/struct.ScaleFactors.html),
+    /// with the scale factor for its dimension and time step. Returns an error under
+    /// the same conditions as [`get_embeddings_at`](#method.get_embeddings_at).
+    pub fn get_dequantized_embeddings_at<'a>(
+        &self,
+        t: impl Into<TimestepRef<'a>>,
+    ) -> Result<RankTwoTensor<f32>> {
+        let t = self.file.resolve_timestep(t)?;
+        let header = self.file.header();
+        let scales = self
+            .relative_scales_at(t)
+            .iter()
+            .map(|&relative_scale| header.scale_factor * relative_scale)
+            .collect::<Vec<_>>();
+        let dequantized = self
+            .get_embeddings_at(t)?
+            .into_inner()
+            .chunks_exact(header.embedding_dim as usize)
+            .flat_map(|embedding| {
+                embedding
+                    .iter()
+                    .zip(scales.iter().cycle())
+                    .map(|(&component, &scale)| scale * component.to_i64() as f32)
+            })
+            .collect();
+
+        Ok(RankTwoTensor::from_flattened(
+            dequantized,
             header.vocab_size as usize,
             header.embedding_dim as usize,
-        )
+        ))
     }
 
+    /// Returns an error if `target_word` is out of bounds. Both `min_increasing` and
+    /// `min_decreasing` are clipped to `amt`. Scalar products take the file's
+    /// [`ScaleFactors`](../embedding_file/scale_factors/struct.ScaleFactors.html)
+    /// into account, if any.
     pub fn largest_changes_wrt(
         &self,
         target_word: u32,
         amt: u32,
         min_increasing: u32,
         min_decreasing: u32,
-    ) -> Vec<u32> {
+    ) -> Result<Vec<u32>> {
+        self.check_word_indices(&[target_word])?;
+        let min_increasing = min_increasing.min(amt);
+        let min_decreasing = min_decreasing.min(amt);
         let header = self.file.header();
         let num_timesteps = header.num_timesteps;
         let vocab_size = header.vocab_size;
         let embedding_dim = header.embedding_dim;
 
-        let extract_single_embedding_vector = |t, i| {
-            let mut timestep = self.file.timestep(t).unwrap();
-            timestep.jump_to(i).unwrap();
+        // The last time step can be read directly from the file only if it's a
+        // keyframe. Otherwise, we have to decode it in full.
+        let segments = self.file.segments();
+        let decoded_last = if segments.is_keyframe(segments.len() - 1) {
+            None
+        } else {
+            Some(self.get_embeddings_at(num_timesteps - 1)?)
+        };
+
+        // Weights the target embedding vector with the squared scale factors (see
+        // `most_related_to_at_t`).
+        let extract_single_embedding_vector = |t, i| -> Result<_> {
+            let mut timestep = match &decoded_last {
+                Some(decoded) if t != 0 => TimestepOrDecoded::Decoded {
+                    embeddings: decoded.as_view(),
+                    word_index: 0,
+                },
+                _ => TimestepOrDecoded::Timestep(self.file.timestep(t)?),
+            };
+            timestep.jump_to(i)?;
             let mut emb_vector = Vec::with_capacity(embedding_dim as usize);
-            timestep
-                .read_single_embedding_vector(0..embedding_dim, |s, _| emb_vector.push(s))
-                .unwrap();
-            timestep.jump_to(0).unwrap();
-            (emb_vector, timestep.into_inner())
+            timestep.read_single_embedding_vector(
+                self.relative_scales_at(t)
+                    .iter()
+                    .cycle()
+                    .take(embedding_dim as usize),
+                |s, &scale| emb_vector.push(s.to_i64() as f64 * (scale as f64 * scale as f64)),
+            )?;
+            timestep.jump_to(0)?;
+            Ok((emb_vector, timestep))
         };
 
-        let (first_target, (mut first_timestep_decoder, first_timestep_model)) =
-            extract_single_embedding_vector(0, target_word);
-        let (last_target, (mut last_timestep_decoder, last_timestep_model)) =
-            extract_single_embedding_vector(num_timesteps - 1, target_word);
+        let (first_target, mut first_timestep) = extract_single_embedding_vector(0, target_word)?;
+        let (last_target, mut last_timestep) =
+            extract_single_embedding_vector(num_timesteps - 1, target_word)?;
+        let dot_product_with = |timestep: &mut TimestepOrDecoded<'_, F::Timestep<'_>>,
+                                target: &[f64]| {
+            let mut dot_product = 0.0;
+            timestep.read_single_embedding_vector(target.iter(), |a, &b| {
+                dot_product += a.to_i64() as f64 * b
+            })?;
+            Ok::<_, Error>(dot_product)
+        };
 
-        let mut increasing_front_runners = Vec::<FrontRunnerCandidate<i64>>::new();
+        let mut increasing_front_runners = Vec::<FrontRunnerCandidate<f64>>::new();
         increasing_front_runners.resize_with(amt as usize, Default::default);
 
-        let mut decreasing_front_runners = Vec::<FrontRunnerCandidate<i64>>::new();
+        let mut decreasing_front_runners = Vec::<FrontRunnerCandidate<f64>>::new();
         decreasing_front_runners.resize_with(amt as usize, Default::default);
 
         for word in 0..vocab_size {
-            let first_dot_product = first_timestep_decoder
-                .decode_iid_symbols(embedding_dim as usize, first_timestep_model)
-                .zip(&first_target)
-                .map(|(a, &b)| a.unwrap_infallible() as i32 * b as i32)
-                .sum::<i32>();
-            let last_dot_product = last_timestep_decoder
-                .decode_iid_symbols(embedding_dim as usize, last_timestep_model)
-                .zip(&last_target)
-                .map(|(a, &b)| a.unwrap_infallible() as i32 * b as i32)
-                .sum::<i32>();
+            let first_dot_product = dot_product_with(&mut first_timestep, &first_target)?;
+            let last_dot_product = dot_product_with(&mut last_timestep, &last_target)?;
 
             if word != target_word {
-                let diff = last_dot_product as i64 - first_dot_product as i64;
+                let diff = last_dot_product - first_dot_product;
 
                 let increasing_last_better = increasing_front_runners
                     .iter()
@@ -383,19 +631,20 @@ impl RandomAccessReader {
         // Then put the remaining items and sort them.
         combined.extend_from_slice(&increasing_front_runners[min_increasing as usize..]);
         combined.extend_from_slice(&decreasing_front_runners[min_decreasing as usize..]);
-        combined[(min_increasing + min_decreasing) as usize..].sort_by_key(|fr| -fr.n);
+        combined[(min_increasing + min_decreasing) as usize..]
+            .sort_by(FrontRunnerCandidate::cmp_desc);
 
         // We will keep on only the first half of the list. Sort it as well by magnitude
         // of the change, so that in particular the first result (which a viewer may
         // highlight by default) is the one with the largest change in magnitude.
-        combined[..amt as usize].sort_by_key(|fr| -fr.n);
+        combined[..amt as usize].sort_by(FrontRunnerCandidate::cmp_desc);
 
         // Retain only the `word` part of the first half of the list.
-        combined
+        Ok(combined
             .into_iter()
             .take(amt as usize)
             .map(|fr| fr.word)
-            .collect()
+            .collect())
     }
 }
 
@@ -405,75 +654,155 @@ struct FrontRunnerCandidate<T> {
     n: T,
 }
 
-impl Default for FrontRunnerCandidate<i32> {
-    fn default() -> Self {
-        Self {
-            word: u32::MAX,
-            n: i32::MIN,
-        }
+impl FrontRunnerCandidate<f64> {
+    /// Orders candidates by descending `n`.
+    fn cmp_desc(&self, other: &Self) -> Ordering {
+        other.n.partial_cmp(&self.n).unwrap_or(Equal)
     }
 }
 
-impl Default for FrontRunnerCandidate<i64> {
+impl Default for FrontRunnerCandidate<f64> {
     fn default() -> Self {
         Self {
             word: u32::MAX,
-            n: i64::MIN,
+            n: f64::NEG_INFINITY,
         }
     }
 }
 
-struct AccumulatingReader<R: TimestepReader, LI: Iterator<Item = i16>, RI: Iterator<Item = i16>> {
+struct AccumulatingReader<
+    R: TimestepReader,
+    LI: Iterator<Item = R::Symbol>,
+    RI: Iterator<Item = R::Symbol>,
+> {
     inner: R,
     left_parent: LI,
     right_parent: RI,
+    prediction: Prediction,
 }
 
 impl<'a, R: TimestepReader>
     AccumulatingReader<
         R,
-        std::iter::Cloned<std::slice::Iter<'a, i16>>,
-        std::iter::Cloned<std::slice::Iter<'a, i16>>,
+        std::iter::Cloned<std::slice::Iter<'a, R::Symbol>>,
+        std::iter::Cloned<std::slice::Iter<'a, R::Symbol>>,
     >
 {
     fn new(
-        left_parent: RankTwoTensorView<'a, i16>,
-        right_parent: RankTwoTensorView<'a, i16>,
+        left_parent: RankTwoTensorView<'a, R::Symbol>,
+        right_parent: RankTwoTensorView<'a, R::Symbol>,
         center: R,
+        prediction: Prediction,
     ) -> Self {
         Self {
             left_parent: left_parent.slice().iter().cloned(),
             right_parent: right_parent.slice().iter().cloned(),
             inner: center,
+            prediction,
         }
     }
 }
 
-impl<R: TimestepReader, LI: Iterator<Item = i16>, RI: Iterator<Item = i16>> TimestepReader
-    for AccumulatingReader<R, LI, RI>
+impl<R: TimestepReader, LI: Iterator<Item = R::Symbol>, RI: Iterator<Item = R::Symbol>>
+    TimestepReader for AccumulatingReader<R, LI, RI>
 {
+    type Symbol = R::Symbol;
+
     fn read_single_embedding_vector<I: Iterator>(
         &mut self,
         dest_iter: I,
-        mut callback: impl FnMut(i16, I::Item),
-    ) -> Result<(), ()> {
+        mut callback: impl FnMut(R::Symbol, I::Item),
+    ) -> Result<()> {
+        let prediction = self.prediction;
         self.inner.read_single_embedding_vector(
             dest_iter
                 .zip(&mut self.left_parent)
                 .zip(&mut self.right_parent),
             |center, ((dest, left), right)| {
-                let value = center.wrapping_add(((left as i32 + right as i32) / 2) as i16);
+                let value = center.wrapping_add(prediction.predict(left, right));
                 callback(value, dest);
             },
         )
     }
 
     #[inline(always)]
-    fn jump_to(&mut self, word_index: u32) -> Result<(), ()> {
+    fn jump_to(&mut self, word_index: u32) -> Result<()> {
         self.inner.jump_to(word_index)
     }
 }
 
+/// Reads a time step either from the file or from a copy that has already been
+/// decoded in full.
+enum TimestepOrDecoded<'a, R: TimestepReader> {
+    Timestep(R),
+    Decoded {
+        embeddings: RankTwoTensorView<'a, R::Symbol>,
+        word_index: u32,
+    },
+}
+
+impl<R: TimestepReader> TimestepReader for TimestepOrDecoded<'_, R> {
+    type Symbol = R::Symbol;
+
+    fn read_single_embedding_vector<I: Iterator>(
+        &mut self,
+        dest_iter: I,
+        mut callback: impl FnMut(R::Symbol, I::Item),
+    ) -> Result<()> {
+        match self {
+            TimestepOrDecoded::Timestep(timestep) => {
+                timestep.read_single_embedding_vector(dest_iter, callback)
+            }
+            TimestepOrDecoded::Decoded {
+                embeddings,
+                word_index,
+            } => {
+                for (&value, dest) in embeddings
+                    .subview(*word_index as usize)
+                    .iter()
+                    .zip(dest_iter)
+                {
+                    callback(value, dest);
+                }
+                *word_index += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn jump_to(&mut self, new_word_index: u32) -> Result<()> {
+        match self {
+            TimestepOrDecoded::Timestep(timestep) => timestep.jump_to(new_word_index),
+            TimestepOrDecoded::Decoded {
+                embeddings,
+                word_index,
+            } => {
+                let vocab_size = embeddings.shape().0 as u32;
+                if new_word_index >= vocab_size {
+                    return Err(Error::WordIndexOutOfRange {
+                        word_index: new_word_index,
+                        vocab_size,
+                    });
+                }
+                *word_index = new_word_index;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Decodes the embedding vectors of all words from `reader` into `dest`.
+fn read_timestep<R: TimestepReader>(
+    mut reader: R,
+    dest: &mut [R::Symbol],
+    embedding_dim: u32,
+) -> Result<()> {
+    for embedding in dest.chunks_exact_mut(embedding_dim as usize) {
+        reader.read_single_embedding_vector(embedding.iter_mut(), |value, dest| *dest = value)?;
+    }
+    Ok(())
+}
+
 fn traverse_subtree(
     level: u32,
     left_t: u32,
@@ -497,7 +826,16 @@ fn traverse_subtree(
 
 #[cfg(test)]
 mod test {
-    use crate::embedding_file::builder::write_compressed_dwe_file;
+    use crate::embedding_file::{
+        builder::{
+            write_compressed_dwe_file, write_compressed_dwe_file_with_options, CompressionOptions,
+        },
+        lazy::{InMemoryRangeSource, LazyEmbeddingFile},
+        scale_factors::ScaleFactors,
+        timestep_labels::{TimestepLabel, TimestepLabels},
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use super::*;
 
@@ -510,6 +848,7 @@ mod test {
 
         let trajectories = reader
             .pairwise_trajectories(vec![3, 50, 1], vec![70, 3, 12])
+            .unwrap()
             .into_inner();
 
         const EXPECTED: [f32; 3 * 6] = [
@@ -542,6 +881,18 @@ mod test {
                 found
             );
         }
+
+        assert!(matches!(
+            reader.pairwise_trajectories(vec![3, 100], vec![70, 3]),
+            Err(Error::WordIndexOutOfRange {
+                word_index: 100,
+                vocab_size: 100
+            })
+        ));
+        assert!(matches!(
+            reader.pairwise_trajectories(vec![3, 50], vec![70]),
+            Err(Error::LengthMismatch { .. })
+        ));
     }
 
     #[test]
@@ -578,13 +929,181 @@ mod test {
         for t in 0..6 {
             let related_words = reader
                 .most_related_to_at_t(vec![3, 34, 4], t, 10)
+                .unwrap()
                 .into_inner();
 
             assert_eq!(related_words, EXPECTED[t as usize]);
         }
     }
 
+    #[test]
+    fn query_by_timestep_label() {
+        let labels = TimestepLabels::from_integers((1800..1806).collect()).unwrap();
+        let reader = RandomAccessReader::new(create_sample_file_with_labels(Some(&labels)));
+
+        let label = TimestepLabel::Integer(1803);
+        assert_eq!(reader.file().resolve_timestep(&label).unwrap(), 3);
+        assert_eq!(
+            reader
+                .most_related_to_at_t(vec![3, 34], &label, 5)
+                .unwrap()
+                .into_inner(),
+            reader
+                .most_related_to_at_t(vec![3, 34], 3, 5)
+                .unwrap()
+                .into_inner()
+        );
+        assert_eq!(
+            reader.get_embeddings_at(&label).unwrap().into_inner(),
+            reader.get_embeddings_at(3).unwrap().into_inner()
+        );
+
+        assert!(matches!(
+            reader.get_embeddings_at(&TimestepLabel::Integer(1806)),
+            Err(Error::UnknownTimestepLabel(label)) if label == "1806"
+        ));
+        assert!(matches!(
+            reader.get_embeddings_at(6),
+            Err(Error::TimestepOutOfRange {
+                timestep: 6,
+                num_timesteps: 6
+            })
+        ));
+    }
+
+    #[test]
+    fn scale_factors() {
+        const NUM_TIMESTEPS: usize = 5;
+        const VOCAB_SIZE: usize = 40;
+        const EMBEDDING_DIM: usize = 6;
+        const SCALE_FACTOR: f32 = 0.5;
+
+        let mut rng = StdRng::seed_from_u64(20_201_118);
+        let uncompressed = (0..NUM_TIMESTEPS * VOCAB_SIZE * EMBEDDING_DIM)
+            .map(|_| rng.random_range(-50..=50))
+            .collect::<Vec<i16>>();
+        let uncompressed =
+            RankThreeTensor::from_flattened(uncompressed, NUM_TIMESTEPS, VOCAB_SIZE, EMBEDDING_DIM);
+
+        let per_dimension = (0..EMBEDDING_DIM)
+            .map(|_| rng.random_range(0.1..2.0))
+            .collect::<Vec<f32>>();
+        let per_timestep_and_dimension = (0..NUM_TIMESTEPS * EMBEDDING_DIM)
+            .map(|_| rng.random_range(0.1..2.0))
+            .collect::<Vec<f32>>();
+        let all_scale_factors = [
+            ScaleFactors::per_timestep(vec![1.0, 0.5, 3.0, 0.25, 2.0]),
+            ScaleFactors::per_dimension(per_dimension),
+            ScaleFactors::from_flattened(per_timestep_and_dimension, NUM_TIMESTEPS, EMBEDDING_DIM),
+        ];
+
+        for scale_factors in all_scale_factors {
+            let mut options = CompressionOptions::new(8, SCALE_FACTOR);
+            options.scale_factors = Some(scale_factors.clone());
+            let mut compressed = Vec::<u8>::new();
+            write_compressed_dwe_file_with_options(
+                uncompressed.as_view(),
+                None,
+                None,
+                &options,
+                &mut compressed,
+            )
+            .unwrap();
+
+            let file = EmbeddingFile::from_reader(&compressed[..]).unwrap();
+            assert_eq!(file.scale_factors(), Some(&scale_factors));
+            let lazy = LazyEmbeddingFile::new(InMemoryRangeSource::new(&compressed[..]))
+                .unwrap()
+                .into_random_access_reader();
+            assert_eq!(lazy.file().scale_factors(), Some(&scale_factors));
+            let reader = file.into_random_access_reader();
+
+            // Dequantize by hand.
+            let expected = (0..NUM_TIMESTEPS)
+                .map(|t| {
+                    let embeddings = uncompressed.as_view().subview(t);
+                    (0..VOCAB_SIZE)
+                        .map(|i| {
+                            embeddings
+                                .subview(i)
+                                .iter()
+                                .enumerate()
+                                .map(|(d, &u)| {
+                                    SCALE_FACTOR * scale_factors.get(t as u32, d as u32) * u as f32
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let dot = |t: usize, i: usize, j: usize| {
+                expected[t][i]
+                    .iter()
+                    .zip(&expected[t][j])
+                    .map(|(&a, &b)| a as f64 * b as f64)
+                    .sum::<f64>()
+            };
+
+            for (t, expected) in expected.iter().enumerate() {
+                let dequantized = reader
+                    .get_dequantized_embeddings_at(t as u32)
+                    .unwrap()
+                    .into_inner();
+                assert_eq!(dequantized, expected.concat());
+            }
+
+            let words1 = vec![0, 7, 39];
+            let words2 = vec![3, 7, 12];
+            let trajectories = reader
+                .pairwise_trajectories(words1.clone(), words2.clone())
+                .unwrap()
+                .into_inner();
+            assert_eq!(
+                lazy.pairwise_trajectories(words1.clone(), words2.clone())
+                    .unwrap()
+                    .into_inner(),
+                trajectories
+            );
+            for (k, (&w1, &w2)) in words1.iter().zip(&words2).enumerate() {
+                for t in 0..NUM_TIMESTEPS {
+                    let expected = dot(t, w1 as usize, w2 as usize);
+                    let found = trajectories[k * NUM_TIMESTEPS + t] as f64;
+                    assert!((found - expected).abs() < 1e-3 * (1.0 + expected.abs()));
+                }
+            }
+
+            for t in 0..NUM_TIMESTEPS {
+                let related = reader
+                    .most_related_to_at_t(vec![5], t as u32, 4)
+                    .unwrap()
+                    .into_inner();
+                let mut candidates = (0..VOCAB_SIZE).filter(|&i| i != 5).collect::<Vec<_>>();
+                candidates.sort_by(|&i, &j| dot(t, 5, j).partial_cmp(&dot(t, 5, i)).unwrap());
+                let candidates = candidates[..4]
+                    .iter()
+                    .map(|&i| i as u32)
+                    .collect::<Vec<_>>();
+                assert_eq!(related, candidates);
+            }
+
+            let changes = reader.largest_changes_wrt(5, 4, 0, 0).unwrap();
+            assert_eq!(lazy.largest_changes_wrt(5, 4, 0, 0).unwrap(), changes);
+            let change = |i| (dot(NUM_TIMESTEPS - 1, 5, i) - dot(0, 5, i)).abs();
+            let mut candidates = (0..VOCAB_SIZE).filter(|&i| i != 5).collect::<Vec<_>>();
+            candidates.sort_by(|&i, &j| change(j).partial_cmp(&change(i)).unwrap());
+            let candidates = candidates[..4]
+                .iter()
+                .map(|&i| i as u32)
+                .collect::<Vec<_>>();
+            assert_eq!(changes, candidates);
+        }
+    }
+
     fn create_sample_file() -> EmbeddingFile {
+        create_sample_file_with_labels(None)
+    }
+
+    fn create_sample_file_with_labels(timestep_labels: Option<&TimestepLabels>) -> EmbeddingFile {
         const NUM_TIMESTEPS: u32 = 6;
         const VOCAB_SIZE: u32 = 100;
         const EMBEDDING_DIM: u32 = 16;
@@ -631,6 +1150,8 @@ mod test {
 
         write_compressed_dwe_file(
             uncompressed.as_view(),
+            None,
+            timestep_labels,
             JUMP_INTERVAL,
             SCALE_FACTOR,
             &mut compressed,
@@ -204,6 +204,13 @@ impl<'a, T> RankTwoTensorView<'a, T> {
         Self { stride0, data }
     }
 
+    pub fn shape(self) -> (usize, usize) {
+        match self.stride0 {
+            0 => (0, 0),
+            stride0 => (self.data.len() / stride0, stride0),
+        }
+    }
+
     pub fn subview(self, index0: usize) -> &'a [T] {
         let start = index0 * self.stride0;
         let end = start + self.stride0;
@@ -1,9 +1,11 @@
+use std::convert::TryFrom;
 use std::mem::MaybeUninit;
 
 use wasm_bindgen::prelude::*;
 
 use compressed_dynamic_word_embeddings::{
-    embedding_file::{EmbeddingFile, FileHeader, HEADER_SIZE},
+    embedding_file::{EmbeddingFile, FileHeader, HEADER_SIZE, HEADER_SIZE_V2},
+    error::Error,
     random_access_reader::RandomAccessReader,
 };
 
@@ -35,7 +37,7 @@ impl EmbeddingFileBuilder {
         self.buf.resize_with(
             usize::max(
                 (self.bytes_initialized + additional_bytes).div_ceil(4),
-                HEADER_SIZE as usize,
+                HEADER_SIZE_V2 as usize,
             ),
             MaybeUninit::uninit,
         );
@@ -59,34 +61,41 @@ impl EmbeddingFileBuilder {
     /// `file_size` bytes have to be written to the builder (including the ones
     /// already written).
     ///
+    /// Throws an error if the header declares a file size that is smaller than the
+    /// header itself or too large to fit into memory.
+    ///
     /// # Safety
     ///
     /// The builder trusts the caller that it really has initialized `amt`
     /// additional bytes before this method is called.
-    pub fn avail(&mut self, amt: usize) -> Option<PointerAndLen> {
+    pub fn avail(&mut self, amt: usize) -> Result<Option<PointerAndLen>, JsError> {
         self.bytes_initialized += amt;
 
         unsafe {
-            const HEADER_BYTES: usize = HEADER_SIZE as usize * 4;
-            if self.bytes_initialized >= HEADER_BYTES {
-                let ptr = self.buf.as_ptr();
-                let header_u32s =
-                    std::slice::from_raw_parts(ptr as *const u32, HEADER_SIZE as usize);
-                let file_size = FileHeader::memory_map_unsafe(header_u32s).file_size;
-
-                if file_size as usize >= self.buf.len() {
-                    self.buf.reserve_exact(file_size as usize - self.buf.len());
-                    self.buf
-                        .resize_with(file_size as usize, MaybeUninit::uninit);
-                }
-
-                Some(PointerAndLen {
-                    pointer: self.buf.as_mut_ptr() as *mut u8,
-                    len: file_size as usize * 4,
+            let ptr = self.buf.as_ptr();
+            let available = usize::min(self.bytes_initialized, 4 * self.buf.len());
+            let header_bytes = std::slice::from_raw_parts(ptr as *const u8, available);
+            let file_size = match FileHeader::from_le_bytes(header_bytes) {
+                Ok(header) => header.file_size,
+                Err(Error::Truncated) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            let file_size = usize::try_from(file_size)
+                .ok()
+                .filter(|&file_size| {
+                    file_size >= HEADER_SIZE as usize && file_size.checked_mul(4).is_some()
                 })
-            } else {
-                None
+                .ok_or_else(|| JsError::new("Invalid file size in file header."))?;
+
+            if file_size >= self.buf.len() {
+                self.buf.reserve_exact(file_size - self.buf.len());
+                self.buf.resize_with(file_size, MaybeUninit::uninit);
             }
+
+            Ok(Some(PointerAndLen {
+                pointer: self.buf.as_mut_ptr() as *mut u8,
+                len: file_size * 4,
+            }))
         }
     }
 
@@ -107,7 +116,10 @@ impl EmbeddingFileBuilder {
     ///   call to `avail`.
     ///
     /// After calling this method, the caller may no longer write to the buffer.
-    pub fn finish(self) -> EmbeddingHandle {
+    ///
+    /// Throws an error that describes the problem if the buffer doesn't contain a
+    /// valid compressed dynamic word embeddings file.
+    pub fn finish(self) -> Result<EmbeddingHandle, JsError> {
         unsafe {
             // This is really not safe, so the method should be declared as unsafe
             // but wasm-bindgen doesn't allow exporting unsafe function (isn't that
@@ -115,8 +127,10 @@ impl EmbeddingFileBuilder {
             let len = self.buf.len();
             let ptr = std::boxed::Box::into_raw(self.buf.into_boxed_slice());
             let u32_vec = Vec::from_raw_parts(ptr as *mut u32, len, len);
-            let embedding_file = EmbeddingFile::new(u32_vec.into()).unwrap();
-            EmbeddingHandle::new(embedding_file.into_random_access_reader())
+            let embedding_file = EmbeddingFile::new(u32_vec.into_boxed_slice())?;
+            Ok(EmbeddingHandle::new(
+                embedding_file.into_random_access_reader(),
+            ))
         }
     }
 }
@@ -140,14 +154,57 @@ impl EmbeddingHandle {
 
 #[wasm_bindgen]
 impl EmbeddingHandle {
-    pub fn pairwise_trajectories(&self, words1: Vec<u32>, words2: Vec<u32>) -> Vec<f32> {
+    pub fn pairwise_trajectories(
+        &self,
+        words1: Vec<u32>,
+        words2: Vec<u32>,
+    ) -> Result<Vec<f32>, JsError> {
+        Ok(self
+            .reader
+            .pairwise_trajectories(words1, words2)?
+            .into_inner())
+    }
+
+    pub fn most_related_to_at_t(
+        &self,
+        words: Vec<u32>,
+        t: u32,
+        amt: u32,
+    ) -> Result<Vec<u32>, JsError> {
+        Ok(self
+            .reader
+            .most_related_to_at_t(words, t, amt)?
+            .into_inner())
+    }
+
+    /// Returns the ID of `word`, or `undefined` if the file has no vocabulary or
+    /// `word` is not in it.
+    pub fn word_to_id(&self, word: &str) -> Option<u32> {
+        self.reader.file().word_to_id(word)
+    }
+
+    /// Returns the word with ID `id`, or `undefined` if the file has no vocabulary
+    /// or `id` is out of bounds.
+    pub fn id_to_word(&self, id: u32) -> Option<String> {
+        self.reader.file().id_to_word(id).map(Into::into)
+    }
+
+    /// Returns the labels of all time steps as strings, or an empty array if the
+    /// file has no time step labels.
+    pub fn timestep_labels(&self) -> Vec<JsValue> {
         self.reader
-            .pairwise_trajectories(words1, words2)
-            .into_inner()
+            .file()
+            .timestep_labels()
+            .into_iter()
+            .flat_map(|labels| labels.iter())
+            .map(|label| label.to_string().into())
+            .collect()
     }
 
-    pub fn most_related_to_at_t(&self, words: Vec<u32>, t: u32, amt: u32) -> Vec<u32> {
-        self.reader.most_related_to_at_t(words, t, amt).into_inner()
+    /// Returns the index of the time step with label `label`, or `undefined` if
+    /// the file has no time step labels or none of them matches `label`.
+    pub fn timestep_label_to_index(&self, label: &str) -> Option<u32> {
+        self.reader.file().timestep_labels()?.position_of_str(label)
     }
 
     pub fn largest_changes_wrt(
@@ -156,8 +213,9 @@ impl EmbeddingHandle {
         amt: u32,
         min_increasing: u32,
         min_decreasing: u32,
-    ) -> Vec<u32> {
-        self.reader
-            .largest_changes_wrt(target_word, amt, min_increasing, min_decreasing)
+    ) -> Result<Vec<u32>, JsError> {
+        Ok(self
+            .reader
+            .largest_changes_wrt(target_word, amt, min_increasing, min_decreasing)?)
     }
 }